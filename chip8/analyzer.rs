@@ -5,37 +5,52 @@ use chip8::sim::Interpreter;
 use chip8::state::State;
 use chip8::arch::*;
 use graph::flow::*;
+use std::collections::BTreeSet;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::time::Instant;
+
+// States reaching a node in a slice are widened once this many have been
+// added there, so that loops in the slice stop adding new states...
+const WIDEN_AFTER: usize = 4;
+// ...and a slice that still hasn't converged after this many states, e.g.
+// because of recursion, is given up on, even with no limit on states.
+const MAX_SLICE_STATES: usize = 10000;
 
 pub struct Analyzer {
     pub old_shift_behavior: bool
 }
 
 impl<'a> Analyzer {
-    fn from_operand(&self, graph: &FlowGraph<Instruction>, offset: usize, operand: Operand) -> Result<Slice<Instruction, State<'a>>, String> {
+    fn from_operand(&self, graph: &FlowGraph<Instruction>, offset: usize, operand: Operand) -> Result<Slice<Instruction, State<'a>>, DecompilerError> {
+        self.from_operands(graph, offset, &[operand])
+    }
+
+    fn from_operands(&self, graph: &FlowGraph<Instruction>, offset: usize, operands: &[Operand]) -> Result<Slice<Instruction, State<'a>>, DecompilerError> {
         println!("{}", graph);
 
-        match operand {
-            Operand::I | Operand::V(_) => (),
-            _ => return Err(String::from("Invalid operand to construct slice from."))
+        for operand in operands.iter() {
+            match *operand {
+                Operand::I | Operand::V(_) => (),
+                _ => return Err(unresolved(offset, graph.instruction_at(offset)?,
+                    "Invalid operand to construct slice from."))
+            }
         }
             
-        let mut current_node = match graph.get_node_at(offset) {
-            None => return Err(format!("no node found at offset 0x{:x}", offset)),
-            Some(node) => node
-        };
+        let mut current_node = graph.node_at(offset)?;
 
         let call_graph = graph.call_graph()?;
-        let mut coverage_sets: HashMap<usize, HashSet<Operand>> = HashMap::new();
-        let mut coverage: HashSet<Operand> = [operand].iter().cloned().collect();
+        let mut coverage_sets: HashMap<usize, BTreeSet<Operand>> = HashMap::new();
+        let mut coverage: BTreeSet<Operand> = operands.iter().cloned().collect();
         let mut insts = graph.get_instructions_at(current_node);
         let mut index = insts.iter().position(|&inst| inst == offset).unwrap();
         let mut live_nodes = Vec::new();
 
         let mut offsets: HashSet<usize> = [offset].iter().cloned().collect();
-        let mut entry_nodes = HashSet::new();
-        let mut explored_nodes: HashSet<usize> = [current_node].iter().cloned().collect();
+        // Ordered, so that the slice's nodes are numbered, and so its
+        // states simulated, the same way every run.
+        let mut entry_nodes = BTreeSet::new();
+        let mut explored_nodes: BTreeSet<usize> = [current_node].iter().cloned().collect();
 
         loop {
             if coverage.len() == 0 {
@@ -94,8 +109,13 @@ impl<'a> Analyzer {
             }
 
             let inst_offset = insts[index];
-            let inst = graph.get_inst(inst_offset).expect(format!(
-                    "graph doesn't contain index {}", inst_offset).as_str()).unwrap();
+            let inst = match graph.get_inst(inst_offset) {
+                Some(&Meta::Inst(inst)) => inst,
+                // Meta-instructions don't touch registers or memory.
+                Some(_) => continue,
+                None => return Err(DecompilerError::graph_at_offset(inst_offset,
+                    String::from("no instruction in listing")))
+            };
 
             println!("coverage set: {:?}", coverage);
             println!("considering offset 0x{:x}: instruction {}", inst_offset, inst);
@@ -118,7 +138,8 @@ impl<'a> Analyzer {
                         coverage.insert(inst.op1.expect("expected op1"));
                         match inst.op2 {
                             None => {
-                                return Err(String::from("expected op2 for ADD instruction"));
+                                return Err(unresolved(inst_offset, inst,
+                                    "expected op2 for ADD instruction"));
                             },
                             Some(Operand::V(x)) => { coverage.insert(Operand::V(x)); }
                             _ => ()
@@ -165,7 +186,8 @@ impl<'a> Analyzer {
                                             coverage.insert(Operand::V(index));
                                         }
                                     },
-                                    _ => return Err(String::from("expected operand 2 to be register"))
+                                    _ => return Err(unresolved(inst_offset, inst,
+                                        "expected operand 2 to be register"))
                                 }
                             }
                         },
@@ -177,7 +199,7 @@ impl<'a> Analyzer {
                     coverage.insert(inst.op1.expect("expected op1"));
                     match inst.op2 {
                         None => {
-                            return Err(String::from("exported op2"));
+                            return Err(unresolved(inst_offset, inst, "expected op2"));
                         },
                         Some(Operand::V(x)) => { coverage.insert(Operand::V(x)); },
                         _ => ()
@@ -187,7 +209,7 @@ impl<'a> Analyzer {
                         offsets.insert(inst_offset);
                         match inst.op2 {
                             None => {
-                                return Err(String::from("exported op2"));
+                                return Err(unresolved(inst_offset, inst, "expected op2"));
                             },
                             Some(Operand::V(x)) => { coverage.insert(Operand::V(x)); },
                             _ => ()
@@ -245,8 +267,10 @@ impl<'a> Analyzer {
                     empty = false;
                 }
 
-                let inst = graph.get_inst(*offset).unwrap().unwrap();
-                slice.add_inst_to_listing(*offset, inst);
+                if let Some(&Meta::Inst(inst)) = graph.get_inst(*offset) {
+                    slice.add_inst_to_listing(*offset, inst);
+                    slice.set_code_offset(*offset, graph.listing().code_offset(*offset));
+                }
             }
 
             empty_nodes.push(empty);
@@ -286,7 +310,7 @@ impl<'a> Analyzer {
         Ok(slice)
     }
 
-    fn simulate_slice(&self, file_buffer: &'a [u8], mut slice: Slice<Instruction, State<'a>>, target_offset: usize) -> Result<Vec<State<'a>>, String> {
+    fn simulate_slice(&self, file_buffer: &'a [u8], mut slice: Slice<Instruction, State<'a>>, target_offset: usize, limits: &Limits) -> Result<Vec<State<'a>>, DecompilerError> {
         println!("{}", slice);
         let interpreter = Interpreter {
             old_shift_behavior: self.old_shift_behavior
@@ -308,11 +332,33 @@ impl<'a> Analyzer {
             slice.add_state(state, node);
         }
 
+        let max_states = limits.max_states.map_or(MAX_SLICE_STATES,
+            |max_states| max_states.min(MAX_SLICE_STATES));
+        let mut simulated = 0;
+        let mut steps = 0;
+
         while let Some(mut state) = slice.next_live_state() {
             slice.log_state_count();
+
+            simulated += 1;
+            if simulated > max_states {
+                return Err(invalid(target_offset, slice.instruction_at(target_offset)?,
+                    &format!("stopped simulating the slice after {} states", max_states)));
+            }
+
+            if let Some(deadline) = limits.deadline {
+                if Instant::now() >= deadline {
+                    return Err(invalid(target_offset, slice.instruction_at(target_offset)?,
+                        "stopped simulating the slice at the deadline"));
+                }
+            }
+
             let offset = Interpreter::next_inst_offset(&state);
-            let node = slice.get_node_at(offset)
-                .expect(format!("Expected instruction at offset {}", offset).as_str());
+            let node = match slice.get_node_at(offset) {
+                None => return Err(DecompilerError::graph_at_offset(offset,
+                    String::from("Expected instruction at offset"))),
+                Some(node) => node
+            };
 
             let insts: Vec<usize> = slice.get_instructions_at(node)
                 .iter()
@@ -327,23 +373,28 @@ impl<'a> Analyzer {
                     let mut new_state = state.clone();
                     let offset = slice.initial_instruction(adjacent_node)?;
                     new_state.pc = offset as u16 + 0x200;
-                    slice.add_state(new_state, adjacent_node);
+                    slice.add_widened_state(new_state, adjacent_node, WIDEN_AFTER);
                 }
             }
 
             while index < insts.len() {
                 let offset = insts[index];
-                state.pc = offset as u16 + 0x200;
+                state.pc = slice.code_offset(offset) as u16 + 0x200;
                 
                 if offset == target_offset {
                     println!("{}", state);
                     final_states.push(state.clone());
                 }
 
-                let inst = match slice.get_inst(offset) {
-                    None => panic!("no instruction at offset {}", offset),
-                    Some(inst) => inst.unwrap()
-                };
+                let inst = slice.instruction_at(offset)?;
+
+                steps += 1;
+                if let Some(max_steps) = limits.max_steps {
+                    if steps > max_steps {
+                        return Err(invalid(target_offset, slice.instruction_at(target_offset)?,
+                            &format!("stopped simulating the slice after {} instructions", max_steps)));
+                    }
+                }
                     
                 match interpreter.simulate_next_instruction(state, inst) {
                     SimResult::State(next_state) => {
@@ -357,7 +408,7 @@ impl<'a> Analyzer {
                         for new_state in new_states {
                             if let Some(node) = slice.get_node_at(
                                 Interpreter::next_inst_offset(&new_state)) {
-                                slice.add_state(new_state, node);
+                                slice.add_widened_state(new_state, node, WIDEN_AFTER);
                             }
                         }
                         break;
@@ -371,7 +422,7 @@ impl<'a> Analyzer {
                         let mut new_state = state.clone();
                         let offset = slice.initial_instruction(adjacent_node)?;
                         new_state.pc = offset as u16 + 0x200;
-                        slice.add_state(new_state, adjacent_node);
+                        slice.add_widened_state(new_state, adjacent_node, WIDEN_AFTER);
                     }
                 }
             }
@@ -382,7 +433,7 @@ impl<'a> Analyzer {
 }
 
 impl AnalyzerTrait<Instruction> for Analyzer {
-    fn written_offsets(&self, file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize) -> Result<HashSet<usize>, String> {
+    fn written_offsets(&self, file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize, limits: &Limits) -> Result<HashSet<usize>, DecompilerError> {
         let slice = match self.from_operand(graph, offset, Operand::I) {
             Err(err) => {
                 println!("Error generating slice:");
@@ -391,7 +442,7 @@ impl AnalyzerTrait<Instruction> for Analyzer {
             Ok(slice) => slice
         };
 
-        let states = match self.simulate_slice(file_buffer, slice, offset) {
+        let states = match self.simulate_slice(file_buffer, slice, offset, limits) {
             Err(err) => {
                 println!("Error simulating slice:");
                 return Err(err);
@@ -399,14 +450,10 @@ impl AnalyzerTrait<Instruction> for Analyzer {
             Ok(states) => states
         };
 
+        let instruction = graph.instruction_at(offset)?;
         let value = states.iter().fold(
             Value::Word(Word::from_vec(Vec::new())),
             |acc, state| {
-                let instruction = graph
-                    .get_inst(state.pc as usize - 0x200)
-                    .unwrap()
-                    .unwrap();
-
                 let base = state.get_value(Operand::I);
 
                 let range = match instruction.op2 {
@@ -420,33 +467,103 @@ impl AnalyzerTrait<Instruction> for Analyzer {
         );
 
         match value {
-            Value::Byte(_) => Err("expected word".into()),
-            Value::Word(word) => match word {
-                Word::Undefined => Err("undefined word".into()),
-                Word::AnyValue => Err("word can have any value".into()),
-                Word::Bytes(_, _) => Err("word can be two bytes".into()),
-                Word::Int(set) => Ok(set.iter().map(|&value| (value - 0x200) as usize).collect())
+            Value::Byte(_) => Err(invalid(offset, instruction, "expected word")),
+            Value::Word(word) => match word.expand() {
+                Word::Undefined => Err(invalid(offset, instruction, "undefined word")),
+                Word::AnyValue => Err(invalid(offset, instruction, "word can have any value")),
+                Word::Bytes(_, _) => Err(invalid(offset, instruction, "word can be two bytes")),
+                Word::Int(set) => Ok(set.iter().map(|&value| (value - 0x200) as usize).collect()),
+                _ => panic!("shouldn't be here")
             }
         }
     }
 
-    fn determine_successors(&self, file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize) -> Result<HashSet<usize>, String> {
-        let instruction = match graph.get_inst(offset) {
-            None => return Err(format!("no instruction found at offset 0x{:x}", offset)),
-            Some(instruction) => instruction.unwrap()
+    fn written_bytes(&self, file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize, limits: &Limits) -> Result<HashMap<usize, Option<HashSet<u8>>>, DecompilerError> {
+        let instruction = graph.instruction_at(offset)?;
+        let last_register = match instruction.op2 {
+            Some(Operand::V(reg)) => reg,
+            _ => panic!("expected op2")
+        };
+
+        // The registers written are sliced along with I, so that what
+        // they hold is known as well as where it goes.
+        let mut operands = vec!(Operand::I);
+        operands.extend((0..=last_register).map(|reg| Operand::V(reg)));
+
+        let states = match self.from_operands(graph, offset, &operands)
+            .and_then(|slice| self.simulate_slice(file_buffer, slice, offset, limits)) {
+            Ok(states) => states,
+            Err(err) => {
+                // The registers can take in much more of the program
+                // than I, which can't always be simulated. Where the
+                // write goes is still worth knowing.
+                println!("Error slicing written values: {}", err);
+                let written = self.written_offsets(file_buffer, graph, offset, limits)?;
+                return Ok(written.into_iter().map(|written_offset| (written_offset, None)).collect());
+            }
         };
 
+        let mut written: HashMap<usize, Option<HashSet<u8>>> = HashMap::new();
+        for state in states.iter() {
+            let addresses = match state.get_value(Operand::I) {
+                Value::Byte(_) => return Err(invalid(offset, instruction, "expected word")),
+                Value::Word(word) => match word.expand() {
+                    Word::Undefined => return Err(invalid(offset, instruction, "undefined word")),
+                    Word::AnyValue => return Err(invalid(offset, instruction, "word can have any value")),
+                    Word::Bytes(_, _) => return Err(invalid(offset, instruction, "word can be two bytes")),
+                    Word::Int(set) => set,
+                    _ => panic!("shouldn't be here")
+                }
+            };
+
+            for reg in 0..=last_register {
+                let values = match state.get_value(Operand::V(reg)) {
+                    Value::Word(_) => return Err(invalid(offset, instruction, "expected byte")),
+                    Value::Byte(byte) => match byte.expand() {
+                        Byte::Undefined | Byte::AnyValue => None,
+                        Byte::Int(set) => Some(set),
+                        _ => panic!("shouldn't be here")
+                    }
+                };
+
+                for address in addresses.iter() {
+                    // Writes below the program go to the interpreter's memory.
+                    let address = *address as usize + reg;
+                    if address < 0x200 {
+                        continue;
+                    }
+
+                    let entry = written.entry(address - 0x200)
+                        .or_insert(Some(HashSet::new()));
+                    *entry = match (entry.take(), values.clone()) {
+                        (Some(old_values), Some(new_values)) =>
+                            Some(old_values.union(&new_values).cloned().collect()),
+                        _ => None
+                    };
+                }
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn determine_successors(&self, file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize, limits: &Limits) -> Result<HashSet<usize>, DecompilerError> {
+        let instruction = graph.instruction_at(offset)?;
+
         if let Mnemonic::JP = instruction.mnemonic {
             if let Operand::V(reg) = instruction.unpack_op1() {
                 let slice = self.from_operand(graph, offset, Operand::V(reg))?;
-                let states = self.simulate_slice(file_buffer, slice, offset)?;
+                let states = self.simulate_slice(file_buffer, slice, offset, limits)?;
                 let mut offsets = HashSet::new();
                 for state in states {
                     match state.get_value(Operand::V(reg)) {
-                        Value::Word(_) => return Err("expected byte".into()),
-                        Value::Byte(byte) => match byte {
-                            Byte::Undefined => return Err("undefined byte".into()),
-                            Byte::AnyValue => return Err("byte can have any value".into()),
+                        Value::Word(_) =>
+                            return Err(unresolved(offset, instruction, "expected byte")),
+                        Value::Byte(byte) => match byte.expand() {
+                            Byte::Undefined =>
+                                return Err(unresolved(offset, instruction, "undefined byte")),
+                            Byte::AnyValue =>
+                                return Err(unresolved(offset, instruction, "byte can have any value")),
                             Byte::Int(set) => {
                                 match instruction.op2 {
                                     Some(Operand::Address(address)) =>
@@ -454,9 +571,11 @@ impl AnalyzerTrait<Instruction> for Analyzer {
                                             offsets.insert(address as usize
                                                 + byte as usize - 0x200);
                                         },
-                                    _ => return Err("op2 should be byte".into())
+                                    _ => return Err(unresolved(offset, instruction,
+                                        "op2 should be byte"))
                                 }
-                            }
+                            },
+                            _ => panic!("shouldn't be here")
                         }
                     }
                 }
@@ -465,6 +584,22 @@ impl AnalyzerTrait<Instruction> for Analyzer {
             }
         }
 
-        Err(format!("not sure how to determine successors for instruction {}", instruction))
+        Err(unresolved(offset, instruction, "not sure how to determine successors"))
+    }
+}
+
+fn unresolved(offset: usize, instruction: Instruction, reason: &str) -> DecompilerError {
+    DecompilerError::UnresolvedBranch {
+        offset: offset,
+        instruction: format!("{}", instruction),
+        reason: String::from(reason)
+    }
+}
+
+fn invalid(offset: usize, instruction: Instruction, reason: &str) -> DecompilerError {
+    DecompilerError::InvalidState {
+        offset: offset,
+        instruction: format!("{}", instruction),
+        reason: String::from(reason)
     }
 }
@@ -47,7 +47,7 @@ fn main() {
 
     let result = analyse::analyse(
         &buffer, chip8::arch::Chip8 {},
-        &analyzer, 0);
+        &analyzer, 0, &defs::main::Limits::none());
 
     match result {
         Ok(graph) => {
@@ -55,7 +55,7 @@ fn main() {
                 .expect("Couldn't create output file.");
 
             let source = match compiler.source_string(graph, stem, buffer) {
-                Err(err) => panic!(err),
+                Err(err) => panic!("{}", err),
                 Ok(source) => source
             };
             
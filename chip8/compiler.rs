@@ -1,3 +1,4 @@
+use defs::main::{DecompilerError, InstructionTrait, Meta};
 use chip8::arch::*;
 use graph::flow::{Function, FlowGraph};
 
@@ -134,7 +135,7 @@ impl Compiler {
         MAKEFILE.into()
     }
 
-    pub fn source_string(&self, graph: FlowGraph<Instruction>, file_stem: &str, data: Vec<u8>) -> Result<String, String> {
+    pub fn source_string(&self, graph: FlowGraph<Instruction>, file_stem: &str, data: Vec<u8>) -> Result<String, DecompilerError> {
         let mut data_string = String::new();
 
         for byte in data {
@@ -171,13 +172,18 @@ impl Compiler {
                   .replace("{main}", &main))
     }
 
-    fn compile_function(&self, graph: &FlowGraph<Instruction>, function: Function, main: bool) -> Result<String, String> {
+    fn compile_function(&self, graph: &FlowGraph<Instruction>, function: Function, main: bool) -> Result<String, DecompilerError> {
         let mut output = String::new();
         let mut node_outputs = Vec::new();
 
         for node in function.nodes() {
             if let Some(offset) = graph.initial_instruction(*node)? {
-                node_outputs.push((offset, self.compile_node(&graph, *node, main)?));
+                // Meta-instructions choose versions of rewritten code with
+                // flags the analysis keeps. The compiled code has the
+                // memory itself, so it checks that instead.
+                if let Some(&Meta::Inst(_)) = graph.get_inst(offset) {
+                    node_outputs.push((offset, self.compile_node(&graph, *node, main)?));
+                }
             }
         }
 
@@ -190,15 +196,21 @@ impl Compiler {
         Ok(output)
     }
 
-    fn compile_node(&self, graph: &FlowGraph<Instruction>, node: usize, main: bool) -> Result<String, String> {
-        let node_address = graph.initial_instruction(node).unwrap()
-            .expect(format!("no instruction at node {}", node).as_str()) + 0x200;
+    fn compile_node(&self, graph: &FlowGraph<Instruction>, node: usize, main: bool) -> Result<String, DecompilerError> {
+        let node_address = match graph.initial_instruction(node)? {
+            None => return Err(DecompilerError::graph_at_node(node,
+                String::from("no instruction at node"))),
+            Some(offset) => offset + 0x200
+        };
         let mut output = format!("\nl{:x}:\n", node_address);
         
         for offset in graph.get_instructions_at(node) {
-            let inst = graph.get_inst(*offset)
-                .expect(format!("no instruction at offset {:x}", offset).as_str())
-                .unwrap();
+            let inst = graph.instruction_at(*offset)?;
+            let code_offset = graph.listing().code_offset(*offset);
+
+            if code_offset == *offset {
+                output.push_str(dispatch(graph, *offset).as_str());
+            }
 
             output.push_str("\t");
             output.push_str( match inst.mnemonic {
@@ -217,11 +229,11 @@ impl Compiler {
                 Mnemonic::ADD => add(inst.unpack_op1(), inst.unpack_op2()),
                 Mnemonic::SUB => sub(inst.unpack_op1(), inst.unpack_op2()),
                 Mnemonic::SUBN => subn(inst.unpack_op1(), inst.unpack_op2()),
-                Mnemonic::SNE => skip(false, *offset, inst.unpack_op1(), inst.unpack_op2()),
-                Mnemonic::SE => skip(true, *offset, inst.unpack_op1(), inst.unpack_op2()),
+                Mnemonic::SNE => skip(false, code_offset, inst.unpack_op1(), inst.unpack_op2()),
+                Mnemonic::SE => skip(true, code_offset, inst.unpack_op1(), inst.unpack_op2()),
                 Mnemonic::JP => jump(graph, *offset, inst.unpack_op1(), inst.op2)?,
-                Mnemonic::SKP => skip_key(true, *offset, inst.unpack_op1()),
-                Mnemonic::SKNP => skip_key(false, *offset, inst.unpack_op1()),
+                Mnemonic::SKP => skip_key(true, code_offset, inst.unpack_op1()),
+                Mnemonic::SKNP => skip_key(false, code_offset, inst.unpack_op1()),
                 Mnemonic::DRW => draw(inst.unpack_op1(), inst.unpack_op2(), inst.unpack_op3()),
                 Mnemonic::RND => random(inst.unpack_op1(), inst.unpack_op2()),
                 Mnemonic::CALL => call(inst.unpack_op1()),
@@ -231,6 +243,13 @@ impl Compiler {
                 Mnemonic::SCR => "scroll_right();\n".into(),
                 Mnemonic::SCD => format!("scroll_down({});\n", encode_op(inst.unpack_op1())),
             }.as_str());
+
+            // A version of rewritten code goes on where the code it
+            // replaces would.
+            if code_offset != *offset {
+                output.push_str(format!("\tgoto l{:x};\n",
+                    code_offset + inst.length() + 0x200).as_str());
+            }
         }
 
         Ok(output)
@@ -294,7 +313,7 @@ fn xor(op1: Operand, op2: Operand) -> String {
     format!("{} = {} ^ {};\n", lhs, lhs, encode_op(op2))
 }
 
-fn jump(graph: &FlowGraph<Instruction>, offset: usize, op1: Operand, op2: Option<Operand>) -> Result<String, String> {
+fn jump(graph: &FlowGraph<Instruction>, offset: usize, op1: Operand, op2: Option<Operand>) -> Result<String, DecompilerError> {
     match op1 {
         Operand::Address(address) => {
             Ok(format!("goto l{:x};\n", address))
@@ -302,16 +321,21 @@ fn jump(graph: &FlowGraph<Instruction>, offset: usize, op1: Operand, op2: Option
         Operand::V(0) => match op2 {
             Some(Operand::Address(address)) => {
                 let mut output = String::from("switch (V[0]) {\n");
-                let node = graph.get_node_at(offset).unwrap();
+                let node = graph.node_at(offset)?;
 
                 let (targets, _) = graph.get_next_nodes(node);
                 for target in targets {
-                    let target_offset = graph.initial_instruction(target)
-                        .unwrap().unwrap();
+                    let target_offset = match graph.initial_instruction(target)? {
+                        None => return Err(DecompilerError::graph_at_node(target,
+                            String::from("no instruction at node"))),
+                        Some(target_offset) => target_offset
+                    };
                     if (target_offset + 0x200) < address as usize {
-                        return Err(format!(
-                            "Instruction 'JMP V0, {:x}' at offset 0x{:x} leads to offset 0x{:x}",
-                            address, offset, target_offset));
+                        return Err(DecompilerError::UnresolvedBranch {
+                            offset: offset,
+                            instruction: format!("JP V0, {:x}", address),
+                            reason: format!("leads to offset 0x{:x}", target_offset)
+                        });
                     }
                     let v_0 = (target_offset + 0x200) - address as usize;
                     output.push_str(format!("\t\tcase {}:\n\t\tgoto l{:x};\n\n",
@@ -328,9 +352,17 @@ fn jump(graph: &FlowGraph<Instruction>, offset: usize, op1: Operand, op2: Option
 
                 Ok(output)
             },
-            _ => Err(String::from("Invalid operand for JMP V0, ?."))
+            _ => Err(DecompilerError::UnresolvedBranch {
+                offset: offset,
+                instruction: String::from("JP V0, ?"),
+                reason: String::from("Invalid operand for JMP V0, ?.")
+            })
         },
-        _ => Err(String::from("Invalid operand for JMP."))
+        _ => Err(DecompilerError::UnresolvedBranch {
+            offset: offset,
+            instruction: String::from("JP"),
+            reason: String::from("Invalid operand for JMP.")
+        })
     }
 }
  
@@ -358,6 +390,23 @@ fn load_ptr(op1: Operand, op2: Operand) -> String {
     }
 }
 
+// Jumps to the version of the instruction at offset that's in memory,
+// if code has rewritten it.
+fn dispatch(graph: &FlowGraph<Instruction>, offset: usize) -> String {
+    let mut output = String::new();
+
+    for version in graph.listing().versions(offset).iter().skip(1) {
+        let comparisons: Vec<String> = version.bytes.iter().enumerate()
+            .map(|(index, byte)| format!("memory[0x{:x}] == 0x{:02x}",
+                offset + index + 0x200, byte))
+            .collect();
+        output.push_str(format!("\tif ({}) goto l{:x};\n",
+            comparisons.join(" && "), version.listed_at + 0x200).as_str());
+    }
+
+    output
+}
+
 fn skip(equal: bool, offset: usize, op1: Operand, op2: Operand) -> String {
     let address = offset + 0x200;
     let comparison = if equal { "==" } else { "!=" };
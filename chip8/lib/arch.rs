@@ -1,20 +1,236 @@
 use defs::main::*;
+use defs::data::{DataRegion, DataType};
+use defs::ir::{self, LifterTrait};
 use chip8::dis;
+use chip8::lift::Lifter;
+use graph::summary::FunctionSummary;
+use std::collections::HashMap;
 use std::fmt;
 
 #[derive(Copy, Clone)]
 pub struct Chip8 {}
 
+fn function_name(listing: &Listing<Instruction>, offset: usize) -> String {
+    if offset == listing.entry_offset {
+        String::from("main")
+    } else {
+        format!("sub_{:x}", offset + 0x200)
+    }
+}
+
+fn location_names(locations: &[ir::Location]) -> Vec<String> {
+    let lifter = Lifter { old_shift_behavior: false };
+    locations.iter().map(|location| match *location {
+        ir::Location::Reg(index) => lifter.register_name(index),
+        ir::Location::Flag(index) => lifter.flag_name(index),
+        ir::Location::Port(index) => lifter.port_name(index),
+        ir::Location::Temp(index) => format!("t{}", index),
+        ir::Location::Memory => String::from("memory")
+    }).collect()
+}
+
+// Prints a call to the function summary describes, as in
+// "VD = sub_276(V0)".
+fn call(listing: &Listing<Instruction>, summary: &FunctionSummary) -> String {
+    let call = format!("{}({})", function_name(listing, summary.entry),
+        location_names(&summary.parameters).join(", "));
+
+    match summary.results.len() {
+        0 => call,
+        1 => format!("{} = {}", location_names(&summary.results)[0], call),
+        _ => format!("({}) = {}", location_names(&summary.results).join(", "), call)
+    }
+}
+
+// Prints the signature of the function summary describes, along with
+// the memory it touches, as in "sub_276(V0, I) -> VD; reads 2e8...2f7".
+fn signature(listing: &Listing<Instruction>, summary: &FunctionSummary) -> String {
+    let mut signature = format!("{}({})", function_name(listing, summary.entry),
+        location_names(&summary.parameters).join(", "));
+
+    if !summary.results.is_empty() {
+        signature.push_str(format!(" -> {}", location_names(&summary.results).join(", ")).as_str());
+    }
+    if !summary.memory_reads.is_empty() {
+        signature.push_str(format!("; reads {}", summary.memory_reads).as_str());
+    }
+    if !summary.memory_writes.is_empty() {
+        signature.push_str(format!("; writes {}", summary.memory_writes).as_str());
+    }
+
+    signature
+}
+
 impl<'a> Architecture<Instruction> for Chip8 {
-    fn decode_instruction(&self, buffer: &[u8], offset: usize) -> Result<Instruction, String> {
-        dis::decode_instruction(buffer, offset)
+    fn decode_instruction(&self, buffer: &[u8], offset: usize) -> Result<Instruction, DecompilerError> {
+        if offset + 1 >= buffer.len() {
+            return Err(DecompilerError::Decode {
+                offset,
+                reason: String::from("instruction runs past end of file")
+            });
+        }
+
+        dis::decode_instruction(buffer, offset).map_err(|reason|
+            DecompilerError::Decode {
+                offset,
+                reason
+            })
     }
 
     fn print_listing(listing: &Listing<Instruction>) {
+        Chip8::print_summarized_listing(listing, &[]);
+    }
+
+    // Infers sprites from DRW instructions, using the last address
+    // loaded into I in straight-line code before them. I is forgotten
+    // at labels, calls, and instructions that change it otherwise.
+    //
+    // Also infers jump tables from the bases of JP V0 instructions: the
+    // JP instructions from the base on that haven't been disassembled.
+    fn data_regions(&self, file_buffer: &[u8], listing: &Listing<Instruction>) -> Vec<DataRegion> {
+        let mut regions = Vec::new();
+        let mut index = None;
+
+        for offset in 0..file_buffer.len() {
+            if listing.is_labelled(offset) {
+                index = None;
+            }
+
+            if let Some(&Meta::Inst(inst)) = listing.get(offset) {
+                match (inst.mnemonic, inst.op1, inst.op2, inst.op3) {
+                    (Mnemonic::LD, Some(Operand::I), Some(Operand::Address(address)), _) =>
+                        index = Some(address as usize),
+                    (Mnemonic::DRW, _, _, Some(Operand::Byte(height))) => {
+                        if let Some(address) = index {
+                            let (length, row_bytes) = match height {
+                                0 => (32, 2),
+                                _ => (height as usize, 1)
+                            };
+
+                            if address >= 0x200 && address - 0x200 + length <= file_buffer.len() {
+                                let start = address - 0x200;
+                                regions.push(DataRegion::new(start, DataType::Sprite(row_bytes),
+                                    &file_buffer[start..(start + length)]));
+                            }
+                        }
+                    },
+                    (Mnemonic::JP, Some(Operand::V(0)), Some(Operand::Address(base)), _) => {
+                        if let Some(region) = jump_table(file_buffer, listing, base as usize) {
+                            regions.push(region);
+                        }
+                    },
+                    (Mnemonic::CALL, _, _, _) | (Mnemonic::LDPTR, _, _, _)
+                    | (_, Some(Operand::I), _, _) => index = None,
+                    _ => ()
+                }
+            }
+        }
+
+        regions
+    }
+}
+
+// Returns the JP instructions from the address on, up to the first
+// other word or disassembled code, as a jump table.
+fn jump_table(file_buffer: &[u8], listing: &Listing<Instruction>, address: usize) -> Option<DataRegion> {
+    if address < 0x200 {
+        return None;
+    }
+
+    let start = address - 0x200;
+    let mut end = start;
+    while end + 2 <= file_buffer.len() && file_buffer[end] & 0xf0 == 0x10
+        && !listing.is_code(end) && !listing.is_code(end + 1) {
+        end += 2;
+    }
+
+    if end > start {
+        Some(DataRegion::new(start, DataType::JumpTable, &file_buffer[start..end]))
+    } else {
+        None
+    }
+}
+                
+#[derive(Copy, Clone)]
+pub struct Instruction {
+    pub mnemonic: Mnemonic,
+    pub op1: Option<Operand>,
+    pub op2: Option<Operand>,
+    pub op3: Option<Operand>
+}
+
+impl Chip8 {
+    // Prints listing, noting the signature of each function summaries
+    // describes at its entry and what each call to one leaves behind.
+    pub fn print_summarized_listing(listing: &Listing<Instruction>, summaries: &[FunctionSummary]) {
+        let summaries: HashMap<usize, &FunctionSummary> = summaries.iter()
+            .map(|summary| (summary.entry, summary))
+            .collect();
         let mut output = String::new();
         let mut last_inst_was_skip = false;
+        let mut data_count = 0;
+        let mut region_end = 0;
         for i in 0..0x1000 {
+            if i < region_end {
+                continue;
+            }
+
+            if let Some(region) = listing.get_region(i) {
+                if data_count > 0 {
+                    output.push('\n');
+                    data_count = 0;
+                }
+
+                for (index, directive) in region.directives(true).iter().enumerate() {
+                    if index == 0 && listing.is_labelled(i) {
+                        output.push_str(format!("{:4x}:   ", i + 0x200).as_str());
+                    } else {
+                        output.push_str("        ");
+                    }
+                    output.push_str(format!("{}\n", directive).as_str());
+                }
+
+                region_end = region.end();
+                last_inst_was_skip = false;
+                continue;
+            }
+
+            if let Some(byte) = listing.get_data(i) {
+                if data_count == 0 || data_count == 8 || listing.is_labelled(i) {
+                    if data_count > 0 {
+                        output.push('\n');
+                    }
+
+                    if listing.is_labelled(i) {
+                        output.push_str(format!("{:4x}:   ", i + 0x200).as_str());
+                    } else {
+                        output.push_str("        ");
+                    }
+
+                    output.push_str(format!("DB 0x{:02x}", byte).as_str());
+                    data_count = 1;
+                } else {
+                    output.push_str(format!(", 0x{:02x}", byte).as_str());
+                    data_count += 1;
+                }
+                last_inst_was_skip = false;
+                continue;
+            } else if data_count > 0 {
+                output.push('\n');
+                data_count = 0;
+            }
+
+            if let Some(error) = listing.get_invalid(i) {
+                output.push_str(format!("{:4x}:   ; {}\n", i + 0x200, error).as_str());
+                last_inst_was_skip = false;
+                continue;
+            }
+
             if let Some(Meta::Inst(instruction)) = listing.instructions.get(&i) {
+                if let Some(summary) = summaries.get(&i) {
+                    output.push_str(format!("        ; {}\n", signature(listing, summary)).as_str());
+                }
+
                 if listing.is_labelled(i) {
                     output.push_str(format!("{:4x}:   ", i + 0x200).as_str());
                 } else {
@@ -32,6 +248,36 @@ impl<'a> Architecture<Instruction> for Chip8 {
                     } else {
                         ""
                     }, instruction).as_str());
+
+                // The versions other code can rewrite it into.
+                for version in listing.versions(i).iter().skip(1) {
+                    if let Some(Meta::Inst(rewritten)) = listing.instructions.get(&version.listed_at) {
+                        let writers: Vec<String> = version.writers.iter()
+                            .map(|writer| format!("{:x}", writer + 0x200))
+                            .collect();
+                        output.push_str(format!("        ; or {}, written at {}\n",
+                            rewritten, writers.join(", ")).as_str());
+                    }
+                }
+
+                // What the call leaves for the code after it.
+                if let Ok((_, callees, _, _)) = instruction.successors(i) {
+                    for callee in callees {
+                        if let Some(summary) = summaries.get(&callee) {
+                            output.push_str(format!("        ; {}\n", call(listing, summary)).as_str());
+                        }
+                    }
+                }
+
+                // Other instructions decoded from some of the same bytes.
+                let overlapping: Vec<String> = listing.overlapping(i).iter()
+                    .map(|offset| format!("{:x}", offset + 0x200))
+                    .collect();
+                if !overlapping.is_empty() {
+                    output.push_str(format!("        ; overlaps {}\n",
+                        overlapping.join(", ")).as_str());
+                }
+
                 if instruction.mnemonic == Mnemonic::SE
                     || instruction.mnemonic == Mnemonic::SNE
                     || instruction.mnemonic == Mnemonic::SKP
@@ -44,17 +290,12 @@ impl<'a> Architecture<Instruction> for Chip8 {
                 }
             }
         }
+        if data_count > 0 {
+            output.push('\n');
+        }
         println!("{}", output);
     }
 }
-                
-#[derive(Copy, Clone)]
-pub struct Instruction {
-    pub mnemonic: Mnemonic,
-    pub op1: Option<Operand>,
-    pub op2: Option<Operand>,
-    pub op3: Option<Operand>
-}
 
 impl Instruction {
     pub fn new(mnemonic: Mnemonic) -> Instruction {
@@ -107,16 +348,25 @@ impl InstructionTrait for Instruction {
         false
     }
 
-    fn successors(&self, offset: usize) -> (Vec<usize>, Vec<usize>, bool, bool) {
-        match self.mnemonic {
-            Mnemonic::CALL => match self.unpack_op1() {
-                Operand::Address(address) =>
+    fn successors(&self, offset: usize) -> Result<(Vec<usize>, Vec<usize>, bool, bool), DecompilerError> {
+        Ok(match self.mnemonic {
+            Mnemonic::CALL => match self.op1 {
+                Some(Operand::Address(address)) if address >= 0x200 =>
                     (vec!(offset + 2), vec!(address as usize - 0x200), true, false),
-                _ => panic!("CALL instruction should have address as operand.")
+                _ => return Err(DecompilerError::UnresolvedBranch {
+                    offset,
+                    instruction: format!("{}", self),
+                    reason: String::from("CALL instruction should have a program address as operand.")
+                })
             },
-            Mnemonic::JP => match self.unpack_op1() {
-                Operand::Address(address) =>
+            Mnemonic::JP => match self.op1 {
+                Some(Operand::Address(address)) if address >= 0x200 =>
                     (vec!(address as usize - 0x200), Vec::new(), true, false),
+                Some(Operand::Address(_)) => return Err(DecompilerError::UnresolvedBranch {
+                    offset,
+                    instruction: format!("{}", self),
+                    reason: String::from("JP target lies below the program load address.")
+                }),
                 _ => (Vec::new(), Vec::new(), true, true)
             },
             Mnemonic::RET => (Vec::new(), Vec::new(), true, false),
@@ -124,7 +374,17 @@ impl InstructionTrait for Instruction {
                 (vec!(offset + 2, offset + 4), Vec::new(), true, false),
             Mnemonic::EXIT => (Vec::new(), Vec::new(), true, false),
             _ => (vec!(offset + 2), Vec::new(), false, false)
-        }
+        })
+    }
+
+    fn mnemonic(&self) -> String {
+        format!("{:?}", self.mnemonic)
+    }
+
+    fn operands(&self) -> Vec<String> {
+        [self.op1, self.op2, self.op3].iter()
+            .filter_map(|operand| operand.map(|operand| format!("{}", operand)))
+            .collect()
     }
 }
 
@@ -144,7 +404,7 @@ impl fmt::Display for Instruction {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum Operand {
     I,
     V(usize),
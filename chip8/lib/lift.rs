@@ -0,0 +1,352 @@
+use defs::ir;
+use defs::ir::{Mnemonic as Op, Operand as Arg, Width};
+use chip8::arch::*;
+
+// Registers V0 to VF are IR registers 0 to 15, and I is register 16.
+// CHIP-8 has no flags: carries and borrows go to VF.
+pub const REG_VF: usize = 0xf;
+pub const REG_I: usize = 16;
+
+// Devices are modeled as ports. Display commands (CLS, SCD, ...) are
+// written to the display port as their opcode; DRW writes its
+// coordinates and sprite rows, then reads the collision flag back.
+pub const PORT_DISPLAY: usize = 0;
+pub const PORT_KEYS: usize = 1;
+pub const PORT_KEY_WAIT: usize = 2;
+pub const PORT_DELAY_TIMER: usize = 3;
+pub const PORT_SOUND_TIMER: usize = 4;
+pub const PORT_RANDOM: usize = 5;
+pub const PORT_USER_FLAGS: usize = 6;
+
+// Lifts CHIP-8 instructions, following the semantics of the
+// interpreter in chip8::sim. old_shift_behavior selects the same
+// shift quirk as there.
+pub struct Lifter {
+    pub old_shift_behavior: bool
+}
+
+fn v(x: usize) -> Arg {
+    Arg::Reg(x, Width::Byte)
+}
+
+fn i() -> Arg {
+    Arg::Reg(REG_I, Width::Word)
+}
+
+fn byte(value: u8) -> Arg {
+    Arg::Const(value as u32, Width::Byte)
+}
+
+fn word(value: u32) -> Arg {
+    Arg::Const(value, Width::Word)
+}
+
+fn temp8(index: usize) -> Arg {
+    Arg::Temp(index, Width::Byte)
+}
+
+fn temp16(index: usize) -> Arg {
+    Arg::Temp(index, Width::Word)
+}
+
+fn bit(index: usize) -> Arg {
+    Arg::Temp(index, Width::Bit)
+}
+
+// Returns the IR operand for a byte-sized CHIP-8 operand, or nothing
+// if it isn't a register or a byte.
+fn byte_operand(operand: Option<Operand>) -> Option<Arg> {
+    match operand {
+        Some(Operand::V(x)) => Some(v(x)),
+        Some(Operand::Byte(value)) => Some(byte(value)),
+        _ => None
+    }
+}
+
+impl ir::LifterTrait<Instruction> for Lifter {
+    fn lift(&self, inst: Instruction, offset: usize) -> Vec<ir::Instruction> {
+        match inst.mnemonic {
+            Mnemonic::CLS => vec!(display_command(0x00e0)),
+            Mnemonic::SCD => match inst.op1 {
+                Some(Operand::Byte(n)) => vec!(display_command(0x00c0 | n as u32)),
+                _ => unsupported()
+            },
+            Mnemonic::SCR => vec!(display_command(0x00fb)),
+            Mnemonic::SCL => vec!(display_command(0x00fc)),
+            Mnemonic::LOW => vec!(display_command(0x00fe)),
+            Mnemonic::HIGH => vec!(display_command(0x00ff)),
+            Mnemonic::EXIT => vec!(ir::Instruction::new(Op::HALT)),
+            Mnemonic::RET => vec!(ir::Instruction::new(Op::RET)),
+            Mnemonic::CALL => match inst.op1 {
+                Some(Operand::Address(address)) if address >= 0x200 =>
+                    vec!(ir::Instruction::unary(Op::CALL,
+                        Arg::Address(address as usize - 0x200))),
+                _ => unsupported()
+            },
+            Mnemonic::JP => lift_jump(inst),
+            Mnemonic::SE | Mnemonic::SNE => {
+                let jump = if inst.mnemonic == Mnemonic::SE { Op::JMPNZ } else { Op::JMPZ };
+                match (byte_operand(inst.op1), byte_operand(inst.op2)) {
+                    (Some(x), Some(y)) => vec!(
+                        ir::Instruction::ternary(Op::CMPEQ, bit(0), x, y),
+                        ir::Instruction::binary(jump, bit(0), Arg::Address(offset + 4))
+                    ),
+                    _ => unsupported()
+                }
+            },
+            Mnemonic::SKP | Mnemonic::SKNP => {
+                let jump = if inst.mnemonic == Mnemonic::SKP { Op::JMPNZ } else { Op::JMPZ };
+                match byte_operand(inst.op1) {
+                    Some(key) => vec!(
+                        ir::Instruction::binary(Op::IN, temp16(0), Arg::Port(PORT_KEYS)),
+                        ir::Instruction::binary(Op::ZEXT, temp16(1), key),
+                        ir::Instruction::ternary(Op::SHR, temp16(0), temp16(0), temp16(1)),
+                        ir::Instruction::binary(Op::TRUNC, bit(2), temp16(0)),
+                        ir::Instruction::binary(jump, bit(2), Arg::Address(offset + 4))
+                    ),
+                    None => unsupported()
+                }
+            },
+            Mnemonic::LD => lift_ld(inst),
+            Mnemonic::ADD => match (inst.unpack_op1(), inst.unpack_op2()) {
+                (Operand::I, Operand::V(x)) => vec!(
+                    ir::Instruction::binary(Op::ZEXT, temp16(0), v(x)),
+                    ir::Instruction::ternary(Op::ADD, i(), i(), temp16(0))
+                ),
+                (Operand::V(x), Operand::Byte(value)) =>
+                    vec!(ir::Instruction::ternary(Op::ADD, v(x), v(x), byte(value))),
+                (Operand::V(x), Operand::V(y)) => vec!(
+                    ir::Instruction::ternary(Op::CARRY, bit(0), v(x), v(y)),
+                    ir::Instruction::ternary(Op::ADD, v(x), v(x), v(y)),
+                    ir::Instruction::binary(Op::ZEXT, v(REG_VF), bit(0))
+                ),
+                _ => unsupported()
+            },
+            Mnemonic::OR | Mnemonic::AND | Mnemonic::XOR => {
+                let op = match inst.mnemonic {
+                    Mnemonic::OR => Op::OR,
+                    Mnemonic::AND => Op::AND,
+                    _ => Op::XOR
+                };
+                match (byte_operand(inst.op1), byte_operand(inst.op2)) {
+                    (Some(x), Some(y)) => vec!(ir::Instruction::ternary(op, x, x, y)),
+                    _ => unsupported()
+                }
+            },
+            Mnemonic::SUB | Mnemonic::SUBN => {
+                let (x, y) = match (byte_operand(inst.op1), byte_operand(inst.op2)) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => return unsupported()
+                };
+                let (minuend, subtrahend) = if inst.mnemonic == Mnemonic::SUB {
+                    (x, y)
+                } else {
+                    (y, x)
+                };
+
+                // VF is set if the minuend is strictly greater than
+                // the subtrahend, as in the interpreter.
+                vec!(
+                    ir::Instruction::ternary(Op::CMPLT, bit(0), subtrahend, minuend),
+                    ir::Instruction::ternary(Op::SUB, x, minuend, subtrahend),
+                    ir::Instruction::binary(Op::ZEXT, v(REG_VF), bit(0))
+                )
+            },
+            Mnemonic::SHL | Mnemonic::SHR => {
+                let x = match byte_operand(inst.op1) {
+                    Some(x) => x,
+                    None => return unsupported()
+                };
+                let source = if self.old_shift_behavior {
+                    x
+                } else {
+                    match byte_operand(inst.op2) {
+                        Some(y) => y,
+                        None => return unsupported()
+                    }
+                };
+
+                // VF gets the bit that's shifted out.
+                let (shift, flag) = if inst.mnemonic == Mnemonic::SHL {
+                    (Op::SHL, ir::Instruction::ternary(Op::SHR, temp8(0), source, byte(7)))
+                } else {
+                    (Op::SHR, ir::Instruction::ternary(Op::AND, temp8(0), source, byte(1)))
+                };
+
+                vec!(
+                    flag,
+                    ir::Instruction::ternary(shift, x, source, byte(1)),
+                    ir::Instruction::binary(Op::STR, v(REG_VF), temp8(0))
+                )
+            },
+            Mnemonic::RND => match (inst.unpack_op1(), inst.op2) {
+                (Operand::V(x), Some(Operand::Byte(mask))) => vec!(
+                    ir::Instruction::binary(Op::IN, temp8(0), Arg::Port(PORT_RANDOM)),
+                    ir::Instruction::ternary(Op::AND, v(x), temp8(0), byte(mask))
+                ),
+                _ => unsupported()
+            },
+            Mnemonic::DRW => lift_draw(inst),
+            Mnemonic::LDBCD => match inst.unpack_op1() {
+                Operand::V(x) => vec!(
+                    ir::Instruction::ternary(Op::DIV, temp8(0), v(x), byte(100)),
+                    ir::Instruction::binary(Op::STM, i(), temp8(0)),
+                    ir::Instruction::ternary(Op::DIV, temp8(1), v(x), byte(10)),
+                    ir::Instruction::ternary(Op::MOD, temp8(1), temp8(1), byte(10)),
+                    ir::Instruction::ternary(Op::ADD, temp16(2), i(), word(1)),
+                    ir::Instruction::binary(Op::STM, temp16(2), temp8(1)),
+                    ir::Instruction::ternary(Op::MOD, temp8(3), v(x), byte(10)),
+                    ir::Instruction::ternary(Op::ADD, temp16(4), i(), word(2)),
+                    ir::Instruction::binary(Op::STM, temp16(4), temp8(3))
+                ),
+                _ => unsupported()
+            },
+            Mnemonic::LDPTR => lift_ldptr(inst)
+        }
+    }
+
+    fn register_name(&self, index: usize) -> String {
+        match index {
+            REG_I => String::from("I"),
+            _ => format!("V{:X}", index)
+        }
+    }
+
+    fn flag_name(&self, index: usize) -> String {
+        format!("F{}", index)
+    }
+
+    fn port_name(&self, index: usize) -> String {
+        String::from(match index {
+            PORT_DISPLAY => "DISPLAY",
+            PORT_KEYS => "KEYS",
+            PORT_KEY_WAIT => "K",
+            PORT_DELAY_TIMER => "DT",
+            PORT_SOUND_TIMER => "ST",
+            PORT_RANDOM => "RANDOM",
+            PORT_USER_FLAGS => "R",
+            _ => "?"
+        })
+    }
+}
+
+fn unsupported() -> Vec<ir::Instruction> {
+    vec!(ir::Instruction::new(Op::UNSUPPORTED))
+}
+
+fn display_command(opcode: u32) -> ir::Instruction {
+    ir::Instruction::binary(Op::OUT, Arg::Port(PORT_DISPLAY), word(opcode))
+}
+
+fn lift_jump(inst: Instruction) -> Vec<ir::Instruction> {
+    match (inst.unpack_op1(), inst.op2) {
+        (Operand::Address(address), _) if address >= 0x200 =>
+            vec!(ir::Instruction::unary(Op::JMP, Arg::Address(address as usize - 0x200))),
+        (Operand::V(0), Some(Operand::Address(base))) => vec!(
+            ir::Instruction::binary(Op::ZEXT, temp16(0), v(0)),
+            ir::Instruction::ternary(Op::ADD, temp16(0), temp16(0), word(base as u32)),
+            ir::Instruction::ternary(Op::SUB, temp16(0), temp16(0), word(0x200)),
+            ir::Instruction::unary(Op::JMP, temp16(0))
+        ),
+        _ => unsupported()
+    }
+}
+
+fn lift_ld(inst: Instruction) -> Vec<ir::Instruction> {
+    match (inst.unpack_op1(), inst.unpack_op2()) {
+        (Operand::V(x), Operand::Byte(value)) =>
+            vec!(ir::Instruction::binary(Op::STR, v(x), byte(value))),
+        (Operand::V(x), Operand::V(y)) =>
+            vec!(ir::Instruction::binary(Op::STR, v(x), v(y))),
+        (Operand::I, Operand::Address(address)) =>
+            vec!(ir::Instruction::binary(Op::STR, i(), word(address as u32))),
+        (Operand::V(x), Operand::DelayTimer) =>
+            vec!(ir::Instruction::binary(Op::IN, v(x), Arg::Port(PORT_DELAY_TIMER))),
+        (Operand::V(x), Operand::KeyPress) =>
+            vec!(ir::Instruction::binary(Op::IN, v(x), Arg::Port(PORT_KEY_WAIT))),
+        (Operand::DelayTimer, Operand::V(x)) =>
+            vec!(ir::Instruction::binary(Op::OUT, Arg::Port(PORT_DELAY_TIMER), v(x))),
+        (Operand::SoundTimer, Operand::V(x)) =>
+            vec!(ir::Instruction::binary(Op::OUT, Arg::Port(PORT_SOUND_TIMER), v(x))),
+        (Operand::I, Operand::Numeral(x)) => vec!(
+            ir::Instruction::binary(Op::ZEXT, temp16(0), v(x)),
+            ir::Instruction::ternary(Op::MUL, i(), temp16(0), word(5))
+        ),
+        (Operand::I, Operand::LargeNumeral(x)) => vec!(
+            ir::Instruction::binary(Op::ZEXT, temp16(0), v(x)),
+            ir::Instruction::ternary(Op::MUL, temp16(0), temp16(0), word(10)),
+            ir::Instruction::ternary(Op::ADD, i(), temp16(0), word(80))
+        ),
+        (Operand::UserFlags, Operand::V(x)) => (0..(x + 1))
+            .map(|index| ir::Instruction::binary(Op::OUT, Arg::Port(PORT_USER_FLAGS), v(index)))
+            .collect(),
+        (Operand::V(x), Operand::UserFlags) => (0..(x + 1))
+            .map(|index| ir::Instruction::binary(Op::IN, v(index), Arg::Port(PORT_USER_FLAGS)))
+            .collect(),
+        _ => unsupported()
+    }
+}
+
+// Returns the registers DRW reads, for queries of what sets them, or
+// None for other instructions.
+pub fn draw_reads(inst: Instruction) -> Option<Vec<ir::Location>> {
+    match (inst.mnemonic, inst.op1, inst.op2) {
+        (Mnemonic::DRW, Some(Operand::V(x)), Some(Operand::V(y))) =>
+            Some(vec!(ir::Location::Reg(x), ir::Location::Reg(y), ir::Location::Reg(REG_I))),
+        _ => None
+    }
+}
+
+// DRW Vx, Vy, n draws n rows (or 32 bytes if n is 0) from memory at I.
+fn lift_draw(inst: Instruction) -> Vec<ir::Instruction> {
+    let (x, y, height) = match (inst.unpack_op1(), inst.unpack_op2(), inst.op3) {
+        (Operand::V(x), Operand::V(y), Some(Operand::Byte(height))) => (x, y, height),
+        _ => return unsupported()
+    };
+
+    let length = match height {
+        0 => 32,
+        _ => height as u32
+    };
+
+    let mut ir = vec!(
+        ir::Instruction::binary(Op::OUT, Arg::Port(PORT_DISPLAY), v(x)),
+        ir::Instruction::binary(Op::OUT, Arg::Port(PORT_DISPLAY), v(y))
+    );
+
+    for row in 0..length {
+        ir.push(ir::Instruction::ternary(Op::ADD, temp16(0), i(), word(row)));
+        ir.push(ir::Instruction::binary(Op::LDM, temp8(1), temp16(0)));
+        ir.push(ir::Instruction::binary(Op::OUT, Arg::Port(PORT_DISPLAY), temp8(1)));
+    }
+
+    ir.push(ir::Instruction::binary(Op::IN, v(REG_VF), Arg::Port(PORT_DISPLAY)));
+    ir
+}
+
+// LDPTR reads or writes V0 to Vx at I to I + x, then advances I past
+// them, as in the interpreter.
+fn lift_ldptr(inst: Instruction) -> Vec<ir::Instruction> {
+    let mut ir = Vec::new();
+
+    let last = match (inst.unpack_op1(), inst.unpack_op2()) {
+        (Operand::Pointer, Operand::V(x)) => {
+            for index in 0..(x + 1) {
+                ir.push(ir::Instruction::ternary(Op::ADD, temp16(0), i(), word(index as u32)));
+                ir.push(ir::Instruction::binary(Op::STM, temp16(0), v(index)));
+            }
+            x
+        },
+        (Operand::V(x), Operand::Pointer) => {
+            for index in 0..(x + 1) {
+                ir.push(ir::Instruction::ternary(Op::ADD, temp16(0), i(), word(index as u32)));
+                ir.push(ir::Instruction::binary(Op::LDM, v(index), temp16(0)));
+            }
+            x
+        },
+        _ => return unsupported()
+    };
+
+    ir.push(ir::Instruction::ternary(Op::ADD, i(), i(), word(last as u32 + 1)));
+    ir
+}
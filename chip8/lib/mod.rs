@@ -1,4 +1,5 @@
 pub mod arch;
 pub mod state;
 pub mod sim;
+pub mod lift;
 mod dis;
@@ -51,13 +51,21 @@ impl<'a> Interpreter {
                 state.sp += 1;
                 state.pc = match instruction.unpack_op1() {
                     Operand::Address(word) => word,
-                    _ => return SimResult::Error(state,
-                        "CALL operand should be immediate address.".into())
+                    _ => {
+                        let error = unresolved(&state, instruction,
+                            "CALL operand should be immediate address.");
+                        return SimResult::Error(state, error);
+                    }
                 };
                 new_labels.push(Interpreter::next_inst_offset(&state));
                 new_states.push(state);
             },
             Mnemonic::RET => {
+                if state.sp == 0 {
+                    let error = invalid(&state, instruction,
+                        "Can't return with an empty stack.");
+                    return SimResult::Error(state, error);
+                }
                 state.sp -= 1;
                 state.pc = state.stack[state.sp];
                 state.stack[state.sp] = 0;
@@ -72,26 +80,37 @@ impl<'a> Interpreter {
                     },
                     Operand::V(0) => match instruction.unpack_op2() {
                         Operand::Address(base) => {
-                            match state.V[0] {
-                                Byte::Undefined => return SimResult::Error(
-                                    state.clone(), "Can't jump to undefined offset".into()),
-                                Byte::AnyValue => return SimResult::Error(
-                                    state.clone(), "Can't jump to every address".into()),
+                            match state.V[0].clone().expand() {
+                                Byte::Undefined => return SimResult::Error(state.clone(),
+                                    unresolved(&state, instruction, "Can't jump to undefined offset")),
+                                Byte::AnyValue => return SimResult::Error(state.clone(),
+                                    unresolved(&state, instruction, "Can't jump to every address")),
                                 Byte::Int(ref set) => {
-                                    for offset in set {
+                                    // In order, so the branches are explored
+                                    // the same way on every run.
+                                    let mut offsets: Vec<&u8> = set.iter().collect();
+                                    offsets.sort();
+                                    for offset in offsets {
                                         let mut new_state = state.clone();
                                         new_state.pc = base + *offset as u16;
                                         new_labels.push(Interpreter::
                                             next_inst_offset(&new_state));
                                         new_states.push(new_state);
                                     }
-                                }
+                                },
+                                _ => panic!("shouldn't be here")
                             }
                         },
-                        _ => return SimResult::Error(state,
-                            "base of indirect jump should be imm16".into())
+                        _ => {
+                            let error = unresolved(&state, instruction,
+                                "base of indirect jump should be imm16");
+                            return SimResult::Error(state, error);
+                        }
                     },
-                    _ => return SimResult::Error(state, "Invalid JP operand.".into())
+                    _ => {
+                        let error = unresolved(&state, instruction, "Invalid JP operand.");
+                        return SimResult::Error(state, error);
+                    }
                 }
             },
             Mnemonic::SKP | Mnemonic::SKNP => {
@@ -106,7 +125,13 @@ impl<'a> Interpreter {
 
                 let op2byte = &state.get_byte(op2);
                 let intersect = state.get_byte(op1).intersect(op2byte);
-                let difference = state.get_byte(op1).difference(op2byte);
+
+                // op1 can only be told apart from every value of op2 if
+                // op2 has just the one value.
+                let difference = match op2byte.len() {
+                    1 => state.get_byte(op1).difference(op2byte),
+                    _ => state.get_byte(op1)
+                };
 
                 match instruction.mnemonic {
                     Mnemonic::SE => {
@@ -132,7 +157,10 @@ impl<'a> Interpreter {
                     _ => panic!("shouldn't be here")
                 }
             },
-            _ => panic!("unimplemented jump instruction")
+            _ => {
+                let error = unsupported(&state, instruction, "unimplemented jump instruction");
+                return SimResult::Error(state, error);
+            }
         }
 
         SimResult::Branch(new_states, new_labels)
@@ -174,13 +202,13 @@ impl<'a> Interpreter {
     }
 
     fn apply_to_words(&self, op1: Word, op2: Word, op: Mnemonic) -> (Word, Option<Byte>) {
-        match op1 {
+        match op1.expand() {
             Word::Undefined => (Word::Undefined, Some(Byte::Undefined)),
             Word::AnyValue => match op2 {
                 Word::Undefined => (Word::Undefined, Some(Byte::Undefined)),
                 _ => (Word::AnyValue, Some(Byte::AnyValue))
             },
-            Word::Int(set1) => match op2 {
+            Word::Int(set1) => match op2.expand() {
                 Word::Undefined => (Word::Undefined, Some(Byte::Undefined)),
                 Word::AnyValue => (Word::AnyValue, Some(Byte::AnyValue)),
                 Word::Int(set2) => {
@@ -194,7 +222,7 @@ impl<'a> Interpreter {
                             vf = vf.union(new_vf);
                         }
                     }
-                    (Word::Int(set), match vf {
+                    (Word::from_set(set), match vf {
                         Bit::Undefined => None,
                         Bit::True => Some(Byte::new(1)),
                         Bit::False => Some(Byte::new(0)),
@@ -202,21 +230,49 @@ impl<'a> Interpreter {
                     })
                 },
                 Word::Bytes(_, _) => 
-                    panic!("applying binary operator to split word unimplemented.")
+                    panic!("applying binary operator to split word unimplemented."),
+                _ => panic!("shouldn't be here")
             },
             Word::Bytes(_, _) =>
-                panic!("applying binary operator to split word unimplemented.")
+                panic!("applying binary operator to split word unimplemented."),
+            _ => panic!("shouldn't be here")
         }
     }
 
     fn apply_to_bytes(&self, op1: Byte, op2: Byte, op: Mnemonic) -> (Byte, Option<Byte>) {
-        match op1 {
+        match (&op1, &op2) {
+            (&Byte::Undefined, _) | (_, &Byte::Undefined) =>
+                return (Byte::Undefined, Some(Byte::Undefined)),
+            _ => ()
+        }
+
+        // Bitwise operators and shifts work on known bits, so masking a
+        // byte that can have any value still gives a precise result.
+        match op {
+            Mnemonic::OR => return (op1.or(op2), None),
+            Mnemonic::AND => return (op1.and(op2), None),
+            Mnemonic::XOR => return (op1.xor(op2), None),
+            Mnemonic::SHL | Mnemonic::SHR => {
+                let byte = match self.old_shift_behavior {
+                    true => op1,
+                    false => op2
+                };
+
+                return match op {
+                    Mnemonic::SHL => (byte.clone().shl(1), Some(byte.and(Byte::new(0x80)).shr(7))),
+                    _ => (byte.clone().shr(1), Some(byte.and(Byte::new(0x01))))
+                };
+            },
+            _ => ()
+        }
+
+        match op1.expand() {
             Byte::Undefined => (Byte::Undefined, Some(Byte::Undefined)),
             Byte::AnyValue => match op2 {
                 Byte::Undefined => (Byte::Undefined, Some(Byte::Undefined)),
                 _ => (Byte::AnyValue, Some(Byte::AnyValue))
             },
-            Byte::Int(set1) => match op2 {
+            Byte::Int(set1) => match op2.expand() {
                 Byte::Undefined => (Byte::Undefined, Some(Byte::Undefined)),
                 Byte::AnyValue => (Byte::AnyValue, Some(Byte::AnyValue)),
                 Byte::Int(set2) => {
@@ -230,14 +286,16 @@ impl<'a> Interpreter {
                             vf = vf.union(new_vf);
                         }
                     }
-                    (Byte::Int(set), match vf {
+                    (Byte::from_set(set), match vf {
                         Bit::Undefined => None,
                         Bit::True => Some(Byte::new(1)),
                         Bit::False => Some(Byte::new(0)),
                         Bit::TrueAndFalse => Some(Byte::new(0).union(Byte::new(1)))
                     })
-                }
-            }
+                },
+                _ => panic!("shouldn't be here")
+            },
+            _ => panic!("shouldn't be here")
         }
     }
 
@@ -268,31 +326,6 @@ impl<'a> Interpreter {
                 } else {
                     Bit::False
                 }),
-            Mnemonic::SHL => {
-                let byte = match self.old_shift_behavior {
-                    true => byte1,
-                    false => byte2
-                };
-
-                (byte << 1, match byte & 0x80 {
-                    0x80 => Bit::True,
-                    _ => Bit::False
-                })
-            },
-            Mnemonic::SHR => {
-                let byte = match self.old_shift_behavior {
-                    true => byte1,
-                    false => byte2
-                };
-
-                (byte >> 1, match byte & 0x01 {
-                    0x01 => Bit::True,
-                    _ => Bit::False
-                })
-            },
-            Mnemonic::OR => (byte1 | byte2, Bit::Undefined),
-            Mnemonic::AND => (byte1 & byte2, Bit::Undefined),
-            Mnemonic::XOR => (byte1 ^ byte2, Bit::Undefined),
             _ => panic!("unknown binary op for u8s")
         }
     }
@@ -300,8 +333,17 @@ impl<'a> Interpreter {
 
 fn simulate_ld<'a>(state: State<'a>, inst: Instruction) -> SimResult<State<'a>> {
     let (op1, op2) = (inst.unpack_op1(), inst.unpack_op2());
-    let value = state.get_value(op2);
-    SimResult::State(state.set_value(op1, value))
+    match (op1, op2) {
+        // The user flags outlive the program, so they can hold anything,
+        // and nothing in the program reads what's stored in them.
+        (Operand::UserFlags, _) => SimResult::State(state),
+        (Operand::V(x), Operand::UserFlags) => SimResult::State((0..(x + 1))
+            .fold(state, |state, reg| state.set_byte(Operand::V(reg), Byte::AnyValue))),
+        _ => {
+            let value = state.get_value(op2);
+            SimResult::State(state.set_value(op1, value))
+        }
+    }
 }
 
 fn simulate_rnd<'a>(state: State<'a>, inst: Instruction) -> SimResult<State<'a>> {
@@ -311,23 +353,30 @@ fn simulate_rnd<'a>(state: State<'a>, inst: Instruction) -> SimResult<State<'a>>
             for i in 0..256u16 {
                 set.insert(i as u8 & byte);
             }
-            SimResult::State(state.set_byte(inst.unpack_op1(), Byte::Int(set)))
+            SimResult::State(state.set_byte(inst.unpack_op1(), Byte::from_set(set)))
         },
-        _ => panic!("RND second operand should be an immediate byte.")
+        _ => {
+            let error = unsupported(&state, inst,
+                "RND second operand should be an immediate byte.");
+            SimResult::Error(state, error)
+        }
     }
 }
 
-fn simulate_ldbcd<'a>(mut state: State<'a>, _inst: Instruction) -> SimResult<State<'a>> {
-    match state.I {
-        Word::Undefined => panic!("Can't write to undefined memory location."),
-        Word::AnyValue => panic!("Can't write to all memory locations."),
-        Word::Int(ref set) => {
-            for address in set.iter() {
-                state.memory.write_string(*address as usize,
-                    &vec!(Byte::AnyValue, Byte::AnyValue, Byte::AnyValue));
-            }
-        },
-        Word::Bytes(_, _) => panic!("Index register shouldn't be split.")
+fn simulate_ldbcd<'a>(mut state: State<'a>, inst: Instruction) -> SimResult<State<'a>> {
+    let addresses = match state.I.clone().expand() {
+        Word::Undefined => return SimResult::Error(state.clone(),
+            invalid(&state, inst, "Can't write to undefined memory location.")),
+        Word::AnyValue => return SimResult::Error(state.clone(),
+            invalid(&state, inst, "Can't write to all memory locations.")),
+        Word::Int(ref set) => sorted(set),
+        Word::Bytes(_, _) => return SimResult::Error(state.clone(),
+            invalid(&state, inst, "Index register shouldn't be split.")),
+        _ => panic!("shouldn't be here")
+    };
+    for address in addresses.iter() {
+        state.memory.write_string(*address as usize,
+            &vec!(Byte::AnyValue, Byte::AnyValue, Byte::AnyValue));
     }
     SimResult::State(state)
 }
@@ -344,47 +393,56 @@ fn simulate_ldptr<'a>(mut state: State<'a>, inst: Instruction) -> SimResult<Stat
                     for i in 0..(bytes_read) {
                         string.push(state.V[i].clone());
                     }
-                    match state.I {
+                    let addresses = match state.I.clone().expand() {
                         Word::Undefined => return SimResult::Error(state.clone(),
-                            "Can't write to undefined memory location.".into()),
+                            invalid(&state, inst, "Can't write to undefined memory location.")),
                         Word::AnyValue => return SimResult::Error(state.clone(),
-                            "Can't write to all memory locations.".into()),
-                        Word::Int(ref set) => {
-                            for address in set.iter() {
-                                state.memory.write_string(*address as usize, &string);
-                            }
-                        },
-                        Word::Bytes(_, _) => panic!("Index register shouldn't be split.")
+                            invalid(&state, inst, "Can't write to all memory locations.")),
+                        Word::Int(ref set) => sorted(set),
+                        Word::Bytes(_, _) => return SimResult::Error(state.clone(),
+                            invalid(&state, inst, "Index register shouldn't be split.")),
+                        _ => panic!("shouldn't be here")
+                    };
+                    for address in addresses.iter() {
+                        state.memory.write_string(*address as usize, &string);
                     }
                 },
-                _ => panic!("Only registers can be written to memory.")
+                _ => {
+                    let error = unsupported(&state, inst,
+                        "Only registers can be written to memory.");
+                    return SimResult::Error(state, error);
+                }
             }
         },
         Operand::V(x) => {
             bytes_read = x+1;
-            match state.I {
+            let addresses = match state.I.clone().expand() {
                 Word::Undefined => return SimResult::Error(state.clone(),
-                    "Can't read from undefined memory location.".into()),
+                    invalid(&state, inst, "Can't read from undefined memory location.")),
                 Word::AnyValue => return SimResult::Error(state.clone(),
-                    "Can't read from every memory location.".into()),
-                Word::Int(ref set) => {
-                    for i in 0..(bytes_read) {
-                        let mut values = Byte::from_vec(Vec::new());
-                        for address in set.iter() {
-                            let memory_byte = state.memory.get_byte(*address as usize  + i);
-                            match memory_byte {
-                                Some(byte) => values = values.union(byte),
-                                None => return SimResult::Error(state.clone(),
-                                    format!("Tried to read from uninitialized memory location {:x}", *address))
-                            }
-                        }
-                        state.V[i] = values;
+                    invalid(&state, inst, "Can't read from every memory location.")),
+                Word::Int(ref set) => sorted(set),
+                Word::Bytes(_, _) => return SimResult::Error(state.clone(),
+                    invalid(&state, inst, "Index register shouldn't be split.")),
+                _ => panic!("shouldn't be here")
+            };
+            for i in 0..(bytes_read) {
+                let mut values = Byte::from_vec(Vec::new());
+                for address in addresses.iter() {
+                    let memory_byte = state.memory.get_byte(*address as usize  + i);
+                    match memory_byte {
+                        Some(byte) => values = values.union(byte),
+                        None => return SimResult::Error(state.clone(), invalid(&state, inst,
+                            format!("Tried to read from uninitialized memory location {:x}", *address).as_str()))
                     }
-                },
-                Word::Bytes(_, _) => panic!("Index register shouldn't be split.")
+                }
+                state.V[i] = values;
             }
         },
-        _ => panic!("LDPTR not implemented for operands.")
+        _ => {
+            let error = unsupported(&state, inst, "LDPTR not implemented for operands.");
+            return SimResult::Error(state, error);
+        }
     }
 
     #[allow(non_snake_case)]
@@ -392,3 +450,40 @@ fn simulate_ldptr<'a>(mut state: State<'a>, inst: Instruction) -> SimResult<Stat
     SimResult::State(state.set_word(Operand::I, new_I))
 }
 
+// Addresses in order, so that overlapping writes land the same way and
+// errors name the same address on every run.
+fn sorted(set: &HashSet<u16>) -> Vec<u16> {
+    let mut addresses: Vec<u16> = set.iter().cloned().collect();
+    addresses.sort();
+    addresses
+}
+
+// The offset of the instruction being simulated, given a state
+// whose program counter has already been advanced past it.
+fn current_offset(state: &State) -> usize {
+    state.pc.wrapping_sub(0x202) as usize
+}
+
+fn unresolved(state: &State, inst: Instruction, reason: &str) -> DecompilerError {
+    DecompilerError::UnresolvedBranch {
+        offset: current_offset(state),
+        instruction: format!("{}", inst),
+        reason: String::from(reason)
+    }
+}
+
+fn unsupported(state: &State, inst: Instruction, reason: &str) -> DecompilerError {
+    DecompilerError::UnsupportedInstruction {
+        offset: current_offset(state),
+        instruction: format!("{}", inst),
+        reason: String::from(reason)
+    }
+}
+
+fn invalid(state: &State, inst: Instruction, reason: &str) -> DecompilerError {
+    DecompilerError::InvalidState {
+        offset: current_offset(state),
+        instruction: format!("{}", inst),
+        reason: String::from(reason)
+    }
+}
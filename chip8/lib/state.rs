@@ -278,6 +278,50 @@ impl<'a> StateTrait<State<'a>> for State<'a> {
         }
     }
 
+    fn widen(&self, state: &State<'a>) -> Option<State<'a>> {
+        if self.pc != state.pc || self.sp != state.sp || self.stack != state.stack {
+            return None;
+        }
+        Some(State {
+            pc: self.pc,
+            sp: self.sp,
+            I: self.I.clone().widen(state.I.clone()),
+            V: {
+                let mut new_v = Vec::new();
+                for i in 0..16 {
+                    new_v.push(self.V[i].clone().widen(state.V[i].clone()))
+                }
+                new_v
+            },
+            delay_timer: self.delay_timer.clone().widen(state.delay_timer.clone()),
+            sound_timer: self.sound_timer.clone().widen(state.sound_timer.clone()),
+            stack: self.stack.clone(),
+            memory: self.memory.clone().widen(state.memory.clone())
+        })
+    }
+
+    fn narrow(&self, state: &State<'a>) -> Option<State<'a>> {
+        if self.pc != state.pc || self.sp != state.sp || self.stack != state.stack {
+            return None;
+        }
+        Some(State {
+            pc: self.pc,
+            sp: self.sp,
+            I: self.I.clone().narrow(state.I.clone()),
+            V: {
+                let mut new_v = Vec::new();
+                for i in 0..16 {
+                    new_v.push(self.V[i].clone().narrow(state.V[i].clone()))
+                }
+                new_v
+            },
+            delay_timer: self.delay_timer.clone().narrow(state.delay_timer.clone()),
+            sound_timer: self.sound_timer.clone().narrow(state.sound_timer.clone()),
+            stack: self.stack.clone(),
+            memory: self.memory.clone().narrow(state.memory.clone())
+        })
+    }
+
     fn debug_string(&self) -> String {
         let line1 = format!("PC={:04x}, SP: {:04x} I={:?} DT={:?} ST={:?}\n",
             self.pc, self.sp, self.I, self.delay_timer, self.sound_timer);
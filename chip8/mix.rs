@@ -1,14 +1,17 @@
 pub mod analyse;
+mod recur;
 pub mod defs;
 pub mod graph;
 pub mod chip8;
 mod c8analyzer;
+mod decompile;
+mod json;
 
-use defs::main::Architecture;
+use defs::main::Limits;
 
 fn main() {
     use std::env;
-    use std::io::Read;
+    use std::io::{Read, Write};
     use std::fs::File;
 
 	if let Some(arg) = env::args().nth(1) {
@@ -18,18 +21,119 @@ fn main() {
 		file.read_to_end(&mut buffer).expect(
 			"Failed to read into buffer.");
 
-        let result = analyse::analyse(
+        // Code the entry doesn't reach is analysed too, and shows up as
+        // functions nothing calls.
+        let roots = recur::unreached_entries(&buffer, chip8::arch::Chip8 {}, 0);
+        let result = analyse::analyse_from(
             &buffer, chip8::arch::Chip8 {},
-            c8analyzer::Chip8Analyzer {}, 0);
+            &c8analyzer::Analyzer { old_shift_behavior: false }, 0, &roots,
+            &limits(env::args().skip(2)));
+
+        // --json <file> writes the results to file, errors included.
+        if env::args().nth(2).map_or(false, |flag| flag == "--json") {
+            let json_file = env::args().nth(3).expect("--json needs a file name.");
+            let mut file = File::create(json_file).expect(
+                "Failed to create JSON file.");
+            file.write_all(format!("{}\n", json::analysis(&buffer, &result)).as_bytes()).expect(
+                "Failed to write JSON file.");
+            return;
+        }
 
         match result {
-            Ok(graph) => {
-                println!("{}", graph);
-                chip8::arch::Chip8::print_listing(graph.listing());
+            Ok(mut graph) => {
+                if env::args().nth(2).map_or(false, |flag| flag == "--decompile") {
+                    graph.lift(&chip8::lift::Lifter { old_shift_behavior: false });
+                    match decompile::decompile(&graph, &chip8::lift::Lifter { old_shift_behavior: false }) {
+                        Ok(functions) => for function in functions {
+                            println!("{}", function);
+                        },
+                        Err(error) => println!("{}", error)
+                    }
+                } else if env::args().nth(2).map_or(false, |flag| flag == "--reaching") {
+                    // --reaching shows which instructions may have set
+                    // the registers each DRW reads.
+                    graph.lift(&chip8::lift::Lifter { old_shift_behavior: false });
+                    match graph.call_graph() {
+                        Ok(call_graph) => {
+                            let queried = graph::dataflow::reaching_reads_of(&graph, &call_graph, chip8::lift::draw_reads);
+                            print!("{}", graph::dataflow::format_reaching_reads(&graph, &queried,
+                                &chip8::lift::Lifter { old_shift_behavior: false }, 0x200));
+                        },
+                        Err(error) => println!("{}", error)
+                    }
+                } else if env::args().nth(2).map_or(false, |flag| flag == "--dot") {
+                    let dot_file = env::args().nth(3).expect("--dot needs a file name.");
+                    match graph.call_graph() {
+                        Ok(call_graph) => {
+                            let mut file = File::create(dot_file).expect(
+                                "Failed to create DOT file.");
+                            file.write_all(graph::dot::call_graph(&graph, &call_graph).as_bytes()).expect(
+                                "Failed to write DOT file.");
+                        },
+                        Err(error) => println!("{}", error)
+                    }
+                } else {
+                    graph.lift(&chip8::lift::Lifter { old_shift_behavior: false });
+                    let mut summaries = Vec::new();
+                    // CHIP-8 only keeps 16 return addresses.
+                    match graph.call_graph() {
+                        Ok(call_graph) => {
+                            for &function in call_graph.recursive_functions() {
+                                let entry = call_graph.functions()[function].entry();
+                                if let Ok(Some(offset)) = graph.initial_instruction(entry) {
+                                    println!("Function at {:x} is recursive and may overflow the stack.", offset + 0x200);
+                                }
+                            }
+                            for function in call_graph.unreachable() {
+                                let entry = call_graph.functions()[function].entry();
+                                if let Ok(Some(offset)) = graph.initial_instruction(entry) {
+                                    println!("Function at {:x} is never called.", offset + 0x200);
+                                }
+                            }
+                            match graph::summary::function_summaries(&graph, &call_graph) {
+                                Ok(function_summaries) => summaries = function_summaries,
+                                Err(error) => println!("{}", error)
+                            }
+                        },
+                        Err(error) => println!("{}", error)
+                    }
+                    println!("{}", graph);
+                    print!("{}", recur::describe_overlaps(graph.listing()));
+                    chip8::arch::Chip8::print_summarized_listing(graph.listing(), &summaries);
+                }
             },
             Err(error) => println!("{}", error)
         }
     } else {
-		println!("usage: dis <file-to-disassemble>");
+		println!("usage: dis <file-to-disassemble> [--decompile | --reaching | --dot <file> | --json <file>] [-s <states>] [-i <instructions>] [-t <seconds>]");
     }
 }
+
+// -s <states>, -i <instructions> and -t <seconds> stop simulating each
+// slice after that many states or simulated instructions, or that many
+// seconds from now, leaving what it was simulated for unresolved.
+fn limits<T: Iterator<Item = String>>(mut args: T) -> Limits {
+    use std::time::{Duration, Instant};
+
+    let mut limits = Limits::none();
+
+    while let Some(arg) = args.next() {
+        if arg == "-s" {
+            limits.max_states = Some(args.next().and_then(|states| states.parse().ok())
+                .expect("-s needs a number of states."));
+        }
+
+        if arg == "-i" {
+            limits.max_steps = Some(args.next().and_then(|steps| steps.parse().ok())
+                .expect("-i needs a number of instructions."));
+        }
+
+        if arg == "-t" {
+            let seconds = args.next().and_then(|seconds| seconds.parse().ok())
+                .expect("-t needs a number of seconds.");
+            limits.deadline = Some(Instant::now() + Duration::from_secs(seconds));
+        }
+    }
+
+    limits
+}
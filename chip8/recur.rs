@@ -1,5 +1,7 @@
 mod recur;
+mod classify;
 pub mod defs;
+pub mod graph;
 pub mod chip8;
 
 use defs::main::*;
@@ -9,26 +11,43 @@ fn main() {
     use std::io::Read;
     use std::fs::File;
 
-	if let Some(arg) = env::args().nth(1) {
+    let mut args = env::args();
+    args.next();
+
+	if let Some(arg) = args.next() {
 		let mut file = File::open(arg).expect(
 			"Failed to open file.");
 		let mut buffer = Vec::new();
 		file.read_to_end(&mut buffer).expect(
 			"Failed to read into buffer.");
 
-        let start_offset = match env::args().nth(2) {
-            None => 0,
-            Some(offset) => usize::from_str_radix(&offset, 16).unwrap_or(0)
-        };
+        let mut start_offset = 0;
+        let mut mode = recur::Mode::Recursive;
+
+        while let Some(arg) = args.next() {
+            if arg == "--linear" {
+                mode = recur::Mode::Linear;
+            } else if arg == "--hybrid" {
+                mode = recur::Mode::Hybrid;
+            } else {
+                start_offset = usize::from_str_radix(&arg, 16).unwrap_or(0);
+            }
+        }
 
-        let listing = recur::recursive_descent(
+        let mut listing = recur::disassemble(
             &buffer,
             chip8::arch::Chip8 {},
-            start_offset
+            start_offset,
+            mode
         );
 
+        print!("{}", recur::describe_invalid(&listing));
+        print!("{}", recur::describe_overlaps(&listing));
+
+        classify::classify(&buffer, &mut listing, chip8::arch::Chip8 {});
+
         chip8::arch::Chip8::print_listing(&listing);
     } else {
-		println!("usage: dis <file-to-disassemble> [<start-offset>]");
+		println!("usage: dis <file-to-disassemble> [<start-offset>] [--linear | --hybrid]");
     }
 }
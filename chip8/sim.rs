@@ -3,13 +3,16 @@ pub mod defs;
 pub mod chip8;
 mod exhaust;
 
-use defs::main::Architecture;
+use defs::main::{Architecture, Limits};
 use chip8::arch::Chip8;
+use graph::worklist::Strategy;
+use graph::dot;
 
 fn main() {
     use std::env;
-    use std::io::Read;
+    use std::io::{Read, Write};
     use std::fs::File;
+    use std::time::{Duration, Instant};
 
     let mut args = env::args();
     args.next();
@@ -21,10 +24,18 @@ fn main() {
 		file.read_to_end(&mut buffer).expect(
 			"Failed to read into buffer.");
 
-        let simulator = chip8::sim::Interpreter {};
+        let simulator = chip8::sim::Interpreter {
+            old_shift_behavior: false
+        };
         let initial_state = chip8::state::State::new(&buffer, 0);
         
         let mut log_type = None;
+        let mut widen_after = 0;
+        let mut narrowing_passes = 0;
+        let mut limits = Limits::none();
+        let mut strategy = Strategy::DepthFirst;
+        let mut threads = 1;
+        let mut dot_file = None;
 
         while let Some(arg) = args.next() {
             if arg == "-v" {
@@ -34,23 +45,98 @@ fn main() {
             if arg == "-c" {
                 log_type = Some(exhaust::LogType::StateCount);
             }
+
+            // -w <visits> widens states at loop headers once they've
+            // been visited that many times. States aren't widened
+            // unless it's given, or if it's 0.
+            if arg == "-w" {
+                widen_after = args.next().and_then(|visits| visits.parse().ok())
+                    .expect("-w needs a number of visits.");
+            }
+
+            // -n <passes> narrows widened states in that many passes.
+            if arg == "-n" {
+                narrowing_passes = args.next().and_then(|passes| passes.parse().ok())
+                    .expect("-n needs a number of passes.");
+            }
+
+            // -s <states>, -i <instructions> and -t <seconds> stop
+            // exploring after that many states, simulated instructions
+            // or seconds, printing what was found so far.
+            if arg == "-s" {
+                limits.max_states = Some(args.next().and_then(|states| states.parse().ok())
+                    .expect("-s needs a number of states."));
+            }
+
+            if arg == "-i" {
+                limits.max_steps = Some(args.next().and_then(|steps| steps.parse().ok())
+                    .expect("-i needs a number of instructions."));
+            }
+
+            if arg == "-t" {
+                let seconds = args.next().and_then(|seconds| seconds.parse().ok())
+                    .expect("-t needs a number of seconds.");
+                limits.deadline = Some(Instant::now() + Duration::from_secs(seconds));
+            }
+
+            // -o <order> picks the order states are explored in: dfs,
+            // bfs, nearest or round-robin.
+            if arg == "-o" {
+                strategy = args.next().and_then(|order| Strategy::from_name(&order))
+                    .expect("-o needs dfs, bfs, nearest or round-robin.");
+            }
+
+            // -j <threads> simulates states ahead of time on that many
+            // threads. The listing is the same as with one.
+            if arg == "-j" {
+                threads = args.next().and_then(|threads| threads.parse().ok())
+                    .filter(|&threads| threads > 0)
+                    .expect("-j needs a number of threads.");
+            }
+
+            // -d <file> writes the state flow graph to file in DOT.
+            if arg == "-d" {
+                dot_file = Some(args.next().expect("-d needs a file name."));
+            }
         }
 
+        let widening = match widen_after {
+            0 => None,
+            _ => Some(exhaust::Widening {
+                widen_after,
+                narrowing_passes
+            })
+        };
+
         let result = exhaust::simulate_exhaustively(
             &buffer,
             simulator,
             initial_state,
             Chip8 {},
             log_type,
+            widening,
+            limits,
+            strategy,
+            threads,
         );
 
-        match result {
-            Ok(state_graph) => {
-                Chip8::print_listing(state_graph.listing());
-            },
-            Err(error) => println!("{}", error)
+        let (state_graph, stop) = match result {
+            exhaust::ExhaustResult::Complete(state_graph) => (state_graph, None),
+            exhaust::ExhaustResult::Partial(state_graph, stop) => (state_graph, Some(stop))
+        };
+
+        Chip8::print_listing(state_graph.listing());
+        if let Some(stop) = stop {
+            println!("{}", stop);
+        }
+
+        if let Some(dot_file) = dot_file {
+            let mut file = File::create(dot_file).expect(
+                "Failed to create DOT file.");
+            file.write_all(dot::state_flow_graph(&state_graph).as_bytes()).expect(
+                "Failed to write DOT file.");
         }
     } else {
-		println!("usage: dis <file-to-disassemble> (<options>)");
+		println!("usage: dis <file-to-disassemble> [-v | -c] [-w <visits>] [-n <passes>] [-s <states>] [-i <instructions>] [-t <seconds>] [-o <order>] [-j <threads>] [-d <file>]");
     }
 }
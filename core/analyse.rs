@@ -1,60 +1,119 @@
 use defs::main::*;
 use defs::range::*;
 use graph::flow::*;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
 
-pub fn analyse<I, A, Z>(file_buffer: &Vec<u8>, architecture: A, analyzer: &Z, entry_offset: usize) -> Result<FlowGraph<I>, String>
+// The most versions an instruction can have before it's given up on
+// as polymorphic. Versions are never taken away, so this also bounds
+// how often the writers are sliced again as what they write changes.
+const MAX_VERSIONS: usize = 16;
+
+pub fn analyse<I, A, Z>(file_buffer: &Vec<u8>, architecture: A, analyzer: &Z, entry_offset: usize, limits: &Limits) -> Result<FlowGraph<I>, DecompilerError>
+    where I: InstructionTrait,
+          A: Architecture<I>,
+          Z: AnalyzerTrait<I>
+{
+    analyse_roots(file_buffer, architecture, analyzer, entry_offset, &[], limits)
+}
+
+// Like analyse, but also decodes the code at roots, which the entry may
+// not reach (e.g. from recur::unreached_entries). Nothing leads to the
+// roots, so the call graph gives them functions of their own. Roots are
+// only guesses, so if what they lead to can't be analysed, the entry is
+// analysed alone.
+pub fn analyse_from<I, A, Z>(file_buffer: &Vec<u8>, architecture: A, analyzer: &Z, entry_offset: usize, roots: &[usize], limits: &Limits) -> Result<FlowGraph<I>, DecompilerError>
+    where I: InstructionTrait,
+          A: Architecture<I>,
+          Z: AnalyzerTrait<I>
+{
+    match analyse_roots(file_buffer, architecture, analyzer, entry_offset, roots, limits) {
+        Err(error) => if roots.is_empty() {
+            Err(error)
+        } else {
+            println!("Code the entry doesn't reach left out: {}", error);
+            analyse_roots(file_buffer, architecture, analyzer, entry_offset, &[], limits)
+        },
+        result => result
+    }
+}
+
+fn analyse_roots<I, A, Z>(file_buffer: &Vec<u8>, architecture: A, analyzer: &Z, entry_offset: usize, roots: &[usize], limits: &Limits) -> Result<FlowGraph<I>, DecompilerError>
     where I: InstructionTrait,
           A: Architecture<I>,
           Z: AnalyzerTrait<I>
 {
     let mut graph = FlowGraph::with_entry(entry_offset);
-    let mut code_offsets = USize::from_value(entry_offset);
     let mut written_offsets = USize::new();
+    let mut versions = Versions::new(file_buffer);
 
     let mut new_code = true;
     let mut unexplored = Vec::new();
     let mut indeterminates = Vec::new();
+    let mut written_code = Vec::new();
+
+    // Under the entry, so everything it reaches is decoded first.
+    for &root in roots.iter().rev() {
+        if graph.get_node_at(root).is_none() {
+            graph.add_node_at(root);
+            unexplored.push(root);
+        }
+    }
     unexplored.push(entry_offset);
 
     while new_code {
-        while let Some(offset) = unexplored.pop() {
-            // change this for non-chip8
-            if written_offsets.contains(offset) || written_offsets.contains(offset + 1) {
-                return Err(format!("Code is polymorphic at offset {}", offset));
-            }
+        loop {
+            while let Some(offset) = unexplored.pop() {
+                let inst = architecture.decode_instruction(file_buffer, offset)?;
 
-            let inst = match architecture.decode_instruction(file_buffer, offset) {
-                Ok(instruction) => instruction,
-                Err(err) => return Err(format!("Error decoding offset {:x}:\n{}",
-                    offset, err))
-            };
+                graph.add_inst_to_listing(offset, inst);
 
-            graph.add_inst_to_listing(offset, inst);
-            code_offsets = code_offsets.insert_range(offset, offset + inst.length() - 1);
-
-            let (targets, calls, branching, indeterminate) = inst.successors(offset);
+                if versions.is_written(offset, inst.length()) {
+                    written_code.push(offset);
+                }
 
-            let add_to_indeterminates = indeterminate || inst.writes_memory();
+                let (targets, calls, branching, indeterminate) = inst.successors(offset)?;
 
-            if add_to_indeterminates {
-                indeterminates.push(offset);
-            } else {
-                let valid_successors = targets.into_iter()
-                    .filter(|&target| target < file_buffer.len()).collect();
+                let add_to_indeterminates = indeterminate || inst.writes_memory();
 
-                let edge_value = if inst.is_call() {
-                    EdgeValue::CallSuccessor
+                if add_to_indeterminates {
+                    indeterminates.push(offset);
                 } else {
-                    EdgeValue::Regular
-                };
+                    let valid_successors = targets.into_iter()
+                        .filter(|&target| target < file_buffer.len()).collect();
 
-                let mut new_offsets = graph.
-                    insert_offsets(offset, valid_successors, branching, edge_value);
-                unexplored.append(&mut new_offsets);
+                    let edge_value = if inst.is_call() {
+                        EdgeValue::CallSuccessor
+                    } else {
+                        EdgeValue::Regular
+                    };
+
+                    let mut new_offsets = graph.
+                        insert_offsets(offset, valid_successors, branching, edge_value)?;
+                    unexplored.append(&mut new_offsets);
+
+                    let mut new_call_offsets = graph.
+                        insert_offsets(offset, calls, true, EdgeValue::Call)?;
+                    unexplored.append(&mut new_call_offsets);
+                }
+            }
 
-                let mut new_call_offsets = graph.
-                    insert_offsets(offset, calls, true, EdgeValue::Call);
-                unexplored.append(&mut new_call_offsets);
+            // Code found after the writes to it gets its versions once the
+            // rest is decoded, so that its writers can be sliced.
+            if written_code.is_empty() {
+                break;
+            }
+
+            for offset in written_code.drain(..) {
+                for writer in versions.unvalued_writers(&graph, offset)? {
+                    let written = analyzer.written_bytes(file_buffer, &graph, writer, limits)?;
+                    versions.set_values(writer, &written);
+                }
+
+                let (mut new_offsets, _) = versions.update(file_buffer, &architecture, &mut graph, offset)?;
+                unexplored.append(&mut new_offsets);
             }
         }
 
@@ -64,28 +123,29 @@ pub fn analyse<I, A, Z>(file_buffer: &Vec<u8>, architecture: A, analyzer: &Z, en
         while !new_code && index < indeterminates.len() {
             let offset = indeterminates[index];
             let mut unexplored_offsets;
-            let inst = graph.get_inst(offset).unwrap().unwrap();
+            let inst = graph.instruction_at(offset)?;
 
             if inst.writes_memory() {
-                for written_offset in analyzer.written_offsets(file_buffer, &graph, offset)? {
-                    if code_offsets.contains(written_offset) {
-                        return Err(format!("Instruction at offset 0x{:x} writes to code offset 0x{:x}",
-                            offset, written_offset));
-                    }
+                let mut written = analyzer.written_offsets(file_buffer, &graph, offset, limits)?
+                    .into_iter().collect::<Vec<usize>>();
+                written.sort();
 
-                    print!("writing 0x{:x} to get:", written_offset);
-                    written_offsets = written_offsets.insert(written_offset);
-                    println!(" {}", written_offsets.display());
-                }
+                // What's written is only worked out when it's code, and
+                // before the successors go in, since they aren't decoded.
+                let values = if written.iter().any(|&written_offset| graph.listing().is_code(written_offset)) {
+                    Some(analyzer.written_bytes(file_buffer, &graph, offset, limits)?)
+                } else {
+                    None
+                };
 
-                let (successors, _, branching, _) = inst.successors(offset);
+                let (successors, _, branching, _) = inst.successors(offset)?;
 
                 for target in successors.iter() {
                     match graph.get_node_at(*target) {
                         None => new_code = true,
                         Some(target_node) => {
-                            let node = graph.get_node_at(offset).unwrap();
-                            if !(node == target_node || graph.has_edge(node, target_node)) {
+                            let node = graph.node_at(offset)?;
+                            if !(node == target_node || versions.leads_to(&graph, node, *target)?) {
                                 new_code = true;
                             }
                         }
@@ -93,22 +153,51 @@ pub fn analyse<I, A, Z>(file_buffer: &Vec<u8>, architecture: A, analyzer: &Z, en
                 }
 
                 unexplored_offsets = graph.
-                    insert_offsets(offset, successors, branching, EdgeValue::Regular);
+                    insert_offsets(offset, successors, branching, EdgeValue::Regular)?;
+
+                let mut rewritten: Vec<usize> = Vec::new();
+                for written_offset in written {
+                    print!("writing 0x{:x} to get:", written_offset);
+                    written_offsets = written_offsets.insert(written_offset);
+                    println!(" {}", written_offsets.display());
+
+                    versions.add_write(offset, written_offset);
+                    for &start in graph.listing().owners(written_offset) {
+                        if !rewritten.contains(&start) {
+                            rewritten.push(start);
+                        }
+                    }
+                }
+
+                if let Some(ref values) = values {
+                    versions.set_values(offset, values);
+                }
+
+                for start in rewritten {
+                    let (mut new_offsets, new_versions) = versions.update(file_buffer, &architecture, &mut graph, start)?;
+                    unexplored_offsets.append(&mut new_offsets);
+                    if new_versions {
+                        new_code = true;
+                    }
+                }
             } else {
-                let successors = analyzer.determine_successors(file_buffer, &graph, offset)?;
+                // Sorted, so that nodes are numbered the same way every run.
+                let mut successors = analyzer.determine_successors(file_buffer, &graph, offset, limits)?
+                    .into_iter().collect::<Vec<usize>>();
+                successors.sort();
 
                 for target in successors.iter() {
                     match graph.get_node_at(*target) {
                         None => new_code = true,
-                        Some(node) => if !graph.has_edge(
-                            graph.get_node_at(offset).unwrap(), node) {
+                        Some(_) => if !versions.leads_to(
+                            &graph, graph.node_at(offset)?, *target)? {
                             new_code = true
                         }
                     }
                 }
 
                 unexplored_offsets = graph.
-                    insert_offsets(offset, successors.iter().cloned().collect(), true, EdgeValue::Regular);
+                    insert_offsets(offset, successors, true, EdgeValue::Regular)?;
             }
 
             unexplored.append(&mut unexplored_offsets);
@@ -116,7 +205,350 @@ pub fn analyse<I, A, Z>(file_buffer: &Vec<u8>, architecture: A, analyzer: &Z, en
         }
     }
 
-    println!("code offsets: {}", code_offsets.display());
+    versions.redirect(&mut graph)?;
+
     println!("written offsets: {}", written_offsets.display());
     return Ok(graph);
 }
+
+// Keeps track of the code that other code rewrites. Each instruction
+// that's written to gets a version for each set of bytes the writes
+// can leave in it, and the meta-instructions that choose between them
+// are put in the graph (see Meta).
+//
+// Only the values each writer can leave in each byte are known, not
+// which go together, so a writer gets a version for each way its bytes
+// can go together, whether or not the program ever puts them together
+// that way. Versions that don't decode are left out.
+struct Versions {
+    // Where the next version or meta-instruction is listed.
+    next_offset: usize,
+    next_flag: usize,
+    // The values each writer can leave in each byte written, or None
+    // if it can leave any.
+    writes: BTreeMap<usize, Vec<(usize, Option<HashSet<u8>>)>>,
+    // The writers whose values in writes are up to date.
+    valued: BTreeSet<usize>,
+    versions: BTreeMap<usize, Vec<CodeVersion>>,
+    // The JmpTrue meta-instructions in front of each rewritten
+    // instruction, the first one jumped to first.
+    dispatchers: BTreeMap<usize, Vec<usize>>,
+    // The SetFlags already following each writer.
+    setters: BTreeSet<(usize, usize)>
+}
+
+impl Versions {
+    fn new(file_buffer: &[u8]) -> Versions {
+        Versions {
+            next_offset: (file_buffer.len() + 0xfff) & !0xfff,
+            next_flag: 0,
+            writes: BTreeMap::new(),
+            valued: BTreeSet::new(),
+            versions: BTreeMap::new(),
+            dispatchers: BTreeMap::new(),
+            setters: BTreeSet::new()
+        }
+    }
+
+    fn allocate(&mut self, length: usize) -> usize {
+        let offset = self.next_offset;
+        self.next_offset += length.max(1);
+        offset
+    }
+
+    // Whether any of the bytes of the instruction at offset have been
+    // written to.
+    fn is_written(&self, offset: usize, length: usize) -> bool {
+        (offset..offset + length).any(|byte_offset| self.writes.contains_key(&byte_offset))
+    }
+
+    // Records that writer can write to written_offset. What it writes
+    // isn't known until set_values.
+    fn add_write(&mut self, writer: usize, written_offset: usize) {
+        self.valued.remove(&writer);
+        let writes = self.writes.entry(written_offset).or_insert(Vec::new());
+        if !writes.iter().any(|&(other, _)| other == writer) {
+            writes.push((writer, None));
+        }
+    }
+
+    fn set_values(&mut self, writer: usize, written: &HashMap<usize, Option<HashSet<u8>>>) {
+        for (written_offset, writes) in self.writes.iter_mut() {
+            for write in writes.iter_mut().filter(|write| write.0 == writer) {
+                write.1 = match written.get(written_offset) {
+                    None => None,
+                    Some(values) => values.clone()
+                };
+            }
+        }
+
+        self.valued.insert(writer);
+    }
+
+    // The writers to the instruction at offset whose values aren't
+    // known yet.
+    fn unvalued_writers<I: InstructionTrait>(&self, graph: &FlowGraph<I>, offset: usize) -> Result<Vec<usize>, DecompilerError> {
+        let length = graph.instruction_at(offset)?.length();
+        let mut writers: Vec<usize> = (offset..offset + length)
+            .filter_map(|byte_offset| self.writes.get(&byte_offset))
+            .flat_map(|writes| writes.iter().map(|&(writer, _)| writer))
+            .filter(|writer| !self.valued.contains(writer))
+            .collect();
+        writers.sort();
+        writers.dedup();
+        Ok(writers)
+    }
+
+    // Whether source has an edge to the instruction at target, or to
+    // the meta-instructions in front of it if it's been rewritten.
+    fn leads_to<I: InstructionTrait>(&self, graph: &FlowGraph<I>, source: usize, target: usize) -> Result<bool, DecompilerError> {
+        if graph.has_edge(source, graph.node_at(target)?) {
+            return Ok(true);
+        }
+
+        match self.dispatchers.get(&target) {
+            None => Ok(false),
+            Some(dispatchers) => Ok(graph.has_edge(source, graph.node_at(dispatchers[0])?))
+        }
+    }
+
+    // Brings the versions of the instruction at offset up to date with
+    // the writes to it. Returns the offsets still to be decoded, and
+    // whether any versions were added.
+    fn update<I, A>(&mut self, file_buffer: &[u8], architecture: &A, graph: &mut FlowGraph<I>, offset: usize) -> Result<(Vec<usize>, bool), DecompilerError>
+        where I: InstructionTrait,
+              A: Architecture<I>
+    {
+        let length = graph.instruction_at(offset)?.length();
+        let end = (offset + length).min(file_buffer.len());
+
+        let mut writers: Vec<usize> = (offset..end)
+            .filter_map(|byte_offset| self.writes.get(&byte_offset))
+            .flat_map(|writes| writes.iter().map(|&(writer, _)| writer))
+            .collect();
+        writers.sort();
+        writers.dedup();
+
+        // The bytes the instruction can hold, starting with the ones in
+        // the file. Each writer can leave its bytes over any of them,
+        // so this goes on until no writer adds anything.
+        let mut combinations: Vec<Vec<u8>> = vec!(file_buffer[offset..end].to_vec());
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &writer in writers.iter() {
+                let mut written = Vec::new();
+                for byte_offset in offset..end {
+                    match self.written_by(writer, byte_offset) {
+                        None => (),
+                        Some(&None) => return Err(polymorphic(graph, writer, byte_offset)),
+                        Some(&Some(ref values)) => {
+                            let mut values: Vec<u8> = values.iter().cloned().collect();
+                            values.sort();
+                            written.push((byte_offset - offset, values));
+                        }
+                    }
+                }
+
+                for index in 0..combinations.len() {
+                    let mut rewritten = vec!(combinations[index].clone());
+                    for &(position, ref values) in written.iter() {
+                        rewritten = rewritten.iter()
+                            .flat_map(|bytes| values.iter().map(move |&value| {
+                                let mut bytes = bytes.clone();
+                                bytes[position] = value;
+                                bytes
+                            }))
+                            .collect();
+                    }
+
+                    for bytes in rewritten {
+                        if !combinations.contains(&bytes) {
+                            combinations.push(bytes);
+                            changed = true;
+                        }
+                    }
+
+                    if combinations.len() > MAX_VERSIONS {
+                        return Err(polymorphic(graph, writer, offset));
+                    }
+                }
+            }
+        }
+
+        let mut versions = match self.versions.get(&offset) {
+            None => Vec::new(),
+            Some(versions) => versions.clone()
+        };
+        let mut unexplored = Vec::new();
+        let mut added = Vec::new();
+
+        for bytes in combinations {
+            if versions.iter().any(|version| version.bytes == bytes) {
+                continue;
+            }
+
+            // What the writers leave can change each time they're sliced,
+            // adding versions alongside the old ones, so the count is
+            // checked across every update, not just this one.
+            if versions.len() >= MAX_VERSIONS {
+                return Err(polymorphic(graph, writers[0], offset));
+            }
+
+            let flag = self.next_flag;
+            if versions.is_empty() {
+                self.next_flag += 1;
+                versions.push(CodeVersion { offset, listed_at: offset, bytes, writers: Vec::new(), flag });
+                continue;
+            }
+
+            let mut buffer = file_buffer.to_vec();
+            buffer[offset..end].copy_from_slice(&bytes);
+            let inst = match architecture.decode_instruction(&buffer, offset) {
+                Err(_) => continue,
+                Ok(inst) => inst
+            };
+
+            let (targets, calls, _, indeterminate) = inst.successors(offset)?;
+            if indeterminate || inst.writes_memory() {
+                return Err(polymorphic(graph, writers[0], offset));
+            }
+
+            self.next_flag += 1;
+            let listed_at = self.allocate(inst.length());
+            graph.add_synthetic_to_listing(listed_at, Meta::Inst(inst));
+            graph.add_node_at(listed_at);
+
+            // Versions are listed apart from the code around them, so
+            // even the next instruction is branched to.
+            let valid_successors = targets.into_iter()
+                .filter(|&target| target < file_buffer.len()).collect();
+            let edge_value = if inst.is_call() {
+                EdgeValue::CallSuccessor
+            } else {
+                EdgeValue::Regular
+            };
+            unexplored.append(&mut graph.
+                insert_offsets(listed_at, valid_successors, true, edge_value)?);
+            unexplored.append(&mut graph.
+                insert_offsets(listed_at, calls, true, EdgeValue::Call)?);
+
+            versions.push(CodeVersion { offset, listed_at, bytes, writers: Vec::new(), flag });
+            added.push(versions.len() - 1);
+        }
+
+        for version in versions.iter_mut() {
+            version.writers = self.writers_of(version, &writers, end);
+        }
+
+        // With only the version in the file, nothing has to choose.
+        if versions.len() > 1 {
+            for version in versions.iter() {
+                for &writer in version.writers.iter() {
+                    if self.setters.insert((writer, version.flag)) {
+                        unexplored.append(&mut self.add_setter(file_buffer, graph, writer, version.flag)?);
+                    }
+                }
+            }
+        }
+
+        if !added.is_empty() {
+            let node = graph.node_at(offset)?;
+            graph.split_node_at(node, offset)?;
+        }
+
+        for &index in added.iter() {
+            let version_node = graph.node_at(versions[index].listed_at)?;
+            let dispatcher_offset = self.allocate(1);
+            graph.add_synthetic_to_listing(dispatcher_offset,
+                Meta::JmpTrue(version_node, versions[index].flag));
+            let dispatcher = graph.add_node_at(dispatcher_offset);
+
+            let dispatchers = self.dispatchers.entry(offset).or_insert(Vec::new());
+            let next = match dispatchers.first() {
+                None => graph.node_at(offset)?,
+                Some(&head) => graph.node_at(head)?
+            };
+            graph.add_edge(dispatcher, version_node, EdgeValue::Regular);
+            graph.add_edge(dispatcher, next, EdgeValue::Regular);
+            graph.redirect_inbound_edges(next, dispatcher, &[dispatcher]);
+            dispatchers.insert(0, dispatcher_offset);
+        }
+
+        graph.set_versions(offset, versions.clone());
+        self.versions.insert(offset, versions);
+        Ok((unexplored, !added.is_empty()))
+    }
+
+    fn written_by(&self, writer: usize, byte_offset: usize) -> Option<&Option<HashSet<u8>>> {
+        self.writes.get(&byte_offset)
+            .and_then(|writes| writes.iter().find(|&&(other, _)| other == writer))
+            .map(|&(_, ref values)| values)
+    }
+
+    // The writers that can leave version's bytes in every byte they
+    // write to.
+    fn writers_of(&self, version: &CodeVersion, writers: &[usize], end: usize) -> Vec<usize> {
+        writers.iter().cloned()
+            .filter(|&writer| (version.offset..end).all(|byte_offset| {
+                match self.written_by(writer, byte_offset) {
+                    None => true,
+                    Some(&Some(ref values)) => values.contains(&version.bytes[byte_offset - version.offset]),
+                    Some(&None) => false
+                }
+            }))
+            .collect()
+    }
+
+    // Puts a SetFlag for flag after writer, on a branch of its own next
+    // to the one straight to the next instruction, since the write
+    // doesn't always go to this instruction.
+    fn add_setter<I: InstructionTrait>(&mut self, file_buffer: &[u8], graph: &mut FlowGraph<I>, writer: usize, flag: usize) -> Result<Vec<usize>, DecompilerError> {
+        let next = writer + graph.instruction_at(writer)?.length();
+        let node = graph.node_at(writer)?;
+        if graph.get_node_at(next) == Some(node) {
+            graph.split_node_at(node, next)?;
+        }
+
+        let setter_offset = self.allocate(1);
+        graph.add_synthetic_to_listing(setter_offset, Meta::SetFlag(flag));
+        let setter = graph.add_node_at(setter_offset);
+        let node = graph.node_at(writer)?;
+        graph.add_edge(node, setter, EdgeValue::Regular);
+
+        if next < file_buffer.len() {
+            graph.insert_offsets(setter_offset, vec!(next), true, EdgeValue::Regular)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    // Sends the edges into each rewritten instruction through the
+    // meta-instructions in front of it, but for the one from the last
+    // of them.
+    fn redirect<I: InstructionTrait>(&self, graph: &mut FlowGraph<I>) -> Result<(), DecompilerError> {
+        let mut offsets: Vec<usize> = self.dispatchers.keys().cloned().collect();
+        offsets.sort();
+
+        for offset in offsets {
+            let dispatchers = &self.dispatchers[&offset];
+            let head = graph.node_at(dispatchers[0])?;
+            let tail = graph.node_at(dispatchers[dispatchers.len() - 1])?;
+            let node = graph.node_at(offset)?;
+            graph.redirect_inbound_edges(node, head, &[tail]);
+        }
+
+        Ok(())
+    }
+}
+
+fn polymorphic<I: InstructionTrait>(graph: &FlowGraph<I>, writer: usize, written_offset: usize) -> DecompilerError {
+    DecompilerError::Polymorphic {
+        offset: writer,
+        instruction: match graph.get_inst(writer) {
+            None => String::from("???"),
+            Some(inst) => format!("{}", inst)
+        },
+        written_offset
+    }
+}
@@ -0,0 +1,154 @@
+use defs::main::*;
+use defs::data::*;
+
+// Shortest run of printable characters that is treated as a string.
+const MIN_STRING_LENGTH: usize = 4;
+
+// Builds a code/data/unknown map of the whole file buffer and stores
+// it in the listing, together with typed data regions:
+//
+// 1. bytes covered by instructions are code, and bytes marked as data
+// during disassembly are data.
+// 2. regions inferred by the architecture (e.g. sprites) are added,
+// unless they overlap code or an earlier region.
+// 3. the remaining non-code bytes are split into strings, where they
+// look like text, and raw bytes.
+
+pub fn classify<I, A>(file_buffer: &[u8], listing: &mut Listing<I>, architecture: A)
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    let mut classes = vec![ByteClass::Unknown; file_buffer.len()];
+    let mut claimed = vec![false; file_buffer.len()];
+
+    for (offset, meta) in listing.instructions.iter() {
+        if let Meta::Inst(inst) = *meta {
+            for code_offset in *offset..(*offset + inst.length()) {
+                if code_offset < classes.len() {
+                    classes[code_offset] = ByteClass::Code;
+                }
+            }
+        }
+    }
+
+    for offset in 0..file_buffer.len() {
+        if classes[offset] != ByteClass::Code && listing.is_data(offset) {
+            classes[offset] = ByteClass::Data;
+        }
+    }
+
+    let mut hints = architecture.data_regions(file_buffer, listing);
+    hints.sort_by(|a, b| a.offset.cmp(&b.offset).then(b.len().cmp(&a.len())));
+
+    for region in hints {
+        if region.len() == 0 || region.end() > file_buffer.len() {
+            continue;
+        }
+
+        let free = (region.offset..region.end()).all(|offset|
+            classes[offset] != ByteClass::Code && !claimed[offset]);
+
+        if free {
+            for offset in region.offset..region.end() {
+                classes[offset] = ByteClass::Data;
+                claimed[offset] = true;
+            }
+            listing.add_region(region);
+        }
+    }
+
+    let mut offset = 0;
+
+    while offset < file_buffer.len() {
+        if classes[offset] == ByteClass::Code || claimed[offset] {
+            offset += 1;
+            continue;
+        }
+
+        let mut end = offset;
+        while end < file_buffer.len() && classes[end] != ByteClass::Code && !claimed[end] {
+            end += 1;
+        }
+
+        for region in split_gap(file_buffer, &classes, offset, end) {
+            if region.data_type != DataType::Byte {
+                for string_offset in region.offset..region.end() {
+                    classes[string_offset] = ByteClass::Data;
+                }
+            }
+            listing.add_region(region);
+        }
+
+        offset = end;
+    }
+
+    listing.set_classes(classes);
+}
+
+// Splits the non-code bytes in [start, end) into strings and runs of
+// raw bytes. Runs of raw bytes don't mix data and unknown bytes.
+fn split_gap(file_buffer: &[u8], classes: &Vec<ByteClass>, start: usize, end: usize) -> Vec<DataRegion> {
+    let mut regions = Vec::new();
+    let mut bytes_start = start;
+    let mut offset = start;
+
+    while offset < end {
+        if let Some(string) = find_string(file_buffer, offset, end) {
+            push_bytes(&mut regions, file_buffer, classes, bytes_start, offset);
+            offset = string.end();
+            bytes_start = offset;
+            regions.push(string);
+        } else {
+            offset = printable_end(file_buffer, offset, end).max(offset + 1);
+        }
+    }
+
+    push_bytes(&mut regions, file_buffer, classes, bytes_start, end);
+    regions
+}
+
+fn push_bytes(regions: &mut Vec<DataRegion>, file_buffer: &[u8], classes: &Vec<ByteClass>, start: usize, end: usize) {
+    let mut run_start = start;
+
+    for offset in start..end {
+        if offset + 1 == end || classes[offset + 1] != classes[run_start] {
+            regions.push(DataRegion::new(run_start, DataType::Byte,
+                &file_buffer[run_start..(offset + 1)]));
+            run_start = offset + 1;
+        }
+    }
+}
+
+// Returns the string starting at offset, if the bytes there look like
+// text: at least MIN_STRING_LENGTH printable characters, at least half
+// of them letters, optionally terminated by a zero or by '$'.
+fn find_string(file_buffer: &[u8], offset: usize, end: usize) -> Option<DataRegion> {
+    let mut string_end = printable_end(file_buffer, offset, end);
+    let text = &file_buffer[offset..string_end];
+    let letters = text.iter().filter(|byte| (**byte as char).is_ascii_alphabetic()).count();
+
+    if text.len() < MIN_STRING_LENGTH || 2 * letters < text.len() {
+        return None;
+    }
+
+    if let Some(position) = text.iter().position(|&byte| byte == b'$') {
+        if position + 1 >= MIN_STRING_LENGTH {
+            return Some(DataRegion::new(offset, DataType::DollarString,
+                &file_buffer[offset..(offset + position + 1)]));
+        }
+    }
+
+    if string_end < end && file_buffer[string_end] == 0 {
+        string_end += 1;
+    }
+
+    Some(DataRegion::new(offset, DataType::Ascii, &file_buffer[offset..string_end]))
+}
+
+fn printable_end(file_buffer: &[u8], offset: usize, end: usize) -> usize {
+    let mut printable_end = offset;
+    while printable_end < end && is_printable(file_buffer[printable_end]) {
+        printable_end += 1;
+    }
+    printable_end
+}
@@ -0,0 +1,976 @@
+use defs::main::*;
+use defs::ir;
+use defs::ir::{Location, Mnemonic, Operand, Width};
+use graph::flow::{FlowGraph, CallGraph};
+use graph::dom;
+use graph::loops;
+use graph::ssa::{self, SsaFunction, Value, Site};
+use graph::summary::{self, FunctionSummary, is_variable};
+use graph::structure::{self, Statement, StructuredFunction, Condition};
+use std::fmt;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+// Decompiles a flow graph, whose instructions have been lifted into IR
+// (see FlowGraph::lift), into pseudo-C.
+//
+// Each function is put into SSA form and structured. Values that are
+// never really used are dropped, and a value used once, later in the
+// same block, is folded into the expression that uses it. The values
+// left over are named as locals: values joined by phis (or passed
+// through calls untouched) share one local.
+//
+// Signatures come from the function summaries (see graph::summary): a
+// function's parameters are the variables live on entry to it, and its
+// return values are its results. A call only uses its callee's
+// parameters, and only defines its callee's return values.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub parameters: Vec<Location>,
+    pub returns: Vec<Location>
+}
+
+pub struct DecompiledFunction {
+    pub code: String
+}
+
+impl fmt::Display for DecompiledFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code)
+    }
+}
+
+// Decompiles every function in graph, in the same order as the
+// functions of its call graph.
+pub fn decompile<I, L>(graph: &FlowGraph<I>, lifter: &L) -> Result<Vec<DecompiledFunction>, DecompilerError>
+    where I: InstructionTrait,
+          L: ir::LifterTrait<I>
+{
+    let call_graph = graph.call_graph()?;
+    let dominators = dom::function_dominators(graph, &call_graph);
+    let loop_forests = loops::function_loops(graph, &call_graph, &dominators);
+    let ssa_functions = ssa::function_ssa(graph, &call_graph, &dominators);
+
+    let mut structured = Vec::new();
+    let mut successors = Vec::new();
+    for (index, function) in call_graph.functions().iter().enumerate() {
+        structured.push(structure::structure(graph, function,
+            &dominators[index], &loop_forests[index])?);
+        successors.push(graph.function_edges(function).0);
+    }
+
+    let names = function_names(graph, &call_graph)?;
+    let callees = call_targets(&call_graph, &ssa_functions);
+    let summaries = summary::function_summaries(graph, &call_graph)?;
+    let analyses: Vec<Analysis> = (0..ssa_functions.len())
+        .map(|index| analyse(&ssa_functions[index], &callees[index], &summaries, index))
+        .collect();
+
+    let mut functions = Vec::new();
+    for index in 0..ssa_functions.len() {
+        let signature = Signature {
+            name: names[index].clone(),
+            parameters: summaries[index].parameters.clone(),
+            returns: summaries[index].results.clone()
+        };
+
+        let code = Renderer {
+            graph,
+            lifter,
+            own: index,
+            signature: &signature,
+            ssa: &ssa_functions[index],
+            structured: &structured[index],
+            successors: &successors[index],
+            analysis: &analyses[index],
+            summaries: &summaries,
+            callees: &callees[index],
+            names: &names,
+            classes: HashMap::new(),
+            widths: HashMap::new(),
+            folded: HashSet::new(),
+            locals: HashMap::new(),
+            declared: Vec::new(),
+            last_block: None
+        }.render();
+
+        functions.push(DecompiledFunction {
+            code
+        });
+    }
+
+    Ok(functions)
+}
+
+// Names functions after the offset of their entry. The first function
+// starts at the graph's virtual entry node, and is main.
+fn function_names<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph) -> Result<Vec<String>, DecompilerError> {
+    let mut names = Vec::new();
+
+    for function in call_graph.functions().iter() {
+        names.push(match graph.initial_instruction(function.entry())? {
+            Some(offset) if function.entry() != 0 => format!("sub_{:x}", offset),
+            _ => String::from("main")
+        });
+    }
+
+    Ok(names)
+}
+
+// Returns, for each function, the functions called by its call nodes.
+fn call_targets(call_graph: &CallGraph, functions: &[SsaFunction]) -> Vec<HashMap<usize, usize>> {
+    functions.iter()
+        .map(|function| function.nodes().iter()
+            .filter_map(|&node| call_graph.get_entry(node).map(|&callee| (node, callee)))
+            .collect())
+        .collect()
+}
+
+// Live statements and phis, and the values they really use.
+struct Analysis {
+    live: HashSet<Site>,
+    used: HashSet<Value>
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Plain,
+    Effect,
+    Collapsed,
+    Call(Option<usize>),
+    Return
+}
+
+fn kind(statement: &ssa::Statement, node: usize, callees: &HashMap<usize, usize>) -> Kind {
+    if statement.instructions.len() != 1 {
+        return Kind::Collapsed;
+    }
+
+    match statement.instructions[0].mnemonic {
+        Mnemonic::CALL => Kind::Call(callees.get(&node).cloned()),
+        Mnemonic::RET => Kind::Return,
+        Mnemonic::STM | Mnemonic::OUT | Mnemonic::IN | Mnemonic::HALT | Mnemonic::SYS
+        | Mnemonic::UNSUPPORTED | Mnemonic::JMP | Mnemonic::JMPZ | Mnemonic::JMPNZ => Kind::Effect,
+        _ => Kind::Plain
+    }
+}
+
+// Finds the statements of function that matter, and the values they
+// really use. Statements with effects are live, and so is anything
+// defining a value that a live statement really uses.
+fn analyse(function: &SsaFunction, callees: &HashMap<usize, usize>, summaries: &[FunctionSummary], own: usize) -> Analysis {
+    let mut analysis = Analysis {
+        live: HashSet::new(),
+        used: HashSet::new()
+    };
+    let mut work: Vec<Value> = Vec::new();
+
+    for &node in function.nodes() {
+        for (index, statement) in function.block(node).unwrap().statements.iter().enumerate() {
+            if kind(statement, node, callees) != Kind::Plain {
+                analysis.live.insert(Site::Statement(node, index));
+                work.extend(real_uses(statement, kind(statement, node, callees), summaries, own));
+            }
+        }
+    }
+
+    while let Some(value) = work.pop() {
+        if !analysis.used.insert(value) {
+            continue;
+        }
+
+        let site = match function.definition(value) {
+            Some(site) => site,
+            None => continue
+        };
+
+        match site {
+            Site::Entry => (),
+            Site::Phi(_, _) => {
+                analysis.live.insert(site);
+                let phi = function.phi(site).unwrap();
+                work.extend(phi.sources.iter().map(|&(_, source)| source));
+            },
+            Site::Statement(node, _) => {
+                let statement = function.statement(site).unwrap();
+                let statement_kind = kind(statement, node, callees);
+
+                // A call passes through the locations its callee
+                // doesn't return.
+                if let Kind::Call(Some(callee)) = statement_kind {
+                    if !summaries[callee].results.contains(&value.location) {
+                        work.extend(statement.use_of(value.location));
+                    }
+                }
+
+                if analysis.live.insert(site) {
+                    work.extend(real_uses(statement, statement_kind, summaries, own));
+                }
+            }
+        }
+    }
+
+    analysis
+}
+
+// Returns the number of operands of instruction that read location.
+fn reads_of(instruction: &ir::Instruction, location: Location) -> usize {
+    let writes = instruction.writes();
+    let operands = [instruction.op1, instruction.op2, instruction.op3];
+
+    operands.iter().enumerate()
+        .filter(|&(index, operand)| {
+            let operand_location = operand.and_then(|operand| operand.location());
+            operand_location == Some(location) && (index > 0 || !writes.contains(&location))
+        })
+        .count()
+}
+
+fn real_uses(statement: &ssa::Statement, kind: Kind, summaries: &[FunctionSummary], own: usize) -> Vec<Value> {
+    statement.uses.iter().cloned()
+        .filter(|value| match kind {
+            Kind::Call(Some(callee)) => !is_variable(value.location)
+                || summaries[callee].parameters.contains(&value.location),
+            Kind::Return => summaries[own].results.contains(&value.location),
+            _ => true
+        })
+        .collect()
+}
+
+// Pseudo-C expressions. Operands of binary operators are parenthesized
+// when they're binary operations themselves.
+#[derive(Clone)]
+enum Expression {
+    Atom(String),
+    Unary(String, Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>)
+}
+
+impl Expression {
+    fn unary(op: &str, operand: Expression) -> Expression {
+        Expression::Unary(String::from(op), Box::new(operand))
+    }
+
+    fn binary(op: &'static str, left: Expression, right: Expression) -> Expression {
+        Expression::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    fn negate(self) -> Expression {
+        match self {
+            Expression::Binary(op, left, right) => {
+                let negated = match op {
+                    "==" => Some("!="),
+                    "!=" => Some("=="),
+                    "<" => Some(">="),
+                    ">=" => Some("<"),
+                    ">" => Some("<="),
+                    "<=" => Some(">"),
+                    _ => None
+                };
+                match negated {
+                    Some(negated) => Expression::Binary(negated, left, right),
+                    None => Expression::unary("!", Expression::Binary(op, left, right))
+                }
+            },
+            Expression::Unary(ref op, ref operand) if op == "!" => (**operand).clone(),
+            other => Expression::unary("!", other)
+        }
+    }
+
+    fn operand(&self) -> String {
+        match *self {
+            Expression::Binary(_, _, _) => format!("({})", self),
+            _ => format!("{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expression::Atom(ref text) => write!(f, "{}", text),
+            Expression::Unary(ref op, ref operand) => write!(f, "{}{}", op, operand.operand()),
+            Expression::Binary(op, ref left, ref right) =>
+                write!(f, "{} {} {}", left.operand(), op, right.operand()),
+            Expression::Call(ref name, ref arguments) => {
+                let arguments: Vec<String> = arguments.iter()
+                    .map(|argument| format!("{}", argument))
+                    .collect();
+                write!(f, "{}({})", name, arguments.join(", "))
+            }
+        }
+    }
+}
+
+fn type_name(width: Width) -> &'static str {
+    match width {
+        Width::Bit => "bool",
+        Width::Byte => "u8",
+        Width::Word => "u16",
+        Width::Long => "u32"
+    }
+}
+
+fn constant(value: u32) -> Expression {
+    if value < 10 {
+        Expression::Atom(format!("{}", value))
+    } else {
+        Expression::Atom(format!("0x{:x}", value))
+    }
+}
+
+struct Renderer<'a, I: 'a + InstructionTrait, L: 'a + ir::LifterTrait<I>> {
+    graph: &'a FlowGraph<I>,
+    lifter: &'a L,
+    own: usize,
+    signature: &'a Signature,
+    ssa: &'a SsaFunction,
+    structured: &'a StructuredFunction,
+    successors: &'a HashMap<usize, Vec<usize>>,
+    analysis: &'a Analysis,
+    summaries: &'a [FunctionSummary],
+    callees: &'a HashMap<usize, usize>,
+    names: &'a [String],
+    classes: HashMap<Value, Value>,
+    widths: HashMap<Value, Width>,
+    folded: HashSet<Site>,
+    locals: HashMap<Value, String>,
+    declared: Vec<Value>,
+    last_block: Option<usize>
+}
+
+impl<'a, I: InstructionTrait, L: ir::LifterTrait<I>> Renderer<'a, I, L> {
+    fn render(&mut self) -> String {
+        self.join_classes();
+        self.find_widths();
+        self.fold();
+
+        let mut parameters = Vec::new();
+        for (index, &location) in self.signature.parameters.iter().enumerate() {
+            let class = self.class(Value { location, version: 0 });
+            let name = format!("arg{}", index + 1);
+            parameters.push(format!("{} {}", type_name(self.width(class)), name));
+            self.locals.insert(class, name);
+        }
+
+        let mut body = String::new();
+        let statements = self.structured.body.clone();
+        self.write_statements(&mut body, &statements, 1, false);
+
+        let returns: Vec<&str> = self.signature.returns.iter()
+            .map(|&location| type_name(self.location_width(location)))
+            .collect();
+        let return_type = match returns.len() {
+            0 => String::from("void"),
+            1 => String::from(returns[0]),
+            _ => format!("{{{}}}", returns.join(", "))
+        };
+
+        let mut output = format!("{} {}({}) {{\n", return_type,
+            self.signature.name, parameters.join(", "));
+
+        for class in self.declared.iter() {
+            output.push_str(format!("    {} {};\n", type_name(self.width(*class)),
+                self.locals[class]).as_str());
+        }
+        if !self.declared.is_empty() {
+            output.push('\n');
+        }
+
+        output.push_str(&body);
+        output.push_str("}\n");
+        output
+    }
+
+    // Puts values joined by phis into the same class, along with the
+    // values passed through calls and through statements that may or
+    // may not write them.
+    fn join_classes(&mut self) {
+        for &node in self.ssa.nodes() {
+            let block = self.ssa.block(node).unwrap();
+
+            for phi in block.phis.iter() {
+                for &(_, source) in phi.sources.iter() {
+                    self.union(phi.target, source);
+                }
+            }
+
+            for statement in block.statements.iter() {
+                let passed: Vec<Location> = match kind(statement, node, self.callees) {
+                    Kind::Call(Some(callee)) => statement.defs.iter()
+                        .map(|value| value.location)
+                        .filter(|location| !self.summaries[callee].results.contains(location))
+                        .collect(),
+                    Kind::Call(None) | Kind::Collapsed => statement.defs.iter()
+                        .map(|value| value.location)
+                        .collect(),
+                    _ if statement.instructions[0].clobbers_all() => statement.defs.iter()
+                        .map(|value| value.location)
+                        .collect(),
+                    _ => Vec::new()
+                };
+
+                for location in passed {
+                    if let (Some(def), Some(source)) = (statement.def_of(location), statement.use_of(location)) {
+                        self.union(def, source);
+                    }
+                }
+            }
+        }
+    }
+
+    fn class(&self, mut value: Value) -> Value {
+        while let Some(&parent) = self.classes.get(&value) {
+            value = parent;
+        }
+        value
+    }
+
+    fn union(&mut self, a: Value, b: Value) {
+        let (a, b) = (self.class(a), self.class(b));
+        if a != b {
+            self.classes.insert(b, a);
+        }
+    }
+
+    // Records the width of every class, from the operands that refer
+    // to its values.
+    fn find_widths(&mut self) {
+        let mut widths = Vec::new();
+
+        for &node in self.ssa.nodes() {
+            for statement in self.ssa.block(node).unwrap().statements.iter() {
+                for instruction in statement.instructions.iter() {
+                    let operands = [(instruction.op1, true), (instruction.op2, false), (instruction.op3, false)];
+                    for &(operand, first) in operands.iter() {
+                        let (location, width) = match operand {
+                            Some(operand) => match (operand.location(), operand.width()) {
+                                (Some(location), Some(width)) => (location, width),
+                                _ => continue
+                            },
+                            None => continue
+                        };
+                        let value = if first {
+                            statement.def_of(location).or(statement.use_of(location))
+                        } else {
+                            statement.use_of(location)
+                        };
+                        if let Some(value) = value {
+                            widths.push((value, width));
+                        }
+                    }
+                }
+            }
+        }
+
+        for (value, width) in widths {
+            let class = self.class(value);
+            let current = self.widths.entry(class).or_insert(width);
+            if width.bits() > current.bits() {
+                *current = width;
+            }
+        }
+    }
+
+    fn width(&self, class: Value) -> Width {
+        self.widths.get(&class).cloned().unwrap_or(Width::Word)
+    }
+
+    fn location_width(&self, location: Location) -> Width {
+        self.widths.iter()
+            .filter(|&(value, _)| value.location == location)
+            .map(|(_, width)| *width)
+            .max_by_key(|width| width.bits())
+            .unwrap_or(Width::Word)
+    }
+
+    // Decides which statements are folded into the statement that uses
+    // their value. A statement is folded if it has no effects, defines
+    // one value, and that value is used exactly once, later in the same
+    // block, with nothing in between writing a local (or memory) that
+    // the folded expression reads.
+    fn fold(&mut self) {
+        for &node in self.ssa.nodes() {
+            let block = self.ssa.block(node).unwrap();
+            // Folds that are waiting for their use, with the classes
+            // their expressions read.
+            let mut pending: HashMap<Value, (Site, HashSet<Value>, bool)> = HashMap::new();
+
+            for (index, statement) in block.statements.iter().enumerate() {
+                let site = Site::Statement(node, index);
+                if !self.analysis.live.contains(&site) {
+                    continue;
+                }
+
+                // Collect what this statement's expression reads, taking
+                // in the folds it uses.
+                let mut reads: HashSet<Value> = HashSet::new();
+                let mut reads_memory = statement.instructions.iter()
+                    .any(|instruction| instruction.mnemonic == Mnemonic::LDM);
+                for value in statement.uses.iter() {
+                    match pending.remove(value) {
+                        Some((_, folded_reads, folded_memory)) => {
+                            reads.extend(folded_reads);
+                            reads_memory |= folded_memory;
+                        },
+                        None => {
+                            reads.insert(self.class(*value));
+                        }
+                    }
+                }
+
+                // Cancel the folds whose inputs this statement changes.
+                let written: HashSet<Value> = statement.defs.iter()
+                    .filter(|value| value.location != Location::Memory)
+                    .map(|value| self.class(*value))
+                    .collect();
+                let writes_memory = statement.defs.iter()
+                    .any(|value| value.location == Location::Memory);
+                let cancelled: Vec<Value> = pending.iter()
+                    .filter(|&(_, &(_, ref folded_reads, folded_memory))|
+                        folded_reads.iter().any(|class| written.contains(class))
+                        || (folded_memory && writes_memory))
+                    .map(|(value, _)| *value)
+                    .collect();
+                for value in cancelled {
+                    let (cancelled_site, _, _) = pending.remove(&value).unwrap();
+                    self.folded.remove(&cancelled_site);
+                }
+
+                if let Some(value) = self.foldable(node, index, statement) {
+                    self.folded.insert(site);
+                    pending.insert(value, (site, reads, reads_memory));
+                }
+            }
+
+            // Folds whose use never came are printed where they are.
+            for (_, (site, _, _)) in pending {
+                self.folded.remove(&site);
+            }
+        }
+    }
+
+    fn foldable(&self, node: usize, index: usize, statement: &ssa::Statement) -> Option<Value> {
+        if kind(statement, node, self.callees) != Kind::Plain || statement.defs.len() != 1 {
+            return None;
+        }
+
+        let value = statement.defs[0];
+        let uses: Vec<Site> = self.ssa.uses(value).iter().cloned()
+            .filter(|site| self.analysis.live.contains(site))
+            .collect();
+
+        match (uses.len(), uses.first()) {
+            (1, Some(&Site::Statement(use_node, use_index))) if use_node == node && use_index > index => {
+                let user = self.ssa.statement(uses[0]).unwrap();
+                // Calls and returns only read some of their uses, so
+                // folding into a use they pass over would hide the value.
+                let read = match kind(user, use_node, self.callees) {
+                    Kind::Plain | Kind::Effect => reads_of(&user.instructions[0], value.location) == 1,
+                    Kind::Call(Some(callee)) => self.summaries[callee].parameters.contains(&value.location)
+                        && (self.summaries[callee].results.contains(&value.location)
+                            || !user.def_of(value.location)
+                                .map_or(false, |passed| self.analysis.used.contains(&passed))),
+                    Kind::Return => self.summaries[self.own].results.contains(&value.location),
+                    _ => false
+                };
+                if read {
+                    Some(value)
+                } else {
+                    None
+                }
+            },
+            _ => None
+        }
+    }
+
+    fn local(&mut self, value: Value) -> String {
+        let class = self.class(value);
+        if let Some(name) = self.locals.get(&class) {
+            return name.clone();
+        }
+
+        let name = format!("var{}", self.declared.len() + 1);
+        self.locals.insert(class, name.clone());
+        self.declared.push(class);
+        name
+    }
+
+    fn value(&mut self, value: Value) -> Expression {
+        match self.ssa.definition(value) {
+            Some(site @ Site::Statement(_, _)) if self.folded.contains(&site) => {
+                let statement = self.ssa.statement(site).unwrap();
+                self.expression(statement, &statement.instructions[0])
+            },
+            _ if is_variable(value.location) || self.ssa.definition(value) != Some(Site::Entry) =>
+                Expression::Atom(self.local(value)),
+            _ => Expression::Atom(ssa::location_name(value.location, self.lifter))
+        }
+    }
+
+    fn operand(&mut self, statement: &ssa::Statement, operand: Option<Operand>) -> Expression {
+        match operand {
+            None => Expression::Atom(String::from("?")),
+            Some(Operand::Const(value, _)) => constant(value),
+            Some(Operand::Address(offset)) => Expression::Atom(format!("0x{:x}", offset)),
+            Some(Operand::Port(index)) => Expression::Atom(self.lifter.port_name(index)),
+            Some(Operand::Local(index)) | Some(Operand::Node(index)) =>
+                Expression::Atom(format!("{}", index)),
+            Some(operand) => match operand.location().and_then(|location| statement.use_of(location)) {
+                Some(value) => self.value(value),
+                None => Expression::Atom(String::from("?"))
+            }
+        }
+    }
+
+    // Returns the value computed by an instruction with a destination.
+    fn expression(&mut self, statement: &ssa::Statement, instruction: &ir::Instruction) -> Expression {
+        let width = instruction.op1.and_then(|operand| operand.width()).unwrap_or(Width::Word);
+        let a = self.operand(statement, instruction.op2);
+        let binary = |op: &'static str, renderer: &mut Self, a: Expression| {
+            let b = renderer.operand(statement, instruction.op3);
+            Expression::binary(op, a, b)
+        };
+        let call = |name: &str, renderer: &mut Self, a: Expression, arity: usize| {
+            let mut arguments = vec!(a);
+            if arity == 2 {
+                arguments.push(renderer.operand(statement, instruction.op3));
+            }
+            Expression::Call(String::from(name), arguments)
+        };
+
+        match instruction.mnemonic {
+            Mnemonic::STR => a,
+            Mnemonic::LDM => Expression::unary(&format!("*({} *)", type_name(width)), a),
+            Mnemonic::ADD => binary("+", self, a),
+            Mnemonic::SUB => binary("-", self, a),
+            Mnemonic::MUL => binary("*", self, a),
+            Mnemonic::DIV => binary("/", self, a),
+            Mnemonic::MOD => binary("%", self, a),
+            Mnemonic::AND => binary("&", self, a),
+            Mnemonic::OR => binary("|", self, a),
+            Mnemonic::XOR => binary("^", self, a),
+            Mnemonic::SHL => binary("<<", self, a),
+            Mnemonic::SHR => binary(">>", self, a),
+            Mnemonic::SAR => call("sar", self, a, 2),
+            Mnemonic::ROL => call("rol", self, a, 2),
+            Mnemonic::ROR => call("ror", self, a, 2),
+            Mnemonic::NOT => Expression::unary("~", a),
+            Mnemonic::NEG => Expression::unary("-", a),
+            Mnemonic::ZEXT => Expression::unary(&format!("({})", type_name(width)), a),
+            Mnemonic::SEXT => call(&format!("sext{}", width.bits()), self, a, 1),
+            Mnemonic::TRUNC if width == Width::Bit => Expression::binary("&", a, constant(1)),
+            Mnemonic::TRUNC => Expression::unary(&format!("({})", type_name(width)), a),
+            Mnemonic::CMPZ => Expression::binary("==", a, constant(0)),
+            Mnemonic::CMPEQ => binary("==", self, a),
+            Mnemonic::CMPLT => binary("<", self, a),
+            Mnemonic::CMPSLT => call("signed_less", self, a, 2),
+            Mnemonic::CARRY => call("carry", self, a, 2),
+            Mnemonic::BORROW => call("borrow", self, a, 2),
+            Mnemonic::OVF => call("overflow", self, a, 2),
+            Mnemonic::PARITY => call("parity", self, a, 1),
+            Mnemonic::SIGN => call("sign", self, a, 1),
+            Mnemonic::IN => call("in", self, a, 1),
+            _ => Expression::Atom(format!("{:?}", instruction.mnemonic))
+        }
+    }
+
+    // Returns the C statement for an IR statement, if it needs one.
+    fn statement(&mut self, node: usize, index: usize) -> Option<String> {
+        let site = Site::Statement(node, index);
+        if !self.analysis.live.contains(&site) || self.folded.contains(&site) {
+            return None;
+        }
+
+        let statement = &self.ssa.block(node).unwrap().statements[index];
+        if statement.instructions.len() != 1 {
+            let instructions: Vec<String> = statement.instructions.iter()
+                .map(|instruction| instruction.format_with(self.lifter))
+                .collect();
+            return Some(format!("/* {} */", instructions.join("; ")));
+        }
+
+        let instruction = statement.instructions[0];
+        match instruction.mnemonic {
+            Mnemonic::JMP | Mnemonic::JMPZ | Mnemonic::JMPNZ | Mnemonic::RET | Mnemonic::NOP => None,
+            Mnemonic::CALL => Some(self.call(node, statement, &instruction)),
+            Mnemonic::HALT => Some(String::from("halt();")),
+            Mnemonic::SYS => {
+                let number = self.operand(statement, instruction.op1);
+                Some(format!("syscall({});", number))
+            },
+            Mnemonic::UNSUPPORTED => Some(String::from("unsupported();")),
+            Mnemonic::STM => {
+                let width = instruction.op2.and_then(|operand| operand.width()).unwrap_or(Width::Byte);
+                let address = self.operand(statement, instruction.op1);
+                let value = self.operand(statement, instruction.op2);
+                Some(format!("*({} *){} = {};", type_name(width), address.operand(), value))
+            },
+            Mnemonic::OUT => {
+                let port = self.operand(statement, instruction.op1);
+                let value = self.operand(statement, instruction.op2);
+                Some(format!("out({}, {});", port, value))
+            },
+            _ => {
+                let value = match statement.defs.first() {
+                    Some(value) => *value,
+                    None => return None
+                };
+                let name = self.local(value);
+                let expression = self.expression(statement, &instruction);
+                Some(format!("{} = {};", name, expression))
+            }
+        }
+    }
+
+    fn call(&mut self, node: usize, statement: &ssa::Statement, instruction: &ir::Instruction) -> String {
+        let callee = match self.callees.get(&node) {
+            Some(&callee) => callee,
+            None => {
+                let target = self.operand(statement, instruction.op1);
+                return format!("call({});", target);
+            }
+        };
+
+        let parameters = self.summaries[callee].parameters.clone();
+        let mut arguments = Vec::new();
+        for location in parameters {
+            arguments.push(match statement.use_of(location) {
+                Some(value) => format!("{}", self.value(value)),
+                None => String::from("?")
+            });
+        }
+        let call = format!("{}({})", self.names[callee], arguments.join(", "));
+
+        let returns = self.summaries[callee].results.clone();
+        let mut targets = Vec::new();
+        for location in returns {
+            targets.push(match statement.def_of(location) {
+                Some(value) if self.analysis.used.contains(&value) => self.local(value),
+                _ => String::from("_")
+            });
+        }
+
+        if targets.iter().all(|target| target == "_") {
+            return format!("{};", call);
+        }
+
+        match targets.len() {
+            1 => format!("{} = {};", targets[0], call),
+            _ => format!("{{{}}} = {};", targets.join(", "), call)
+        }
+    }
+
+    fn block(&mut self, output: &mut String, node: usize, depth: usize) {
+        let indent = "    ".repeat(depth);
+        self.last_block = Some(node);
+
+        if self.structured.is_labelled(node) {
+            let label = self.label(node);
+            output.push_str(format!("{}:\n", label).as_str());
+        }
+
+        let count = match self.ssa.block(node) {
+            Some(block) => block.statements.len(),
+            None => return
+        };
+
+        for index in 0..count {
+            if let Some(line) = self.statement(node, index) {
+                output.push_str(format!("{}{}\n", indent, line).as_str());
+            }
+        }
+    }
+
+    fn label(&self, node: usize) -> String {
+        match self.graph.initial_instruction(node) {
+            Ok(Some(offset)) => format!("label_{:x}", offset),
+            _ => format!("label_n{}", node)
+        }
+    }
+
+    // Returns the final statement of node with the given mnemonics.
+    fn final_statement(&self, node: usize, mnemonics: &[Mnemonic]) -> Option<&'a ssa::Statement> {
+        let ssa = self.ssa;
+        ssa.block(node).and_then(|block| block.statements.iter().rev()
+            .find(|statement| statement.instructions.len() == 1
+                && mnemonics.contains(&statement.instructions[0].mnemonic)))
+    }
+
+    // Returns the condition under which control goes from the node
+    // of condition to its second successor (see structure).
+    fn condition(&mut self, condition: Condition) -> Expression {
+        let taken = self.successors.get(&condition.node)
+            .and_then(|successors| successors.get(1))
+            .and_then(|&successor| self.graph.initial_instruction(successor).ok())
+            .and_then(|offset| offset);
+
+        let statement = match self.final_statement(condition.node, &[Mnemonic::JMPZ, Mnemonic::JMPNZ, Mnemonic::JMP]) {
+            Some(statement) => statement,
+            None => return Expression::Atom(String::from("?"))
+        };
+
+        let instruction = statement.instructions[0];
+        let mut expression = match instruction.mnemonic {
+            // A computed jump with two targets.
+            Mnemonic::JMP => {
+                let target = self.operand(statement, instruction.op1);
+                match taken {
+                    Some(offset) => Expression::binary("==", target, constant(offset as u32)),
+                    None => Expression::Atom(String::from("?"))
+                }
+            },
+            _ => {
+                let mut expression = self.operand(statement, instruction.op1);
+                if instruction.mnemonic == Mnemonic::JMPZ {
+                    expression = expression.negate();
+                }
+                // The second successor may be the one the branch falls
+                // through to.
+                if let (Some(Operand::Address(target)), Some(offset)) = (instruction.op2, taken) {
+                    if target != offset {
+                        expression = expression.negate();
+                    }
+                }
+                expression
+            }
+        };
+
+        if condition.negated {
+            expression = expression.negate();
+        }
+        expression
+    }
+
+    // Writes statements at depth. continues is whether falling off the
+    // end of them continues the innermost loop, which makes a continue at
+    // the end redundant, so it's left out.
+    fn write_statements(&mut self, output: &mut String, statements: &[Statement], depth: usize, continues: bool) {
+        let indent = "    ".repeat(depth);
+
+        for (index, statement) in statements.iter().enumerate() {
+            let continues = continues && index + 1 == statements.len();
+
+            match *statement {
+                Statement::Block(node) => self.block(output, node, depth),
+                Statement::If { condition, ref then_branch, ref else_branch } => {
+                    let mut condition = self.condition(condition);
+
+                    // Branches are written first, so that one that turns
+                    // out empty can be left out.
+                    let mut then_output = String::new();
+                    self.write_statements(&mut then_output, then_branch, depth + 1, continues);
+                    let mut else_output = String::new();
+                    self.write_statements(&mut else_output, else_branch, depth + 1, continues);
+
+                    if then_output.is_empty() && else_output.is_empty() {
+                        continue;
+                    }
+                    if then_output.is_empty() {
+                        condition = condition.negate();
+                        then_output = else_output;
+                        else_output = String::new();
+                    }
+
+                    // A lone break or continue goes on the same line.
+                    let jump = then_output.trim();
+                    if else_output.is_empty() && (jump == "break;" || jump == "continue;") {
+                        output.push_str(format!("{}if ({}) {}\n", indent, condition, jump).as_str());
+                        continue;
+                    }
+
+                    output.push_str(format!("{}if ({}) {{\n", indent, condition).as_str());
+                    output.push_str(&then_output);
+                    if !else_output.is_empty() {
+                        output.push_str(format!("{}}} else {{\n", indent).as_str());
+                        output.push_str(&else_output);
+                    }
+                    output.push_str(format!("{}}}\n", indent).as_str());
+                },
+                Statement::While { condition, ref body } => {
+                    // The header runs before every test, so if it does
+                    // anything, the test moves into the loop.
+                    let mut header = String::new();
+                    self.block(&mut header, condition.node, depth + 1);
+                    let test = self.condition(condition);
+
+                    if header.is_empty() {
+                        output.push_str(format!("{}while ({}) {{\n", indent, test).as_str());
+                    } else {
+                        output.push_str(format!("{}while (1) {{\n", indent).as_str());
+                        output.push_str(&header);
+                        output.push_str(format!("{}    if ({}) break;\n", indent, test.negate()).as_str());
+                    }
+                    self.write_statements(output, body, depth + 1, true);
+                    output.push_str(format!("{}}}\n", indent).as_str());
+                },
+                Statement::DoWhile { ref body, condition } => {
+                    output.push_str(format!("{}do {{\n", indent).as_str());
+                    self.write_statements(output, body, depth + 1, true);
+                    let test = self.condition(condition);
+                    output.push_str(format!("{}}} while ({});\n", indent, test).as_str());
+                },
+                Statement::Loop(ref body) => {
+                    output.push_str(format!("{}while (1) {{\n", indent).as_str());
+                    self.write_statements(output, body, depth + 1, true);
+                    output.push_str(format!("{}}}\n", indent).as_str());
+                },
+                Statement::Switch(node, ref cases) => {
+                    let target = match self.final_statement(node, &[Mnemonic::JMP]) {
+                        Some(statement) => self.operand(statement, statement.instructions[0].op1),
+                        None => Expression::Atom(String::from("?"))
+                    };
+                    output.push_str(format!("{}switch ({}) {{\n", indent, target).as_str());
+                    for &(case, ref body) in cases.iter() {
+                        let label = match self.graph.initial_instruction(case) {
+                            Ok(Some(offset)) => format!("0x{:x}", offset),
+                            _ => format!("{}", case)
+                        };
+                        output.push_str(format!("{}case {}:\n", indent, label).as_str());
+                        self.write_statements(output, body, depth + 1, false);
+                        output.push_str(format!("{}    break;\n", indent).as_str());
+                    }
+                    output.push_str(format!("{}}}\n", indent).as_str());
+                },
+                Statement::Break => output.push_str(format!("{}break;\n", indent).as_str()),
+                Statement::Continue => if !continues {
+                    output.push_str(format!("{}continue;\n", indent).as_str());
+                },
+                Statement::Goto(node) => {
+                    let label = self.label(node);
+                    output.push_str(format!("{}goto {};\n", indent, label).as_str());
+                },
+                Statement::Return => {
+                    let values = self.return_values();
+                    match values.len() {
+                        0 => output.push_str(format!("{}return;\n", indent).as_str()),
+                        1 => output.push_str(format!("{}return {};\n", indent, values[0]).as_str()),
+                        _ => output.push_str(format!("{}return {{{}}};\n", indent, values.join(", ")).as_str())
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns the values returned by the return at the end of the last
+    // block written.
+    fn return_values(&mut self) -> Vec<String> {
+        let statement = match self.last_block.and_then(|node| self.final_statement(node, &[Mnemonic::RET])) {
+            Some(statement) => statement,
+            None => return Vec::new()
+        };
+
+        let returns = self.summaries[self.own].results.clone();
+        let mut values = Vec::new();
+        for location in returns {
+            values.push(match statement.use_of(location) {
+                Some(value) => format!("{}", self.value(value)),
+                None => String::from("?")
+            });
+        }
+        values
+    }
+}
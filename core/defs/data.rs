@@ -0,0 +1,148 @@
+// Classification of the bytes in a file buffer:
+//
+// Code:
+// the byte is part of a disassembled instruction.
+//
+// Data:
+// the byte is known not to be code.
+//
+// Unknown:
+// the byte isn't reached by disassembly, and nothing is known about it.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ByteClass {
+    Code,
+    Data,
+    Unknown
+}
+
+// Types of data region:
+//
+// Byte, Word:
+// raw bytes or words, rendered as db/dw directives.
+//
+// Ascii:
+// printable characters, optionally followed by a terminating zero.
+//
+// DollarString:
+// printable characters terminated by '$', as used by DOS print calls.
+//
+// Sprite(row_bytes):
+// a bitmap drawn row_bytes bytes at a time.
+//
+// JumpTable:
+// a table of code addresses, one word per entry.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DataType {
+    Byte,
+    Word,
+    Ascii,
+    DollarString,
+    Sprite(usize),
+    JumpTable
+}
+
+#[derive(Clone, Debug)]
+pub struct DataRegion {
+    pub offset: usize,
+    pub data_type: DataType,
+    pub bytes: Vec<u8>
+}
+
+impl DataRegion {
+    pub fn new(offset: usize, data_type: DataType, bytes: &[u8]) -> DataRegion {
+        DataRegion {
+            offset: offset,
+            data_type: data_type,
+            bytes: bytes.to_vec()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.bytes.len()
+    }
+
+    pub fn contains(&self, offset: usize) -> bool {
+        offset >= self.offset && offset < self.end()
+    }
+
+    // Renders the region as a list of directives, one per output line.
+    // Words are read big-endian if big_endian is set, little-endian
+    // otherwise.
+    pub fn directives(&self, big_endian: bool) -> Vec<String> {
+        match self.data_type {
+            DataType::Byte => self.bytes.chunks(8)
+                .map(|chunk| format!("DB {}", chunk.iter()
+                    .map(|byte| format!("0x{:02x}", byte))
+                    .collect::<Vec<String>>()
+                    .join(", ")))
+                .collect(),
+            DataType::Word | DataType::JumpTable => {
+                let words: Vec<String> = self.bytes.chunks(2)
+                    .map(|chunk| if chunk.len() < 2 {
+                        format!("0x{:02x}", chunk[0])
+                    } else if big_endian {
+                        format!("0x{:02x}{:02x}", chunk[0], chunk[1])
+                    } else {
+                        format!("0x{:02x}{:02x}", chunk[1], chunk[0])
+                    })
+                    .collect();
+
+                if self.data_type == DataType::JumpTable {
+                    words.iter().map(|word| format!("DW {}", word)).collect()
+                } else {
+                    words.chunks(4)
+                        .map(|chunk| format!("DW {}", chunk.join(", ")))
+                        .collect()
+                }
+            },
+            DataType::Ascii => {
+                let (text, terminated) = match self.bytes.last() {
+                    Some(&0) => (&self.bytes[..self.bytes.len() - 1], true),
+                    _ => (&self.bytes[..], false)
+                };
+
+                vec!(format!("DB \"{}\"{}", escape(text),
+                    if terminated { ", 0" } else { "" }))
+            },
+            DataType::DollarString =>
+                vec!(format!("DB \"{}\"", escape(&self.bytes))),
+            DataType::Sprite(row_bytes) => self.bytes.chunks(row_bytes.max(1))
+                .map(|row| {
+                    let values: Vec<String> = row.iter()
+                        .map(|byte| format!("0x{:02x}", byte))
+                        .collect();
+                    let pixels: String = row.iter()
+                        .map(|byte| (0..8).rev()
+                            .map(|bit| if byte & (1 << bit) != 0 { '#' } else { '.' })
+                            .collect::<String>())
+                        .collect();
+                    format!("DB {}\t; {}", values.join(", "), pixels)
+                })
+                .collect()
+        }
+    }
+}
+
+pub fn is_printable(byte: u8) -> bool {
+    byte >= 0x20 && byte < 0x7f
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut output = String::new();
+
+    for &byte in bytes {
+        match byte {
+            b'"' => output.push_str("\\\""),
+            b'\\' => output.push_str("\\\\"),
+            _ => output.push(byte as char)
+        }
+    }
+
+    output
+}
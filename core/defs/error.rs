@@ -0,0 +1,138 @@
+use std::fmt;
+
+// Errors that can occur anywhere in the disassembly and analysis
+// pipeline. Every variant carries the file offset it concerns, and
+// where an instruction was decoded, its text, so that callers can
+// react to the specific kind of failure.
+//
+// Decode:
+// the bytes at "offset" couldn't be decoded into an instruction.
+//
+// Polymorphic:
+// the instruction at "offset" writes to "written_offset", which
+// holds code, in a way that can't be analysed.
+//
+// UnresolvedBranch:
+// the set of targets of the branch at "offset" couldn't be determined.
+//
+// UnsupportedInstruction:
+// the simulator or analyzer doesn't know how to handle the instruction.
+//
+// InvalidState:
+// simulating the instruction led to a state the simulator can't
+// represent (e.g. reading from uninitialized memory).
+//
+// GraphInconsistency:
+// a flow graph is missing a node, instruction or edge it should have.
+
+#[derive(Clone, Debug)]
+pub enum DecompilerError {
+    Decode {
+        offset: usize,
+        reason: String
+    },
+    Polymorphic {
+        offset: usize,
+        instruction: String,
+        written_offset: usize
+    },
+    UnresolvedBranch {
+        offset: usize,
+        instruction: String,
+        reason: String
+    },
+    UnsupportedInstruction {
+        offset: usize,
+        instruction: String,
+        reason: String
+    },
+    InvalidState {
+        offset: usize,
+        instruction: String,
+        reason: String
+    },
+    GraphInconsistency {
+        offset: Option<usize>,
+        node: Option<usize>,
+        reason: String
+    }
+}
+
+impl DecompilerError {
+    pub fn graph(reason: String) -> DecompilerError {
+        DecompilerError::GraphInconsistency {
+            offset: None,
+            node: None,
+            reason: reason
+        }
+    }
+
+    pub fn graph_at_node(node: usize, reason: String) -> DecompilerError {
+        DecompilerError::GraphInconsistency {
+            offset: None,
+            node: Some(node),
+            reason: reason
+        }
+    }
+
+    pub fn graph_at_offset(offset: usize, reason: String) -> DecompilerError {
+        DecompilerError::GraphInconsistency {
+            offset: Some(offset),
+            node: None,
+            reason: reason
+        }
+    }
+
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            DecompilerError::Decode { offset, .. }
+            | DecompilerError::Polymorphic { offset, .. }
+            | DecompilerError::UnresolvedBranch { offset, .. }
+            | DecompilerError::UnsupportedInstruction { offset, .. }
+            | DecompilerError::InvalidState { offset, .. } => Some(offset),
+            DecompilerError::GraphInconsistency { offset, .. } => offset
+        }
+    }
+
+    pub fn instruction(&self) -> Option<&str> {
+        match *self {
+            DecompilerError::Polymorphic { ref instruction, .. }
+            | DecompilerError::UnresolvedBranch { ref instruction, .. }
+            | DecompilerError::UnsupportedInstruction { ref instruction, .. }
+            | DecompilerError::InvalidState { ref instruction, .. } =>
+                Some(instruction.as_str()),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for DecompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecompilerError::Decode { offset, ref reason } =>
+                write!(f, "Error decoding offset 0x{:x}: {}", offset, reason),
+            DecompilerError::Polymorphic { offset, ref instruction, written_offset } =>
+                write!(f, "Code is polymorphic: instruction {} at offset 0x{:x} writes to code offset 0x{:x}",
+                    instruction, offset, written_offset),
+            DecompilerError::UnresolvedBranch { offset, ref instruction, ref reason } =>
+                write!(f, "Can't resolve branch {} at offset 0x{:x}: {}",
+                    instruction, offset, reason),
+            DecompilerError::UnsupportedInstruction { offset, ref instruction, ref reason } =>
+                write!(f, "Unsupported instruction {} at offset 0x{:x}: {}",
+                    instruction, offset, reason),
+            DecompilerError::InvalidState { offset, ref instruction, ref reason } =>
+                write!(f, "Invalid state simulating {} at offset 0x{:x}: {}",
+                    instruction, offset, reason),
+            DecompilerError::GraphInconsistency { offset, node, ref reason } => {
+                write!(f, "Graph inconsistency")?;
+                if let Some(node) = node {
+                    write!(f, " at node {}", node)?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, " at offset 0x{:x}", offset)?;
+                }
+                write!(f, ": {}", reason)
+            }
+        }
+    }
+}
@@ -1,23 +1,366 @@
-//use defs::main::InstructionTrait;
-
-struct Instruction {
-    mnemonic: Mnemonic,
-    op1: Option<Operand>,
-    op2: Option<Operand>,
-    op3: Option<Operand>
-}
-
-enum Operand {
-    Bit(bool),
-    Byte(u8),
-    Word(u16),
-    Flag(bool),
-    Reg8(usize),
-    Reg16(usize),
+use defs::main::*;
+use std::fmt;
+
+// An architecture-neutral intermediate representation. Each native
+// instruction is lifted into a short sequence of IR instructions whose
+// register, flag and memory effects are all explicit, so that analyses
+// can be written once for every architecture.
+//
+// Operands:
+//
+// Const(value, width): an immediate value.
+// Reg(index, width): an architecture register; the lifter names it.
+// Flag(index): a one-bit architecture flag; the lifter names it.
+// Temp(index, width): a temporary, local to the IR sequence of one
+// native instruction.
+// Address(offset): a code address, as a file offset.
+// Port(index): an I/O port or device (e.g. a timer or the display);
+// the lifter names it.
+// Local(index): the index of an instruction in the same IR sequence,
+// used for loops inside one native instruction (e.g. REP prefixes).
+// Node(index): a flow graph node.
+//
+// Instructions: op1 is the destination, op2 and op3 the sources,
+// except for STM, OUT and the jumps, which have no destination.
+//
+// STR dst, src: dst <- src
+// LDM dst, addr: dst <- memory[addr], reading dst's width
+// STM addr, src: memory[addr] <- src, writing src's width
+// ADD, SUB, MUL, DIV, MOD, AND, OR, XOR, SHL, SHR, SAR, ROL, ROR
+// dst, a, b: dst <- a op b (DIV and MOD unsigned; SAR arithmetic)
+// NOT, NEG dst, src: dst <- op src
+// ZEXT, SEXT, TRUNC dst, src: dst <- src, extended or truncated to
+// dst's width
+// CMPZ dst, src: dst <- (src == 0)
+// CMPEQ dst, a, b: dst <- (a == b)
+// CMPLT dst, a, b: dst <- (a < b), unsigned
+// CMPSLT dst, a, b: dst <- (a < b), signed
+// CARRY dst, a, b: dst <- carry out of a + b
+// BORROW dst, a, b: dst <- borrow out of a - b
+// OVF dst, a, b: dst <- signed overflow of a + b
+// PARITY dst, src: dst <- even parity of the low byte of src
+// SIGN dst, src: dst <- top bit of src
+// JMP target: jump to target
+// JMPZ cond, target: jump to target if cond is zero
+// JMPNZ cond, target: jump to target if cond isn't zero
+// CALL target: call the function at target
+// RET: return from the current function
+// HALT: stop the program
+// SYS number: system call or interrupt; may change any state
+// IN dst, port: dst <- read from port
+// OUT port, src: write src to port
+// NOP: does nothing
+// UNSUPPORTED: an instruction the lifter doesn't model; may change
+// any state
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Width {
+    Bit,
+    Byte,
+    Word,
+    Long
+}
+
+impl Width {
+    pub fn bits(&self) -> u32 {
+        match *self {
+            Width::Bit => 1,
+            Width::Byte => 8,
+            Width::Word => 16,
+            Width::Long => 32
+        }
+    }
+
+    pub fn mask(&self) -> u32 {
+        match *self {
+            Width::Long => 0xffffffff,
+            _ => (1 << self.bits()) - 1
+        }
+    }
+}
+
+impl fmt::Display for Width {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Const(u32, Width),
+    Reg(usize, Width),
+    Flag(usize),
+    Temp(usize, Width),
+    Address(usize),
+    Port(usize),
+    Local(usize),
     Node(usize)
 }
 
-enum Mnemonic {
-    ADD, SUB, MUL, DIV, MOD, BSH, AND, OR, XOR,
-    LDM, STR, STM, CMPZ, JMPZ, CALL, RET, ERROR
+impl Operand {
+    pub fn width(&self) -> Option<Width> {
+        match *self {
+            Operand::Const(_, width) | Operand::Reg(_, width)
+            | Operand::Temp(_, width) => Some(width),
+            Operand::Flag(_) => Some(Width::Bit),
+            _ => None
+        }
+    }
+
+    // Returns the location that the operand names, if it's one that
+    // an instruction can read or write.
+    pub fn location(&self) -> Option<Location> {
+        match *self {
+            Operand::Reg(index, _) => Some(Location::Reg(index)),
+            Operand::Flag(index) => Some(Location::Flag(index)),
+            Operand::Temp(index, _) => Some(Location::Temp(index)),
+            Operand::Port(index) => Some(Location::Port(index)),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Const(value, width) => write!(f, "#{:x}:{}", value, width),
+            Operand::Reg(index, width) => write!(f, "r{}:{}", index, width),
+            Operand::Flag(index) => write!(f, "f{}", index),
+            Operand::Temp(index, width) => write!(f, "t{}:{}", index, width),
+            Operand::Address(offset) => write!(f, "<{:x}>", offset),
+            Operand::Port(index) => write!(f, "p{}", index),
+            Operand::Local(index) => write!(f, "@{}", index),
+            Operand::Node(index) => write!(f, "<node {}>", index)
+        }
+    }
+}
+
+// A place that IR instructions read from or write to. Memory isn't
+// split by address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    Reg(usize),
+    Flag(usize),
+    Temp(usize),
+    Port(usize),
+    Memory
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Mnemonic {
+    STR, LDM, STM,
+    ADD, SUB, MUL, DIV, MOD, AND, OR, XOR, SHL, SHR, SAR, ROL, ROR,
+    NOT, NEG, ZEXT, SEXT, TRUNC,
+    CMPZ, CMPEQ, CMPLT, CMPSLT, CARRY, BORROW, OVF, PARITY, SIGN,
+    JMP, JMPZ, JMPNZ, CALL, RET, HALT, SYS, IN, OUT, NOP, UNSUPPORTED
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub mnemonic: Mnemonic,
+    pub op1: Option<Operand>,
+    pub op2: Option<Operand>,
+    pub op3: Option<Operand>
+}
+
+impl Instruction {
+    pub fn new(mnemonic: Mnemonic) -> Instruction {
+        Instruction {
+            mnemonic: mnemonic,
+            op1: None,
+            op2: None,
+            op3: None
+        }
+    }
+
+    pub fn unary(mnemonic: Mnemonic, op1: Operand) -> Instruction {
+        Instruction {
+            op1: Some(op1),
+            .. Instruction::new(mnemonic)
+        }
+    }
+
+    pub fn binary(mnemonic: Mnemonic, op1: Operand, op2: Operand) -> Instruction {
+        Instruction {
+            op1: Some(op1),
+            op2: Some(op2),
+            .. Instruction::new(mnemonic)
+        }
+    }
+
+    pub fn ternary(mnemonic: Mnemonic, op1: Operand, op2: Operand, op3: Operand) -> Instruction {
+        Instruction {
+            op1: Some(op1),
+            op2: Some(op2),
+            op3: Some(op3),
+            .. Instruction::new(mnemonic)
+        }
+    }
+
+    fn has_destination(&self) -> bool {
+        match self.mnemonic {
+            Mnemonic::STM | Mnemonic::OUT | Mnemonic::JMP | Mnemonic::JMPZ
+            | Mnemonic::JMPNZ | Mnemonic::CALL | Mnemonic::RET | Mnemonic::HALT
+            | Mnemonic::SYS | Mnemonic::NOP | Mnemonic::UNSUPPORTED => false,
+            _ => true
+        }
+    }
+
+    // Returns true if the instruction may read or write any state,
+    // so that analyses must assume the worst about it.
+    pub fn clobbers_all(&self) -> bool {
+        match self.mnemonic {
+            Mnemonic::CALL | Mnemonic::SYS | Mnemonic::UNSUPPORTED => true,
+            _ => false
+        }
+    }
+
+    // Returns the locations the instruction reads. Instructions that
+    // clobber everything aren't considered to read anything in
+    // particular.
+    pub fn reads(&self) -> Vec<Location> {
+        let mut reads = Vec::new();
+        let sources = if self.has_destination() {
+            [self.op2, self.op3, None]
+        } else {
+            [self.op1, self.op2, self.op3]
+        };
+
+        for operand in sources.iter() {
+            if let Some(location) = operand.and_then(|operand| operand.location()) {
+                if !reads.contains(&location) {
+                    reads.push(location);
+                }
+            }
+        }
+
+        if self.mnemonic == Mnemonic::LDM {
+            reads.push(Location::Memory);
+        }
+
+        if self.mnemonic == Mnemonic::OUT {
+            reads.retain(|location| match *location {
+                Location::Port(_) => false,
+                _ => true
+            });
+        }
+
+        reads
+    }
+
+    // Returns the locations the instruction writes.
+    pub fn writes(&self) -> Vec<Location> {
+        match self.mnemonic {
+            Mnemonic::STM => vec!(Location::Memory),
+            Mnemonic::OUT => self.op1.and_then(|operand| operand.location())
+                .into_iter().collect(),
+            _ if self.has_destination() =>
+                self.op1.and_then(|operand| operand.location())
+                    .into_iter().collect(),
+            _ => Vec::new()
+        }
+    }
+
+    // Prints the instruction, using lifter to name registers, flags
+    // and ports.
+    pub fn format_with<I, L>(&self, lifter: &L) -> String
+        where I: InstructionTrait,
+              L: LifterTrait<I>
+    {
+        let operands: Vec<String> = [self.op1, self.op2, self.op3].iter()
+            .filter_map(|operand| *operand)
+            .map(|operand| match operand {
+                Operand::Reg(index, _) => lifter.register_name(index),
+                Operand::Flag(index) => lifter.flag_name(index),
+                Operand::Port(index) => lifter.port_name(index),
+                _ => format!("{}", operand)
+            })
+            .collect();
+
+        if operands.is_empty() {
+            format!("{:?}", self.mnemonic)
+        } else {
+            format!("{:?} {}", self.mnemonic, operands.join(", "))
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operands: Vec<String> = [self.op1, self.op2, self.op3].iter()
+            .filter_map(|operand| *operand)
+            .map(|operand| format!("{}", operand))
+            .collect();
+
+        if operands.is_empty() {
+            write!(f, "{:?}", self.mnemonic)
+        } else {
+            write!(f, "{:?} {}", self.mnemonic, operands.join(", "))
+        }
+    }
+}
+
+// IR instructions can be laid out in a Listing of their own. Offsets
+// are then indices into the IR sequence: each instruction has length
+// one, and Local operands are offsets in the same sequence.
+impl InstructionTrait for Instruction {
+    fn length(&self) -> usize {
+        1
+    }
+
+    fn is_call(&self) -> bool {
+        self.mnemonic == Mnemonic::CALL
+    }
+
+    fn is_return(&self) -> bool {
+        self.mnemonic == Mnemonic::RET
+    }
+
+    fn is_rel_branch(&self) -> bool {
+        false
+    }
+
+    fn writes_memory(&self) -> bool {
+        self.mnemonic == Mnemonic::STM
+    }
+
+    fn successors(&self, offset: usize) -> Result<(Vec<usize>, Vec<usize>, bool, bool), DecompilerError> {
+        let target = |operand: Option<Operand>| match operand {
+            Some(Operand::Address(target)) | Some(Operand::Local(target)) => Some(target),
+            _ => None
+        };
+
+        Ok(match self.mnemonic {
+            Mnemonic::JMP => match target(self.op1) {
+                Some(target) => (vec!(target), Vec::new(), true, false),
+                None => (Vec::new(), Vec::new(), true, true)
+            },
+            Mnemonic::JMPZ | Mnemonic::JMPNZ => match target(self.op2) {
+                Some(target) => (vec!(offset + 1, target), Vec::new(), true, false),
+                None => return Err(DecompilerError::UnresolvedBranch {
+                    offset: offset,
+                    instruction: format!("{}", self),
+                    reason: String::from("conditional jump target should be an address")
+                })
+            },
+            Mnemonic::CALL => match target(self.op1) {
+                Some(target) => (vec!(offset + 1), vec!(target), true, false),
+                None => (vec!(offset + 1), Vec::new(), true, true)
+            },
+            Mnemonic::RET | Mnemonic::HALT => (Vec::new(), Vec::new(), true, false),
+            Mnemonic::SYS | Mnemonic::UNSUPPORTED =>
+                (vec!(offset + 1), Vec::new(), false, true),
+            _ => (vec!(offset + 1), Vec::new(), false, false)
+        })
+    }
+}
+
+// Lifts native instructions into IR. Register, flag and port indices
+// are chosen by the lifter, which also names them for printing.
+pub trait LifterTrait<I: InstructionTrait> {
+    // Returns the IR for instruction, found at offset.
+    fn lift(&self, instruction: I, offset: usize) -> Vec<Instruction>;
+    fn register_name(&self, index: usize) -> String;
+    fn flag_name(&self, index: usize) -> String;
+    fn port_name(&self, index: usize) -> String;
 }
@@ -1,10 +1,20 @@
+pub use defs::error::DecompilerError;
+use defs::data::{ByteClass, DataRegion};
+use defs::ir;
 use std::fmt;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::time::Instant;
 
 pub trait Architecture<I: InstructionTrait> : Copy + Clone {
-    fn decode_instruction(&self, buffer: &[u8], offset: usize) -> Result<I, String>;
+    fn decode_instruction(&self, buffer: &[u8], offset: usize) -> Result<I, DecompilerError>;
     fn print_listing(listing: &Listing<I>);
+
+    // Returns data regions whose type can be inferred from the
+    // instructions that use them (e.g. sprites or jump tables).
+    fn data_regions(&self, _file_buffer: &[u8], _listing: &Listing<I>) -> Vec<DataRegion> {
+        Vec::new()
+    }
 }
 
 pub trait SimulatorTrait<S: StateTrait<S>, I: InstructionTrait> {
@@ -17,6 +27,20 @@ pub trait StateTrait<S: StateTrait<S>> : Clone + fmt::Display {
     fn union(self, state: S) -> S;
     fn is_subset(&self, state: &S) -> bool;
     fn combine(&self, state: &S) -> CombineResult<S>;
+
+    // widen(state) -> Some(widened_state)
+    // widened_state contains both states, with the values that grew
+    // extrapolated so that widening it again and again soon stops
+    // changing it. It's None if the states can't be joined, e.g.
+    // because they're at different offsets.
+    fn widen(&self, state: &S) -> Option<S>;
+
+    // narrow(state) -> Some(narrowed_state)
+    // narrowed_state refines a widened state with state, the union of
+    // the states that reached it since, undoing some of the
+    // extrapolation. It's None if the states can't be joined.
+    fn narrow(&self, state: &S) -> Option<S>;
+
     fn debug_string(&self) -> String;
 }
 
@@ -41,8 +65,32 @@ pub trait InstructionTrait : Copy + Clone + fmt::Display {
     // couldn't be determined without knowledge of program state.
     // (e.g. because instruction is an indirect jump or an interrupt
     // that might end the program).
+    //
+    // An error is returned if the instruction's operands don't make
+    // sense for its optype.
 
-    fn successors(&self, offset: usize) -> (Vec<usize>, Vec<usize>, bool, bool);
+    fn successors(&self, offset: usize) -> Result<(Vec<usize>, Vec<usize>, bool, bool), DecompilerError>;
+
+    // The instruction's mnemonic and operands as text, for exporting
+    // listings. By default they're read off its Display, taking it to
+    // be the mnemonic followed by the operands, separated by commas.
+    fn mnemonic(&self) -> String {
+        let text = format!("{}", self);
+        match text.find(' ') {
+            None => text,
+            Some(end) => text[..end].to_string()
+        }
+    }
+
+    fn operands(&self) -> Vec<String> {
+        let text = format!("{}", self);
+        match text.find(' ') {
+            None => Vec::new(),
+            Some(end) => text[end + 1..].split(", ")
+                .map(|operand| operand.to_string())
+                .collect()
+        }
+    }
 }
 
 // Meta-instructions to help with decoding self-modifying code:
@@ -59,6 +107,14 @@ pub trait InstructionTrait : Copy + Clone + fmt::Display {
 //
 // SetFlag(flag_num):
 // Sets meta flag flag_num
+//
+// Each version of a rewritten instruction has a flag of its own (see
+// CodeVersion), and setting it clears the flags of the other versions
+// of the same instruction, since only one can be there at a time. The
+// instructions that rewrite it are followed by a SetFlag for each
+// version they can write, and the instruction is preceded by a JmpTrue
+// to each version but the one in the file, which is run if no flag is
+// set. All of these are listed at offsets past the end of the file.
 
 #[derive(Clone)]
 pub enum Meta<I: InstructionTrait> {
@@ -69,10 +125,13 @@ pub enum Meta<I: InstructionTrait> {
 }
 
 impl<I: InstructionTrait> Meta<I> {
-    pub fn unwrap(&self) -> I {
+    // Returns the wrapped instruction, assuming the meta-instruction
+    // was found at offset.
+    pub fn unwrap(&self, offset: usize) -> Result<I, DecompilerError> {
         match *self {
-            Meta::Inst(instruction) => instruction,
-            _ => panic!("can't unwrap meta-instruction")
+            Meta::Inst(instruction) => Ok(instruction),
+            _ => Err(DecompilerError::graph_at_offset(offset,
+                format!("can't unwrap meta-instruction {}", self)))
         }
     }
 }
@@ -88,6 +147,22 @@ impl<I: InstructionTrait> fmt::Display for Meta<I> {
     }
 }
 
+// A version of the instruction at "offset", as code elsewhere can
+// rewrite it. "bytes" are the instruction's bytes in this version,
+// "writers" the offsets of the instructions that can write them, and
+// "flag" the meta flag that's set while it's the current version. The
+// first version of each instruction is the one in the file, listed at
+// its own offset; the others are listed at "listed_at", past the end
+// of the file.
+#[derive(Clone, Debug)]
+pub struct CodeVersion {
+    pub offset: usize,
+    pub listed_at: usize,
+    pub bytes: Vec<u8>,
+    pub writers: Vec<usize>,
+    pub flag: usize
+}
+
 #[derive(Clone)]
 pub struct Listing<I: InstructionTrait> {
     pub entry_offset: usize,
@@ -95,6 +170,14 @@ pub struct Listing<I: InstructionTrait> {
     pub instructions: HashMap<usize, Meta<I>>,
     labels: HashSet<usize>,
     indeterminates: HashSet<usize>,
+    data: HashMap<usize, u8>,
+    invalid: HashMap<usize, DecompilerError>,
+    regions: HashMap<usize, DataRegion>,
+    classes: Vec<ByteClass>,
+    ir: HashMap<usize, Vec<ir::Instruction>>,
+    versions: HashMap<usize, Vec<CodeVersion>>,
+    version_offsets: HashMap<usize, usize>,
+    owners: HashMap<usize, Vec<usize>>,
     iter_offset: usize
 }
 
@@ -106,6 +189,14 @@ impl<I: InstructionTrait> Listing<I> {
             instructions: HashMap::<usize, Meta<I>>::new(),
             labels: HashSet::new(),
             indeterminates: HashSet::new(),
+            data: HashMap::new(),
+            invalid: HashMap::new(),
+            regions: HashMap::new(),
+            classes: Vec::new(),
+            ir: HashMap::new(),
+            versions: HashMap::new(),
+            version_offsets: HashMap::new(),
+            owners: HashMap::new(),
             iter_offset: 0
         }
     }
@@ -117,6 +208,14 @@ impl<I: InstructionTrait> Listing<I> {
             instructions: HashMap::new(),
             labels: HashSet::new(),
             indeterminates: HashSet::new(),
+            data: HashMap::new(),
+            invalid: HashMap::new(),
+            regions: HashMap::new(),
+            classes: Vec::new(),
+            ir: HashMap::new(),
+            versions: HashMap::new(),
+            version_offsets: HashMap::new(),
+            owners: HashMap::new(),
             iter_offset: 0
         }
     }
@@ -126,13 +225,124 @@ impl<I: InstructionTrait> Listing<I> {
             self.highest_offset = offset;
         }
 
-        self.instructions.insert(offset, Meta::Inst(instruction));
+        // The instruction this replaces no longer owns its bytes.
+        if let Some(Meta::Inst(previous)) = self.instructions.insert(offset, Meta::Inst(instruction)) {
+            for byte_offset in offset..offset + previous.length() {
+                let now_unowned = match self.owners.get_mut(&byte_offset) {
+                    Some(owners) => {
+                        owners.retain(|&owner| owner != offset);
+                        owners.is_empty()
+                    },
+                    None => false
+                };
+                if now_unowned {
+                    self.owners.remove(&byte_offset);
+                }
+            }
+        }
+
+        for byte_offset in offset..offset + instruction.length() {
+            let owners = self.owners.entry(byte_offset).or_insert(Vec::new());
+            if !owners.contains(&offset) {
+                owners.push(offset);
+                owners.sort();
+            }
+        }
     }
 
     pub fn get(&self, offset: usize) -> Option<&Meta<I>> {
         self.instructions.get(&offset)
     }
 
+    // Adds a meta-instruction, or a version of an instruction, at an
+    // offset past the end of the file. These don't count towards the
+    // highest offset, so iterating over the listing skips them.
+    pub fn add_synthetic(&mut self, offset: usize, meta: Meta<I>) {
+        self.instructions.insert(offset, meta);
+    }
+
+    // Replaces the versions of the instruction at offset, the one in
+    // the file first.
+    pub fn set_versions(&mut self, offset: usize, versions: Vec<CodeVersion>) {
+        for version in versions.iter() {
+            self.version_offsets.insert(version.listed_at, offset);
+        }
+        self.versions.insert(offset, versions);
+    }
+
+    // Returns the versions of the instruction at offset, or nothing
+    // if no code rewrites it.
+    pub fn versions(&self, offset: usize) -> &[CodeVersion] {
+        match self.versions.get(&offset) {
+            None => &[],
+            Some(versions) => versions
+        }
+    }
+
+    pub fn versioned_offsets(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self.versions.keys().cloned().collect();
+        offsets.sort();
+        offsets
+    }
+
+    // Returns the version listed at listed_at, if there is one.
+    pub fn version_at(&self, listed_at: usize) -> Option<&CodeVersion> {
+        self.version_offsets.get(&listed_at).and_then(|offset|
+            self.versions(*offset).iter().find(|version| version.listed_at == listed_at))
+    }
+
+    // The offset the instruction listed at offset runs at, which is
+    // offset itself unless it's a version of another instruction.
+    pub fn code_offset(&self, offset: usize) -> usize {
+        match self.version_offsets.get(&offset) {
+            None => offset,
+            Some(code_offset) => *code_offset
+        }
+    }
+
+    // Returns the offsets of the instructions the byte at offset is
+    // part of, in order. There's more than one where they overlap.
+    pub fn owners(&self, offset: usize) -> &[usize] {
+        match self.owners.get(&offset) {
+            None => &[],
+            Some(owners) => owners
+        }
+    }
+
+    pub fn is_code(&self, offset: usize) -> bool {
+        self.owners.contains_key(&offset)
+    }
+
+    // Returns the other instructions that share a byte with the one
+    // at offset, in order.
+    pub fn overlapping(&self, offset: usize) -> Vec<usize> {
+        let length = match self.get(offset) {
+            Some(&Meta::Inst(instruction)) => instruction.length(),
+            _ => return Vec::new()
+        };
+
+        let mut overlapping: Vec<usize> = (offset..offset + length)
+            .flat_map(|byte_offset| self.owners(byte_offset).iter().cloned())
+            .filter(|&owner| owner != offset)
+            .collect();
+        overlapping.sort();
+        overlapping.dedup();
+        overlapping
+    }
+
+    // Returns each pair of overlapping instructions, the lower offset
+    // first, in order.
+    pub fn overlaps(&self) -> Vec<(usize, usize)> {
+        let mut overlaps: Vec<(usize, usize)> = self.owners.values()
+            .filter(|owners| owners.len() > 1)
+            .flat_map(|owners| (0..owners.len()).flat_map(move |first|
+                (first + 1..owners.len()).map(move |second| (owners[first], owners[second]))))
+            .collect();
+        overlaps.sort();
+        overlaps.dedup();
+        overlaps
+    }
+
     pub fn add_label(&mut self, offset: usize) {
         self.labels.insert(offset);
     }
@@ -148,6 +358,107 @@ impl<I: InstructionTrait> Listing<I> {
     pub fn is_indeterminate(&self, offset: usize) -> bool {
         self.indeterminates.contains(&offset)
     }
+
+    // Marks the byte at offset as data rather than code.
+    pub fn add_data(&mut self, offset: usize, byte: u8) {
+        self.data.insert(offset, byte);
+    }
+
+    pub fn get_data(&self, offset: usize) -> Option<u8> {
+        self.data.get(&offset).cloned()
+    }
+
+    pub fn is_data(&self, offset: usize) -> bool {
+        self.data.contains_key(&offset)
+    }
+
+    // Records that disassembly failed at offset, and why.
+    pub fn add_invalid(&mut self, offset: usize, error: DecompilerError) {
+        self.invalid.insert(offset, error);
+    }
+
+    pub fn get_invalid(&self, offset: usize) -> Option<&DecompilerError> {
+        self.invalid.get(&offset)
+    }
+
+    pub fn is_invalid(&self, offset: usize) -> bool {
+        self.invalid.contains_key(&offset)
+    }
+
+    pub fn add_region(&mut self, region: DataRegion) {
+        self.regions.insert(region.offset, region);
+    }
+
+    // Returns the data region starting at offset, if there is one.
+    pub fn get_region(&self, offset: usize) -> Option<&DataRegion> {
+        self.regions.get(&offset)
+    }
+
+    pub fn set_classes(&mut self, classes: Vec<ByteClass>) {
+        self.classes = classes;
+    }
+
+    pub fn byte_class(&self, offset: usize) -> ByteClass {
+        match self.classes.get(offset) {
+            Some(class) => *class,
+            None => ByteClass::Unknown
+        }
+    }
+
+    // Returns the offset one past the highest instruction or
+    // classified byte in the listing.
+    pub fn end_offset(&self) -> usize {
+        (self.highest_offset + 1).max(self.classes.len())
+    }
+
+    // Attaches the IR sequence for the instruction at offset.
+    pub fn add_ir(&mut self, offset: usize, instructions: Vec<ir::Instruction>) {
+        self.ir.insert(offset, instructions);
+    }
+
+    pub fn get_ir(&self, offset: usize) -> Option<&Vec<ir::Instruction>> {
+        self.ir.get(&offset)
+    }
+
+    // Lifts every instruction in the listing, replacing any IR that
+    // was attached before.
+    pub fn lift<L: ir::LifterTrait<I>>(&mut self, lifter: &L) {
+        let mut lifted = HashMap::new();
+
+        for (offset, meta) in self.instructions.iter() {
+            if let Meta::Inst(instruction) = *meta {
+                lifted.insert(*offset, lifter.lift(instruction, self.code_offset(*offset)));
+            }
+        }
+
+        self.ir = lifted;
+    }
+
+    // Returns the offsets of the instructions in the listing, in order.
+    pub fn offsets(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self.instructions.keys().cloned().collect();
+        offsets.sort();
+        offsets
+    }
+
+    pub fn labels(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self.labels.iter().cloned().collect();
+        offsets.sort();
+        offsets
+    }
+
+    pub fn indeterminates(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self.indeterminates.iter().cloned().collect();
+        offsets.sort();
+        offsets
+    }
+
+    // Returns the offsets at which disassembly failed, in order.
+    pub fn invalid_offsets(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self.invalid.keys().cloned().collect();
+        offsets.sort();
+        offsets
+    }
 }
 
 impl<I: InstructionTrait> Iterator for Listing<I> {
@@ -171,7 +482,7 @@ impl<I: InstructionTrait> Iterator for Listing<I> {
 
 
 pub enum SimResult<S: StateTrait<S>> {
-    Error(S, String),
+    Error(S, DecompilerError),
     End,
     State(S),
     Branch(Vec<S>, Vec<usize>)
@@ -185,6 +496,28 @@ pub enum CombineResult<S: StateTrait<S>> {
     Combination(S)
 }
 
+// Bounds on the work done simulating programs that might never finish.
+// In exhaust::simulate_exhaustively, each exploration stops once its
+// graph holds "max_states" states or it has simulated "max_steps"
+// instructions, and every exploration stops at "deadline". In analysis,
+// the same bounds apply to each slice an analyzer simulates. None means
+// there's no bound.
+pub struct Limits {
+    pub max_states: Option<usize>,
+    pub max_steps: Option<usize>,
+    pub deadline: Option<Instant>
+}
+
+impl Limits {
+    pub fn none() -> Limits {
+        Limits {
+            max_states: None,
+            max_steps: None,
+            deadline: None
+        }
+    }
+}
+
 pub fn get_word_le(buffer : &[u8], offset: usize) -> u16 {
     let word = buffer[offset + 1] as u16;
     (word << 8) + buffer[offset] as u16
@@ -1,4 +1,6 @@
 pub mod main;
+pub mod error;
+pub mod data;
 pub mod set;
 pub mod range;
-//pub mod ir;
+pub mod ir;
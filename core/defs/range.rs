@@ -242,7 +242,7 @@ impl RangeTrait<usize, RangeVec<usize>> for RangeVec<usize> {
     }
 }*/
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Range<T> {
     min: T,
     max: T
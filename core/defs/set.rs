@@ -1,6 +1,6 @@
 use defs::main::*;
 use std::fmt;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::ops::Add;
 
@@ -9,16 +9,17 @@ pub struct Memory<'a> {
     load_offset: usize,
     endian: Endian,
     base: &'a [u8],
-    deltas: HashMap<usize, Value>
+    // Ordered by address, so that states print the same every run.
+    deltas: BTreeMap<usize, Value>
 }
 
 impl<'a> Memory<'a> {
     pub fn new(buffer: &'a [u8], load_offset: usize, endian: Endian) -> Memory<'a> {
         Memory {
-            load_offset: load_offset,
-            endian: endian,
+            load_offset,
+            endian,
             base: buffer,
-            deltas: HashMap::new()
+            deltas: BTreeMap::new()
         }
     }
 
@@ -33,6 +34,65 @@ impl<'a> Memory<'a> {
         self
     }
 
+    // Unlike union, widen keeps the original contents of an address that
+    // only one of the memories has written to.
+    pub fn widen(mut self, memory: Memory<'a>) -> Memory<'a> {
+        let addresses: Vec<usize> = self.deltas.keys()
+            .filter(|address| !memory.deltas.contains_key(address))
+            .cloned()
+            .collect();
+        for address in addresses {
+            if let Some(original_value) = self.original_value(address, &self.deltas[&address]) {
+                let value = self.deltas.remove(&address).unwrap();
+                self.deltas.insert(address, value.widen(original_value));
+            }
+        }
+
+        for (address, value) in memory.deltas {
+            let widened_value = match self.deltas.remove(&address) {
+                Some(memory_value) => memory_value.widen(value),
+                None => match self.original_value(address, &value) {
+                    Some(original_value) => original_value.widen(value),
+                    None => value
+                }
+            };
+            self.deltas.insert(address, widened_value);
+        }
+        self
+    }
+
+    // Returns what was at memory_address before anything was written to
+    // it, as a value of the same size as value, if it's in the buffer.
+    fn original_value(&self, memory_address: usize, value: &Value) -> Option<Value> {
+        let byte_at = |address: usize| if address < self.load_offset ||
+            address - self.load_offset >= self.base.len()
+        {
+            None
+        } else {
+            Some(Byte::new(self.base[address - self.load_offset]))
+        };
+
+        match *value {
+            Value::Byte(_) => byte_at(memory_address).map(Value::Byte),
+            Value::Word(_) => match (byte_at(memory_address), byte_at(memory_address + 1)) {
+                (Some(byte1), Some(byte2)) => match self.endian {
+                    Endian::Little => Some(Value::Word(byte1.combine(byte2))),
+                    Endian::Big => Some(Value::Word(byte2.combine(byte1)))
+                },
+                _ => None
+            }
+        }
+    }
+
+    pub fn narrow(mut self, memory: Memory<'a>) -> Memory<'a> {
+        for (address, value) in memory.deltas {
+            if let Some(memory_value) = self.deltas.remove(&address) {
+                self.deltas.insert(address, memory_value.narrow(value));
+            }
+        }
+        self
+    }
+
     pub fn is_subset(&self, memory: &Memory<'a>) -> bool {
         for (address, value1) in self.deltas.iter() {
             match memory.deltas.get(&address) {
@@ -59,15 +119,14 @@ impl<'a> Memory<'a> {
         }
     }
 
+    // Returns None if nothing was written to memory_address and it's
+    // outside the buffer, whether before or after it.
     pub fn get_byte(&self, memory_address: usize) -> Option<Byte> {
         match self.deltas.get(&memory_address) {
-            None => {
-                let offset = memory_address - self.load_offset;
-                if offset >= self.base.len() {
-                    None
-                } else {
-                    Some(Byte::new(self.base[offset]))
-                }
+            None => match memory_address.checked_sub(self.load_offset) {
+                Some(offset) if offset < self.base.len() =>
+                    Some(Byte::new(self.base[offset])),
+                _ => None
             },
             Some(value) => match value {
                 &Value::Byte(ref new_byte) => Some(new_byte.clone()),
@@ -87,7 +146,7 @@ impl<'a> Memory<'a> {
         }
     }
 
-    pub fn get_deltas(&self) -> &HashMap<usize, Value> {
+    pub fn get_deltas(&self) -> &BTreeMap<usize, Value> {
         &self.deltas
     }
 }
@@ -144,6 +203,39 @@ impl Value {
             Value::Byte(byte) => Value::Byte(byte.plus(addendum as u8))
         }
     }
+
+    pub fn widen(self, value: Value) -> Value {
+        match self {
+            Value::Word(word1) => match value {
+                Value::Word(word2) => Value::Word(word1.widen(word2)),
+                _ => panic!("can't widen words with bytes.")
+            },
+            Value::Byte(byte1) => match value {
+                Value::Byte(byte2) => Value::Byte(byte1.widen(byte2)),
+                _ => panic!("can't widen bytes with words.")
+            }
+        }
+    }
+
+    pub fn narrow(self, value: Value) -> Value {
+        match self {
+            Value::Word(word1) => match value {
+                Value::Word(word2) => Value::Word(word1.narrow(word2)),
+                _ => panic!("can't narrow words with bytes.")
+            },
+            Value::Byte(byte1) => match value {
+                Value::Byte(byte2) => Value::Byte(byte1.narrow(byte2)),
+                _ => panic!("can't narrow bytes with words.")
+            }
+        }
+    }
+
+    pub fn expand(self) -> Value {
+        match self {
+            Value::Word(word) => Value::Word(word.expand()),
+            Value::Byte(byte) => Value::Byte(byte.expand())
+        }
+    }
 }
 
 impl fmt::Debug for Value {
@@ -164,12 +256,33 @@ impl fmt::Display for Value {
     }
 }
 
+// Strided intervals are the values lo, lo + stride, ..., hi, where
+// lo < hi and stride divides hi - lo; they don't wrap around. They keep
+// counters and table indices small. Sets of at least MIN_STRIDED values
+// that form such a progression are always kept as strided intervals.
+//
+// Known bits (known, value) are the values whose bits in known are the
+// same as in value, and whose other bits can be anything. Sets of at
+// least MIN_BITS values that are exactly such a pattern, but aren't a
+// progression, are kept as known bits. Bitwise operations and shifts on
+// patterns give patterns, so masking an unknown register stays precise.
+//
+// Operations on bytes are exact. Operations on words are exact too,
+// except that a result involving a strided interval or known bits that
+// would have more than MAX_EXPANDED values, and isn't a progression or a
+// pattern itself, is widened to the smallest one containing it.
+const MIN_STRIDED: usize = 3;
+const MIN_BITS: usize = 4;
+const MAX_EXPANDED: usize = 256;
+
 #[derive(Clone)]
 pub enum Word {
     Undefined,
     AnyValue,
     Int(HashSet<u16>),
     Bytes(Byte, Byte),
+    Strided(u16, u16, u16),
+    Bits(u16, u16)
 }
 
 impl Word {
@@ -184,14 +297,186 @@ impl Word {
         for word in vector {
             word_set.insert(word);
         }
-        Word::Int(word_set)
+        Word::from_set(word_set)
+    }
+
+    pub fn from_set(set: HashSet<u16>) -> Word {
+        let mut values: Vec<u32> = set.iter().map(|&word| word as u32).collect();
+        match progression(&mut values) {
+            Some((lo, hi, stride)) => Word::Strided(lo as u16, hi as u16, stride as u16),
+            None => match pattern(&values, 0xffff) {
+                Some((known, value)) => Word::Bits(known as u16, value as u16),
+                None => Word::Int(set)
+            }
+        }
+    }
+
+    // Returns the word whose bits in known are the same as in value.
+    pub fn from_bits(known: u16, value: u16) -> Word {
+        let (unknown, value) = (!known, value & known);
+        if unknown == 0 {
+            Word::new(value)
+        } else if known == 0 {
+            Word::AnyValue
+        } else if is_run(unknown as u32) {
+            Word::strided(value as u32, (value | unknown) as u32,
+                1 << unknown.trailing_zeros())
+        } else {
+            Word::Bits(known, value)
+        }
+    }
+
+    // Returns the bits every value of the word has in common, as the
+    // mask of those bits and their values. The result is exact for
+    // patterns, and for explicit sets that form one.
+    pub fn known_bits(&self) -> (u16, u16) {
+        match *self {
+            Word::Undefined => panic!("taking the known bits of undefined word"),
+            Word::AnyValue => (0, 0),
+            Word::Int(ref set) => {
+                let values: Vec<u32> = set.iter().map(|&word| word as u32).collect();
+                let (known, value) = bits_of(&values, 0xffff);
+                (known as u16, value as u16)
+            },
+            Word::Bytes(ref bytel, ref byteh) => {
+                let ((knownl, valuel), (knownh, valueh)) =
+                    (bytel.known_bits(), byteh.known_bits());
+                (knownl as u16 | (knownh as u16) << 8, valuel as u16 | (valueh as u16) << 8)
+            },
+            Word::Strided(lo, hi, stride) => {
+                let (known, value) = strided_bits(lo as u32, hi as u32, stride as u32, 0xffff);
+                (known as u16, value as u16)
+            },
+            Word::Bits(known, value) => (known, value)
+        }
+    }
+
+    // Tests whether the word holds exactly the values of its known bits.
+    fn is_pattern(&self) -> bool {
+        match *self {
+            Word::Undefined => false,
+            _ => {
+                let (known, _) = self.known_bits();
+                self.len() == bits_len(known as u32, 0xffff)
+            }
+        }
+    }
+
+    // Returns the word holding the strided interval (lo, hi, stride),
+    // where a stride of 0 means the single value lo.
+    fn strided(lo: u32, hi: u32, stride: u32) -> Word {
+        if stride == 0 || lo == hi {
+            Word::new(lo as u16)
+        } else if strided_len(lo, hi, stride) < MIN_STRIDED {
+            Word::Int(strided_values(lo, hi, stride).into_iter().map(|word| word as u16).collect())
+        } else {
+            Word::Strided(lo as u16, hi as u16, stride as u16)
+        }
+    }
+
+    // Returns the word with a strided interval or known bits written out
+    // as a set.
+    pub fn expand(self) -> Word {
+        match self {
+            Word::Strided(_, _, _) | Word::Bits(_, _) =>
+                Word::Int(self.values().into_iter().collect()),
+            word => word
+        }
+    }
+
+    fn values(&self) -> Vec<u16> {
+        match *self {
+            Word::Int(ref set) => set.iter().cloned().collect(),
+            Word::Strided(lo, hi, stride) =>
+                strided_values(lo as u32, hi as u32, stride as u32).into_iter()
+                    .map(|word| word as u16)
+                    .collect(),
+            Word::Bits(known, value) =>
+                bits_values(known as u32, value as u32, 0xffff).into_iter()
+                    .map(|word| word as u16)
+                    .collect(),
+            _ => panic!("word has no explicit values")
+        }
+    }
+
+    // Returns the smallest strided interval containing an explicit
+    // word, if it has any values.
+    fn hull(&self) -> Option<(u32, u32, u32)> {
+        match *self {
+            Word::Strided(lo, hi, stride) => Some((lo as u32, hi as u32, stride as u32)),
+            Word::Bits(known, value) => {
+                let unknown = !known;
+                Some((value as u32, (value | unknown) as u32, 1 << unknown.trailing_zeros()))
+            },
+            _ => strided_hull(self.values().iter().map(|&word| word as u32))
+        }
+    }
+
+    // Returns the number of values two explicit words have in common.
+    fn common(&self, word: &Word) -> usize {
+        match (self, word) {
+            (&Word::Strided(lo1, hi1, stride1), &Word::Strided(lo2, hi2, stride2)) =>
+                match strided_meet((lo1 as u32, hi1 as u32, stride1 as u32),
+                    (lo2 as u32, hi2 as u32, stride2 as u32))
+                {
+                    Some((lo, hi, 0)) => if lo == hi { 1 } else { 0 },
+                    Some((lo, hi, stride)) => strided_len(lo, hi, stride),
+                    None => 0
+                },
+            (&Word::Bits(known1, value1), &Word::Bits(known2, value2)) =>
+                match bits_meet((known1 as u32, value1 as u32), (known2 as u32, value2 as u32)) {
+                    Some((known, _)) => bits_len(known, 0xffff),
+                    None => 0
+                },
+            (&Word::Int(ref set), _) => set.iter().filter(|&&value| word.can_be(value)).count(),
+            (_, &Word::Int(_)) => word.common(self),
+            _ => if self.len() <= word.len() {
+                self.values().iter().filter(|&&value| word.can_be(value)).count()
+            } else {
+                word.common(self)
+            }
+        }
+    }
+
+    // Returns the union of two explicit words.
+    fn union_values(self, word: Word) -> Word {
+        let (hull1, hull2) = match (self.hull(), word.hull()) {
+            (None, _) => return word,
+            (_, None) => return self,
+            (Some(hull1), Some(hull2)) => (hull1, hull2)
+        };
+
+        let (lo, hi, stride) = strided_join(hull1, hull2);
+        if stride == 0 {
+            return Word::new(lo as u16);
+        }
+
+        let ((known1, value1), (known2, value2)) = (self.known_bits(), word.known_bits());
+        let (known, value) = bits_join((known1 as u32, value1 as u32),
+            (known2 as u32, value2 as u32));
+
+        let exact = self.len() + word.len() - self.common(&word);
+        let (strided, bits) = (strided_len(lo, hi, stride), bits_len(known, 0xffff));
+        if exact == strided {
+            Word::strided(lo, hi, stride)
+        } else if exact == bits {
+            Word::from_bits(known as u16, value as u16)
+        } else if exact <= MAX_EXPANDED {
+            let mut set: HashSet<u16> = self.values().into_iter().collect();
+            set.extend(word.values());
+            Word::from_set(set)
+        } else if bits < strided {
+            Word::from_bits(known as u16, value as u16)
+        } else {
+            Word::strided(lo, hi, stride)
+        }
     }
 
     pub fn union(self, word: Word) -> Word {
         if let Word::Bytes(byte1, byte2) = word {
             Word::Bytes(byte1.union(self.split_low()),
                 byte2.union(self.split_high()))
-        } else if let Word::Bytes(byte1, byte2) = self { 
+        } else if let Word::Bytes(byte1, byte2) = self {
             Word::Bytes(byte1.union(word.split_low()),
                 byte2.union(word.split_high()))
         } else {
@@ -201,14 +486,62 @@ impl Word {
                 Word::Int(set1) => match word {
                     Word::Undefined => Word::Undefined,
                     Word::AnyValue => Word::AnyValue,
-                    Word::Int(set2) => Word::Int(set1.union(&set2).cloned().collect()),
+                    Word::Int(set2) => Word::from_set(set1.union(&set2).cloned().collect()),
+                    Word::Strided(_, _, _) | Word::Bits(_, _) => Word::Int(set1).union_values(word),
                     _ => panic!("invalid word")
                     },
+                Word::Strided(_, _, _) | Word::Bits(_, _) => match word {
+                    Word::Undefined => Word::Undefined,
+                    Word::AnyValue => Word::AnyValue,
+                    _ => self.union_values(word)
+                },
                 _ => panic!("invalid word")
             }
         }
     }
 
+    // Returns a word containing both words, like union. A bound that
+    // the values grew past moves on to the next threshold, so widening
+    // a word again and again soon stops changing it.
+    pub fn widen(self, word: Word) -> Word {
+        if let Word::Bytes(byte1, byte2) = word {
+            return Word::Bytes(self.split_low().widen(byte1),
+                self.split_high().widen(byte2));
+        } else if let Word::Bytes(byte1, byte2) = self {
+            return Word::Bytes(byte1.widen(word.split_low()),
+                byte2.widen(word.split_high()));
+        }
+        if word.is_subset(&self) {
+            return self;
+        }
+
+        let old_hull = match self {
+            Word::Undefined | Word::AnyValue => None,
+            _ => self.hull()
+        };
+        let union = self.union(word);
+        match union {
+            Word::Undefined | Word::AnyValue | Word::Bits(_, _) => union,
+            _ => match (old_hull, union.hull()) {
+                (Some((lo1, hi1, _)), Some((lo2, hi2, stride))) => {
+                    let (lo, hi) = widen_bounds((lo1, hi1), (lo2, hi2, stride), 0xffff);
+                    Word::strided(lo, hi, stride)
+                },
+                _ => union
+            }
+        }
+    }
+
+    // Returns the values of a widened word that are also in word, which
+    // holds the values that reached it since.
+    pub fn narrow(self, word: Word) -> Word {
+        match (&self, &word) {
+            (&Word::Undefined, _) => word.clone(),
+            (_, &Word::Undefined) => self.clone(),
+            _ => self.intersect(&word)
+        }
+    }
+
     pub fn is_subset(&self, word: &Word) -> bool {
         if let Word::Bytes(ref byte1, ref byte2) = *word {
             self.split_low().is_subset(byte1) &&
@@ -230,6 +563,27 @@ impl Word {
                     Word::Undefined => false,
                     Word::AnyValue => true,
                     Word::Int(ref set2) => set1.is_subset(&set2),
+                    Word::Strided(_, _, _) | Word::Bits(_, _) =>
+                        set1.iter().all(|&value| word.can_be(value)),
+                    _ => panic!("shouldn't be here")
+                },
+                Word::Strided(lo1, hi1, stride1) => match *word {
+                    Word::Undefined => false,
+                    Word::AnyValue => true,
+                    Word::Int(_) => self.len() <= word.len()
+                        && self.values().iter().all(|&value| word.can_be(value)),
+                    Word::Strided(lo2, hi2, stride2) => lo1 >= lo2 && hi1 <= hi2
+                        && (lo1 - lo2) % stride2 == 0 && stride1 % stride2 == 0,
+                    Word::Bits(_, _) => self.values().iter().all(|&value| word.can_be(value)),
+                    _ => panic!("shouldn't be here")
+                },
+                Word::Bits(known1, value1) => match *word {
+                    Word::Undefined => false,
+                    Word::AnyValue => true,
+                    Word::Int(_) | Word::Strided(_, _, _) => self.len() <= word.len()
+                        && self.values().iter().all(|&value| word.can_be(value)),
+                    Word::Bits(known2, value2) =>
+                        known1 & known2 == known2 && value1 & known2 == value2,
                     _ => panic!("shouldn't be here")
                 },
                 _ => panic!("shouldn't be here")
@@ -243,7 +597,10 @@ impl Word {
             Word::AnyValue => 65536,
             Word::Int(ref words) => words.len(),
             Word::Bytes(ref bytel, ref byteh) =>
-                bytel.clone().combine(byteh.clone()).len()
+                bytel.clone().combine(byteh.clone()).len(),
+            Word::Strided(lo, hi, stride) =>
+                strided_len(lo as u32, hi as u32, stride as u32),
+            Word::Bits(known, _) => bits_len(known as u32, 0xffff)
         }
     }
 
@@ -251,13 +608,34 @@ impl Word {
         match self {
             Word::Undefined => Word::Undefined,
             Word::AnyValue => Word::AnyValue,
-            Word::Int(words) => Word::Int(
+            Word::Int(words) => Word::from_set(
                 words.iter().map(
                     |word| word.wrapping_add(addendum)
                 ).collect()
             ),
             Word::Bytes(_, _) =>
-                panic!("adding to split word not implemented.")
+                panic!("adding to split word not implemented."),
+            Word::Strided(lo, hi, stride) => {
+                let (lo, hi, stride, addendum) =
+                    (lo as u32, hi as u32, stride as u32, addendum as u32);
+
+                if hi + addendum <= 0xffff {
+                    Word::strided(lo + addendum, hi + addendum, stride)
+                } else if lo + addendum > 0xffff {
+                    Word::strided(lo + addendum - 0x10000, hi + addendum - 0x10000, stride)
+                } else {
+                    // Only some of the values wrap around, so they no
+                    // longer form a single progression.
+                    Word::from_set(self.values().iter().map(
+                        |word| word.wrapping_add(addendum as u16)
+                    ).collect())
+                }
+            },
+            Word::Bits(_, _) => Word::from_set(
+                self.values().iter().map(
+                    |word| word.wrapping_add(addendum)
+                ).collect()
+            )
         }
     }
 
@@ -273,7 +651,10 @@ impl Word {
                     };
                     set
                 }),
-            Word::Bytes(ref byte_low, _) => byte_low.clone()
+            Word::Bytes(ref byte_low, _) => byte_low.clone(),
+            Word::Strided(_, _, _) =>
+                Byte::from_set(self.values().iter().map(|&word| word as u8).collect()),
+            Word::Bits(known, value) => Byte::from_bits(known as u8, value as u8)
         }
     }
 
@@ -289,7 +670,10 @@ impl Word {
                     };
                     set
                 }),
-            Word::Bytes(_, ref byte_high) => byte_high.clone()
+            Word::Bytes(_, ref byte_high) => byte_high.clone(),
+            Word::Strided(_, _, _) =>
+                Byte::from_set(self.values().iter().map(|&word| (word >> 8) as u8).collect()),
+            Word::Bits(known, value) => Byte::from_bits((known >> 8) as u8, (value >> 8) as u8)
         }
     }
 
@@ -300,7 +684,10 @@ impl Word {
             Word::Int(ref set) => set.contains(&word),
             Word::Bytes(ref bytel, ref byteh) =>
                 bytel.can_be((word % 0x100) as u8)
-                && byteh.can_be((word / 0x100) as u8)
+                && byteh.can_be((word / 0x100) as u8),
+            Word::Strided(lo, hi, stride) =>
+                strided_contains((lo as u32, hi as u32, stride as u32), word as u32),
+            Word::Bits(known, value) => word & known == value
         }
     }
 
@@ -322,7 +709,33 @@ impl Word {
             Word::Int(ref set1) => match *word {
                 Word::AnyValue => self.clone(),
                 Word::Int(ref set2) =>
-                    Word::Int(set1.intersection(set2).cloned().collect::<HashSet<u16>>()),
+                    Word::from_set(set1.intersection(set2).cloned().collect::<HashSet<u16>>()),
+                Word::Strided(_, _, _) | Word::Bits(_, _) =>
+                    Word::from_set(set1.iter().cloned().filter(|&value| word.can_be(value)).collect()),
+                _ => panic!("shouldn't be here")
+            },
+            Word::Strided(lo1, hi1, stride1) => match *word {
+                Word::AnyValue => self.clone(),
+                Word::Int(_) | Word::Bits(_, _) => word.intersect(self),
+                Word::Strided(lo2, hi2, stride2) =>
+                    match strided_meet((lo1 as u32, hi1 as u32, stride1 as u32),
+                        (lo2 as u32, hi2 as u32, stride2 as u32))
+                    {
+                        Some((lo, hi, stride)) => Word::strided(lo, hi, stride),
+                        None => Word::Int(HashSet::new())
+                    },
+                _ => panic!("shouldn't be here")
+            },
+            Word::Bits(known1, value1) => match *word {
+                Word::AnyValue => self.clone(),
+                Word::Int(_) => word.intersect(self),
+                Word::Strided(_, _, _) =>
+                    Word::from_set(word.values().into_iter().filter(|&value| self.can_be(value)).collect()),
+                Word::Bits(known2, value2) =>
+                    match bits_meet((known1 as u32, value1 as u32), (known2 as u32, value2 as u32)) {
+                        Some((known, value)) => Word::from_bits(known as u16, value as u16),
+                        None => Word::Int(HashSet::new())
+                    },
                 _ => panic!("shouldn't be here")
             },
             _ => panic!("shouldn't be here")
@@ -350,7 +763,51 @@ impl Word {
             Word::Int(ref set1) => match word {
                 Word::AnyValue => Word::Int(HashSet::new()),
                 Word::Int(ref set2) =>
-                    Word::Int(set1.difference(&set2).cloned().collect::<HashSet<u16>>()),
+                    Word::from_set(set1.difference(&set2).cloned().collect::<HashSet<u16>>()),
+                Word::Strided(_, _, _) | Word::Bits(_, _) =>
+                    Word::from_set(set1.iter().cloned().filter(|&value| !word.can_be(value)).collect()),
+                _ => panic!("shouldn't be here")
+            },
+            Word::Strided(lo, hi, stride) => match word {
+                Word::AnyValue => Word::Int(HashSet::new()),
+                Word::Int(_) | Word::Strided(_, _, _) | Word::Bits(_, _) => {
+                    let common = self.common(&word);
+                    if common == 0 {
+                        self.clone()
+                    } else if self.len() - common <= MAX_EXPANDED {
+                        Word::from_set(self.values().into_iter()
+                            .filter(|&value| !word.can_be(value))
+                            .collect())
+                    } else {
+                        // Only values at the ends can be taken away
+                        // without widening.
+                        let (mut lo, mut hi) = (lo, hi);
+                        while word.can_be(lo) {
+                            lo += stride;
+                        }
+                        while word.can_be(hi) {
+                            hi -= stride;
+                        }
+                        Word::strided(lo as u32, hi as u32, stride as u32)
+                    }
+                },
+                _ => panic!("shouldn't be here")
+            },
+            Word::Bits(_, _) => match word {
+                Word::AnyValue => Word::Int(HashSet::new()),
+                Word::Int(_) | Word::Strided(_, _, _) | Word::Bits(_, _) => {
+                    // Taking values away from a pattern only keeps it a
+                    // pattern in special cases, so large results are
+                    // left as they are.
+                    let common = self.common(&word);
+                    if common == 0 || self.len() - common > MAX_EXPANDED {
+                        self.clone()
+                    } else {
+                        Word::from_set(self.values().into_iter()
+                            .filter(|&value| !word.can_be(value))
+                            .collect())
+                    }
+                },
                 _ => panic!("shouldn't be here")
             },
             _ => panic!("shouldn't be here")
@@ -393,9 +850,105 @@ impl Word {
             Word::Int(ref set) => (set.contains(&word),
                 set.contains(&word) || set.len() > 1),
             Word::Bytes(ref bytel, ref byteh) =>
-                bytel.clone().combine(byteh.clone()).compare_u16(word)
+                bytel.clone().combine(byteh.clone()).compare_u16(word),
+            Word::Strided(_, _, _) | Word::Bits(_, _) => (self.can_be(word), true)
         }
     }
+
+    pub fn and(self, word: Word) -> Word {
+        self.bitwise(word, |word1, word2| word1 & word2, bits_and)
+    }
+
+    pub fn or(self, word: Word) -> Word {
+        self.bitwise(word, |word1, word2| word1 | word2, bits_or)
+    }
+
+    pub fn xor(self, word: Word) -> Word {
+        self.bitwise(word, |word1, word2| word1 ^ word2, bits_xor)
+    }
+
+    // Applies a bitwise operator, value by value when that's exact and
+    // small enough and bit by bit otherwise.
+    fn bitwise<F, G>(self, word: Word, op: F, bits_op: G) -> Word
+        where F: Fn(u16, u16) -> u16, G: Fn((u32, u32), (u32, u32)) -> (u32, u32)
+    {
+        let word1 = if let Word::Bytes(bytel, byteh) = self {
+            bytel.combine(byteh)
+        } else {
+            self
+        };
+        let word2 = if let Word::Bytes(bytel, byteh) = word {
+            bytel.combine(byteh)
+        } else {
+            word
+        };
+        match (&word1, &word2) {
+            (&Word::Undefined, _) | (_, &Word::Undefined) => return Word::Undefined,
+            _ => ()
+        }
+        if word1.len() == 0 || word2.len() == 0 {
+            return Word::Int(HashSet::new());
+        }
+
+        if (!word1.is_pattern() || !word2.is_pattern())
+            && word1.len() * word2.len() <= MAX_EXPANDED
+        {
+            let mut set = HashSet::new();
+            for value1 in word1.values() {
+                for value2 in word2.values() {
+                    set.insert(op(value1, value2));
+                }
+            }
+            Word::from_set(set)
+        } else {
+            let ((known1, value1), (known2, value2)) = (word1.known_bits(), word2.known_bits());
+            let (known, value) = bits_op((known1 as u32, value1 as u32),
+                (known2 as u32, value2 as u32));
+            Word::from_bits(known as u16, value as u16)
+        }
+    }
+
+    pub fn shl(self, bits: u32) -> Word {
+        self.shift(|word| word.checked_shl(bits).unwrap_or(0),
+            |known, value| (known << bits | ((1 << bits) - 1), value << bits), bits)
+    }
+
+    pub fn shr(self, bits: u32) -> Word {
+        self.shift(|word| word.checked_shr(bits).unwrap_or(0),
+            |known, value| (known >> bits | !(0xffff >> bits), value >> bits), bits)
+    }
+
+    fn shift<F, G>(self, op: F, bits_op: G, bits: u32) -> Word
+        where F: Fn(u16) -> u16, G: Fn(u32, u32) -> (u32, u32)
+    {
+        let word = if let Word::Bytes(bytel, byteh) = self {
+            bytel.combine(byteh)
+        } else {
+            self
+        };
+        match word {
+            Word::Undefined => Word::Undefined,
+            _ if bits >= 16 => if word.len() == 0 {
+                word
+            } else {
+                Word::new(0)
+            },
+            _ if word.is_pattern() => {
+                let (known, value) = word.known_bits();
+                let (known, value) = bits_op(known as u32, value as u32);
+                Word::from_bits(known as u16, value as u16)
+            },
+            _ => Word::from_set(word.values().into_iter().map(op).collect())
+        }
+    }
+
+    // Returns the sum of two words as the pattern of its known bits.
+    fn add_bits(&self, word: &Word) -> Word {
+        let ((known1, value1), (known2, value2)) = (self.known_bits(), word.known_bits());
+        let (known, value) = bits_add((known1 as u32, value1 as u32),
+            (known2 as u32, value2 as u32), 0xffff);
+        Word::from_bits(known as u16, value as u16)
+    }
 }
 
 impl fmt::Debug for Word {
@@ -410,7 +963,10 @@ impl fmt::Debug for Word {
                 }
                 write!(f, "[{} ]", output)
             },
-            Word::Bytes(ref bytel, ref byteh) => write!(f, "{:?}{:?}", bytel, byteh)
+            Word::Bytes(ref bytel, ref byteh) => write!(f, "{:?}{:?}", bytel, byteh),
+            Word::Strided(lo, hi, stride) => write!(f, "[ {:x}..={:x} by {:x} ]", lo, hi, stride),
+            Word::Bits(known, value) =>
+                write!(f, "[ {} ]", bits_pattern(known as u32, value as u32, 16))
         }
     }
 }
@@ -426,7 +982,8 @@ impl fmt::Display for Word {
                 } else {
                     String::from("{--}")
                 },
-            Word::Bytes(ref reg1, ref reg2) => format!("{}{}", reg2, reg1)
+            Word::Bytes(ref reg1, ref reg2) => format!("{}{}", reg2, reg1),
+            Word::Strided(_, _, _) | Word::Bits(_, _) => String::from("{--}")
         })
     }
 }
@@ -457,13 +1014,45 @@ impl Add<Word> for Word {
                     let mut set = HashSet::new();
                     for word1 in set1 {
                         for word2 in set2.clone() {
-                            set.insert(word1 + word2);
+                            set.insert(word1.wrapping_add(word2));
                         }
                     };
-                    Word::Int(set)
+                    Word::from_set(set)
                 },
+                Word::Strided(_, _, _) | Word::Bits(_, _) => word2 + Word::Int(set1),
                 _ => panic!("shouldn't be here")
             },
+            Word::Strided(lo1, hi1, stride1) => match word2 {
+                Word::Undefined => Word::Undefined,
+                Word::AnyValue => Word::AnyValue,
+                Word::Int(set2) => set2.iter().fold(Word::Int(HashSet::new()),
+                    |sum, &addendum| sum.union(word1.clone().plus(addendum))),
+                Word::Strided(lo2, hi2, stride2) => {
+                    let (lo1, hi1, stride1) = (lo1 as u32, hi1 as u32, stride1 as u32);
+                    let (lo2, hi2, stride2) = (lo2 as u32, hi2 as u32, stride2 as u32);
+
+                    if word1.len() * word2.len() <= MAX_EXPANDED {
+                        word2.values().iter().fold(Word::Int(HashSet::new()),
+                            |sum, &addendum| sum.union(word1.clone().plus(addendum)))
+                    } else if hi1 + hi2 <= 0xffff {
+                        Word::strided(lo1 + lo2, hi1 + hi2, gcd(stride1, stride2))
+                    } else {
+                        word1.add_bits(&word2)
+                    }
+                },
+                Word::Bits(_, _) => word2 + word1,
+                _ => panic!("shouldn't be here")
+            },
+            Word::Bits(_, _) => match word2 {
+                Word::Undefined => Word::Undefined,
+                Word::AnyValue => Word::AnyValue,
+                _ => if word1.len() * word2.len() <= MAX_EXPANDED {
+                    word2.values().iter().fold(Word::Int(HashSet::new()),
+                        |sum, &addendum| sum.union(word1.clone().plus(addendum)))
+                } else {
+                    word1.add_bits(&word2)
+                }
+            },
             _ => panic!("shouldn't be here")
         }
     }
@@ -474,6 +1063,8 @@ pub enum Byte {
     Undefined,
     AnyValue,
     Int(HashSet<u8>),
+    Strided(u8, u8, u8),
+    Bits(u8, u8)
 }
 
 impl Byte {
@@ -488,7 +1079,7 @@ impl Byte {
         for byte in vector {
             set.insert(byte);
         }
-        Byte::Int(set)
+        Byte::from_set(set)
     }
 
     pub fn from_range(lower: u8, upper: u8) -> Byte {
@@ -496,7 +1087,95 @@ impl Byte {
         for byte in lower..=upper {
             set.insert(byte);
         }
-        Byte::Int(set)
+        Byte::from_set(set)
+    }
+
+    pub fn from_set(set: HashSet<u8>) -> Byte {
+        let mut values: Vec<u32> = set.iter().map(|&byte| byte as u32).collect();
+        match progression(&mut values) {
+            Some((lo, hi, stride)) => Byte::Strided(lo as u8, hi as u8, stride as u8),
+            None => match pattern(&values, 0xff) {
+                Some((known, value)) => Byte::Bits(known as u8, value as u8),
+                None => Byte::Int(set)
+            }
+        }
+    }
+
+    // Returns the byte whose bits in known are the same as in value.
+    pub fn from_bits(known: u8, value: u8) -> Byte {
+        let (unknown, value) = (!known, value & known);
+        if unknown == 0 {
+            Byte::new(value)
+        } else if known == 0 {
+            Byte::AnyValue
+        } else if is_run(unknown as u32) {
+            Byte::from_vec(bits_values(known as u32, value as u32, 0xff).into_iter()
+                .map(|byte| byte as u8)
+                .collect())
+        } else {
+            Byte::Bits(known, value)
+        }
+    }
+
+    // Returns the bits every value of the byte has in common, as the
+    // mask of those bits and their values.
+    pub fn known_bits(&self) -> (u8, u8) {
+        match *self {
+            Byte::Undefined => panic!("taking the known bits of undefined byte"),
+            Byte::AnyValue => (0, 0),
+            Byte::Int(_) | Byte::Strided(_, _, _) => {
+                let values: Vec<u32> = self.values().iter().map(|&byte| byte as u32).collect();
+                let (known, value) = bits_of(&values, 0xff);
+                (known as u8, value as u8)
+            },
+            Byte::Bits(known, value) => (known, value)
+        }
+    }
+
+    // Tests whether the byte holds exactly the values of its known bits.
+    fn is_pattern(&self) -> bool {
+        match *self {
+            Byte::Undefined => false,
+            _ => {
+                let (known, _) = self.known_bits();
+                self.len() == bits_len(known as u32, 0xff)
+            }
+        }
+    }
+
+    // Returns the byte with a strided interval or known bits written out
+    // as a set.
+    pub fn expand(self) -> Byte {
+        match self {
+            Byte::Strided(_, _, _) | Byte::Bits(_, _) =>
+                Byte::Int(self.values().into_iter().collect()),
+            byte => byte
+        }
+    }
+
+    fn values(&self) -> Vec<u8> {
+        match *self {
+            Byte::AnyValue => (0..=0xff).collect(),
+            Byte::Int(ref set) => set.iter().cloned().collect(),
+            Byte::Strided(lo, hi, stride) =>
+                strided_values(lo as u32, hi as u32, stride as u32).into_iter()
+                    .map(|byte| byte as u8)
+                    .collect(),
+            Byte::Bits(known, value) =>
+                bits_values(known as u32, value as u32, 0xff).into_iter()
+                    .map(|byte| byte as u8)
+                    .collect(),
+            _ => panic!("byte has no explicit values")
+        }
+    }
+
+    // Returns the smallest strided interval containing a defined byte,
+    // if it has any values.
+    fn hull(&self) -> Option<(u32, u32, u32)> {
+        match *self {
+            Byte::Strided(lo, hi, stride) => Some((lo as u32, hi as u32, stride as u32)),
+            _ => strided_hull(self.values().iter().map(|&byte| byte as u32))
+        }
     }
 
     pub fn len(&self) -> usize {
@@ -504,6 +1183,9 @@ impl Byte {
             Byte::Undefined => 0,
             Byte::AnyValue => 256,
             Byte::Int(ref bytes) => bytes.len(),
+            Byte::Strided(lo, hi, stride) =>
+                strided_len(lo as u32, hi as u32, stride as u32),
+            Byte::Bits(known, _) => bits_len(known as u32, 0xff)
         }
     }
 
@@ -518,9 +1200,11 @@ impl Byte {
                         max = *byte;
                     }
                 }
-                
+
                 max
-            }
+            },
+            Byte::Strided(_, hi, _) => hi,
+            Byte::Bits(known, value) => value | !known
         }
     }
 
@@ -528,8 +1212,10 @@ impl Byte {
         match self {
             Byte::Undefined => Byte::Undefined,
             Byte::AnyValue => Byte::AnyValue,
-            Byte::Int(bytes) => Byte::Int(
-                bytes.iter().map(
+            Byte::Strided(lo, hi, stride) if hi.checked_add(addendum).is_some() =>
+                Byte::Strided(lo + addendum, hi + addendum, stride),
+            _ => Byte::from_set(
+                self.values().iter().map(
                     |byte| byte.wrapping_add(addendum)
                 ).collect()
             )
@@ -543,8 +1229,10 @@ impl Byte {
             match self {
                 Byte::Undefined => Byte::Undefined,
                 Byte::AnyValue => Byte::AnyValue,
-                Byte::Int(bytes) => Byte::Int(
-                    bytes.iter().map(
+                Byte::Strided(lo, hi, stride) if hi.checked_mul(multiplicand).is_some() =>
+                    Byte::Strided(lo * multiplicand, hi * multiplicand, stride * multiplicand),
+                _ => Byte::from_set(
+                    self.values().iter().map(
                         |byte| byte.wrapping_mul(multiplicand)
                     ).collect()
                 )
@@ -562,38 +1250,28 @@ impl Byte {
                     words.insert(byte as u16);
                 }
                 Word::Int(words)
-            }
+            },
+            Byte::Strided(lo, hi, stride) => Word::Strided(lo as u16, hi as u16, stride as u16),
+            Byte::Bits(known, value) => Word::from_bits(known as u16 | 0xff00, value as u16)
         }
     }
 
     pub fn combine(self, byte: Byte) -> Word {
-        match self {
-            Byte::Undefined => Word::Undefined,
-            Byte::AnyValue => match byte {
-                Byte::Undefined => Word::Undefined,
-                Byte::AnyValue => Word::AnyValue,
-                Byte::Int(seth) => {
-                    let mut words = HashSet::new();
-                    for bytel in 0..256 {
-                        for byteh in seth.iter() {
-                            words.insert(bytel + ((*byteh as u16) << 8));
-                        }
-                    }
-                    Word::Int(words)
-                }
+        match (&self, &byte) {
+            (&Byte::Undefined, _) | (_, &Byte::Undefined) => Word::Undefined,
+            _ if self.is_pattern() && byte.is_pattern() => {
+                let ((knownl, valuel), (knownh, valueh)) = (self.known_bits(), byte.known_bits());
+                Word::from_bits(knownl as u16 | (knownh as u16) << 8,
+                    valuel as u16 | (valueh as u16) << 8)
             },
-            Byte::Int(setl) => match byte {
-                Byte::Undefined => Word::Undefined,
-                Byte::AnyValue => Word::AnyValue,
-                Byte::Int(seth) => {
-                    let mut words = HashSet::new();
-                    for bytel in setl {
-                        for byteh in seth.iter() {
-                            words.insert(bytel as u16 + ((*byteh as u16) << 8));
-                        }
+            _ => {
+                let mut words = HashSet::new();
+                for bytel in self.values() {
+                    for byteh in byte.values() {
+                        words.insert(bytel as u16 + ((byteh as u16) << 8));
                     }
-                    Word::Int(words)
                 }
+                Word::from_set(words)
             }
         }
     }
@@ -602,14 +1280,55 @@ impl Byte {
         match self {
             Byte::Undefined => Byte::Undefined,
             Byte::AnyValue => Byte::AnyValue,
-            Byte::Int(set1) => match byte {
+            _ => match byte {
                 Byte::Undefined => Byte::Undefined,
                 Byte::AnyValue => Byte::AnyValue,
-                Byte::Int(set2) => Byte::Int(set1.union(&set2).cloned().collect())
+                _ => {
+                    let mut set: HashSet<u8> = self.values().into_iter().collect();
+                    set.extend(byte.values());
+                    Byte::from_set(set)
+                }
+            }
+        }
+    }
+
+    // Returns a byte containing both bytes, like union. A bound that the
+    // values grew past moves on to the next threshold, so that widening
+    // a byte again and again soon stops changing it.
+    pub fn widen(self, byte: Byte) -> Byte {
+        if byte.is_subset(&self) {
+            return self;
+        }
+
+        let old_hull = match self {
+            Byte::Undefined => None,
+            _ => self.hull()
+        };
+        let union = self.union(byte);
+        match union {
+            Byte::Undefined | Byte::AnyValue | Byte::Bits(_, _) => union,
+            _ => match (old_hull, union.hull()) {
+                (Some((lo1, hi1, _)), Some((lo2, hi2, stride))) => {
+                    let (lo, hi) = widen_bounds((lo1, hi1), (lo2, hi2, stride), 0xff);
+                    Byte::from_vec(strided_values(lo, hi, stride).into_iter()
+                        .map(|byte| byte as u8)
+                        .collect())
+                },
+                _ => union
             }
         }
     }
 
+    // Returns the values of a widened byte that are also in byte, which
+    // holds the values that reached it since.
+    pub fn narrow(self, byte: Byte) -> Byte {
+        match (&self, &byte) {
+            (&Byte::Undefined, _) => byte.clone(),
+            (_, &Byte::Undefined) => self.clone(),
+            _ => self.intersect(&byte)
+        }
+    }
+
     pub fn is_subset(&self, byte: &Byte) -> bool {
         match *self {
             Byte::Undefined => match *byte {
@@ -620,10 +1339,10 @@ impl Byte {
                 Byte::AnyValue => true,
                 _ => false
             },
-            Byte::Int(ref set1) => match *byte {
+            _ => match *byte {
                 Byte::Undefined => false,
                 Byte::AnyValue => true,
-                Byte::Int(ref set2) => set1.is_subset(&set2)
+                _ => self.values().iter().all(|&value| byte.can_be(value))
             }
         }
     }
@@ -637,13 +1356,12 @@ impl Byte {
         }
         match *self {
             Byte::AnyValue => byte.clone(),
-            Byte::Int(ref set1) => match *byte {
+            _ => match *byte {
                 Byte::AnyValue => self.clone(),
-                Byte::Int(ref set2) =>
-                    Byte::Int(set1.intersection(set2).cloned().collect::<HashSet<u8>>()),
-                _ => panic!("shouldn't be here")
-            },
-            _ => panic!("shouldn't be here")
+                _ => Byte::from_set(self.values().into_iter()
+                    .filter(|&value| byte.can_be(value))
+                    .collect())
+            }
         }
     }
 
@@ -659,13 +1377,12 @@ impl Byte {
                 Byte::AnyValue => Byte::Int(HashSet::new()),
                 _ => Byte::AnyValue
             },
-            Byte::Int(ref set1) => match *byte {
+            _ => match *byte {
                 Byte::AnyValue => Byte::Int(HashSet::new()),
-                Byte::Int(ref set2) =>
-                    Byte::Int(set1.difference(set2).cloned().collect::<HashSet<u8>>()),
-                _ => panic!("shouldn't be here")
-            },
-            _ => panic!("shouldn't be here")
+                _ => Byte::from_set(self.values().into_iter()
+                    .filter(|&value| !byte.can_be(value))
+                    .collect())
+            }
         }
     }
 
@@ -674,6 +1391,9 @@ impl Byte {
             Byte::Undefined => panic!("testing undefined byte"),
             Byte::AnyValue => true,
             Byte::Int(ref set) => set.contains(&byte),
+            Byte::Strided(lo, hi, stride) =>
+                strided_contains((lo as u32, hi as u32, stride as u32), byte as u32),
+            Byte::Bits(known, value) => byte & known == value
         }
     }
 
@@ -683,6 +1403,73 @@ impl Byte {
             Byte::AnyValue => (true, false),
             Byte::Int(ref set) => (set.contains(&byte),
                 set.contains(&byte) || set.len() > 1),
+            Byte::Strided(_, _, _) | Byte::Bits(_, _) => (self.can_be(byte), true)
+        }
+    }
+
+    pub fn and(self, byte: Byte) -> Byte {
+        self.bitwise(byte, |byte1, byte2| byte1 & byte2, bits_and)
+    }
+
+    pub fn or(self, byte: Byte) -> Byte {
+        self.bitwise(byte, |byte1, byte2| byte1 | byte2, bits_or)
+    }
+
+    pub fn xor(self, byte: Byte) -> Byte {
+        self.bitwise(byte, |byte1, byte2| byte1 ^ byte2, bits_xor)
+    }
+
+    // Applies a bitwise operator, bit by bit when that's exact and value
+    // by value otherwise.
+    fn bitwise<F, G>(self, byte: Byte, op: F, bits_op: G) -> Byte
+        where F: Fn(u8, u8) -> u8, G: Fn((u32, u32), (u32, u32)) -> (u32, u32)
+    {
+        match (&self, &byte) {
+            (&Byte::Undefined, _) | (_, &Byte::Undefined) => Byte::Undefined,
+            _ if self.is_pattern() && byte.is_pattern() => {
+                let ((known1, value1), (known2, value2)) = (self.known_bits(), byte.known_bits());
+                let (known, value) = bits_op((known1 as u32, value1 as u32),
+                    (known2 as u32, value2 as u32));
+                Byte::from_bits(known as u8, value as u8)
+            },
+            _ => {
+                let mut set = HashSet::new();
+                for byte1 in self.values() {
+                    for byte2 in byte.values() {
+                        set.insert(op(byte1, byte2));
+                    }
+                }
+                Byte::from_set(set)
+            }
+        }
+    }
+
+    pub fn shl(self, bits: u32) -> Byte {
+        self.shift(|byte| byte.checked_shl(bits).unwrap_or(0),
+            |known, value| (known << bits | ((1 << bits) - 1), value << bits), bits)
+    }
+
+    pub fn shr(self, bits: u32) -> Byte {
+        self.shift(|byte| byte.checked_shr(bits).unwrap_or(0),
+            |known, value| (known >> bits | !(0xff >> bits), value >> bits), bits)
+    }
+
+    fn shift<F, G>(self, op: F, bits_op: G, bits: u32) -> Byte
+        where F: Fn(u8) -> u8, G: Fn(u32, u32) -> (u32, u32)
+    {
+        match self {
+            Byte::Undefined => Byte::Undefined,
+            _ if bits >= 8 => if self.len() == 0 {
+                self
+            } else {
+                Byte::new(0)
+            },
+            _ if self.is_pattern() => {
+                let (known, value) = self.known_bits();
+                let (known, value) = bits_op(known as u32, value as u32);
+                Byte::from_bits(known as u8, value as u8)
+            },
+            _ => Byte::from_set(self.values().into_iter().map(op).collect())
         }
     }
 }
@@ -698,7 +1485,10 @@ impl fmt::Debug for Byte {
                     output.push_str(format!(" {:x}", byte).as_str());
                 }
                 write!(f, "[{} ]", output)
-            }
+            },
+            Byte::Strided(lo, hi, stride) => write!(f, "[ {:x}..={:x} by {:x} ]", lo, hi, stride),
+            Byte::Bits(known, value) =>
+                write!(f, "[ {} ]", bits_pattern(known as u32, value as u32, 8))
         }
     }
 }
@@ -714,8 +1504,240 @@ impl fmt::Display for Byte {
                 } else {
                     String::from("{}")
                 },
+            Byte::Strided(_, _, _) | Byte::Bits(_, _) => String::from("{}")
+        })
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Returns the strided interval holding exactly values, if they form
+// one with at least MIN_STRIDED values. Sorts and deduplicates values.
+fn progression(values: &mut Vec<u32>) -> Option<(u32, u32, u32)> {
+    values.sort();
+    values.dedup();
+    if values.len() < MIN_STRIDED {
+        return None;
+    }
+
+    let stride = values[1] - values[0];
+    if values.windows(2).any(|pair| pair[1] - pair[0] != stride) {
+        return None;
+    }
+
+    Some((values[0], values[values.len() - 1], stride))
+}
+
+fn strided_len(lo: u32, hi: u32, stride: u32) -> usize {
+    ((hi - lo) / stride) as usize + 1
+}
+
+fn strided_values(lo: u32, hi: u32, stride: u32) -> Vec<u32> {
+    (lo..=hi).step_by(stride as usize).collect()
+}
+
+// In the helpers below, a stride of 0 means the single value lo.
+fn strided_contains((lo, hi, stride): (u32, u32, u32), value: u32) -> bool {
+    if stride == 0 {
+        value == lo
+    } else {
+        value >= lo && value <= hi && (value - lo) % stride == 0
+    }
+}
+
+// Returns the smallest strided interval containing a and b.
+fn strided_join(a: (u32, u32, u32), b: (u32, u32, u32)) -> (u32, u32, u32) {
+    let offset = if a.0 > b.0 { a.0 - b.0 } else { b.0 - a.0 };
+    (a.0.min(b.0), a.1.max(b.1), gcd(gcd(a.2, b.2), offset))
+}
+
+// Returns the smallest strided interval containing values, if any.
+fn strided_hull<T: Iterator<Item = u32>>(values: T) -> Option<(u32, u32, u32)> {
+    values.map(|value| (value, value, 0))
+        .fold(None, |hull, value| match hull {
+            None => Some(value),
+            Some(hull) => Some(strided_join(hull, value))
         })
+}
+
+// Returns the bounds of the strided interval (lo, hi, stride) once
+// widened from the bounds old. A bound that moved past the old one moves
+// on to the next threshold, staying on the stride: a power of two or 0
+// below, or a power of two or one less above, where loop counters
+// usually stop. There are only a few thresholds within mask, so bounds
+// can't keep moving for long.
+fn widen_bounds(old: (u32, u32), (lo, hi, stride): (u32, u32, u32), mask: u32) -> (u32, u32) {
+    let lo = if lo < old.0 {
+        let threshold = if lo == 0 { 0 } else { 1 << (31 - lo.leading_zeros()) };
+        lo - (lo - threshold) / stride * stride
+    } else {
+        lo
+    };
+    let hi = if hi > old.1 {
+        let threshold = if hi.is_power_of_two() { hi } else {
+            (hi + 1).next_power_of_two() - 1
+        };
+        hi + (threshold.min(mask) - hi) / stride * stride
+    } else {
+        hi
+    };
+    (lo, hi)
+}
+
+// Returns the values that a and b have in common, if any.
+fn strided_meet(a: (u32, u32, u32), b: (u32, u32, u32)) -> Option<(u32, u32, u32)> {
+    if a.2 == 0 {
+        return if strided_contains(b, a.0) { Some(a) } else { None };
+    }
+    if b.2 == 0 {
+        return strided_meet(b, a);
+    }
+
+    let (lo, hi) = (a.0.max(b.0), a.1.min(b.1));
+    if lo > hi {
+        return None;
+    }
+
+    // Walk a's values from lo until one is also one of b's; the values
+    // in common then repeat every lcm(a.2, b.2).
+    let divisor = gcd(a.2, b.2);
+    let mut first = a.0 + (lo - a.0 + a.2 - 1) / a.2 * a.2;
+    let mut found = false;
+    for _ in 0..(b.2 / divisor) {
+        if first > hi {
+            break;
+        }
+        if (first - b.0) % b.2 == 0 {
+            found = true;
+            break;
+        }
+        first += a.2;
+    }
+    if !found || first > hi {
+        return None;
+    }
+
+    let stride = (a.2 / divisor) as u64 * b.2 as u64;
+    if first as u64 + stride > hi as u64 {
+        Some((first, first, 0))
+    } else {
+        let stride = stride as u32;
+        Some((first, first + (hi - first) / stride * stride, stride))
+    }
+}
+
+// In the helpers below, known bits are pairs (known, value) within the
+// bits of mask.
+
+// Returns the bits values have in common; for no values, every bit is
+// known.
+fn bits_of(values: &[u32], mask: u32) -> (u32, u32) {
+    let (ones, zeros) = values.iter().fold((mask, mask),
+        |(ones, zeros), &value| (ones & value, zeros & !value));
+    (ones | zeros, ones)
+}
+
+// Returns the known bits holding exactly values, if they form a pattern
+// with at least MIN_BITS values.
+fn pattern(values: &[u32], mask: u32) -> Option<(u32, u32)> {
+    let (known, value) = bits_of(values, mask);
+    if values.len() >= MIN_BITS && values.len() == bits_len(known, mask) {
+        Some((known, value))
+    } else {
+        None
+    }
+}
+
+fn bits_len(known: u32, mask: u32) -> usize {
+    1 << (!known & mask).count_ones()
+}
+
+fn bits_values(known: u32, value: u32, mask: u32) -> Vec<u32> {
+    // Walks the subsets of the unknown bits in increasing order.
+    let unknown = !known & mask;
+    let mut values = Vec::new();
+    let mut bits = 0u32;
+    loop {
+        values.push(value | bits);
+        bits = bits.wrapping_sub(unknown) & unknown;
+        if bits == 0 {
+            break;
+        }
     }
+    values
+}
+
+// Tests whether the set bits of a nonzero mask are adjacent, in which
+// case its patterns are progressions.
+fn is_run(bits: u32) -> bool {
+    let run = bits >> bits.trailing_zeros();
+    run & (run + 1) == 0
+}
+
+// Returns known bits containing the strided interval (lo, hi, stride):
+// its values agree above the highest bit where lo and hi differ, and
+// below the lowest bit of stride.
+fn strided_bits(lo: u32, hi: u32, stride: u32, mask: u32) -> (u32, u32) {
+    let high = !((1 << (32 - (lo ^ hi).leading_zeros())) - 1);
+    let low = (1 << stride.trailing_zeros()) - 1;
+    let known = (high | low) & mask;
+    (known, lo & known)
+}
+
+// Returns the smallest known bits containing a and b.
+fn bits_join(a: (u32, u32), b: (u32, u32)) -> (u32, u32) {
+    let known = a.0 & b.0 & !(a.1 ^ b.1);
+    (known, a.1 & known)
+}
+
+// Returns the values that a and b have in common, if any.
+fn bits_meet(a: (u32, u32), b: (u32, u32)) -> Option<(u32, u32)> {
+    if (a.1 ^ b.1) & a.0 & b.0 != 0 {
+        None
+    } else {
+        Some((a.0 | b.0, a.1 | b.1))
+    }
+}
+
+fn bits_and(a: (u32, u32), b: (u32, u32)) -> (u32, u32) {
+    ((a.0 & b.0) | (a.0 & !a.1) | (b.0 & !b.1), a.1 & b.1)
+}
+
+fn bits_or(a: (u32, u32), b: (u32, u32)) -> (u32, u32) {
+    ((a.0 & b.0) | a.1 | b.1, a.1 | b.1)
+}
+
+fn bits_xor(a: (u32, u32), b: (u32, u32)) -> (u32, u32) {
+    let known = a.0 & b.0;
+    (known, (a.1 ^ b.1) & known)
+}
+
+// Returns known bits containing every sum of a value of a and one of
+// b. A bit of the sum is known when it and the carry into it don't
+// depend on unknown bits; that's where adding the largest and the
+// smallest values agree.
+fn bits_add(a: (u32, u32), b: (u32, u32), mask: u32) -> (u32, u32) {
+    let (unknown1, unknown2) = (!a.0 & mask, !b.0 & mask);
+    let smallest = a.1 + b.1;
+    let largest = smallest + unknown1 + unknown2;
+    let unknown = ((largest ^ smallest) | unknown1 | unknown2) & mask;
+    (!unknown & mask, smallest & !unknown & mask)
+}
+
+fn bits_pattern(known: u32, value: u32, width: u32) -> String {
+    (0..width).rev().map(|bit| if known >> bit & 1 == 0 {
+        '*'
+    } else if value >> bit & 1 == 0 {
+        '0'
+    } else {
+        '1'
+    }).collect()
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -802,3 +1824,219 @@ impl fmt::Display for Bit {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn byte_values(byte: &Byte) -> Vec<u8> {
+        let mut values = byte.values();
+        values.sort();
+        values
+    }
+
+    fn word_values(word: &Word) -> Vec<u16> {
+        let mut values = word.values();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn progressions_are_kept_as_strided_intervals() {
+        assert_eq!(format!("{:?}", Byte::from_vec(vec!(8, 2, 6, 4))), "[ 2..=8 by 2 ]");
+        assert_eq!(format!("{:?}", Word::from_vec(vec!(0x100, 0x300, 0x200))), "[ 100..=300 by 100 ]");
+        assert_eq!(format!("{:?}", Byte::from_range(3, 9)), "[ 3..=9 by 1 ]");
+
+        // Too few values, or not evenly spaced.
+        match Byte::from_vec(vec!(3, 5)) {
+            Byte::Int(_) => (),
+            byte => panic!("{:?} isn't a set", byte)
+        }
+        match Word::from_vec(vec!(1, 2, 4)) {
+            Word::Int(_) => (),
+            word => panic!("{:?} isn't a set", word)
+        }
+    }
+
+    #[test]
+    fn strided_intervals_hold_only_values_on_the_stride() {
+        let word = Word::Strided(0x10, 0x40, 0x10);
+        assert_eq!(word.len(), 4);
+        assert!(word.can_be(0x10) && word.can_be(0x30) && word.can_be(0x40));
+        assert!(!word.can_be(0x35) && !word.can_be(0x50) && !word.can_be(0));
+        assert_eq!(word_values(&word), vec!(0x10, 0x20, 0x30, 0x40));
+    }
+
+    #[test]
+    fn join_of_strided_intervals() {
+        assert_eq!(format!("{:?}", Word::Strided(0, 8, 4).union(Word::new(12))), "[ 0..=c by 4 ]");
+        assert_eq!(word_values(&Word::Strided(0, 8, 4).union(Word::new(2))), vec!(0, 2, 4, 8));
+        assert_eq!(format!("{:?}", Byte::Strided(0, 8, 4).union(Byte::Strided(2, 10, 4))), "[ 0..=a by 2 ]");
+
+        // Too many values to keep exactly, so the join grows.
+        let words = vec!(
+            (Word::Strided(0, 0x1000, 0x10), Word::new(0x1001)),
+            (Word::Strided(0, 0xff00, 0x100), Word::Strided(0x80, 0xff80, 0x100)),
+            (Word::Strided(0x10, 0x2000, 0x10), Word::from_vec(vec!(3, 0x4000, 0x4001)))
+        );
+        for (word1, word2) in words {
+            let join = word1.clone().union(word2.clone());
+            assert!(word1.is_subset(&join) && word2.is_subset(&join), "{:?}", join);
+        }
+    }
+
+    #[test]
+    fn meet_of_strided_intervals() {
+        assert_eq!(format!("{:?}", Word::Strided(0, 30, 3).intersect(&Word::Strided(0, 30, 5))),
+            "[ 0..=1e by f ]");
+        assert_eq!(word_values(&Word::Strided(1, 31, 3).intersect(&Word::Strided(0, 30, 5))),
+            vec!(10, 25));
+        assert_eq!(Word::Strided(0, 8, 2).intersect(&Word::Strided(1, 9, 2)).len(), 0);
+        assert_eq!(format!("{:?}", Byte::Strided(0, 0xf0, 0x10).intersect(&Byte::from_range(0x20, 0x50))),
+            "[ 20..=50 by 10 ]");
+    }
+
+    #[test]
+    fn arithmetic_on_strided_intervals() {
+        assert_eq!(format!("{:?}", Word::Strided(0, 8, 4).plus(1)), "[ 1..=9 by 4 ]");
+        assert_eq!(format!("{:?}", Word::Strided(0xfff0, 0xfffc, 4).plus(0x10)), "[ 0..=c by 4 ]");
+        assert_eq!(word_values(&Word::Strided(0xfff0, 0xfffc, 4).plus(8)),
+            vec!(0, 4, 0xfff8, 0xfffc));
+        assert_eq!(format!("{:?}", Byte::Strided(1, 3, 1).times(5)), "[ 5..=f by 5 ]");
+        assert_eq!(format!("{:?}", Word::Strided(0, 0x100, 0x10) + Word::Strided(0, 0x1000, 0x100)),
+            "[ 0..=1100 by 10 ]");
+    }
+
+    #[test]
+    fn difference_of_large_strided_intervals_trims_the_ends() {
+        assert_eq!(format!("{:?}", Word::Strided(0, 400, 1).difference(Word::new(400))),
+            "[ 0..=18f by 1 ]");
+        assert_eq!(format!("{:?}", Word::Strided(0, 400, 1).difference(Word::new(200))),
+            "[ 0..=190 by 1 ]");
+        assert_eq!(word_values(&Word::Strided(0, 8, 2).difference(Word::new(4))), vec!(0, 2, 6, 8));
+    }
+
+    #[test]
+    fn widening_moves_bounds_to_thresholds() {
+        assert_eq!(format!("{:?}", Byte::from_range(0, 4).widen(Byte::new(5))), "[ 0..=7 by 1 ]");
+        assert_eq!(format!("{:?}", Word::Strided(0, 8, 2).widen(Word::new(10))), "[ 0..=e by 2 ]");
+        assert_eq!(format!("{:?}", Byte::Strided(0x20, 0x30, 8).widen(Byte::new(0x18))),
+            "[ 10..=30 by 8 ]");
+
+        // Nothing new, so nothing changes.
+        assert_eq!(format!("{:?}", Byte::from_range(0, 4).widen(Byte::new(2))), "[ 0..=4 by 1 ]");
+    }
+
+    #[test]
+    fn widening_a_loop_counter_stops_soon() {
+        let mut byte = Byte::new(0);
+        let mut steps = 0;
+        loop {
+            let widened = byte.clone().widen(byte.clone().plus(1));
+            if widened.is_subset(&byte) {
+                break;
+            }
+            byte = widened;
+            steps += 1;
+        }
+
+        assert_eq!(byte.len(), 256);
+        assert!(steps < 20, "took {} steps", steps);
+    }
+
+    #[test]
+    fn narrowing_keeps_the_values_that_arrived() {
+        assert_eq!(byte_values(&Byte::from_range(0, 15).narrow(Byte::from_range(0, 9))),
+            (0..10).collect::<Vec<u8>>());
+        assert_eq!(format!("{:?}", Byte::Undefined.narrow(Byte::from_range(0, 9))), "[ 0..=9 by 1 ]");
+        assert_eq!(format!("{:?}", Word::Strided(0, 0xff, 1).narrow(Word::Strided(0, 0x80, 0x40))),
+            "[ 0..=80 by 40 ]");
+    }
+
+    #[test]
+    fn patterns_are_kept_as_known_bits() {
+        assert_eq!(format!("{:?}", Byte::from_bits(0xf5, 0x31)), "[ 0011*0*1 ]");
+        assert_eq!(byte_values(&Byte::from_bits(0xf5, 0x31)), vec!(0x31, 0x33, 0x39, 0x3b));
+        assert_eq!(format!("{:?}", Byte::from_vec(vec!(0x3b, 0x31, 0x39, 0x33))), "[ 0011*0*1 ]");
+
+        // Adjacent unknown bits make a progression.
+        assert_eq!(format!("{:?}", Byte::from_bits(0xf0, 0x3a)), "[ 30..=3f by 1 ]");
+        assert_eq!(format!("{:?}", Word::from_bits(0xff0f, 0x1205)), "[ 1205..=12f5 by 10 ]");
+        assert_eq!(Byte::from_bits(0xff, 0x12).len(), 1);
+        match Word::from_bits(0, 0x1234) {
+            Word::AnyValue => (),
+            word => panic!("{:?} isn't any value", word)
+        }
+    }
+
+    #[test]
+    fn known_bits_of_sets_and_intervals() {
+        assert_eq!(Byte::from_vec(vec!(0x12, 0x16)).known_bits(), (0xfb, 0x12));
+        assert_eq!(Word::Strided(0x1200, 0x12ff, 1).known_bits(), (0xff00, 0x1200));
+        assert_eq!(Word::Strided(0, 0x30, 0x10).known_bits(), (0xffcf, 0));
+        assert_eq!(Byte::AnyValue.known_bits(), (0, 0));
+    }
+
+    #[test]
+    fn join_and_meet_of_known_bits() {
+        let join = Word::from_bits(0xf0f0, 0x1020).union(Word::from_bits(0xf0f0, 0x1030));
+        assert_eq!(join.known_bits(), (0xf0e0, 0x1020));
+        assert_eq!(join.len(), 512);
+
+        assert_eq!(word_values(&Word::from_bits(0xf0f0, 0x1020).intersect(&Word::from_bits(0x0f0f, 0x0304))),
+            vec!(0x1324));
+        assert_eq!(Word::from_bits(0xf0f0, 0x1020).intersect(&Word::from_bits(0xf0f0, 0x2020)).len(), 0);
+        assert_eq!(byte_values(&Byte::from_bits(0xf5, 0x31).intersect(&Byte::from_range(0x30, 0x38))),
+            vec!(0x31, 0x33));
+    }
+
+    #[test]
+    fn bitwise_operations_on_known_bits() {
+        assert_eq!(format!("{:?}", Byte::AnyValue.and(Byte::new(0x0f))), "[ 0..=f by 1 ]");
+        assert_eq!(format!("{:?}", Byte::AnyValue.or(Byte::new(0xf0))), "[ f0..=ff by 1 ]");
+        assert_eq!(byte_values(&Byte::from_bits(0xf5, 0x31).xor(Byte::new(0xff))),
+            vec!(0xc4, 0xc6, 0xcc, 0xce));
+        assert_eq!(format!("{:?}", Word::AnyValue.and(Word::new(0x00ff))), "[ 0..=ff by 1 ]");
+
+        // Sets that aren't patterns are worked out value by value.
+        assert_eq!(format!("{:?}", Byte::from_vec(vec!(1, 2, 7)).and(Byte::new(3))), "[ 1..=3 by 1 ]");
+    }
+
+    #[test]
+    fn shifts_of_known_bits() {
+        assert_eq!(format!("{:?}", Byte::AnyValue.shl(4)), "[ 0..=f0 by 10 ]");
+        assert_eq!(format!("{:?}", Byte::AnyValue.shr(4)), "[ 0..=f by 1 ]");
+        assert_eq!(byte_values(&Byte::AnyValue.shl(8)), vec!(0));
+        assert_eq!(format!("{:?}", Word::Strided(0x1200, 0x12ff, 1).shl(4)), "[ 2000..=2ff0 by 10 ]");
+    }
+
+    #[test]
+    fn sums_of_known_bits_hold_every_sum() {
+        let (word1, word2) = (Word::from_bits(0xf0f0, 0x1020), Word::from_bits(0xfffc, 0));
+        let sum = word1.clone() + word2.clone();
+        assert_eq!(sum.known_bits(), (0xf0e0, 0x1020));
+        for value1 in word1.values() {
+            for value2 in word2.values() {
+                assert!(sum.can_be(value1.wrapping_add(value2)));
+            }
+        }
+
+        // Small enough to work out exactly.
+        let mut expected: Vec<u16> = word1.values().iter().map(|value| value + 0x100).collect();
+        expected.sort();
+        assert_eq!(word_values(&(word1 + Word::new(0x100))), expected);
+    }
+
+    #[test]
+    fn bytes_outside_the_buffer_are_missing() {
+        let buffer = [0x12, 0x34];
+        let mut memory = Memory::new(&buffer, 0x200, Endian::Big);
+        assert_eq!(memory.get_byte(0x201).map(|byte| byte_values(&byte)), Some(vec!(0x34)));
+        assert!(memory.get_byte(0x1ff).is_none());
+        assert!(memory.get_byte(0x202).is_none());
+
+        // Unless they've been written to.
+        memory.write_value(0x100, Value::Byte(Byte::new(0x56)));
+        assert_eq!(memory.get_byte(0x100).map(|byte| byte_values(&byte)), Some(vec!(0x56)));
+    }
+}
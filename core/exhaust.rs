@@ -1,81 +1,466 @@
 use defs::main::*;
 use graph::state::StateFlowGraph;
+use graph::worklist::Strategy;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Instant;
 
 pub enum LogType {
     StateCount,
     Verbose
 }
 
-pub fn simulate_exhaustively<S, I, A, Z>(file_buffer: &Vec<u8>, simulator: Z, initial_state: S, architecture: A, log_type: Option<LogType>) -> Result<StateFlowGraph<I, S>, String>
+// States reaching a loop header, the target of a branch back to an
+// earlier offset, are widened once "widen_after" states have been added
+// at it, so that exploring loops terminates. Each narrowing pass then
+// explores the program again, starting loops from the widened states
+// narrowed with the states that actually reached them.
+pub struct Widening {
+    pub widen_after: usize,
+    pub narrowing_passes: usize
+}
+
+// Why simulate_exhaustively stopped before exploring every state.
+#[derive(Debug)]
+pub enum Stop {
+    StateLimit(usize),
+    StepLimit(usize),
+    Deadline,
+    Error(DecompilerError)
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::StateLimit(states) => write!(f, "Stopped after reaching {} states", states),
+            Stop::StepLimit(steps) => write!(f, "Stopped after simulating {} instructions", steps),
+            Stop::Deadline => write!(f, "Stopped at the deadline"),
+            Stop::Error(ref error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl From<DecompilerError> for Stop {
+    fn from(error: DecompilerError) -> Stop {
+        Stop::Error(error)
+    }
+}
+
+// Complete:
+// every reachable state was explored.
+//
+// Partial:
+// exploring stopped early, and the graph holds the code found so far.
+pub enum ExhaustResult<I: InstructionTrait, S: StateTrait<S>> {
+    Complete(StateFlowGraph<I, S>),
+    Partial(StateFlowGraph<I, S>, Stop)
+}
+
+pub fn simulate_exhaustively<S, I, A, Z>(file_buffer: &Vec<u8>, simulator: Z, initial_state: S, architecture: A, log_type: Option<LogType>, widening: Option<Widening>, limits: Limits, strategy: Strategy, threads: usize) -> ExhaustResult<I, S>
+    where S: StateTrait<S> + Send + Sync,
+          I: InstructionTrait + Send + Sync,
+          A: Architecture<I> + Sync,
+          Z: SimulatorTrait<S, I> + Sync
+{
+    let graph = match widening {
+        Some(Widening { narrowing_passes, .. }) if narrowing_passes > 0 =>
+            StateFlowGraph::with_invariants(HashMap::new()),
+        _ => StateFlowGraph::new()
+    };
+    let mut graph = match explore(graph, file_buffer, &simulator, initial_state.clone(),
+        architecture, &log_type, &widening, &limits, strategy, threads) {
+        ExhaustResult::Complete(graph) => graph,
+        partial => return partial
+    };
+
+    if let Some(Widening { narrowing_passes, .. }) = widening {
+        for _ in 0..narrowing_passes {
+            let invariants = graph.narrowed_states();
+            if invariants.is_empty() {
+                break;
+            }
+
+            // The states can be large, so don't keep two graphs' worth.
+            drop(graph);
+            graph = match explore(StateFlowGraph::with_invariants(invariants), file_buffer,
+                &simulator, initial_state.clone(), architecture, &log_type, &widening, &limits, strategy, threads) {
+                ExhaustResult::Complete(graph) => graph,
+                partial => return partial
+            };
+        }
+    }
+
+    ExhaustResult::Complete(graph)
+}
+
+fn explore<S, I, A, Z>(mut graph: StateFlowGraph<I, S>, file_buffer: &Vec<u8>, simulator: &Z, initial_state: S, architecture: A, log_type: &Option<LogType>, widening: &Option<Widening>, limits: &Limits, strategy: Strategy, threads: usize) -> ExhaustResult<I, S>
+    where S: StateTrait<S> + Send + Sync,
+          I: InstructionTrait + Send + Sync,
+          A: Architecture<I> + Sync,
+          Z: SimulatorTrait<S, I> + Sync
+{
+    let entry_offset = Z::next_inst_offset(&initial_state);
+    graph.set_worklist(strategy.worklist());
+    let node = graph.add_node_at(entry_offset);
+    graph.add_state(initial_state, node);
+
+    let graph = RwLock::new(graph);
+    let (jobs, queued) = mpsc::channel();
+    let queued = Mutex::new(queued);
+    let stop = thread::scope(|scope| {
+        let (sender, traced) = mpsc::channel();
+
+        if threads > 1 {
+            for _ in 0..threads {
+                let (graph, queued, sender) = (&graph, &queued, sender.clone());
+                let architecture = &architecture;
+                scope.spawn(move || trace_jobs(graph, file_buffer, simulator, architecture, queued, sender));
+            }
+        }
+        drop(sender);
+
+        // Dropping the pool when exploring stops lets the workers finish.
+        let pool = Pool { jobs, traced };
+        explore_states(&graph, file_buffer, simulator, &architecture, log_type, widening, limits, &pool, threads)
+    });
+
+    let graph = graph.into_inner().expect("state flow graph lock poisoned");
+    match stop {
+        Ok(()) => ExhaustResult::Complete(graph),
+        Err(stop) => ExhaustResult::Partial(graph, stop)
+    }
+}
+
+fn explore_states<S, I, A, Z>(graph: &RwLock<StateFlowGraph<I, S>>, file_buffer: &Vec<u8>, simulator: &Z, architecture: &A, log_type: &Option<LogType>, widening: &Option<Widening>, limits: &Limits, pool: &Pool<S>, threads: usize) -> Result<(), Stop>
+    where S: StateTrait<S> + Send + Sync,
+          I: InstructionTrait + Send + Sync,
+          A: Architecture<I> + Sync,
+          Z: SimulatorTrait<S, I> + Sync
+{
+    let mut steps = 0;
+    let mut traces = HashMap::new();
+
+    loop {
+        let next = graph.write().expect("state flow graph lock poisoned")
+            .next_live_state_with_id();
+        let (state_id, state) = match next {
+            None => return Ok(()),
+            Some(next) => next
+        };
+
+        if let Some(max_states) = limits.max_states {
+            if graph.read().expect("state flow graph lock poisoned").state_count() >= max_states {
+                return Err(Stop::StateLimit(max_states));
+            }
+        }
+
+        if threads > 1 && !traces.contains_key(&state_id) {
+            traces = trace_live_states(&graph.read().expect("state flow graph lock poisoned"),
+                pool, (state_id, &state), traces, threads);
+        }
+
+        explore_state(&mut graph.write().expect("state flow graph lock poisoned"), file_buffer,
+            simulator, state, traces.remove(&state_id), architecture, log_type, widening, limits, &mut steps)?;
+    }
+}
+
+// The most instructions simulated ahead of time from one state.
+const TRACE_LENGTH: usize = 1024;
+
+// In parallel mode, live states are simulated ahead of time on other
+// threads, up to where they'd leave their node in the graph as it was
+// then. A trace holds the offsets of the instructions simulated and
+// what simulating the last one returned; the states in between aren't
+// kept, since they're only needed if the graph has changed since so
+// that the state leaves its node earlier, and can be found again then.
+//
+// The traces are then used in place of simulating when their states
+// come up in the usual order, so the graph is built exactly as if
+// every state were simulated as it came up.
+struct Trace<S: StateTrait<S>> {
+    offsets: Vec<usize>,
+    result: SimResult<S>
+}
+
+// What a worker sends back for each state it's given: the state's id
+// and its trace, if it has one. None means the worker panicked, so the
+// traces it was working on will never come.
+type Traced<S> = Option<(usize, Option<Trace<S>>)>;
+
+// The workers that simulate states ahead of time, which last as long as
+// the exploration does. Each state sent on "jobs" gets an answer on
+// "traced". The workers only read the graph while the explorer waits
+// for their answers, so the graph never changes under them.
+struct Pool<S: StateTrait<S>> {
+    jobs: Sender<(usize, S)>,
+    traced: Receiver<Traced<S>>
+}
+
+// Simulates the popped state and as many other live states without a
+// trace as there are threads to spare, on the pool's workers, and
+// returns the traces of those still live.
+fn trace_live_states<S, I>(graph: &StateFlowGraph<I, S>, pool: &Pool<S>, popped: (usize, &S), traces: HashMap<usize, Trace<S>>, threads: usize) -> HashMap<usize, Trace<S>>
+    where S: StateTrait<S>,
+          I: InstructionTrait
+{
+    let live_states = graph.live_states();
+    let live_ids: HashSet<usize> = live_states.iter()
+        .map(|&(state_id, _)| state_id)
+        .collect();
+    let mut traces: HashMap<usize, Trace<S>> = traces.into_iter()
+        .filter(|&(state_id, _)| live_ids.contains(&state_id))
+        .collect();
+
+    let mut pending = vec![popped];
+    pending.extend(live_states.into_iter()
+        .filter(|&(state_id, _)| !traces.contains_key(&state_id))
+        .take(threads * 4 - 1));
+
+    for &(state_id, state) in pending.iter() {
+        pool.jobs.send((state_id, state.clone())).expect("tracing threads stopped");
+    }
+
+    for _ in 0..pending.len() {
+        match pool.traced.recv().expect("tracing threads stopped") {
+            None => panic!("tracing thread panicked"),
+            Some((state_id, Some(trace))) => { traces.insert(state_id, trace); },
+            Some((_, None)) => ()
+        }
+    }
+
+    traces
+}
+
+// A worker in the pool: traces states until the explorer stops sending
+// them.
+fn trace_jobs<S, I, A, Z>(graph: &RwLock<StateFlowGraph<I, S>>, file_buffer: &Vec<u8>, simulator: &Z, architecture: &A, jobs: &Mutex<Receiver<(usize, S)>>, traced: Sender<Traced<S>>)
     where S: StateTrait<S>,
           I: InstructionTrait,
           A: Architecture<I>,
           Z: SimulatorTrait<S, I>
 {
-    let entry_offset = Z::next_inst_offset(&initial_state);
-    let mut graph = StateFlowGraph::new();
-    let node = graph.add_node_at(entry_offset);
-    graph.add_state(initial_state, node);
+    let watch = Watch { traced: &traced };
+
+    loop {
+        let job = jobs.lock().expect("job queue lock poisoned").recv();
+        let (state_id, state) = match job {
+            Err(_) => break,
+            Ok(job) => job
+        };
 
-    while let Some(mut state) = graph.next_live_state() {
-        match log_type {
-            Some(LogType::StateCount) =>
-                graph.log_state_count(),
-            Some(LogType::Verbose) => {
-                println!("{:?}", graph);
-                println!("Using state:\n{}", state.debug_string());
+        let trace = simulate_ahead(&graph.read().expect("state flow graph lock poisoned"),
+            file_buffer, simulator, architecture, state);
+        if watch.traced.send(Some((state_id, trace))).is_err() {
+            break;
+        }
+    }
+}
+
+// Tells the explorer if the worker it belongs to panics, so that the
+// panic reaches the explorer rather than leaving it waiting.
+struct Watch<'a, S: StateTrait<S>> {
+    traced: &'a Sender<Traced<S>>
+}
+
+impl<'a, S: StateTrait<S>> Drop for Watch<'a, S> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.traced.send(None);
+        }
+    }
+}
+
+// Simulates state until it leaves its node, or None if the first
+// instruction can't be decoded.
+fn simulate_ahead<S, I, A, Z>(graph: &StateFlowGraph<I, S>, file_buffer: &Vec<u8>, simulator: &Z, architecture: &A, mut state: S) -> Option<Trace<S>>
+    where S: StateTrait<S>,
+          I: InstructionTrait,
+          A: Architecture<I>,
+          Z: SimulatorTrait<S, I>
+{
+    let node_index = graph.get_node_at(Z::next_inst_offset(&state));
+    let mut offsets = Vec::new();
+
+    loop {
+        let inst_offset = Z::next_inst_offset(&state);
+        let inst = match graph.get_inst(inst_offset) {
+            None => architecture.decode_instruction(file_buffer, inst_offset).ok(),
+            Some(instruction) => instruction.unwrap(inst_offset).ok()
+        };
+        // Decoding fails again when the trace is used, and it's
+        // reported then.
+        let inst = match inst {
+            None if offsets.is_empty() => return None,
+            None => return Some(Trace { offsets, result: SimResult::State(state) }),
+            Some(inst) => inst
+        };
+
+        offsets.push(inst_offset);
+        match simulator.simulate_next_instruction(state, inst) {
+            SimResult::State(next_state) => {
+                let next_node_index = graph.get_node_at(Z::next_inst_offset(&next_state));
+                if (next_node_index.is_some() && next_node_index != node_index)
+                    || offsets.len() == TRACE_LENGTH {
+                    return Some(Trace { offsets, result: SimResult::State(next_state) });
+                }
+                state = next_state;
             },
-            None => ()
+            result => return Some(Trace { offsets, result })
         }
+    }
+}
 
-        loop {
-            let inst_offset = Z::next_inst_offset(&state);
-            let inst = match graph.get_inst(inst_offset) {
-                None => architecture.decode_instruction(file_buffer, inst_offset)?,
-                Some(instruction) => instruction.unwrap()
-            };
-            
-            graph.add_inst_to_listing(inst_offset, inst);
+// Simulates from state until it reaches another node or ends, following
+// trace instead of simulating as far as it goes, if there is one.
+fn explore_state<S, I, A, Z>(graph: &mut StateFlowGraph<I, S>, file_buffer: &Vec<u8>, simulator: &Z, mut state: S, trace: Option<Trace<S>>, architecture: &A, log_type: &Option<LogType>, widening: &Option<Widening>, limits: &Limits, steps: &mut usize) -> Result<(), Stop>
+    where S: StateTrait<S>,
+          I: InstructionTrait,
+          A: Architecture<I>,
+          Z: SimulatorTrait<S, I>
+{
+    match *log_type {
+        Some(LogType::StateCount) =>
+            graph.log_state_count(),
+        Some(LogType::Verbose) => {
+            println!("{:?}", graph);
+            println!("Using state:\n{}", state.debug_string());
+        },
+        None => ()
+    }
+
+    let mut trace = trace.map(|trace| (trace, 0));
+
+    loop {
+        if let Some(max_steps) = limits.max_steps {
+            if *steps >= max_steps {
+                return Err(Stop::StepLimit(max_steps));
+            }
+        }
+        if let Some(deadline) = limits.deadline {
+            if Instant::now() >= deadline {
+                return Err(Stop::Deadline);
+            }
+        }
+        *steps += 1;
+
+        let inst_offset = match trace {
+            None => Z::next_inst_offset(&state),
+            Some((ref trace, step)) => trace.offsets[step]
+        };
+        let inst = match graph.get_inst(inst_offset) {
+            None => architecture.decode_instruction(file_buffer, inst_offset)?,
+            Some(instruction) => instruction.unwrap(inst_offset)?
+        };
         
-            match simulator.simulate_next_instruction(state, inst) {
-                SimResult::Error(state, error) => {
-                    println!("{}", state.debug_string());
-                    return Err(error)
-                },
-                SimResult::End => break,
-                SimResult::State(next_state) => {
-                    let next_inst_offset = Z::next_inst_offset(&next_state);
-                    let node_index = graph.get_node_at(inst_offset)
-                        .expect(format!("No node at instruction offset {}", inst_offset).as_str());
-                    match graph.get_node_at(next_inst_offset) {
-                        None => {
-                            graph.insert_offset_at_node_index(next_inst_offset, node_index);
-                            state = next_state;
-                        },
-                        Some(next_inst_node_index) => {
-                            if next_inst_node_index != node_index {
-                                graph.extend_with_state(inst_offset, Z::next_inst_offset(&next_state), next_state);
-                                break;
-                            } else {
-                                state = next_state;
-                            }
-                        }
-                    }
-                },
-                SimResult::Branch(new_states, new_labels) => {
-                    for new_state in new_states {
-                        graph.extend_with_state(inst_offset, Z::next_inst_offset(&new_state), new_state) 
-                    }
+        graph.add_inst_to_listing(inst_offset, inst);
 
-                    for label in new_labels {
-                        graph.add_label(label);
+        let result = match trace.take() {
+            None => simulator.simulate_next_instruction(state, inst),
+            Some((ahead, step)) => {
+                if step + 1 == ahead.offsets.len() {
+                    ahead.result
+                } else {
+                    let next_inst_offset = ahead.offsets[step + 1];
+                    if stays_in_node(graph, inst_offset, next_inst_offset)? {
+                        trace = Some((ahead, step + 1));
+                        continue;
                     }
 
+                    // The graph has changed since the trace was made, so
+                    // the state leaves its node earlier than it did then.
+                    let next_state = simulate_again(graph, simulator, state, step + 1)?;
+                    extend(graph, inst_offset, next_inst_offset, next_state, widening)?;
                     break;
                 }
             }
+        };
+
+        match result {
+            SimResult::Error(state, error) => {
+                println!("{}", state.debug_string());
+                return Err(Stop::Error(error))
+            },
+            SimResult::End => break,
+            SimResult::State(next_state) => {
+                let next_inst_offset = Z::next_inst_offset(&next_state);
+                if stays_in_node(graph, inst_offset, next_inst_offset)? {
+                    state = next_state;
+                } else {
+                    extend(graph, inst_offset, next_inst_offset, next_state, widening)?;
+                    break;
+                }
+            },
+            SimResult::Branch(new_states, new_labels) => {
+                for new_state in new_states {
+                    extend(graph, inst_offset, Z::next_inst_offset(&new_state), new_state, widening)?;
+                }
+
+                for label in new_labels {
+                    graph.add_label(label);
+                }
+
+                break;
+            }
         }
     }
 
-    Ok(graph)
+    Ok(())
+}
+
+// Returns whether simulating carries on from inst_offset to
+// next_inst_offset in the same node, adding next_inst_offset to the
+// node if it's in none yet.
+fn stays_in_node<I, S>(graph: &mut StateFlowGraph<I, S>, inst_offset: usize, next_inst_offset: usize) -> Result<bool, DecompilerError>
+    where S: StateTrait<S>,
+          I: InstructionTrait
+{
+    let node_index = match graph.get_node_at(inst_offset) {
+        None => return Err(DecompilerError::graph_at_offset(inst_offset,
+            String::from("No node at instruction offset"))),
+        Some(node_index) => node_index
+    };
+    match graph.get_node_at(next_inst_offset) {
+        None => {
+            graph.insert_offset_at_node_index(next_inst_offset, node_index);
+            Ok(true)
+        },
+        Some(next_inst_node_index) => Ok(next_inst_node_index == node_index)
+    }
+}
+
+// Simulates the first "steps" instructions of a trace from its state
+// again, to find the state after them.
+fn simulate_again<S, I, Z>(graph: &StateFlowGraph<I, S>, simulator: &Z, mut state: S, steps: usize) -> Result<S, DecompilerError>
+    where S: StateTrait<S>,
+          I: InstructionTrait,
+          Z: SimulatorTrait<S, I>
+{
+    for _ in 0..steps {
+        let inst_offset = Z::next_inst_offset(&state);
+        let inst = graph.instruction_at(inst_offset)?;
+        state = match simulator.simulate_next_instruction(state, inst) {
+            SimResult::State(next_state) => next_state,
+            _ => return Err(DecompilerError::graph_at_offset(inst_offset,
+                String::from("Simulating again didn't follow the trace")))
+        };
+    }
+
+    Ok(state)
+}
+
+// Every loop branches back to an earlier offset somewhere, so widening
+// at the targets of such branches is enough for every loop to end.
+fn extend<I, S>(graph: &mut StateFlowGraph<I, S>, inst_offset: usize, new_inst_offset: usize, state: S, widening: &Option<Widening>) -> Result<(), DecompilerError>
+    where S: StateTrait<S>,
+          I: InstructionTrait
+{
+    match *widening {
+        Some(ref widening) if new_inst_offset <= inst_offset =>
+            graph.extend_with_widened_state(inst_offset, new_inst_offset, state, widening.widen_after),
+        _ => graph.extend_with_state(inst_offset, new_inst_offset, state)
+    }
 }
@@ -0,0 +1,466 @@
+use defs::main::*;
+use defs::ir;
+use defs::ir::Location;
+use graph::flow::{FlowGraph, Function, CallGraph};
+use graph::ssa;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+// Iterative dataflow analysis over the nodes of a function.
+//
+// An analysis supplies its direction, the fact that holds at the
+// function's boundary (its entry for forward analyses, its exits for
+// backward ones), a meet operator, and a transfer function that
+// carries a fact across one native instruction. Nodes are revisited
+// from a worklist until no fact changes, so the meet and transfer
+// functions must be monotone over a lattice without infinite chains.
+//
+// As with the dominator trees, edges are the regular and call-successor
+// edges between the function's nodes. A call is just an instruction of
+// the calling node; its effect on the caller's state is up to the
+// transfer function.
+//
+// The analyses shipped here read the IR attached to each instruction
+// (see FlowGraph::lift), so the architecture supplies their transfer
+// functions through its lifter. Temporaries never outlive their native
+// instruction, so they're left out. An instruction that clobbers
+// everything, or that wasn't lifted, reads and may write every location
+// mentioned anywhere in the graph, and so does the IR of an instruction
+// that loops (it may or may not run each of its writes). Memory isn't
+// split by address, so writes to it never hide earlier ones.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Forward,
+    Backward
+}
+
+pub trait Analysis<I: InstructionTrait> {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+
+    // The fact on entry to the function, or on leaving it through one
+    // of its exits.
+    fn boundary(&self) -> Self::Fact;
+
+    // The fact that's known before anything flows into a node; it
+    // must be an identity of meet.
+    fn top(&self) -> Self::Fact;
+
+    fn meet(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact;
+
+    // Returns the fact on the far side of the instruction at offset,
+    // in the analysis' direction.
+    fn transfer(&self, graph: &FlowGraph<I>, offset: usize, fact: &Self::Fact) -> Self::Fact;
+}
+
+// The facts found by an analysis. Whatever the direction, "before"
+// and "after" are in program order: before an instruction is the point
+// just ahead of it.
+pub struct Solution<F> {
+    node_before: HashMap<usize, F>,
+    node_after: HashMap<usize, F>,
+    before: HashMap<usize, F>,
+    after: HashMap<usize, F>
+}
+
+impl<F> Solution<F> {
+    pub fn node_before(&self, node: usize) -> Option<&F> {
+        self.node_before.get(&node)
+    }
+
+    pub fn node_after(&self, node: usize) -> Option<&F> {
+        self.node_after.get(&node)
+    }
+
+    pub fn before(&self, offset: usize) -> Option<&F> {
+        self.before.get(&offset)
+    }
+
+    pub fn after(&self, offset: usize) -> Option<&F> {
+        self.after.get(&offset)
+    }
+}
+
+// Runs analysis over the nodes of function until it reaches a fixed
+// point.
+pub fn solve<I, A>(graph: &FlowGraph<I>, function: &Function, analysis: &A) -> Solution<A::Fact>
+    where I: InstructionTrait,
+          A: Analysis<I>
+{
+    let (successors, predecessors) = graph.function_edges(function);
+    let forward = analysis.direction() == Direction::Forward;
+
+    // Facts flow from a node's sources to its sinks: predecessors and
+    // successors when going forward, the other way round when not.
+    let (sources, sinks) = if forward {
+        (&predecessors, &successors)
+    } else {
+        (&successors, &predecessors)
+    };
+    let boundaries: HashSet<usize> = if forward {
+        vec!(function.entry()).into_iter().collect()
+    } else {
+        function.exits().iter().cloned().collect()
+    };
+
+    let mut inputs: HashMap<usize, A::Fact> = HashMap::new();
+    let mut outputs: HashMap<usize, A::Fact> = function.nodes().iter()
+        .map(|&node| (node, analysis.top()))
+        .collect();
+
+    let mut work: VecDeque<usize> = function.nodes().iter().cloned().collect();
+    let mut queued: HashSet<usize> = work.iter().cloned().collect();
+
+    while let Some(node) = work.pop_front() {
+        queued.remove(&node);
+
+        let mut input = if boundaries.contains(&node) {
+            analysis.boundary()
+        } else {
+            analysis.top()
+        };
+        for source in sources[&node].iter() {
+            input = analysis.meet(&input, &outputs[source]);
+        }
+
+        let output = flow(graph, analysis, node, &input, forward, &mut |_, _, _| ());
+        inputs.insert(node, input);
+
+        if output != outputs[&node] {
+            outputs.insert(node, output);
+            for &sink in sinks[&node].iter() {
+                if queued.insert(sink) {
+                    work.push_back(sink);
+                }
+            }
+        }
+    }
+
+    // Replay each node to record the facts around its instructions.
+    let mut before = HashMap::new();
+    let mut after = HashMap::new();
+
+    for &node in function.nodes() {
+        flow(graph, analysis, node, &inputs[&node], forward, &mut |offset, incoming, outgoing| {
+            if forward {
+                before.insert(offset, incoming.clone());
+                after.insert(offset, outgoing.clone());
+            } else {
+                after.insert(offset, incoming.clone());
+                before.insert(offset, outgoing.clone());
+            }
+        });
+    }
+
+    let (node_before, node_after) = if forward {
+        (inputs, outputs)
+    } else {
+        (outputs, inputs)
+    };
+
+    Solution {
+        node_before: node_before,
+        node_after: node_after,
+        before: before,
+        after: after
+    }
+}
+
+// Carries input across the instructions of node in the analysis'
+// direction, calling visit with each instruction's incoming and
+// outgoing facts.
+fn flow<I, A, V>(graph: &FlowGraph<I>, analysis: &A, node: usize, input: &A::Fact, forward: bool, visit: &mut V) -> A::Fact
+    where I: InstructionTrait,
+          A: Analysis<I>,
+          V: FnMut(usize, &A::Fact, &A::Fact)
+{
+    let mut offsets = graph.get_instructions_at(node).to_vec();
+    if !forward {
+        offsets.reverse();
+    }
+
+    let mut fact = input.clone();
+    for offset in offsets {
+        let next = analysis.transfer(graph, offset, &fact);
+        visit(offset, &fact, &next);
+        fact = next;
+    }
+
+    fact
+}
+
+// What a native instruction does to the locations it touches, going by
+// its IR. reads are the locations it may read before writing them;
+// writes are the locations it certainly writes, and may_write those it
+// might.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Effects {
+    pub reads: Vec<Location>,
+    pub writes: Vec<Location>,
+    pub may_write: Vec<Location>,
+    pub clobbers: bool
+}
+
+// Returns the effects of the instruction at offset. Instructions that
+// clobber everything, or weren't lifted, have no effects in particular
+// but are marked as clobbers.
+pub fn effects<I: InstructionTrait>(graph: &FlowGraph<I>, offset: usize) -> Effects {
+    let mut effects = Effects {
+        reads: Vec::new(),
+        writes: Vec::new(),
+        may_write: Vec::new(),
+        clobbers: false
+    };
+
+    // Meta-instructions only choose between versions of code.
+    match graph.get_inst(offset) {
+        Some(&Meta::Inst(_)) | None => (),
+        Some(_) => return effects
+    }
+
+    let instructions = match graph.listing().get_ir(offset) {
+        Some(instructions) => instructions,
+        None => {
+            effects.clobbers = true;
+            return effects;
+        }
+    };
+
+    let loops = instructions.iter().any(|instruction|
+        [instruction.op1, instruction.op2, instruction.op3].iter().any(|operand| match *operand {
+            Some(ir::Operand::Local(_)) => true,
+            _ => false
+        }));
+
+    for instruction in instructions.iter() {
+        effects.clobbers |= instruction.clobbers_all();
+
+        for location in instruction.reads() {
+            if !is_temp(location) && !effects.reads.contains(&location)
+                && (loops || !effects.writes.contains(&location))
+            {
+                effects.reads.push(location);
+            }
+        }
+
+        for location in instruction.writes() {
+            if is_temp(location) {
+                continue;
+            }
+            if loops || location == Location::Memory {
+                if !effects.may_write.contains(&location) {
+                    effects.may_write.push(location);
+                }
+            } else if !effects.writes.contains(&location) {
+                effects.writes.push(location);
+            }
+        }
+    }
+
+    {
+        let Effects { ref writes, ref mut may_write, .. } = effects;
+        may_write.retain(|location| !writes.contains(location));
+    }
+    effects
+}
+
+// Returns every location, apart from temporaries, that the IR of graph
+// reads or writes.
+pub fn locations<I: InstructionTrait>(graph: &FlowGraph<I>) -> Vec<Location> {
+    let mut locations = Vec::new();
+
+    for node in 0..graph.node_count() {
+        for &offset in graph.get_instructions_at(node) {
+            let effects = effects(graph, offset);
+            for location in effects.reads.iter().chain(effects.writes.iter()).chain(effects.may_write.iter()) {
+                if !locations.contains(location) {
+                    locations.push(*location);
+                }
+            }
+        }
+    }
+
+    locations
+}
+
+fn is_temp(location: Location) -> bool {
+    match location {
+        Location::Temp(_) => true,
+        _ => false
+    }
+}
+
+fn union<T: Clone + Eq + Hash>(a: &HashSet<T>, b: &HashSet<T>) -> HashSet<T> {
+    a.union(b).cloned().collect()
+}
+
+// A definition of a location: the instruction at offset, or, if
+// offset is None, whatever set it before the function was entered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Definition {
+    pub location: Location,
+    pub offset: Option<usize>
+}
+
+// Forward analysis of the definitions that may reach each point.
+pub struct ReachingDefinitions {
+    locations: Vec<Location>
+}
+
+impl ReachingDefinitions {
+    pub fn new<I: InstructionTrait>(graph: &FlowGraph<I>) -> ReachingDefinitions {
+        ReachingDefinitions {
+            locations: locations(graph)
+        }
+    }
+}
+
+impl<I: InstructionTrait> Analysis<I> for ReachingDefinitions {
+    type Fact = HashSet<Definition>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self) -> HashSet<Definition> {
+        self.locations.iter()
+            .map(|&location| Definition {
+                location: location,
+                offset: None
+            })
+            .collect()
+    }
+
+    fn top(&self) -> HashSet<Definition> {
+        HashSet::new()
+    }
+
+    fn meet(&self, a: &HashSet<Definition>, b: &HashSet<Definition>) -> HashSet<Definition> {
+        union(a, b)
+    }
+
+    fn transfer(&self, graph: &FlowGraph<I>, offset: usize, fact: &HashSet<Definition>) -> HashSet<Definition> {
+        let effects = effects(graph, offset);
+        let mut fact: HashSet<Definition> = fact.iter().cloned()
+            .filter(|definition| !effects.writes.contains(&definition.location))
+            .collect();
+
+        let defined = if effects.clobbers {
+            &self.locations
+        } else {
+            &effects.may_write
+        };
+
+        for &location in effects.writes.iter().chain(defined.iter()) {
+            fact.insert(Definition {
+                location: location,
+                offset: Some(offset)
+            });
+        }
+
+        fact
+    }
+}
+
+pub fn reaching_definitions<I: InstructionTrait>(graph: &FlowGraph<I>, function: &Function) -> Solution<HashSet<Definition>> {
+    solve(graph, function, &ReachingDefinitions::new(graph))
+}
+
+// Returns the reaching definitions of every function in call_graph, in
+// the same order as call_graph.functions().
+pub fn function_reaching_definitions<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph) -> Vec<Solution<HashSet<Definition>>> {
+    let analysis = ReachingDefinitions::new(graph);
+    call_graph.functions().iter()
+        .map(|function| solve(graph, function, &analysis))
+        .collect()
+}
+
+// Returns, for each instruction that query picks out by returning the
+// locations it reads, the definitions that may have set them, ordered
+// by location and then by offset. The locations are up to query, since
+// the IR of system calls such as INT 21h clobbers everything rather
+// than saying what it reads. An instruction shared between functions
+// gets the definitions that reach it in any of them.
+pub fn reaching_reads_of<I, Q>(graph: &FlowGraph<I>, call_graph: &CallGraph, query: Q) -> BTreeMap<usize, Vec<Definition>>
+    where I: InstructionTrait,
+          Q: Fn(I) -> Option<Vec<Location>>
+{
+    let solutions = function_reaching_definitions(graph, call_graph);
+    let mut queried = BTreeMap::new();
+
+    for (function, solution) in call_graph.functions().iter().zip(solutions.iter()) {
+        for &node in function.nodes() {
+            for &offset in graph.get_instructions_at(node) {
+                let reads = match graph.get_inst(offset) {
+                    Some(&Meta::Inst(inst)) => match query(inst) {
+                        Some(reads) => reads,
+                        None => continue
+                    },
+                    _ => continue
+                };
+
+                let definitions: &mut Vec<Definition> = queried.entry(offset).or_insert_with(Vec::new);
+                if let Some(reaching) = solution.before(offset) {
+                    for definition in reaching.iter() {
+                        if reads.contains(&definition.location) && !definitions.contains(definition) {
+                            definitions.push(*definition);
+                        }
+                    }
+                }
+                sort_by_reads(definitions, &reads);
+            }
+        }
+    }
+
+    queried
+}
+
+// Formats what reaching_reads_of found, one instruction to a line
+// followed by a line for each location it reads, naming the
+// instructions that may have set it. Offsets are shown plus base, as in
+// the listing; "entry" stands for whatever set a location before its
+// function was entered.
+pub fn format_reaching_reads<I, L>(graph: &FlowGraph<I>, queried: &BTreeMap<usize, Vec<Definition>>, lifter: &L, base: usize) -> String
+    where I: InstructionTrait,
+          L: ir::LifterTrait<I>
+{
+    let mut output = String::new();
+
+    for (&offset, definitions) in queried.iter() {
+        if let Some(&Meta::Inst(inst)) = graph.get_inst(offset) {
+            output.push_str(&format!("{:4x}:   {}\n", offset + base, inst));
+        }
+
+        let mut locations: Vec<Location> = Vec::new();
+        for definition in definitions.iter() {
+            if !locations.contains(&definition.location) {
+                locations.push(definition.location);
+            }
+        }
+
+        for &location in locations.iter() {
+            let setters: Vec<String> = definitions.iter()
+                .filter(|definition| definition.location == location)
+                .map(|definition| match definition.offset {
+                    Some(offset) => format!("{:x}", offset + base),
+                    None => String::from("entry")
+                })
+                .collect();
+            output.push_str(&format!("        {}: {}\n", ssa::location_name(location, lifter), setters.join(", ")));
+        }
+    }
+
+    output
+}
+
+fn sort_by_reads(definitions: &mut [Definition], reads: &[Location]) {
+    definitions.sort_by_key(|definition| (
+        reads.iter().position(|&location| location == definition.location),
+        definition.offset
+    ));
+}
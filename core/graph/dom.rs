@@ -0,0 +1,289 @@
+use defs::main::*;
+use graph::flow::{FlowGraph, Function, CallGraph};
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+// Dominator trees, computed with the iterative algorithm from Cooper,
+// Harvey and Kennedy, "A Simple, Fast Dominance Algorithm".
+//
+// Trees are computed per function, over the regular and
+// call-successor edges between the function's nodes; call edges are
+// ignored. Post-dominator trees are dominator trees of the reversed
+// function graph, rooted at a virtual exit node that every exit of
+// the function (and every node without successors) leads to. Nodes
+// that can't reach an exit end up in an infinite loop, such as a
+// CHIP-8 game's main loop, so each loop no edge leaves also leads to
+// the virtual exit, from its last node in reverse postorder. That way
+// every node has a post-dominator.
+
+pub struct DominatorTree {
+    root: usize,
+    order: Vec<usize>,
+    idoms: HashMap<usize, usize>,
+    children: HashMap<usize, Vec<usize>>,
+    frontiers: HashMap<usize, HashSet<usize>>
+}
+
+impl DominatorTree {
+    // Builds the dominator tree of the graph with the given root and
+    // edges. successors and predecessors must contain every node.
+    pub fn new(root: usize, successors: &HashMap<usize, Vec<usize>>,
+        predecessors: &HashMap<usize, Vec<usize>>) -> DominatorTree
+    {
+        let order = reverse_postorder(root, successors);
+        let index: HashMap<usize, usize> = order.iter().enumerate()
+            .map(|(index, &node)| (node, index))
+            .collect();
+
+        let mut idoms: HashMap<usize, usize> = HashMap::new();
+        idoms.insert(root, root);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for &node in order.iter().skip(1) {
+                let mut new_idom = None;
+
+                for &pred in predecessors[&node].iter() {
+                    if !idoms.contains_key(&pred) {
+                        continue;
+                    }
+
+                    new_idom = match new_idom {
+                        None => Some(pred),
+                        Some(idom) => Some(intersect(&idoms, &index, pred, idom))
+                    };
+                }
+
+                if let Some(new_idom) = new_idom {
+                    if idoms.get(&node) != Some(&new_idom) {
+                        idoms.insert(node, new_idom);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut children: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut frontiers: HashMap<usize, HashSet<usize>> = HashMap::new();
+
+        for &node in order.iter() {
+            children.insert(node, Vec::new());
+            frontiers.insert(node, HashSet::new());
+        }
+
+        for &node in order.iter().skip(1) {
+            children.get_mut(&idoms[&node]).unwrap().push(node);
+        }
+
+        for &node in order.iter() {
+            let preds: Vec<usize> = predecessors[&node].iter().cloned()
+                .filter(|pred| idoms.contains_key(pred))
+                .collect();
+
+            if preds.len() < 2 {
+                continue;
+            }
+
+            for pred in preds {
+                let mut runner = pred;
+                while runner != idoms[&node] {
+                    frontiers.get_mut(&runner).unwrap().insert(node);
+                    runner = idoms[&runner];
+                }
+            }
+        }
+
+        DominatorTree {
+            root: root,
+            order: order,
+            idoms: idoms,
+            children: children,
+            frontiers: frontiers
+        }
+    }
+
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    // Returns the nodes reachable from the root, in reverse postorder.
+    pub fn nodes(&self) -> &[usize] {
+        &self.order
+    }
+
+    pub fn contains(&self, node: usize) -> bool {
+        self.idoms.contains_key(&node)
+    }
+
+    // Returns the immediate dominator of node, or None for the root
+    // and for nodes that aren't reachable from it.
+    pub fn immediate_dominator(&self, node: usize) -> Option<usize> {
+        if node == self.root {
+            None
+        } else {
+            self.idoms.get(&node).cloned()
+        }
+    }
+
+    pub fn dominates(&self, dominator: usize, node: usize) -> bool {
+        if !self.contains(dominator) || !self.contains(node) {
+            return false;
+        }
+
+        let mut current = node;
+        loop {
+            if current == dominator {
+                return true;
+            }
+            if current == self.root {
+                return false;
+            }
+            current = self.idoms[&current];
+        }
+    }
+
+    pub fn strictly_dominates(&self, dominator: usize, node: usize) -> bool {
+        dominator != node && self.dominates(dominator, node)
+    }
+
+    // Returns the nodes immediately dominated by node.
+    pub fn children(&self, node: usize) -> &[usize] {
+        match self.children.get(&node) {
+            Some(children) => children,
+            None => &[]
+        }
+    }
+
+    // Returns the dominance frontier of node: the nodes where its
+    // dominance ends.
+    pub fn frontier(&self, node: usize) -> Option<&HashSet<usize>> {
+        self.frontiers.get(&node)
+    }
+}
+
+// Dominator and post-dominator trees of a single function.
+pub struct Dominators {
+    pub dominators: DominatorTree,
+    pub post_dominators: DominatorTree,
+    virtual_exit: usize
+}
+
+impl Dominators {
+    // Returns the node used as the root of the post-dominator tree.
+    // It isn't part of the flow graph.
+    pub fn virtual_exit(&self) -> usize {
+        self.virtual_exit
+    }
+}
+
+pub fn dominators<I: InstructionTrait>(graph: &FlowGraph<I>, function: &Function) -> Dominators {
+    let (successors, predecessors) = graph.function_edges(function);
+    let dominators = DominatorTree::new(function.entry(), &successors, &predecessors);
+
+    let virtual_exit = graph.node_count();
+    let mut reverse_successors = predecessors;
+    let mut reverse_predecessors = successors;
+
+    reverse_successors.insert(virtual_exit, Vec::new());
+    reverse_predecessors.insert(virtual_exit, Vec::new());
+
+    let exits: HashSet<usize> = function.exits().iter().cloned().collect();
+    for node in function.nodes() {
+        if exits.contains(node) || reverse_predecessors[node].is_empty() {
+            reverse_successors.get_mut(&virtual_exit).unwrap().push(*node);
+            reverse_predecessors.get_mut(node).unwrap().push(virtual_exit);
+        }
+    }
+
+    let mut exited = reachable(virtual_exit, &reverse_successors);
+    for &node in dominators.nodes().iter().rev() {
+        if exited.contains(&node) {
+            continue;
+        }
+
+        // Whatever node leads to leads back to it, so nothing leaves
+        // the loop it's in.
+        let backward = reachable(node, &reverse_successors);
+        if reachable(node, &reverse_predecessors).is_subset(&backward) {
+            reverse_successors.get_mut(&virtual_exit).unwrap().push(node);
+            reverse_predecessors.get_mut(&node).unwrap().push(virtual_exit);
+            exited.extend(backward);
+        }
+    }
+
+    let post_dominators = DominatorTree::new(virtual_exit,
+        &reverse_successors, &reverse_predecessors);
+
+    Dominators {
+        dominators: dominators,
+        post_dominators: post_dominators,
+        virtual_exit: virtual_exit
+    }
+}
+
+// Returns the dominators of every function in call_graph, in the same
+// order as call_graph.functions().
+pub fn function_dominators<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph) -> Vec<Dominators> {
+    call_graph.functions().iter()
+        .map(|function| dominators(graph, function))
+        .collect()
+}
+
+fn reverse_postorder(root: usize, successors: &HashMap<usize, Vec<usize>>) -> Vec<usize> {
+    let mut visited = HashSet::new();
+    let mut postorder = Vec::new();
+    let mut stack = vec!((root, 0));
+    visited.insert(root);
+
+    while let Some((node, next)) = stack.pop() {
+        let empty = Vec::new();
+        let node_successors = successors.get(&node).unwrap_or(&empty);
+
+        if next < node_successors.len() {
+            stack.push((node, next + 1));
+            let successor = node_successors[next];
+            if visited.insert(successor) {
+                stack.push((successor, 0));
+            }
+        } else {
+            postorder.push(node);
+        }
+    }
+
+    postorder.reverse();
+    postorder
+}
+
+// Returns the nodes that can be reached from node by following edges,
+// node included.
+pub fn reachable(node: usize, edges: &HashMap<usize, Vec<usize>>) -> HashSet<usize> {
+    let mut seen: HashSet<usize> = [node].iter().cloned().collect();
+    let mut live_nodes = vec!(node);
+
+    while let Some(live_node) = live_nodes.pop() {
+        for &next in edges[&live_node].iter() {
+            if seen.insert(next) {
+                live_nodes.push(next);
+            }
+        }
+    }
+
+    seen
+}
+
+fn intersect(idoms: &HashMap<usize, usize>, index: &HashMap<usize, usize>,
+    mut finger1: usize, mut finger2: usize) -> usize
+{
+    while finger1 != finger2 {
+        while index[&finger1] > index[&finger2] {
+            finger1 = idoms[&finger1];
+        }
+        while index[&finger2] > index[&finger1] {
+            finger2 = idoms[&finger2];
+        }
+    }
+
+    finger1
+}
@@ -0,0 +1,163 @@
+use defs::main::*;
+use graph::flow::{FlowGraph, CallGraph, Edge, EdgeValue};
+use graph::state::StateFlowGraph;
+use std::collections::HashSet;
+
+// Graphviz DOT renderings of the graphs, for viewing with dot and the
+// like. Each node is a box listing its instructions, one per line; node
+// 0, which every graph has in front of its entry nodes, is drawn as a
+// point if anything comes after it.
+
+pub fn flow_graph<I: InstructionTrait>(graph: &FlowGraph<I>) -> String {
+    let mut output = String::from("digraph flow {\n    node [shape=box, fontname=monospace];\n");
+    output.push_str(&flow_entry_point(graph));
+
+    for node_index in 1..graph.node_count() {
+        output.push_str(&block(node_index, None, graph.get_instructions_at(node_index),
+            graph.listing(), "    "));
+    }
+
+    output.push_str(&flow_edges(graph));
+    output.push_str("}\n");
+    output
+}
+
+// Like flow_graph, but with the nodes of each function in the call
+// graph drawn together in a cluster. Functions can share nodes, e.g.
+// when one jumps into the middle of another, and those are drawn in
+// the first function they're found in.
+pub fn call_graph<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph) -> String {
+    let mut output = String::from("digraph calls {\n    node [shape=box, fontname=monospace];\n");
+    output.push_str(&flow_entry_point(graph));
+
+    let mut drawn = HashSet::new();
+    for (function_index, function) in call_graph.functions().iter().enumerate() {
+        output.push_str(format!("    subgraph cluster_{} {{\n", function_index).as_str());
+        let label = match graph.initial_instruction(function.entry()) {
+            Ok(Some(offset)) => format!("function {} at 0x{:x}", function_index, offset),
+            _ => format!("function {}", function_index)
+        };
+        output.push_str(format!("        label=\"{}\";\n", escape(&label)).as_str());
+
+        // The first function starts at node 0, which is drawn apart.
+        for &node_index in function.nodes().iter() {
+            if node_index != 0 && drawn.insert(node_index) {
+                output.push_str(&block(node_index, None, graph.get_instructions_at(node_index),
+                    graph.listing(), "        "));
+            }
+        }
+        output.push_str("    }\n");
+    }
+
+    // Nodes no function reaches, e.g. ones only found linearly.
+    for node_index in 1..graph.node_count() {
+        if !drawn.contains(&node_index) {
+            output.push_str(&block(node_index, None, graph.get_instructions_at(node_index),
+                graph.listing(), "    "));
+        }
+    }
+
+    output.push_str(&flow_edges(graph));
+    output.push_str("}\n");
+    output
+}
+
+// Each node also says how many states reached it, and nodes that have
+// been removed from the graph are left out.
+pub fn state_flow_graph<I, S>(graph: &StateFlowGraph<I, S>) -> String
+    where I: InstructionTrait,
+          S: StateTrait<S>
+{
+    let mut output = String::from("digraph states {\n    node [shape=box, fontname=monospace];\n");
+    output.push_str(&entry_point(!graph.get_entry_nodes().is_empty()));
+
+    for node_index in 1..graph.node_count() {
+        if graph.is_deleted(node_index) {
+            continue;
+        }
+        let states = match graph.get_states_at_node(node_index).len() {
+            1 => String::from("1 state"),
+            count => format!("{} states", count)
+        };
+        output.push_str(&block(node_index, Some(states), graph.get_instructions_at(node_index),
+            graph.listing(), "    "));
+    }
+
+    let mut edges = Vec::new();
+    for node_index in 0..graph.node_count() {
+        if graph.is_deleted(node_index) {
+            continue;
+        }
+        for next_node_index in graph.get_next_nodes(node_index) {
+            edges.push((node_index, next_node_index));
+        }
+    }
+    edges.sort();
+    edges.dedup();
+
+    for (from, to) in edges {
+        output.push_str(format!("    n{} -> n{};\n", from, to).as_str());
+    }
+
+    output.push_str("}\n");
+    output
+}
+
+fn entry_point(has_entry: bool) -> String {
+    if has_entry {
+        String::from("    n0 [shape=point];\n")
+    } else {
+        String::new()
+    }
+}
+
+fn flow_entry_point<I: InstructionTrait>(graph: &FlowGraph<I>) -> String {
+    let (next_nodes, call_nodes) = graph.get_next_nodes(0);
+    entry_point(!next_nodes.is_empty() || !call_nodes.is_empty())
+}
+
+// A node's box, headed by its index and whatever else heading holds,
+// with its instructions left-justified below.
+fn block<I: InstructionTrait>(node_index: usize, heading: Option<String>, insts: &[usize], listing: &Listing<I>, indent: &str) -> String {
+    let mut label = match heading {
+        None => format!("node {}\\l", node_index),
+        Some(heading) => format!("node {} ({})\\l", node_index, escape(&heading))
+    };
+    for offset in insts.iter() {
+        let text = match listing.get(*offset) {
+            None => String::from("???"),
+            Some(instruction) => format!("{}", instruction)
+        };
+        label.push_str(format!("{:4x}  {}\\l", offset, escape(&text)).as_str());
+    }
+
+    format!("{}n{} [label=\"{}\"];\n", indent, node_index, label)
+}
+
+// Every edge in the graph, sorted so the output is the same each time.
+fn flow_edges<I: InstructionTrait>(graph: &FlowGraph<I>) -> String {
+    let mut edges: Vec<Edge> = (0..graph.node_count())
+        .flat_map(|node_index| graph.get_inbound_edges(node_index))
+        .collect();
+    edges.sort_by_key(|edge| (edge.get_from(), edge.get_to()));
+
+    let mut output = String::new();
+    for edge in edges {
+        output.push_str(format!("    n{} -> n{}{};\n", edge.get_from(), edge.get_to(),
+            edge_style(edge.value())).as_str());
+    }
+    output
+}
+
+fn edge_style(value: EdgeValue) -> &'static str {
+    match value {
+        EdgeValue::Regular => "",
+        EdgeValue::Call => " [style=bold, color=blue]",
+        EdgeValue::CallSuccessor => " [style=dashed]",
+        EdgeValue::Return => " [style=dotted, color=gray]"
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
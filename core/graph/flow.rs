@@ -1,5 +1,7 @@
 use defs::main::*;
+use defs::ir;
 use std::fmt;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
@@ -51,6 +53,11 @@ impl<I: InstructionTrait> FlowGraph<I> {
         }
     }
 
+    pub fn node_at(&self, offset: usize) -> Result<usize, DecompilerError> {
+        self.get_node_at(offset).ok_or(DecompilerError::graph_at_offset(offset,
+            String::from("no node contains the instruction at this offset")))
+    }
+
     pub fn get_entry_node(&self) -> usize {
         let entry_offset = self.listing.entry_offset;
         self.get_node_at(entry_offset).expect("Graph has no entry node!")
@@ -60,6 +67,8 @@ impl<I: InstructionTrait> FlowGraph<I> {
         &self.nodes[node_index].insts
     }
 
+    // Edges are returned in the order they were added, here and in
+    // get_next_nodes, so that what's built from them is the same every run.
     pub fn get_inbound_edges(&self, node_index: usize) -> Vec<Edge> {
         self.nodes[node_index].inbound_edges.iter().map(
             |&edge_index| self.edges[edge_index]
@@ -70,7 +79,7 @@ impl<I: InstructionTrait> FlowGraph<I> {
         let mut next_nodes = Vec::new();
         let mut call_nodes = Vec::new();
 
-        for edge_index in (&self.nodes[node_index]).outbound_edges.iter() {
+        for edge_index in self.nodes[node_index].outbound_edges.iter() {
             let edge = &self.edges[*edge_index];
             match edge.value {
                 EdgeValue::Call => call_nodes.push(edge.get_to()),
@@ -112,25 +121,53 @@ impl<I: InstructionTrait> FlowGraph<I> {
         }
     }
     
+    // Points the edges into node_index at new_target instead, but for
+    // those from the nodes in except. An edge that new_target already
+    // has is dropped rather than moved.
+    pub fn redirect_inbound_edges(&mut self, node_index: usize, new_target: usize, except: &[usize]) {
+        for edge_index in self.nodes[node_index].inbound_edges.clone() {
+            let from = self.edges[edge_index].get_from();
+            if except.contains(&from) {
+                continue;
+            }
+
+            self.nodes[node_index].inbound_edges.remove(&edge_index);
+            if self.has_edge(from, new_target) {
+                self.nodes[from].outbound_edges.remove(&edge_index);
+            } else {
+                self.edges[edge_index].set_to(new_target);
+                self.nodes[new_target].inbound_edges.insert(edge_index);
+            }
+        }
+    }
+
     pub fn get_inst(&self, offset: usize) -> Option<&Meta<I>> {
         self.listing.instructions.get(&offset)
     }
 
+    pub fn instruction_at(&self, offset: usize) -> Result<I, DecompilerError> {
+        match self.get_inst(offset) {
+            None => Err(DecompilerError::graph_at_offset(offset,
+                String::from("no instruction in listing"))),
+            Some(inst) => inst.unwrap(offset)
+        }
+    }
+
     pub fn add_label(&mut self, offset: usize) {
         self.listing.add_label(offset);
     }
 
-    pub fn initial_instruction(&self, node_index: usize) -> Result<Option<usize>, String> {
+    pub fn initial_instruction(&self, node_index: usize) -> Result<Option<usize>, DecompilerError> {
         if node_index >= self.nodes.len() {
-            Err("Node doesn't exist.".into())
+            Err(DecompilerError::graph_at_node(node_index, "Node doesn't exist.".into()))
         } else {
             Ok(self.nodes[node_index].insts.first().cloned())
         }
     }
 
-    pub fn final_instruction(&self, node_index: usize) -> Result<Option<usize>, String> {
+    pub fn final_instruction(&self, node_index: usize) -> Result<Option<usize>, DecompilerError> {
         if node_index >= self.nodes.len() {
-            Err("Node doesn't exist.".into())
+            Err(DecompilerError::graph_at_node(node_index, "Node doesn't exist.".into()))
         } else {
             Ok(self.nodes[node_index].insts.last().cloned())
         }
@@ -140,10 +177,23 @@ impl<I: InstructionTrait> FlowGraph<I> {
         self.listing.add(offset, instruction);
     }
 
+    pub fn add_synthetic_to_listing(&mut self, offset: usize, meta: Meta<I>) {
+        self.listing.add_synthetic(offset, meta);
+    }
+
+    pub fn set_versions(&mut self, offset: usize, versions: Vec<CodeVersion>) {
+        self.listing.set_versions(offset, versions);
+    }
+
     pub fn listing(&self) -> &Listing<I> {
         &self.listing
     }
 
+    // Lifts every instruction in the graph's listing into IR.
+    pub fn lift<L: ir::LifterTrait<I>>(&mut self, lifter: &L) {
+        self.listing.lift(lifter);
+    }
+
     pub fn insert_offset_at_node_index(&mut self, offset: usize, node_index: usize) {
         self.inst_map.insert(offset, node_index);
         self.nodes[node_index].insts.push(offset);
@@ -158,12 +208,16 @@ impl<I: InstructionTrait> FlowGraph<I> {
     // If "inst_offset" points to the first instruction in the node,
     // then there is no need to split the node - we just return "None".
     // Otherwise we return the index of the second node.
-    pub fn split_node_at(&mut self, node_index: usize, inst_offset: usize) -> Option<usize> {
+    pub fn split_node_at(&mut self, node_index: usize, inst_offset: usize) -> Result<Option<usize>, DecompilerError> {
         match self.nodes[node_index].insts.iter().position(|&r| r == inst_offset) {
-            None => panic!("can't find offset to split at in node {}, instruction 0x{:x}.", node_index, inst_offset),
+            None => Err(DecompilerError::GraphInconsistency {
+                offset: Some(inst_offset),
+                node: Some(node_index),
+                reason: String::from("can't find offset to split at in node")
+            }),
             Some(index) => {
                 if index == 0 {
-                    return None;
+                    return Ok(None);
                 }
 
                 let mut new_node = Node::new();
@@ -185,14 +239,13 @@ impl<I: InstructionTrait> FlowGraph<I> {
                     self.inst_map.insert(*inst, new_node_index);
                 }
 
-                Some(node_index)
+                Ok(Some(node_index))
             }
         }
     }
 
-    pub fn insert_offsets(&mut self, source: usize, targets: Vec<usize>, branching: bool, value: EdgeValue) -> Vec<usize> {
-        let node_index = self.get_node_at(source)
-            .expect(format!("No node at instruction offset {:x}", source).as_str());
+    pub fn insert_offsets(&mut self, source: usize, targets: Vec<usize>, branching: bool, value: EdgeValue) -> Result<Vec<usize>, DecompilerError> {
+        let node_index = self.node_at(source)?;
 
         let mut unexplored = Vec::new();
 
@@ -210,7 +263,7 @@ impl<I: InstructionTrait> FlowGraph<I> {
                 },
                 Some(successor_node_index) =>
                     if successor_node_index != node_index {
-                        match self.split_node_at(successor_node_index, successor) {
+                        match self.split_node_at(successor_node_index, successor)? {
                             None => self.add_edge(node_index, successor_node_index, value),
                             Some(new_node_index) =>
                                 self.add_edge(node_index, new_node_index, value)
@@ -219,32 +272,91 @@ impl<I: InstructionTrait> FlowGraph<I> {
             }
         }
 
-        unexplored
+        Ok(unexplored)
     }
 
-    pub fn call_graph(&self) -> Result<CallGraph, String> {
+    // Returns the successors and predecessors of each node in function,
+    // ignoring call edges and edges that leave the function. Both are
+    // sorted by node index.
+    pub fn function_edges(&self, function: &Function) -> (HashMap<usize, Vec<usize>>, HashMap<usize, Vec<usize>>) {
+        let members: HashSet<usize> = function.nodes.iter().cloned().collect();
+        let exits: HashSet<usize> = function.exits.iter().cloned().collect();
+        let mut successors = HashMap::new();
+        let mut predecessors = HashMap::new();
+
+        for node in function.nodes.iter() {
+            successors.insert(*node, Vec::new());
+            predecessors.insert(*node, Vec::new());
+        }
+
+        for node in function.nodes.iter() {
+            if exits.contains(node) {
+                continue;
+            }
+
+            let (mut targets, _) = self.get_next_nodes(*node);
+            targets.sort();
+            targets.dedup();
+
+            for target in targets {
+                if members.contains(&target) {
+                    successors.get_mut(node).unwrap().push(target);
+                    predecessors.get_mut(&target).unwrap().push(*node);
+                }
+            }
+        }
+
+        for preds in predecessors.values_mut() {
+            preds.sort();
+        }
+
+        (successors, predecessors)
+    }
+
+    // Code that no call reaches, such as code decoded from the roots
+    // given to analyse_from, gets functions of its own once everything
+    // the entry calls has been found, so that those come first.
+    pub fn call_graph(&self) -> Result<CallGraph, DecompilerError> {
         let mut call_graph = CallGraph::new();
         let mut functions = vec!(0);
         let mut entries = vec!(0);
+        let mut covered = HashSet::new();
+
+        // Functions are explored in the order they're discovered, so
+        // that each one ends up at the index its callers were given.
+        loop {
+            if entries.is_empty() {
+                match self.uncovered_node(&covered)? {
+                    None => break,
+                    Some(node) => {
+                        functions.push(node);
+                        entries.push(node);
+                    }
+                }
+            }
 
-        while let Some(node) = entries.pop() {
+            let node = entries.remove(0);
             let mut exits = Vec::new();
             let mut current_function_nodes = vec!(node);
             let mut live_nodes = vec!(node);
 
             while let Some(live_node) = live_nodes.pop() {
                 if let Some(final_offset) = self.final_instruction(live_node)? {
-                    let final_instruction = match self.get_inst(final_offset) {
+                    let returns = match self.get_inst(final_offset) {
                         None => {
                             println!("{}", self);
-                            return Err(format!(
-"Node {} lists final instruction {:x}, but this instruction could not be found in the graph"
-                                , live_node, final_offset));
+                            return Err(DecompilerError::GraphInconsistency {
+                                offset: Some(final_offset),
+                                node: Some(live_node),
+                                reason: String::from("node lists final instruction, but this instruction could not be found in the graph")
+                            });
                         },
-                        Some(inst) => inst.unwrap(),
+                        Some(&Meta::Inst(inst)) => inst.is_return(),
+                        // Meta-instructions only choose between versions of code.
+                        Some(_) => false
                     };
 
-                    if final_instruction.is_return() {
+                    if returns {
                         exits.push(live_node);
                         continue
                     }
@@ -271,26 +383,44 @@ impl<I: InstructionTrait> FlowGraph<I> {
                 }
             }
 
+            covered.extend(current_function_nodes.iter().cloned());
             call_graph.add_function(current_function_nodes, exits);
         }
 
+        call_graph.finish();
         Ok(call_graph)
     }
+
+    // Returns the node no function covers that's the likeliest entry of
+    // one: the first in the file of those nothing leads to, or else the
+    // first in the file, for code that only loops back on itself.
+    fn uncovered_node(&self, covered: &HashSet<usize>) -> Result<Option<usize>, DecompilerError> {
+        let mut uncovered = Vec::new();
+        for node in 0..self.nodes.len() {
+            if !covered.contains(&node) {
+                if let Some(offset) = self.initial_instruction(node)? {
+                    uncovered.push((self.nodes[node].inbound_edges.len() > 0, offset, node));
+                }
+            }
+        }
+
+        Ok(uncovered.into_iter().min().map(|(_, _, node)| node))
+    }
 }
 
 #[derive(Clone)]
 struct Node {
     pub insts: Vec<usize>,
-    pub inbound_edges: HashSet<usize>,
-    pub outbound_edges: HashSet<usize>
+    pub inbound_edges: BTreeSet<usize>,
+    pub outbound_edges: BTreeSet<usize>
 }
 
 impl Node {
     fn new() -> Node {
         Node {
             insts: Vec::new(),
-            inbound_edges: HashSet::new(),
-            outbound_edges: HashSet::new()
+            inbound_edges: BTreeSet::new(),
+            outbound_edges: BTreeSet::new()
         }
     }
 }
@@ -386,10 +516,19 @@ impl<I: InstructionTrait> fmt::Display for FlowGraph<I> {
     }
 }
 
+// Each of these may simulate part of the program, and gives up with an
+// error once it goes past "limits".
 pub trait AnalyzerTrait<I: InstructionTrait> {
-    fn determine_successors(&self, file_buffer: &[u8], graph: &FlowGraph<I>, offset: usize) -> Result<HashSet<usize>, String>;
+    fn determine_successors(&self, file_buffer: &[u8], graph: &FlowGraph<I>, offset: usize, limits: &Limits) -> Result<HashSet<usize>, DecompilerError>;
+
+    fn written_offsets(&self, file_buffer: &[u8], graph: &FlowGraph<I>, offset: usize, limits: &Limits) -> Result<HashSet<usize>, DecompilerError>;
 
-    fn written_offsets(&self, file_buffer: &[u8], graph: &FlowGraph<I>, offset: usize) -> Result<HashSet<usize>, String>;
+    // written_bytes(file_buffer, graph, offset, limits) -> written
+    // "written" maps the offsets the instruction at offset can write to
+    // to the values the byte there can take, or None if it can take any.
+    // This takes more of the program than written_offsets, so it's only
+    // asked for when code is written to.
+    fn written_bytes(&self, file_buffer: &[u8], graph: &FlowGraph<I>, offset: usize, limits: &Limits) -> Result<HashMap<usize, Option<HashSet<u8>>>, DecompilerError>;
 }
 
 #[derive(Debug)]
@@ -402,12 +541,26 @@ impl Function {
     pub fn nodes(&self) -> &Vec<usize> {
         &self.nodes
     }
+
+    pub fn entry(&self) -> usize {
+        self.nodes[0]
+    }
+
+    pub fn exits(&self) -> &[usize] {
+        &self.exits
+    }
 }
 
+// The calls between functions and the strongly connected components
+// they form are worked out once all the functions are in.
 #[derive(Debug)]
 pub struct CallGraph {
     functions: Vec<Function>,
-    entries: HashMap<usize, usize>
+    entries: HashMap<usize, usize>,
+    callees: Vec<Vec<usize>>,
+    callers: Vec<Vec<usize>>,
+    components: Vec<Vec<usize>>,
+    recursive: Vec<usize>
 }
 
 impl CallGraph {
@@ -415,9 +568,14 @@ impl CallGraph {
         CallGraph {
             functions: Vec::new(),
             entries: HashMap::new(),
+            callees: Vec::new(),
+            callers: Vec::new(),
+            components: Vec::new(),
+            recursive: Vec::new()
         }
     }
 
+    // Taking functions out leaves the calls between them as they were.
     pub fn pop(&mut self) -> Option<Function> {
         self.functions.pop()
     }
@@ -446,4 +604,175 @@ impl CallGraph {
     pub fn get_entry(&self, node_index: usize) -> Option<&usize> {
         self.entries.get(&node_index)
     }
+
+    fn finish(&mut self) {
+        self.callees = self.functions.iter()
+            .map(|function| {
+                let mut callees: Vec<usize> = function.nodes.iter()
+                    .filter_map(|node| self.entries.get(node).cloned())
+                    .collect();
+                callees.sort();
+                callees.dedup();
+                callees
+            })
+            .collect();
+
+        self.callers = vec!(Vec::new(); self.functions.len());
+        for (caller, callees) in self.callees.iter().enumerate() {
+            for &callee in callees.iter() {
+                self.callers[callee].push(caller);
+            }
+        }
+
+        let mut tarjan = Tarjan {
+            callees: &self.callees,
+            index: vec!(None; self.callees.len()),
+            low_link: vec!(0; self.callees.len()),
+            stack: Vec::new(),
+            on_stack: vec!(false; self.callees.len()),
+            next_index: 0,
+            components: Vec::new()
+        };
+
+        for function_index in 0..self.callees.len() {
+            if tarjan.index[function_index].is_none() {
+                tarjan.visit(function_index);
+            }
+        }
+
+        self.components = tarjan.components;
+
+        let callees = &self.callees;
+        self.recursive = self.components.iter()
+            .filter(|component| component.len() > 1
+                || callees[component[0]].contains(&component[0]))
+            .flat_map(|component| component.iter().cloned())
+            .collect();
+        self.recursive.sort();
+    }
+
+    // Returns the functions called from the nodes of function_index,
+    // in order.
+    pub fn callees(&self, function_index: usize) -> &[usize] {
+        &self.callees[function_index]
+    }
+
+    // Returns the functions that call function_index, in order.
+    pub fn callers(&self, function_index: usize) -> &[usize] {
+        &self.callers[function_index]
+    }
+
+    // Returns the strongly connected components of the call graph, found
+    // with Tarjan's algorithm. Each component's functions are in order,
+    // and components come after every component they call, so that
+    // callees can be handled before their callers.
+    pub fn components(&self) -> &[Vec<usize>] {
+        &self.components
+    }
+
+    // Returns true if function_index can call itself, directly or
+    // through other functions. CHIP-8 only keeps 16 return addresses,
+    // so recursion that goes deep enough overflows the stack.
+    pub fn is_recursive(&self, function_index: usize) -> bool {
+        self.recursive.binary_search(&function_index).is_ok()
+    }
+
+    // Returns the functions that can call themselves, in order.
+    pub fn recursive_functions(&self) -> &[usize] {
+        &self.recursive
+    }
+
+    // Returns the functions reachable from the first one, the program's
+    // entry, in reverse postorder: each function comes before those it
+    // calls, but for calls that close a cycle.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut postorder = Vec::new();
+        if self.functions.is_empty() {
+            return postorder;
+        }
+
+        let mut visited: HashSet<usize> = [0].iter().cloned().collect();
+        let mut stack = vec!((0, 0));
+
+        while let Some((function_index, next)) = stack.pop() {
+            let callees = self.callees(function_index);
+            if next < callees.len() {
+                let callee = callees[next];
+                stack.push((function_index, next + 1));
+                if visited.insert(callee) {
+                    stack.push((callee, 0));
+                }
+            } else {
+                postorder.push(function_index);
+            }
+        }
+
+        postorder.reverse();
+        postorder
+    }
+
+    // Returns every function, each after all those it calls outside of
+    // its own strongly connected component.
+    pub fn bottom_up(&self) -> Vec<usize> {
+        self.components.iter()
+            .flat_map(|component| component.iter().cloned())
+            .collect()
+    }
+
+    // Returns the functions that the program's entry never gets to
+    // call, in order. These start at code that only the entries given
+    // to analyse_from reach.
+    pub fn unreachable(&self) -> Vec<usize> {
+        let reachable: HashSet<usize> = self.reverse_postorder().into_iter().collect();
+        (0..self.functions.len())
+            .filter(|function_index| !reachable.contains(function_index))
+            .collect()
+    }
+}
+
+struct Tarjan<'a> {
+    callees: &'a [Vec<usize>],
+    index: Vec<Option<usize>>,
+    low_link: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    next_index: usize,
+    components: Vec<Vec<usize>>
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, function_index: usize) {
+        self.index[function_index] = Some(self.next_index);
+        self.low_link[function_index] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(function_index);
+        self.on_stack[function_index] = true;
+
+        for &callee in self.callees[function_index].iter() {
+            match self.index[callee] {
+                None => {
+                    self.visit(callee);
+                    self.low_link[function_index] = self.low_link[function_index]
+                        .min(self.low_link[callee]);
+                },
+                Some(index) if self.on_stack[callee] => {
+                    self.low_link[function_index] = self.low_link[function_index].min(index);
+                },
+                _ => ()
+            }
+        }
+
+        if Some(self.low_link[function_index]) == self.index[function_index] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == function_index {
+                    break;
+                }
+            }
+            component.sort();
+            self.components.push(component);
+        }
+    }
 }
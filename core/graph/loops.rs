@@ -0,0 +1,239 @@
+use defs::main::*;
+use graph::flow::{FlowGraph, Function, CallGraph};
+use graph::dom::{self, Dominators, DominatorTree};
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+// Loop detection, per function.
+//
+// A back edge is an edge whose target dominates its source; the
+// natural loop of a back edge is its target (the header) together
+// with every node that can reach the source without passing through
+// the header. Back edges sharing a header make up one loop.
+//
+// A retreating edge (one that leads back to a node on the current
+// depth-first search path) whose target doesn't dominate its source
+// enters a loop with more than one entry. Such loops are irreducible:
+// their "header" is just the target of the retreating edge, and their
+// body is the part of the strongly connected component around it that
+// reaches the edge's source.
+
+#[derive(Debug)]
+pub struct Loop {
+    pub header: usize,
+    pub nodes: HashSet<usize>,
+    pub back_edges: Vec<(usize, usize)>,
+    pub exits: Vec<(usize, usize)>,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub depth: usize,
+    pub irreducible: bool
+}
+
+impl Loop {
+    pub fn contains(&self, node: usize) -> bool {
+        self.nodes.contains(&node)
+    }
+
+    // Returns the nodes that branch back to the header.
+    pub fn latches(&self) -> Vec<usize> {
+        self.back_edges.iter().map(|&(from, _)| from).collect()
+    }
+}
+
+pub struct LoopForest {
+    loops: Vec<Loop>,
+    innermost: HashMap<usize, usize>
+}
+
+impl LoopForest {
+    pub fn loops(&self) -> &Vec<Loop> {
+        &self.loops
+    }
+
+    pub fn get(&self, index: usize) -> &Loop {
+        &self.loops[index]
+    }
+
+    // Returns the indices of the outermost loops.
+    pub fn roots(&self) -> Vec<usize> {
+        (0..self.loops.len())
+            .filter(|&index| self.loops[index].parent.is_none())
+            .collect()
+    }
+
+    // Returns the index of the innermost loop containing node.
+    pub fn loop_of(&self, node: usize) -> Option<usize> {
+        self.innermost.get(&node).cloned()
+    }
+
+    // Returns the index of the loop headed by node.
+    pub fn loop_with_header(&self, node: usize) -> Option<usize> {
+        self.loops.iter().position(|l| l.header == node)
+    }
+
+    pub fn is_header(&self, node: usize) -> bool {
+        self.loop_with_header(node).is_some()
+    }
+
+    pub fn is_back_edge(&self, from: usize, to: usize) -> bool {
+        self.loops.iter().any(|l| l.back_edges.contains(&(from, to)))
+    }
+
+    // Returns the number of loops containing node.
+    pub fn depth(&self, node: usize) -> usize {
+        match self.loop_of(node) {
+            None => 0,
+            Some(index) => self.loops[index].depth
+        }
+    }
+}
+
+pub fn loops<I: InstructionTrait>(graph: &FlowGraph<I>, function: &Function, dominators: &DominatorTree) -> LoopForest {
+    let (successors, predecessors) = graph.function_edges(function);
+    let retreating = retreating_edges(function.entry(), &successors);
+
+    let mut headers: Vec<usize> = Vec::new();
+    let mut edges_by_header: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+
+    for &(from, to) in retreating.iter() {
+        if !edges_by_header.contains_key(&to) {
+            headers.push(to);
+            edges_by_header.insert(to, Vec::new());
+        }
+        edges_by_header.get_mut(&to).unwrap().push((from, to));
+    }
+
+    let mut loops = Vec::new();
+
+    for header in headers {
+        let back_edges = edges_by_header.remove(&header).unwrap();
+        let irreducible = back_edges.iter()
+            .any(|&(from, to)| !dominators.dominates(to, from));
+
+        let component = if irreducible {
+            Some(strongly_connected(header, &successors, &predecessors))
+        } else {
+            None
+        };
+
+        let mut nodes: HashSet<usize> = [header].iter().cloned().collect();
+        let mut live_nodes: Vec<usize> = back_edges.iter().map(|&(from, _)| from).collect();
+
+        while let Some(node) = live_nodes.pop() {
+            if let Some(ref component) = component {
+                if !component.contains(&node) {
+                    continue;
+                }
+            }
+
+            if nodes.insert(node) {
+                for &pred in predecessors[&node].iter() {
+                    live_nodes.push(pred);
+                }
+            }
+        }
+
+        let mut exits = Vec::new();
+        let mut sorted_nodes: Vec<usize> = nodes.iter().cloned().collect();
+        sorted_nodes.sort();
+
+        for node in sorted_nodes {
+            for &target in successors[&node].iter() {
+                if !nodes.contains(&target) {
+                    exits.push((node, target));
+                }
+            }
+        }
+
+        loops.push(Loop {
+            header: header,
+            nodes: nodes,
+            back_edges: back_edges,
+            exits: exits,
+            parent: None,
+            children: Vec::new(),
+            depth: 1,
+            irreducible: irreducible
+        });
+    }
+
+    nest(loops)
+}
+
+// Returns the loops of every function in call_graph, in the same order
+// as call_graph.functions().
+pub fn function_loops<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph, dominators: &[Dominators]) -> Vec<LoopForest> {
+    call_graph.functions().iter().zip(dominators.iter())
+        .map(|(function, function_dominators)|
+            loops(graph, function, &function_dominators.dominators))
+        .collect()
+}
+
+// Orders loops from outermost to innermost and links each one to the
+// smallest loop that contains it.
+fn nest(mut loops: Vec<Loop>) -> LoopForest {
+    loops.sort_by(|a, b| b.nodes.len().cmp(&a.nodes.len()).then(a.header.cmp(&b.header)));
+
+    for index in 0..loops.len() {
+        let parent = (0..index).rev().find(|&candidate|
+            loops[candidate].nodes.is_superset(&loops[index].nodes)
+            && loops[candidate].header != loops[index].header);
+
+        if let Some(parent) = parent {
+            loops[index].parent = Some(parent);
+            loops[index].depth = loops[parent].depth + 1;
+            loops[parent].children.push(index);
+        }
+    }
+
+    let mut innermost = HashMap::new();
+    for index in 0..loops.len() {
+        for node in loops[index].nodes.iter() {
+            innermost.insert(*node, index);
+        }
+    }
+
+    LoopForest {
+        loops: loops,
+        innermost: innermost
+    }
+}
+
+// Returns the edges that lead back to a node on the depth-first search
+// path from root, in the order they're found.
+fn retreating_edges(root: usize, successors: &HashMap<usize, Vec<usize>>) -> Vec<(usize, usize)> {
+    let mut edges = Vec::new();
+    let mut visited = HashSet::new();
+    let mut on_path = HashSet::new();
+    let mut stack = vec!((root, 0));
+    visited.insert(root);
+    on_path.insert(root);
+
+    while let Some((node, next)) = stack.pop() {
+        if next < successors[&node].len() {
+            stack.push((node, next + 1));
+            let successor = successors[&node][next];
+
+            if on_path.contains(&successor) {
+                edges.push((node, successor));
+            } else if visited.insert(successor) {
+                on_path.insert(successor);
+                stack.push((successor, 0));
+            }
+        } else {
+            on_path.remove(&node);
+        }
+    }
+
+    edges
+}
+
+// Returns the nodes that can both reach and be reached from node.
+fn strongly_connected(node: usize, successors: &HashMap<usize, Vec<usize>>,
+    predecessors: &HashMap<usize, Vec<usize>>) -> HashSet<usize>
+{
+    let forward = dom::reachable(node, successors);
+    let backward = dom::reachable(node, predecessors);
+    forward.intersection(&backward).cloned().collect()
+}
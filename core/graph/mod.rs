@@ -1,2 +1,10 @@
 pub mod flow;
 pub mod state;
+pub mod dom;
+pub mod loops;
+pub mod structure;
+pub mod ssa;
+pub mod dataflow;
+pub mod worklist;
+pub mod dot;
+pub mod summary;
@@ -0,0 +1,574 @@
+use defs::main::*;
+use defs::ir;
+use defs::ir::Location;
+use graph::flow::{FlowGraph, Function, CallGraph};
+use graph::dom::{Dominators, DominatorTree};
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+// Static single assignment form over the IR of a function, following
+// Cytron et al., "Efficiently Computing Static Single Assignment Form
+// and the Control Dependence Graph".
+//
+// Blocks are flow graph nodes, and the IR attached to their
+// instructions (see FlowGraph::lift) is read in order. Every location
+// the IR reads or writes (registers, flags, temporaries, ports and
+// memory, which is one location) is renamed. Phis are only placed for
+// locations that are live on entry to some block, so temporaries, which
+// never outlive their native instruction, don't get any.
+//
+// Version 0 of each location is its value on entry to the function.
+//
+// Most IR instructions become one statement each. A native instruction
+// whose IR loops (it has jumps to Local targets, e.g. x86 REP
+// prefixes) becomes a single statement, which may or may not write
+// each location it writes, and so also uses it. Its temporaries are
+// internal to it. Instructions that clobber everything, and
+// instructions that weren't lifted, use and define every location the
+// function mentions. Returns use every location, since the caller may
+// read any of them. When the SSA of a whole call graph is built, each
+// function's locations include those of every other function, since
+// a callee may read or write them.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Value {
+    pub location: Location,
+    pub version: usize
+}
+
+impl Value {
+    pub fn format_with<I, L>(&self, lifter: &L) -> String
+        where I: InstructionTrait,
+              L: ir::LifterTrait<I>
+    {
+        format!("{}_{}", location_name(self.location, lifter), self.version)
+    }
+}
+
+// Where a value is defined or used: on entry to the function, or at
+// a phi or statement of a block, by index.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Site {
+    Entry,
+    Phi(usize, usize),
+    Statement(usize, usize)
+}
+
+#[derive(Debug)]
+pub struct Phi {
+    pub target: Value,
+    pub sources: Vec<(usize, Value)>
+}
+
+#[derive(Debug)]
+pub struct Statement {
+    pub offset: usize,
+    pub index: usize,
+    pub instructions: Vec<ir::Instruction>,
+    pub uses: Vec<Value>,
+    pub defs: Vec<Value>
+}
+
+impl Statement {
+    // Returns the version of location that the statement reads.
+    pub fn use_of(&self, location: Location) -> Option<Value> {
+        self.uses.iter().cloned().find(|value| value.location == location)
+    }
+
+    // Returns the version of location that the statement writes.
+    pub fn def_of(&self, location: Location) -> Option<Value> {
+        self.defs.iter().cloned().find(|value| value.location == location)
+    }
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub phis: Vec<Phi>,
+    pub statements: Vec<Statement>
+}
+
+pub struct SsaFunction {
+    entry: usize,
+    order: Vec<usize>,
+    blocks: HashMap<usize, Block>,
+    variables: Vec<Location>,
+    definitions: HashMap<Value, Site>,
+    uses: HashMap<Value, Vec<Site>>
+}
+
+impl SsaFunction {
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    // Returns the nodes reachable from the entry, in reverse postorder.
+    pub fn nodes(&self) -> &[usize] {
+        &self.order
+    }
+
+    pub fn block(&self, node: usize) -> Option<&Block> {
+        self.blocks.get(&node)
+    }
+
+    // Returns every location the function reads or writes, apart from
+    // temporaries.
+    pub fn variables(&self) -> &[Location] {
+        &self.variables
+    }
+
+    // Returns the site that defines value (its use-def chain).
+    pub fn definition(&self, value: Value) -> Option<Site> {
+        if value.version == 0 {
+            Some(Site::Entry)
+        } else {
+            self.definitions.get(&value).cloned()
+        }
+    }
+
+    // Returns the sites that use value (its def-use chain).
+    pub fn uses(&self, value: Value) -> &[Site] {
+        match self.uses.get(&value) {
+            Some(sites) => sites,
+            None => &[]
+        }
+    }
+
+    // Returns every value defined in the function, apart from the
+    // entry values.
+    pub fn values(&self) -> Vec<Value> {
+        self.definitions.keys().cloned().collect()
+    }
+
+    pub fn phi(&self, site: Site) -> Option<&Phi> {
+        match site {
+            Site::Phi(node, index) =>
+                self.blocks.get(&node).and_then(|block| block.phis.get(index)),
+            _ => None
+        }
+    }
+
+    pub fn statement(&self, site: Site) -> Option<&Statement> {
+        match site {
+            Site::Statement(node, index) =>
+                self.blocks.get(&node).and_then(|block| block.statements.get(index)),
+            _ => None
+        }
+    }
+
+    // Prints the function, using lifter to name locations.
+    pub fn format_with<I, L>(&self, lifter: &L) -> String
+        where I: InstructionTrait,
+              L: ir::LifterTrait<I>
+    {
+        let mut output = String::new();
+        let names = |values: &[Value]| values.iter()
+            .map(|value| value.format_with(lifter))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        for node in self.order.iter() {
+            let block = &self.blocks[node];
+            output.push_str(format!("node {}:\n", node).as_str());
+
+            for phi in block.phis.iter() {
+                let sources: Vec<String> = phi.sources.iter()
+                    .map(|&(pred, value)| format!("{}: {}", pred, value.format_with(lifter)))
+                    .collect();
+                output.push_str(format!("    {} = phi({})\n",
+                    phi.target.format_with(lifter), sources.join(", ")).as_str());
+            }
+
+            for statement in block.statements.iter() {
+                let instructions: Vec<String> = statement.instructions.iter()
+                    .map(|instruction| instruction.format_with(lifter))
+                    .collect();
+                output.push_str(format!("    {:4x}.{}: {}\t; {} <- {}\n",
+                    statement.offset, statement.index, instructions.join("; "),
+                    names(&statement.defs), names(&statement.uses)).as_str());
+            }
+        }
+
+        output
+    }
+}
+
+// Returns the name of a location, using lifter for registers, flags
+// and ports.
+pub fn location_name<I, L>(location: Location, lifter: &L) -> String
+    where I: InstructionTrait,
+          L: ir::LifterTrait<I>
+{
+    match location {
+        Location::Reg(index) => lifter.register_name(index),
+        Location::Flag(index) => lifter.flag_name(index),
+        Location::Temp(index) => format!("t{}", index),
+        Location::Port(index) => lifter.port_name(index),
+        Location::Memory => String::from("mem")
+    }
+}
+
+pub fn ssa<I: InstructionTrait>(graph: &FlowGraph<I>, function: &Function, dominators: &Dominators) -> SsaFunction {
+    build(graph, function, dominators, &[])
+}
+
+// Builds the SSA form of function, whose variables include shared
+// as well as the locations it mentions itself.
+fn build<I: InstructionTrait>(graph: &FlowGraph<I>, function: &Function, dominators: &Dominators, shared: &[Location]) -> SsaFunction {
+    let tree = &dominators.dominators;
+    let (successors, predecessors) = graph.function_edges(function);
+
+    // Gather the IR of each reachable node, grouped into statements,
+    // with the locations each statement reads and writes.
+    let mut variables: Vec<Location> = Vec::new();
+    let mut blocks: HashMap<usize, Vec<(Statement, Effects)>> = HashMap::new();
+
+    for &node in tree.nodes() {
+        let mut statements = Vec::new();
+
+        for &offset in graph.get_instructions_at(node) {
+            for (index, instructions, effects) in statements_at(graph, offset) {
+                for location in effects.reads.iter().chain(effects.writes.iter()) {
+                    if !is_temp(*location) && !variables.contains(location) {
+                        variables.push(*location);
+                    }
+                }
+                statements.push((Statement {
+                    offset: offset,
+                    index: index,
+                    instructions: instructions,
+                    uses: Vec::new(),
+                    defs: Vec::new()
+                }, effects));
+            }
+        }
+
+        blocks.insert(node, statements);
+    }
+
+    for location in shared.iter() {
+        if !variables.contains(location) {
+            variables.push(*location);
+        }
+    }
+
+    // Statements that clobber everything read and write every variable,
+    // and returns read every variable.
+    for statements in blocks.values_mut() {
+        for &mut (ref statement, ref mut effects) in statements.iter_mut() {
+            if statement.instructions.len() == 1
+                && statement.instructions[0].mnemonic == ir::Mnemonic::RET
+            {
+                effects.reads = variables.clone();
+            } else if effects.clobbers {
+                let temps: Vec<Location> = effects.reads.iter().cloned()
+                    .filter(|location| is_temp(*location))
+                    .collect();
+                effects.reads = variables.clone();
+                effects.reads.extend(temps);
+                effects.writes = variables.clone();
+            }
+        }
+    }
+
+    // Find the locations that are live on entry to some block, and the
+    // blocks that write each location.
+    let mut globals: HashSet<Location> = HashSet::new();
+    let mut def_sites: HashMap<Location, Vec<usize>> = HashMap::new();
+
+    for &node in tree.nodes() {
+        let mut killed = HashSet::new();
+
+        for &(_, ref effects) in blocks[&node].iter() {
+            for location in effects.reads.iter() {
+                if !killed.contains(location) {
+                    globals.insert(*location);
+                }
+            }
+            for location in effects.writes.iter() {
+                killed.insert(*location);
+                let sites = def_sites.entry(*location).or_insert(Vec::new());
+                if !sites.contains(&node) {
+                    sites.push(node);
+                }
+            }
+        }
+    }
+
+    // Place phis on the iterated dominance frontiers of each global's
+    // definitions.
+    let mut phis: HashMap<usize, Vec<Phi>> = tree.nodes().iter()
+        .map(|&node| (node, Vec::new()))
+        .collect();
+
+    for &location in variables.iter() {
+        if !globals.contains(&location) {
+            continue;
+        }
+
+        let mut placed: HashSet<usize> = HashSet::new();
+        let mut work: Vec<usize> = def_sites.get(&location).cloned().unwrap_or(Vec::new());
+        let mut queued: HashSet<usize> = work.iter().cloned().collect();
+
+        while let Some(node) = work.pop() {
+            let frontier = match tree.frontier(node) {
+                Some(frontier) => frontier,
+                None => continue
+            };
+            let mut frontier: Vec<usize> = frontier.iter().cloned().collect();
+            frontier.sort();
+
+            for target in frontier {
+                if !placed.insert(target) {
+                    continue;
+                }
+
+                let undefined = Value { location: location, version: 0 };
+                phis.get_mut(&target).unwrap().push(Phi {
+                    target: undefined,
+                    sources: predecessors[&target].iter()
+                        .filter(|pred| tree.contains(**pred))
+                        .map(|&pred| (pred, undefined))
+                        .collect()
+                });
+
+                if queued.insert(target) {
+                    work.push(target);
+                }
+            }
+        }
+    }
+
+    let mut renamer = Renamer {
+        stacks: HashMap::new(),
+        counters: HashMap::new()
+    };
+
+    rename(tree, &successors, &mut blocks, &mut phis, &mut renamer);
+
+    let mut function_blocks: HashMap<usize, Block> = HashMap::new();
+    for &node in tree.nodes() {
+        function_blocks.insert(node, Block {
+            phis: phis.remove(&node).unwrap(),
+            statements: blocks.remove(&node).unwrap().into_iter()
+                .map(|(statement, _)| statement)
+                .collect()
+        });
+    }
+
+    let (definitions, uses) = chains(tree.nodes(), &function_blocks);
+
+    SsaFunction {
+        entry: tree.root(),
+        order: tree.nodes().to_vec(),
+        blocks: function_blocks,
+        variables: variables,
+        definitions: definitions,
+        uses: uses
+    }
+}
+
+// Returns the SSA form of every function in call_graph, in the same
+// order as call_graph.functions(). Every function shares the variables
+// of all the others.
+pub fn function_ssa<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph, dominators: &[Dominators]) -> Vec<SsaFunction> {
+    let mut shared: Vec<Location> = Vec::new();
+    for function in call_graph.functions() {
+        for &node in function.nodes() {
+            for &offset in graph.get_instructions_at(node) {
+                for (_, _, effects) in statements_at(graph, offset) {
+                    for location in effects.reads.iter().chain(effects.writes.iter()) {
+                        if !is_temp(*location) && !shared.contains(location) {
+                            shared.push(*location);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    call_graph.functions().iter().zip(dominators.iter())
+        .map(|(function, function_dominators)| build(graph, function, function_dominators, &shared))
+        .collect()
+}
+
+struct Effects {
+    reads: Vec<Location>,
+    writes: Vec<Location>,
+    clobbers: bool
+}
+
+struct Renamer {
+    stacks: HashMap<Location, Vec<usize>>,
+    counters: HashMap<Location, usize>
+}
+
+impl Renamer {
+    fn current(&self, location: Location) -> Value {
+        Value {
+            location: location,
+            version: self.stacks.get(&location)
+                .and_then(|stack| stack.last().cloned())
+                .unwrap_or(0)
+        }
+    }
+
+    fn define(&mut self, location: Location) -> Value {
+        let counter = self.counters.entry(location).or_insert(0);
+        *counter += 1;
+        self.stacks.entry(location).or_insert(Vec::new()).push(*counter);
+
+        Value {
+            location: location,
+            version: *counter
+        }
+    }
+
+    fn pop(&mut self, location: Location) {
+        self.stacks.get_mut(&location).unwrap().pop();
+    }
+}
+
+// Renames every location, walking the dominator tree depth first. The
+// walk keeps an explicit stack, since functions can be deep.
+fn rename(tree: &DominatorTree, successors: &HashMap<usize, Vec<usize>>,
+    blocks: &mut HashMap<usize, Vec<(Statement, Effects)>>,
+    phis: &mut HashMap<usize, Vec<Phi>>, renamer: &mut Renamer)
+{
+    let mut stack = vec!((tree.root(), false));
+    let mut defined: HashMap<usize, Vec<Location>> = HashMap::new();
+
+    while let Some((node, done)) = stack.pop() {
+        if done {
+            for location in defined.remove(&node).unwrap() {
+                renamer.pop(location);
+            }
+            continue;
+        }
+
+        let mut node_defined = Vec::new();
+
+        for phi in phis.get_mut(&node).unwrap().iter_mut() {
+            phi.target = renamer.define(phi.target.location);
+            node_defined.push(phi.target.location);
+        }
+
+        for &mut (ref mut statement, ref effects) in blocks.get_mut(&node).unwrap().iter_mut() {
+            statement.uses = effects.reads.iter()
+                .map(|&location| renamer.current(location))
+                .collect();
+            statement.defs = effects.writes.iter()
+                .map(|&location| renamer.define(location))
+                .collect();
+            node_defined.extend(effects.writes.iter().cloned());
+        }
+
+        for successor in successors[&node].iter() {
+            if let Some(successor_phis) = phis.get_mut(successor) {
+                for phi in successor_phis.iter_mut() {
+                    let current = renamer.current(phi.target.location);
+                    for source in phi.sources.iter_mut() {
+                        if source.0 == node {
+                            source.1 = current;
+                        }
+                    }
+                }
+            }
+        }
+
+        defined.insert(node, node_defined);
+        stack.push((node, true));
+        for &child in tree.children(node).iter().rev() {
+            stack.push((child, false));
+        }
+    }
+}
+
+// Builds the use-def and def-use chains.
+fn chains(order: &[usize], blocks: &HashMap<usize, Block>) -> (HashMap<Value, Site>, HashMap<Value, Vec<Site>>) {
+    let mut definitions = HashMap::new();
+    let mut uses: HashMap<Value, Vec<Site>> = HashMap::new();
+
+    for &node in order.iter() {
+        let block = &blocks[&node];
+
+        for (index, phi) in block.phis.iter().enumerate() {
+            let site = Site::Phi(node, index);
+            definitions.insert(phi.target, site);
+            for &(_, value) in phi.sources.iter() {
+                uses.entry(value).or_insert(Vec::new()).push(site);
+            }
+        }
+
+        for (index, statement) in block.statements.iter().enumerate() {
+            let site = Site::Statement(node, index);
+            for value in statement.defs.iter() {
+                definitions.insert(*value, site);
+            }
+            for value in statement.uses.iter() {
+                uses.entry(*value).or_insert(Vec::new()).push(site);
+            }
+        }
+    }
+
+    (definitions, uses)
+}
+
+// Splits the IR of the instruction at offset into statements.
+fn statements_at<I: InstructionTrait>(graph: &FlowGraph<I>, offset: usize) -> Vec<(usize, Vec<ir::Instruction>, Effects)> {
+    let instructions = match graph.listing().get_ir(offset) {
+        Some(instructions) => instructions.clone(),
+        None => vec!(ir::Instruction::new(ir::Mnemonic::UNSUPPORTED))
+    };
+
+    let loops = instructions.iter().any(|instruction|
+        [instruction.op1, instruction.op2, instruction.op3].iter().any(|operand| match *operand {
+            Some(ir::Operand::Local(_)) => true,
+            _ => false
+        }));
+
+    if !loops {
+        return instructions.into_iter().enumerate()
+            .map(|(index, instruction)| {
+                let effects = Effects {
+                    reads: instruction.reads(),
+                    writes: instruction.writes(),
+                    clobbers: instruction.clobbers_all()
+                };
+                (index, vec!(instruction), effects)
+            })
+            .collect();
+    }
+
+    let mut writes = Vec::new();
+    for instruction in instructions.iter() {
+        for location in instruction.writes() {
+            if !is_temp(location) && !writes.contains(&location) {
+                writes.push(location);
+            }
+        }
+    }
+
+    let mut reads = writes.clone();
+    for instruction in instructions.iter() {
+        for location in instruction.reads() {
+            if !is_temp(location) && !reads.contains(&location) {
+                reads.push(location);
+            }
+        }
+    }
+
+    let effects = Effects {
+        reads: reads,
+        writes: writes,
+        clobbers: instructions.iter().any(|instruction| instruction.clobbers_all())
+    };
+
+    vec!((0, instructions, effects))
+}
+
+fn is_temp(location: Location) -> bool {
+    match location {
+        Location::Temp(_) => true,
+        _ => false
+    }
+}
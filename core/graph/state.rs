@@ -1,18 +1,32 @@
-//use std::time::{SystemTime, UNIX_EPOCH};
-
 use defs::main::*;
+use graph::worklist::{Frontier, Worklist, DepthFirst};
+use std::cell::RefCell;
 use std::fmt;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::usize;
 
 pub struct StateFlowGraph<I: InstructionTrait, S: StateTrait<S>> {
     listing: Listing<I>,
     nodes: Vec<Node>,
     edges: Vec<Edge>,
     states: Vec<S>,
+    state_ids: Vec<usize>,
+    next_state_id: usize,
     inst_map: HashMap<usize, usize>,
     state_map: HashMap<usize, usize>,
-    live_states: Vec<usize>
+    live_states: Box<dyn Worklist>,
+
+    // The number of states added at each offset, the offsets where
+    // states were widened, the states that reached each loop header when
+    // they're recorded for narrowing, and the states narrowed by a
+    // previous exploration that stand in for states they contain.
+    visits: HashMap<usize, usize>,
+    widened: HashSet<usize>,
+    arrivals: Option<HashMap<usize, Vec<S>>>,
+    invariants: HashMap<usize, Vec<S>>
 }
 
 impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
@@ -22,10 +36,35 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
             nodes: vec!(Node::new()),
             edges: Vec::new(),
             states: Vec::new(),
+            state_ids: Vec::new(),
+            next_state_id: 0,
             inst_map: HashMap::new(),
             state_map: HashMap::new(),
-            live_states: Vec::new()
+            live_states: Box::new(DepthFirst::new()),
+            visits: HashMap::new(),
+            widened: HashSet::new(),
+            arrivals: None,
+            invariants: HashMap::new()
+        }
+    }
+
+    // Creates a graph that records the states reaching loop headers, and
+    // that uses the narrowed states from a previous exploration's
+    // narrowed_states in place of the states they contain.
+    pub fn with_invariants(invariants: HashMap<usize, Vec<S>>) -> StateFlowGraph<I, S> {
+        let mut graph = StateFlowGraph::new();
+        graph.arrivals = Some(HashMap::new());
+        graph.invariants = invariants;
+        graph
+    }
+
+    // Hands the live states to worklist, which decides the order
+    // next_live_state returns them in from now on.
+    pub fn set_worklist(&mut self, mut worklist: Box<dyn Worklist>) {
+        for state_index in self.live_states.state_indices() {
+            worklist.push(state_index);
         }
+        self.live_states = worklist;
     }
 
     pub fn add_empty_node(&mut self) -> usize {
@@ -55,12 +94,13 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
         let state_index = self.states.len();
         let node_index = self.nodes.len();
 
-        let mut node = Node::from_state(offset, state_index);
-        node.insts.push(offset);
-        self.nodes.push(node);
+        self.nodes.push(Node::from_state(offset, state_index));
         self.inst_map.insert(offset, node_index);
 
+        *self.visits.entry(offset).or_insert(0) += 1;
+
         self.states.push(state);
+        self.push_state_id();
         self.state_map.insert(state_index, node_index);
         self.live_states.push(state_index);
         
@@ -96,7 +136,7 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
         nodes
     }
 
-    pub fn remove_node(&mut self, node_index: usize) -> Result<(), String> {
+    pub fn remove_node(&mut self, node_index: usize) -> Result<(), DecompilerError> {
         let node = self.nodes[node_index].clone();
         for in_edge in node.inbound_edges.iter() {
             for out_edge in node.outbound_edges.iter() {
@@ -125,10 +165,20 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
         &self.nodes[node].insts
     }
 
+    // The states are sorted, so that what's done with them is the same
+    // every run.
     pub fn get_states_at_node(&self, node_index: usize) -> Vec<usize> {
         self.nodes[node_index].states.iter().cloned().collect()
     }
 
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_deleted(&self, node_index: usize) -> bool {
+        self.nodes[node_index].deleted
+    }
+
     pub fn has_edge(&self, source: usize, target: usize) -> bool {
         for out in self.nodes[source].outbound_edges.iter() {
             if self.edges[*out].to == target {
@@ -139,13 +189,15 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
         false
     }
 
-    pub fn add_edge(&mut self, source: usize, target: usize) -> Result<(), String> {
+    pub fn add_edge(&mut self, source: usize, target: usize) -> Result<(), DecompilerError> {
         if source >= self.nodes.len() {
-            return Err(format!("Trying to add edge from non-existant node {}.", source));
+            return Err(DecompilerError::graph_at_node(source,
+                String::from("Trying to add edge from non-existant node.")));
         }
 
         if target >= self.nodes.len() {
-            return Err(format!("Trying to add edge to non-existant node {}.", source));
+            return Err(DecompilerError::graph_at_node(target,
+                String::from("Trying to add edge to non-existant node.")));
         }
 
         if !self.has_edge(source, target) {
@@ -161,24 +213,33 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
         self.listing.instructions.get(&offset)
     }
 
+    pub fn instruction_at(&self, offset: usize) -> Result<I, DecompilerError> {
+        match self.get_inst(offset) {
+            None => Err(DecompilerError::graph_at_offset(offset,
+                String::from("no instruction in listing"))),
+            Some(inst) => inst.unwrap(offset)
+        }
+    }
+
     pub fn add_label(&mut self, offset: usize) {
         self.listing.add_label(offset);
     }
 
-    pub fn initial_instruction(&self, node_index: usize) -> Result<usize, String> {
+    pub fn initial_instruction(&self, node_index: usize) -> Result<usize, DecompilerError> {
         if node_index >= self.nodes.len() {
-            Err(String::from("Node doesn't exist."))
+            Err(DecompilerError::graph_at_node(node_index, String::from("Node doesn't exist.")))
         } else {
             match self.nodes[node_index].insts.first() {
-                None => Err(String::from(format!("Node {} has no instructions", node_index))),
+                None => Err(DecompilerError::graph_at_node(node_index,
+                    String::from("Node has no instructions"))),
                 Some(offset) => Ok(*offset)
             }
         }
     }
 
-    pub fn final_instruction(&self, node_index: usize) -> Result<Option<&usize>, String> {
+    pub fn final_instruction(&self, node_index: usize) -> Result<Option<&usize>, DecompilerError> {
         if node_index >= self.nodes.len() {
-            Err(String::from("Node doesn't exist."))
+            Err(DecompilerError::graph_at_node(node_index, String::from("Node doesn't exist.")))
         } else {
             Ok(self.nodes[node_index].insts.last())
         }
@@ -203,9 +264,13 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
     // instructions coming after. It also adds an edge between the end of the
     // first node and the beginning of the second.
     // Return value is the index of the second node.
-    pub fn split_node_at(&mut self, node_index: usize, inst_offset: usize) -> Result<usize, String> {
+    pub fn split_node_at(&mut self, node_index: usize, inst_offset: usize) -> Result<usize, DecompilerError> {
         match self.nodes[node_index].insts.iter().position(|&r| r == inst_offset) {
-            None => panic!("can't find offset to split at in node {}, instruction 0x{:x}.", node_index, inst_offset),
+            None => Err(DecompilerError::GraphInconsistency {
+                offset: Some(inst_offset),
+                node: Some(node_index),
+                reason: String::from("can't find offset to split at in node")
+            }),
             Some(index) => {
                 if index == 0 {
                     return Ok(node_index);
@@ -240,28 +305,103 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
     }
 
     pub fn next_live_state(&mut self) -> Option<S> {
-        /*let length = self.live_states.len();
-        if length == 0 {
-            None
-        } else {
-            match SystemTime::now().duration_since(UNIX_EPOCH) {
-                Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-                Ok(n) => {
-                    let live_states_index = n.as_secs() as usize % length;
-                    let state_index = self.live_states.remove(live_states_index);
-                    Some(self.states[state_index].clone())
-                }
-            }
-        }*/
-        match self.live_states.pop() {
-            Some(index) => Some(self.states[index].clone()),
+        self.next_live_state_with_id().map(|(_, state)| state)
+    }
+
+    // States are renumbered as others are removed, so each state also
+    // gets an id when it's added, that no other state in the graph ever
+    // has. Since states don't change once added, the id stands for the
+    // state's value too.
+    pub fn next_live_state_with_id(&mut self) -> Option<(usize, S)> {
+        let frontier = GraphFrontier {
+            listing: &self.listing,
+            nodes: &self.nodes,
+            edges: &self.edges,
+            state_map: &self.state_map,
+            distances: RefCell::new(None)
+        };
+        match self.live_states.pop(&frontier) {
+            Some(index) => Some((self.state_ids[index], self.states[index].clone())),
             None => None
         }
     }
 
-    pub fn extend_with_state(&mut self, inst_offset: usize, new_inst_offset: usize, state: S) -> Result<(), String> {
-        let node_index = self.get_node_at(inst_offset)
-            .expect("No node at instruction offset");
+    // Returns the ids of the live states and the states themselves, the
+    // most recently added first.
+    pub fn live_states(&self) -> Vec<(usize, &S)> {
+        self.live_states.state_indices().iter().rev()
+            .map(|&index| (self.state_ids[index], &self.states[index]))
+            .collect()
+    }
+
+    fn push_state_id(&mut self) {
+        self.state_ids.push(self.next_state_id);
+        self.next_state_id += 1;
+    }
+
+    // This function adds a state at a loop header. If the header has
+    // had at least "widen_after" states added at it, the state is widened
+    // with one already there, so that simulating the loop over and over
+    // eventually adds nothing new. A narrowed state from a previous
+    // exploration that contains the state is used instead, if any.
+    pub fn extend_with_widened_state(&mut self, inst_offset: usize, new_inst_offset: usize, mut state: S, widen_after: usize) -> Result<(), DecompilerError> {
+        let invariant = match self.invariants.get(&new_inst_offset) {
+            None => None,
+            Some(invariants) => invariants.iter()
+                .find(|&invariant| contains(invariant, &state))
+                .cloned()
+        };
+
+        self.record_arrival(new_inst_offset, &state);
+
+        if let Some(invariant) = invariant {
+            self.widened.insert(new_inst_offset);
+            state = invariant;
+        } else if self.visits_at(new_inst_offset) >= widen_after {
+            let widened_state = match self.get_node_at(new_inst_offset) {
+                None => None,
+                Some(node_index) => self.widened_state_at(node_index, &state)
+            };
+
+            if let Some(widened_state) = widened_state {
+                self.widened.insert(new_inst_offset);
+                state = widened_state;
+            }
+        }
+
+        self.extend_with_state(inst_offset, new_inst_offset, state)
+    }
+
+    // Like extend_with_widened_state, but for a node that's already in
+    // the graph, e.g. in a slice, where every edge is known up front.
+    pub fn add_widened_state(&mut self, mut state: S, node_index: usize, widen_after: usize) {
+        let offset = self.nodes[node_index].insts[0];
+
+        if self.visits_at(offset) >= widen_after {
+            if let Some(widened_state) = self.widened_state_at(node_index, &state) {
+                self.widened.insert(offset);
+                state = widened_state;
+            }
+        }
+
+        self.add_state(state, node_index);
+    }
+
+    // Widens state with the first state at the node that doesn't already
+    // contain it, if any.
+    fn widened_state_at(&self, node_index: usize, state: &S) -> Option<S> {
+        self.get_states_at_node(node_index).iter()
+            .filter(|&&state_index| !contains(&self.states[state_index], state))
+            .filter_map(|&state_index| self.states[state_index].widen(state))
+            .next()
+    }
+
+    pub fn extend_with_state(&mut self, inst_offset: usize, new_inst_offset: usize, state: S) -> Result<(), DecompilerError> {
+        let node_index = match self.get_node_at(inst_offset) {
+            None => return Err(DecompilerError::graph_at_offset(inst_offset,
+                String::from("No node at instruction offset"))),
+            Some(node_index) => node_index
+        };
         match self.get_node_at(new_inst_offset) {
             None => {
                 let new_node_index = self.add_node_with_state_at(state, new_inst_offset);
@@ -278,6 +418,7 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
     }
 
     pub fn add_state(&mut self, mut state: S, node_index: usize) {
+        let offset = self.nodes[node_index].insts[0];
         let mut state_indices = self.get_states_at_node(node_index);
 
         let mut index = 0;
@@ -311,6 +452,65 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
         self.nodes[node_index].add_state(state_index);
         self.live_states.push(state_index);
         self.states.push(state);
+        self.push_state_id();
+        *self.visits.entry(offset).or_insert(0) += 1;
+    }
+
+    pub fn visits_at(&self, offset: usize) -> usize {
+        match self.visits.get(&offset) {
+            None => 0,
+            Some(visits) => *visits
+        }
+    }
+
+    // Keeps the union of the states reaching the loop header at offset,
+    // one for each set of states that can be joined, if arrivals are being
+    // recorded. States are recorded before they're widened, so the unions
+    // only hold what simulating the program actually produced.
+    fn record_arrival(&mut self, offset: usize, state: &S) {
+        if let Some(ref mut arrivals) = self.arrivals {
+            let states = arrivals.entry(offset).or_insert_with(Vec::new);
+            match states.iter().position(|arrival| arrival.widen(state).is_some()) {
+                None => states.push(state.clone()),
+                Some(index) => {
+                    let arrival = states.swap_remove(index);
+                    states.push(arrival.union(state.clone()));
+                }
+            }
+        }
+    }
+
+    // Returns, for each offset where states were widened, the first state
+    // there containing each recorded union, narrowed with that union, for
+    // the next exploration to start from.
+    pub fn narrowed_states(&self) -> HashMap<usize, Vec<S>> {
+        let mut invariants = HashMap::new();
+        let arrivals = match self.arrivals {
+            None => return invariants,
+            Some(ref arrivals) => arrivals
+        };
+
+        for offset in self.widened.iter() {
+            let node_index = match self.get_node_at(*offset) {
+                None => continue,
+                Some(node_index) => node_index
+            };
+            let reaching = match arrivals.get(offset) {
+                None => continue,
+                Some(reaching) => reaching
+            };
+
+            let state_indices = self.get_states_at_node(node_index);
+            let narrowed = reaching.iter()
+                .filter_map(|arrival| state_indices.iter()
+                    .map(|&state_index| &self.states[state_index])
+                    .find(|&state| contains(state, arrival))
+                    .and_then(|state| state.narrow(arrival)))
+                .collect();
+            invariants.insert(*offset, narrowed);
+        }
+
+        invariants
     }
 
     fn remove_state(&mut self, state_index: usize) {
@@ -325,7 +525,8 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
         if state_index == final_index {
             self.state_map.remove(&final_index);
             self.states.remove(final_index);
-            self.live_states.retain(|&x| x != final_index);
+            self.state_ids.remove(final_index);
+            self.live_states.remove(final_index);
             return;
         }
 
@@ -341,40 +542,112 @@ impl<I: InstructionTrait, S: StateTrait<S>> StateFlowGraph<I, S> {
 
         self.state_map.remove(&final_index);
         self.states.swap_remove(state_index);
+        self.state_ids.swap_remove(state_index);
 
-        if self.live_states.contains(&final_index) {
-            self.live_states.retain(|&x| x != final_index);
-            if !self.live_states.contains(&state_index) {
+        if self.live_states.remove(final_index) {
+            if !self.live_states.contains(state_index) {
                 self.live_states.push(state_index);
             }
         } else {
-            self.live_states.retain(|&x| x != state_index);
+            self.live_states.remove(state_index);
         }
     }
 
+    pub fn state_count(&self) -> usize {
+        self.states.len()
+    }
+
     pub fn log_state_count(&self) {
         println!("Total states: {}\t Total live states: {}\t ",
             self.states.len(), self.live_states.len() + 1);
     }
 }
 
+// Tests whether state1 contains state2. Unlike StateTrait::is_subset,
+// this takes everything in the states into account, memory included.
+fn contains<S: StateTrait<S>>(state1: &S, state2: &S) -> bool {
+    match state2.combine(state1) {
+        CombineResult::Subset => true,
+        _ => false
+    }
+}
+
+// The graph as its worklist sees it. A node is unexplored if its first
+// instruction hasn't been simulated, so isn't in the listing yet, and
+// the distances from every node to the nearest unexplored one are found
+// at most once per choice, by searching back from the unexplored nodes.
+struct GraphFrontier<'a, I: 'a + InstructionTrait> {
+    listing: &'a Listing<I>,
+    nodes: &'a Vec<Node>,
+    edges: &'a Vec<Edge>,
+    state_map: &'a HashMap<usize, usize>,
+    distances: RefCell<Option<Vec<usize>>>
+}
+
+impl<'a, I: InstructionTrait> GraphFrontier<'a, I> {
+    fn find_distances(&self) -> Vec<usize> {
+        let mut distances = vec![usize::MAX; self.nodes.len()];
+        let mut queue = VecDeque::new();
+        for (node_index, node) in self.nodes.iter().enumerate() {
+            if node.deleted {
+                continue;
+            }
+            if let Some(&offset) = node.insts.first() {
+                if self.listing.get(offset).is_none() {
+                    distances[node_index] = 0;
+                    queue.push_back(node_index);
+                }
+            }
+        }
+
+        while let Some(node_index) = queue.pop_front() {
+            for edge_index in self.nodes[node_index].inbound_edges.iter() {
+                let previous = self.edges[*edge_index].get_from();
+                if distances[previous] == usize::MAX {
+                    distances[previous] = distances[node_index] + 1;
+                    queue.push_back(previous);
+                }
+            }
+        }
+
+        distances
+    }
+}
+
+impl<'a, I: InstructionTrait> Frontier for GraphFrontier<'a, I> {
+    fn offset_of(&self, state_index: usize) -> usize {
+        self.nodes[self.state_map[&state_index]].insts[0]
+    }
+
+    fn distance_to_unexplored(&self, state_index: usize) -> usize {
+        let mut distances = self.distances.borrow_mut();
+        if distances.is_none() {
+            *distances = Some(self.find_distances());
+        }
+        match *distances {
+            Some(ref distances) => distances[self.state_map[&state_index]],
+            None => panic!("shouldn't be here")
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Node {
     pub deleted: bool,
     pub insts: Vec<usize>,
-    pub states: HashSet<usize>,
-    pub inbound_edges: HashSet<usize>,
-    pub outbound_edges: HashSet<usize>
+    pub states: BTreeSet<usize>,
+    pub inbound_edges: BTreeSet<usize>,
+    pub outbound_edges: BTreeSet<usize>
 }
 
 impl Node {
     fn new() -> Node {
         Node {
             deleted: false,
-            states: HashSet::new(),
+            states: BTreeSet::new(),
             insts: Vec::new(),
-            inbound_edges: HashSet::new(),
-            outbound_edges: HashSet::new()
+            inbound_edges: BTreeSet::new(),
+            outbound_edges: BTreeSet::new()
         }
     }
 
@@ -383,8 +656,8 @@ impl Node {
             deleted: false,
             states: [state_index].iter().cloned().collect(),
             insts: vec!(offset),
-            inbound_edges: HashSet::new(),
-            outbound_edges: HashSet::new()
+            inbound_edges: BTreeSet::new(),
+            outbound_edges: BTreeSet::new()
         }
     }
 
@@ -507,7 +780,7 @@ impl<I: InstructionTrait, S: StateTrait<S>> fmt::Debug for StateFlowGraph<I, S>
             }
         }
         output.push_str(format!("Total States: {}\n", self.states.len()).as_str());
-        output.push_str(format!("Live States: {:?}\n", self.live_states).as_str()); 
+        output.push_str(format!("Live States: {:?}\n", self.live_states.state_indices()).as_str()); 
 
         write!(f, "{}", output)
     }
@@ -515,14 +788,16 @@ impl<I: InstructionTrait, S: StateTrait<S>> fmt::Debug for StateFlowGraph<I, S>
 
 pub struct Slice<I: InstructionTrait, S: StateTrait<S>> {
     graph: StateFlowGraph<I, S>,
-    offsets: HashSet<usize>
+    offsets: HashSet<usize>,
+    code_offsets: HashMap<usize, usize>
 }
 
 impl<I: InstructionTrait, S: StateTrait<S>> Slice<I, S> {
     pub fn with_offsets(offsets: HashSet<usize>) -> Slice<I, S> {
         Slice {
             graph: StateFlowGraph::new(),
-            offsets
+            offsets,
+            code_offsets: HashMap::new()
         }
     }
 
@@ -530,6 +805,21 @@ impl<I: InstructionTrait, S: StateTrait<S>> Slice<I, S> {
         self.offsets.contains(offset)
     }
 
+    // Versions of rewritten instructions are listed apart from where
+    // they run, see Listing::code_offset.
+    pub fn set_code_offset(&mut self, offset: usize, code_offset: usize) {
+        if offset != code_offset {
+            self.code_offsets.insert(offset, code_offset);
+        }
+    }
+
+    pub fn code_offset(&self, offset: usize) -> usize {
+        match self.code_offsets.get(&offset) {
+            None => offset,
+            Some(code_offset) => *code_offset
+        }
+    }
+
     pub fn add_node_with_insts(&mut self, offsets: &[usize]) -> usize {
         self.graph.add_node_with_insts(offsets)
     }
@@ -546,7 +836,7 @@ impl<I: InstructionTrait, S: StateTrait<S>> Slice<I, S> {
         self.graph.get_next_nodes(node_index)
     }
 
-    pub fn remove_node(&mut self, node_index: usize) -> Result<(), String> {
+    pub fn remove_node(&mut self, node_index: usize) -> Result<(), DecompilerError> {
         self.graph.remove_node(node_index)
     }
 
@@ -554,7 +844,7 @@ impl<I: InstructionTrait, S: StateTrait<S>> Slice<I, S> {
         self.graph.get_instructions_at(node)
     }
 
-    pub fn add_edge(&mut self, source: usize, target: usize) -> Result<(), String> {
+    pub fn add_edge(&mut self, source: usize, target: usize) -> Result<(), DecompilerError> {
         self.graph.add_edge(source, target)
     }
 
@@ -562,11 +852,15 @@ impl<I: InstructionTrait, S: StateTrait<S>> Slice<I, S> {
         self.graph.get_inst(offset)
     }
 
-    pub fn initial_instruction(&self, node_index: usize) -> Result<usize, String> {
+    pub fn instruction_at(&self, offset: usize) -> Result<I, DecompilerError> {
+        self.graph.instruction_at(offset)
+    }
+
+    pub fn initial_instruction(&self, node_index: usize) -> Result<usize, DecompilerError> {
         self.graph.initial_instruction(node_index)
     }
 
-    pub fn final_instruction(&self, node_index: usize) -> Result<Option<&usize>, String> {
+    pub fn final_instruction(&self, node_index: usize) -> Result<Option<&usize>, DecompilerError> {
         self.graph.final_instruction(node_index)
     }
 
@@ -582,6 +876,14 @@ impl<I: InstructionTrait, S: StateTrait<S>> Slice<I, S> {
         self.graph.add_state(state, node_index);
     }
 
+    pub fn add_widened_state(&mut self, state: S, node_index: usize, widen_after: usize) {
+        self.graph.add_widened_state(state, node_index, widen_after);
+    }
+
+    pub fn state_count(&self) -> usize {
+        self.graph.state_count()
+    }
+
     pub fn log_state_count(&self) {
         self.graph.log_state_count();
     }
@@ -0,0 +1,489 @@
+use defs::main::*;
+use graph::flow::{FlowGraph, Function, CallGraph};
+use graph::dom::{self, Dominators};
+use graph::loops::{self, LoopForest};
+use std::fmt;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+// Control-flow structuring: turns the nodes of a function into a tree
+// of high-level statements.
+//
+// Block(node):
+// the instructions of node, except for a final conditional branch.
+//
+// If, While, DoWhile:
+// the condition is the branch at the end of condition.node. A While
+// loop's condition node is run, up to its branch, before every test.
+//
+// Loop:
+// an endless loop, left only by Break, Goto or Return.
+//
+// Switch(node, cases):
+// a branch to more than two targets (e.g. an indirect jump).
+//
+// Goto(node):
+// a jump that couldn't be structured. Its target is in the labels of
+// the structured function.
+//
+// Branch order follows FlowGraph: a conditional branch's first
+// successor is the one it falls through to, and its second is the one
+// it branches to when taken.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub node: usize,
+    pub negated: bool
+}
+
+impl Condition {
+    // The condition that holds when the branch at the end of node
+    // is taken.
+    pub fn taken(node: usize) -> Condition {
+        Condition {
+            node: node,
+            negated: false
+        }
+    }
+
+    pub fn negate(self) -> Condition {
+        Condition {
+            negated: !self.negated,
+            .. self
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negated {
+            write!(f, "!taken({})", self.node)
+        } else {
+            write!(f, "taken({})", self.node)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    Block(usize),
+    If {
+        condition: Condition,
+        then_branch: Vec<Statement>,
+        else_branch: Vec<Statement>
+    },
+    While {
+        condition: Condition,
+        body: Vec<Statement>
+    },
+    DoWhile {
+        body: Vec<Statement>,
+        condition: Condition
+    },
+    Loop(Vec<Statement>),
+    Switch(usize, Vec<(usize, Vec<Statement>)>),
+    Break,
+    Continue,
+    Goto(usize),
+    Return
+}
+
+pub struct StructuredFunction {
+    pub entry: usize,
+    pub body: Vec<Statement>,
+    pub labels: HashSet<usize>
+}
+
+impl StructuredFunction {
+    pub fn is_labelled(&self, node: usize) -> bool {
+        self.labels.contains(&node)
+    }
+}
+
+impl fmt::Display for StructuredFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut output = String::new();
+        write_statements(&mut output, self, &self.body, 0);
+        write!(f, "{}", output)
+    }
+}
+
+fn write_statements(output: &mut String, function: &StructuredFunction, statements: &[Statement], depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    for statement in statements {
+        match *statement {
+            Statement::Block(node) => {
+                if function.is_labelled(node) {
+                    output.push_str(format!("{}n{}:\n", indent, node).as_str());
+                }
+                output.push_str(format!("{}block {}\n", indent, node).as_str());
+            },
+            Statement::If { condition, ref then_branch, ref else_branch } => {
+                output.push_str(format!("{}if ({}) {{\n", indent, condition).as_str());
+                write_statements(output, function, then_branch, depth + 1);
+                if !else_branch.is_empty() {
+                    output.push_str(format!("{}}} else {{\n", indent).as_str());
+                    write_statements(output, function, else_branch, depth + 1);
+                }
+                output.push_str(format!("{}}}\n", indent).as_str());
+            },
+            Statement::While { condition, ref body } => {
+                output.push_str(format!("{}while ({}) {{\n", indent, condition).as_str());
+                write_statements(output, function, body, depth + 1);
+                output.push_str(format!("{}}}\n", indent).as_str());
+            },
+            Statement::DoWhile { ref body, condition } => {
+                output.push_str(format!("{}do {{\n", indent).as_str());
+                write_statements(output, function, body, depth + 1);
+                output.push_str(format!("{}}} while ({});\n", indent, condition).as_str());
+            },
+            Statement::Loop(ref body) => {
+                output.push_str(format!("{}loop {{\n", indent).as_str());
+                write_statements(output, function, body, depth + 1);
+                output.push_str(format!("{}}}\n", indent).as_str());
+            },
+            Statement::Switch(node, ref cases) => {
+                output.push_str(format!("{}switch ({}) {{\n", indent, node).as_str());
+                for &(target, ref body) in cases.iter() {
+                    output.push_str(format!("{}case {}:\n", indent, target).as_str());
+                    write_statements(output, function, body, depth + 1);
+                }
+                output.push_str(format!("{}}}\n", indent).as_str());
+            },
+            Statement::Break => output.push_str(format!("{}break;\n", indent).as_str()),
+            Statement::Continue => output.push_str(format!("{}continue;\n", indent).as_str()),
+            Statement::Goto(node) => output.push_str(format!("{}goto n{};\n", indent, node).as_str()),
+            Statement::Return => output.push_str(format!("{}return;\n", indent).as_str())
+        }
+    }
+}
+
+pub fn structure<I: InstructionTrait>(graph: &FlowGraph<I>, function: &Function,
+    dominators: &Dominators, loops: &LoopForest) -> Result<StructuredFunction, DecompilerError>
+{
+    let (successors, _) = graph.function_edges(function);
+    let mut ordered = HashMap::new();
+
+    for (node, node_successors) in successors.into_iter() {
+        ordered.insert(node, order_successors(graph, node, node_successors)?);
+    }
+
+    let mut structurer = Structurer {
+        successors: ordered,
+        exits: function.exits().iter().cloned().collect(),
+        dominators: dominators,
+        loops: loops,
+        visited: HashSet::new(),
+        contexts: Vec::new(),
+        labels: HashSet::new(),
+        pending: Vec::new()
+    };
+
+    let mut body = structurer.sequence(function.entry(), None);
+
+    while let Some(node) = structurer.pending.pop() {
+        if !structurer.visited.contains(&node) {
+            let mut residual = structurer.sequence(node, None);
+            body.append(&mut residual);
+        }
+    }
+
+    Ok(StructuredFunction {
+        entry: function.entry(),
+        body: body,
+        labels: structurer.labels
+    })
+}
+
+// Structures every function in call_graph, in the same order as
+// call_graph.functions().
+pub fn structure_functions<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph) -> Result<Vec<StructuredFunction>, DecompilerError> {
+    let dominators = dom::function_dominators(graph, call_graph);
+    let loops = loops::function_loops(graph, call_graph, &dominators);
+    let mut functions = Vec::new();
+
+    for (index, function) in call_graph.functions().iter().enumerate() {
+        functions.push(structure(graph, function, &dominators[index], &loops[index])?);
+    }
+
+    Ok(functions)
+}
+
+// Puts the successor that node falls through to first, if there are
+// two successors.
+fn order_successors<I: InstructionTrait>(graph: &FlowGraph<I>, node: usize, mut successors: Vec<usize>) -> Result<Vec<usize>, DecompilerError> {
+    if successors.len() != 2 {
+        return Ok(successors);
+    }
+
+    if let Some(final_offset) = graph.final_instruction(node)? {
+        // Meta-instructions don't fall through to anything in the file,
+        // and versions of rewritten code fall through from where they run.
+        if let Some(&Meta::Inst(inst)) = graph.get_inst(final_offset) {
+            let next_offset = graph.listing().code_offset(final_offset) + inst.length();
+            if graph.initial_instruction(successors[1])? == Some(next_offset) {
+                successors.swap(0, 1);
+            }
+        }
+    }
+
+    Ok(successors)
+}
+
+struct LoopContext {
+    header: usize,
+    follow: Option<usize>,
+    nodes: HashSet<usize>
+}
+
+struct Structurer<'a> {
+    successors: HashMap<usize, Vec<usize>>,
+    exits: HashSet<usize>,
+    dominators: &'a Dominators,
+    loops: &'a LoopForest,
+    visited: HashSet<usize>,
+    contexts: Vec<LoopContext>,
+    labels: HashSet<usize>,
+    pending: Vec<usize>
+}
+
+impl<'a> Structurer<'a> {
+    // Structures the nodes from entry onwards, until stop is reached
+    // or control leaves the sequence.
+    fn sequence(&mut self, entry: usize, stop: Option<usize>) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        let mut current = Some(entry);
+
+        while let Some(node) = current {
+            if Some(node) == stop {
+                break;
+            }
+
+            if let Some(jump) = self.loop_jump(node) {
+                statements.push(jump);
+                break;
+            }
+
+            if self.visited.contains(&node) {
+                statements.push(self.goto(node));
+                break;
+            }
+
+            if let Some(index) = self.loops.loop_with_header(node) {
+                if !self.loops.get(index).irreducible {
+                    let (statement, follow) = self.structure_loop(index);
+                    statements.push(statement);
+                    current = follow;
+                    continue;
+                }
+            }
+
+            current = self.node(node, stop, &mut statements);
+        }
+
+        statements
+    }
+
+    // Appends the statements for node, and returns the node that
+    // control continues to afterwards.
+    fn node(&mut self, node: usize, stop: Option<usize>, statements: &mut Vec<Statement>) -> Option<usize> {
+        self.visited.insert(node);
+        statements.push(Statement::Block(node));
+
+        if self.exits.contains(&node) {
+            statements.push(Statement::Return);
+            return None;
+        }
+
+        let successors = self.successors[&node].clone();
+
+        match successors.len() {
+            0 => None,
+            1 => Some(successors[0]),
+            2 => {
+                let follow = self.follow(node).or(stop);
+                let else_branch = self.sequence(successors[0], follow);
+                let then_branch = self.sequence(successors[1], follow);
+                let condition = Condition::taken(node);
+
+                if then_branch.is_empty() && !else_branch.is_empty() {
+                    statements.push(Statement::If {
+                        condition: condition.negate(),
+                        then_branch: else_branch,
+                        else_branch: Vec::new()
+                    });
+                } else if !then_branch.is_empty() {
+                    statements.push(Statement::If {
+                        condition: condition,
+                        then_branch: then_branch,
+                        else_branch: else_branch
+                    });
+                }
+
+                follow
+            },
+            _ => {
+                let follow = self.follow(node).or(stop);
+                let mut cases = Vec::new();
+
+                for target in successors {
+                    cases.push((target, self.sequence(target, follow)));
+                }
+
+                statements.push(Statement::Switch(node, cases));
+                follow
+            }
+        }
+    }
+
+    fn structure_loop(&mut self, index: usize) -> (Statement, Option<usize>) {
+        let (header, nodes, latches) = {
+            let l = self.loops.get(index);
+            (l.header, l.nodes.clone(), l.latches())
+        };
+
+        let header_successors = self.successors[&header].clone();
+        let inside: Vec<usize> = header_successors.iter().cloned()
+            .filter(|node| nodes.contains(node)).collect();
+        let outside: Vec<usize> = header_successors.iter().cloned()
+            .filter(|node| !nodes.contains(node)).collect();
+
+        if header_successors.len() == 2 && inside.len() == 1 && outside.len() == 1
+            && !self.exits.contains(&header)
+        {
+            let condition = if header_successors[1] == inside[0] {
+                Condition::taken(header)
+            } else {
+                Condition::taken(header).negate()
+            };
+
+            self.enter_loop(header, Some(outside[0]), nodes);
+            self.visited.insert(header);
+            let mut body = self.sequence(inside[0], None);
+            self.contexts.pop();
+            strip_continue(&mut body);
+
+            return (Statement::While {
+                condition: condition,
+                body: body
+            }, Some(outside[0]));
+        }
+
+        if latches.len() == 1 && latches[0] != header && !self.exits.contains(&latches[0]) {
+            let latch = latches[0];
+            let latch_successors = self.successors[&latch].clone();
+
+            if latch_successors.len() == 2 && latch_successors.contains(&header)
+                && latch_successors.iter().any(|node| !nodes.contains(node))
+            {
+                let follow = latch_successors.iter().cloned()
+                    .find(|node| *node != header).unwrap();
+                let condition = if latch_successors[1] == header {
+                    Condition::taken(latch)
+                } else {
+                    Condition::taken(latch).negate()
+                };
+
+                self.enter_loop(header, Some(follow), nodes);
+                let mut body = Vec::new();
+                let next = self.node(header, Some(latch), &mut body);
+                if let Some(next) = next {
+                    let mut rest = self.sequence(next, Some(latch));
+                    body.append(&mut rest);
+                }
+                if !self.visited.contains(&latch) {
+                    self.visited.insert(latch);
+                    body.push(Statement::Block(latch));
+                }
+                self.contexts.pop();
+
+                return (Statement::DoWhile {
+                    body: body,
+                    condition: condition
+                }, Some(follow));
+            }
+        }
+
+        let mut exits: Vec<usize> = self.loops.get(index).exits.iter()
+            .map(|&(_, target)| target)
+            .collect();
+        exits.sort();
+        exits.dedup();
+        let follow = exits.first().cloned();
+
+        self.enter_loop(header, follow, nodes);
+        let mut body = Vec::new();
+        if let Some(next) = self.node(header, None, &mut body) {
+            let mut rest = self.sequence(next, None);
+            body.append(&mut rest);
+        }
+        self.contexts.pop();
+        strip_continue(&mut body);
+
+        (Statement::Loop(body), follow)
+    }
+
+    fn enter_loop(&mut self, header: usize, follow: Option<usize>, nodes: HashSet<usize>) {
+        self.contexts.push(LoopContext {
+            header: header,
+            follow: follow,
+            nodes: nodes
+        });
+    }
+
+    // Returns the jump needed to reach node from inside the innermost
+    // loop, if node is its header, its follow, or outside it.
+    fn loop_jump(&mut self, node: usize) -> Option<Statement> {
+        let (header, follow, inside) = match self.contexts.last() {
+            None => return None,
+            Some(context) => (context.header, context.follow, context.nodes.contains(&node))
+        };
+
+        if node == header {
+            Some(Statement::Continue)
+        } else if Some(node) == follow {
+            Some(Statement::Break)
+        } else if !inside {
+            Some(self.goto(node))
+        } else {
+            None
+        }
+    }
+
+    fn goto(&mut self, node: usize) -> Statement {
+        self.labels.insert(node);
+        if !self.visited.contains(&node) {
+            self.pending.push(node);
+        }
+        Statement::Goto(node)
+    }
+
+    // Returns the node where the branches leaving node meet again: its
+    // immediate post-dominator, unless that's the virtual exit or lies
+    // outside the innermost loop.
+    fn follow(&self, node: usize) -> Option<usize> {
+        let follow = match self.dominators.post_dominators.immediate_dominator(node) {
+            None => return None,
+            Some(follow) => follow
+        };
+
+        if follow == self.dominators.virtual_exit() {
+            return None;
+        }
+
+        if let Some(context) = self.contexts.last() {
+            if !context.nodes.contains(&follow) {
+                return None;
+            }
+        }
+
+        Some(follow)
+    }
+}
+
+fn strip_continue(body: &mut Vec<Statement>) {
+    if let Some(&Statement::Continue) = body.last() {
+        body.pop();
+    }
+}
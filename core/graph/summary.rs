@@ -0,0 +1,638 @@
+use defs::main::*;
+use defs::ir::{Location, Mnemonic, Operand, Width};
+use defs::range::*;
+use graph::dataflow::{self, Analysis, Direction, Definition, Solution};
+use graph::flow::{FlowGraph, Function, CallGraph};
+use std::fmt;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+// Summaries of what each function consumes and produces, worked out
+// from the IR (see FlowGraph::lift) across the call graph.
+//
+// A call stands for its callee's summary: it reads the callee's
+// parameters and writes what the callee writes, hiding earlier values
+// only where the callee writes them on every path to a return. Each
+// step is repeated until nothing changes, so recursive functions settle
+// on a fixed point:
+//
+// 1. the variables each function may write, itself or through calls.
+// 2. those it certainly writes, narrowed down from every variable.
+// 3. its parameters, the variables live on entry, and its results, the
+// variables it may write that are live after some call to it. What's
+// live where a function returns is its results.
+// 4. the memory it may read and write. Addresses are followed through
+// the IR from the reaching definitions of the registers they're made
+// of, where those set constants or add constants and bytes, as CHIP-8
+// code does with I. An address that can't be bounded could be anywhere.
+//
+// Only registers and flags are variables: ports and memory aren't
+// passed in or out of functions.
+
+// The largest number of intervals a value is kept as before it's
+// widened to one interval spanning them all.
+const MAX_INTERVALS: usize = 8;
+
+// The values an IR operand can hold, as inclusive intervals.
+type Values = Vec<(u32, u32)>;
+
+// What a function consumes and produces, for the
+// function whose first instruction is at "entry". "parameters" are the
+// locations it may read before writing them, "results" those it may
+// write that a caller reads after it returns, and "writes" all those
+// it may write. Memory is summarized apart, by address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionSummary {
+    pub entry: usize,
+    pub parameters: Vec<Location>,
+    pub results: Vec<Location>,
+    pub writes: Vec<Location>,
+    pub memory_reads: MemoryAccess,
+    pub memory_writes: MemoryAccess
+}
+
+// The addresses a function reads or writes, or Anywhere if they can't
+// all be worked out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MemoryAccess {
+    Ranges(USize),
+    Anywhere
+}
+
+impl MemoryAccess {
+    pub fn new() -> MemoryAccess {
+        MemoryAccess::Ranges(USize::new())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            MemoryAccess::Ranges(ref ranges) => ranges.is_empty(),
+            MemoryAccess::Anywhere => false
+        }
+    }
+
+    pub fn insert_range(self, min: usize, max: usize) -> MemoryAccess {
+        match self {
+            MemoryAccess::Ranges(ranges) => MemoryAccess::Ranges(ranges.insert_range(min, max)),
+            MemoryAccess::Anywhere => MemoryAccess::Anywhere
+        }
+    }
+
+    pub fn combine(self, other: MemoryAccess) -> MemoryAccess {
+        match (self, other) {
+            (MemoryAccess::Ranges(ranges), MemoryAccess::Ranges(other)) => MemoryAccess::Ranges(ranges.combine(other)),
+            _ => MemoryAccess::Anywhere
+        }
+    }
+}
+
+impl fmt::Display for MemoryAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryAccess::Ranges(ref ranges) => write!(f, "{}", ranges.display()),
+            MemoryAccess::Anywhere => write!(f, "anywhere")
+        }
+    }
+}
+
+// Returns the summary of every function in call_graph, in the same
+// order as call_graph.functions().
+pub fn function_summaries<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph) -> Result<Vec<FunctionSummary>, DecompilerError> {
+    let functions = call_graph.functions();
+    let mut variables: Vec<Location> = dataflow::locations(graph).into_iter()
+        .filter(|&location| is_variable(location))
+        .collect();
+    variables.sort_by_key(location_key);
+
+    let may_write = fixed_point(functions.iter()
+        .map(|function| own_writes(graph, call_graph, function, &variables))
+        .collect(), call_graph, |writes, callee_writes| writes.union(callee_writes).cloned().collect());
+
+    let mut writes: Vec<HashSet<Location>> = functions.iter()
+        .map(|_| variables.iter().cloned().collect())
+        .collect();
+    loop {
+        let next: Vec<HashSet<Location>> = functions.iter()
+            .map(|function| certain_writes(graph, call_graph, function, &variables, &writes))
+            .collect();
+        if next == writes {
+            break;
+        }
+        writes = next;
+    }
+
+    let mut parameters: Vec<HashSet<Location>> = functions.iter().map(|_| HashSet::new()).collect();
+    let mut results: Vec<HashSet<Location>> = functions.iter().map(|_| HashSet::new()).collect();
+    loop {
+        let mut changed = false;
+
+        for (index, function) in functions.iter().enumerate() {
+            let solution = dataflow::solve(graph, function, &CallLiveness {
+                variables: &variables,
+                call_graph: call_graph,
+                parameters: &parameters,
+                writes: &writes,
+                results: results[index].clone()
+            });
+
+            if let Some(live) = solution.node_before(function.entry()) {
+                if *live != parameters[index] {
+                    parameters[index] = live.clone();
+                    changed = true;
+                }
+            }
+
+            for &node in function.nodes() {
+                let callee = match call_graph.get_entry(node) {
+                    Some(&callee) => callee,
+                    None => continue
+                };
+                let live = match graph.final_instruction(node)?.and_then(|offset| solution.after(offset)) {
+                    Some(live) => live,
+                    None => continue
+                };
+
+                for location in live.iter() {
+                    if may_write[callee].contains(location) {
+                        changed |= results[callee].insert(*location);
+                    }
+                }
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut own_memory = Vec::new();
+    for function in functions.iter() {
+        own_memory.push(memory(graph, call_graph, function, &may_write)?);
+    }
+    let memory = fixed_point(own_memory, call_graph, |&(ref reads, ref writes), &(ref callee_reads, ref callee_writes)|
+        (reads.clone().combine(callee_reads.clone()), writes.clone().combine(callee_writes.clone())));
+
+    let mut summaries = Vec::new();
+    for (index, function) in functions.iter().enumerate() {
+        // The graph's entry node holds no instructions of its own.
+        let entry = match graph.initial_instruction(function.entry())? {
+            Some(entry) => entry,
+            None => graph.listing().entry_offset
+        };
+
+        summaries.push(FunctionSummary {
+            entry: entry,
+            parameters: sorted(&parameters[index]),
+            results: sorted(&results[index]),
+            writes: sorted(&may_write[index]),
+            memory_reads: memory[index].0.clone(),
+            memory_writes: memory[index].1.clone()
+        });
+    }
+
+    Ok(summaries)
+}
+
+// Combines each function's fact with its callees' until none changes.
+// Going bottom up, only recursive functions change after the first
+// pass.
+fn fixed_point<T, C>(mut facts: Vec<T>, call_graph: &CallGraph, combine: C) -> Vec<T>
+    where T: Clone + PartialEq,
+          C: Fn(&T, &T) -> T
+{
+    let order = call_graph.bottom_up();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &index in order.iter() {
+            for &callee in call_graph.callees(index).iter() {
+                let combined = combine(&facts[index], &facts[callee]);
+                if combined != facts[index] {
+                    facts[index] = combined;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    facts
+}
+
+pub fn is_variable(location: Location) -> bool {
+    match location {
+        Location::Reg(_) | Location::Flag(_) => true,
+        _ => false
+    }
+}
+
+fn location_key(location: &Location) -> (usize, usize) {
+    match *location {
+        Location::Reg(index) => (0, index),
+        Location::Flag(index) => (1, index),
+        Location::Temp(index) => (2, index),
+        Location::Port(index) => (3, index),
+        Location::Memory => (4, 0)
+    }
+}
+
+fn sorted(locations: &HashSet<Location>) -> Vec<Location> {
+    let mut locations: Vec<Location> = locations.iter().cloned().collect();
+    locations.sort_by_key(location_key);
+    locations
+}
+
+// Returns the function called by the instruction at offset, if it's a
+// call to a function in call_graph.
+fn call_target<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph, offset: usize) -> Option<usize> {
+    let calls = graph.listing().get_ir(offset).map_or(false, |instructions|
+        instructions.iter().any(|instruction| instruction.mnemonic == Mnemonic::CALL));
+    if !calls {
+        return None;
+    }
+
+    graph.get_node_at(offset).and_then(|node| call_graph.get_entry(node).cloned())
+}
+
+// Returns the variables the instructions of function may write, not
+// counting its calls.
+fn own_writes<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph, function: &Function, variables: &[Location]) -> HashSet<Location> {
+    let mut writes = HashSet::new();
+
+    for &node in function.nodes() {
+        for &offset in graph.get_instructions_at(node) {
+            if call_target(graph, call_graph, offset).is_some() {
+                continue;
+            }
+
+            let effects = dataflow::effects(graph, offset);
+            if effects.clobbers {
+                writes.extend(variables.iter().cloned());
+            }
+            writes.extend(effects.writes.iter().chain(effects.may_write.iter()).cloned()
+                .filter(|&location| is_variable(location)));
+        }
+    }
+
+    writes
+}
+
+// Returns the variables function writes on every path to a return, or
+// every variable if it never returns.
+fn certain_writes<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph, function: &Function, variables: &[Location], writes: &[HashSet<Location>]) -> HashSet<Location> {
+    let analysis = CertainWrites {
+        variables: variables,
+        call_graph: call_graph,
+        writes: writes
+    };
+    let solution = dataflow::solve(graph, function, &analysis);
+
+    function.exits().iter()
+        .filter_map(|&exit| solution.node_after(exit))
+        .fold(variables.iter().cloned().collect(), |certain: HashSet<Location>, written| certain.intersection(written).cloned().collect())
+}
+
+// Forward analysis of the variables written on every path to each
+// point.
+struct CertainWrites<'a> {
+    variables: &'a [Location],
+    call_graph: &'a CallGraph,
+    writes: &'a [HashSet<Location>]
+}
+
+impl<'a, I: InstructionTrait> Analysis<I> for CertainWrites<'a> {
+    type Fact = HashSet<Location>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self) -> HashSet<Location> {
+        HashSet::new()
+    }
+
+    fn top(&self) -> HashSet<Location> {
+        self.variables.iter().cloned().collect()
+    }
+
+    fn meet(&self, a: &HashSet<Location>, b: &HashSet<Location>) -> HashSet<Location> {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer(&self, graph: &FlowGraph<I>, offset: usize, fact: &HashSet<Location>) -> HashSet<Location> {
+        let mut fact = fact.clone();
+
+        match call_target(graph, self.call_graph, offset) {
+            Some(callee) => fact.extend(self.writes[callee].iter().cloned()),
+            None => fact.extend(dataflow::effects(graph, offset).writes.into_iter()
+                .filter(|&location| is_variable(location)))
+        }
+
+        fact
+    }
+}
+
+// Backward analysis of the variables that may be read before they're
+// next written, with calls going by their callees' summaries.
+struct CallLiveness<'a> {
+    variables: &'a [Location],
+    call_graph: &'a CallGraph,
+    parameters: &'a [HashSet<Location>],
+    writes: &'a [HashSet<Location>],
+    results: HashSet<Location>
+}
+
+impl<'a, I: InstructionTrait> Analysis<I> for CallLiveness<'a> {
+    type Fact = HashSet<Location>;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self) -> HashSet<Location> {
+        self.results.clone()
+    }
+
+    fn top(&self) -> HashSet<Location> {
+        HashSet::new()
+    }
+
+    fn meet(&self, a: &HashSet<Location>, b: &HashSet<Location>) -> HashSet<Location> {
+        a.union(b).cloned().collect()
+    }
+
+    fn transfer(&self, graph: &FlowGraph<I>, offset: usize, fact: &HashSet<Location>) -> HashSet<Location> {
+        if let Some(callee) = call_target(graph, self.call_graph, offset) {
+            let mut fact: HashSet<Location> = fact.difference(&self.writes[callee]).cloned().collect();
+            fact.extend(self.parameters[callee].iter().cloned());
+            return fact;
+        }
+
+        let effects = dataflow::effects(graph, offset);
+        if effects.clobbers {
+            return self.variables.iter().cloned().collect();
+        }
+
+        let mut fact: HashSet<Location> = fact.iter().cloned()
+            .filter(|location| !effects.writes.contains(location))
+            .collect();
+        fact.extend(effects.reads.into_iter().filter(|&location| is_variable(location)));
+        fact
+    }
+}
+
+// Returns the memory the instructions of function may read and write,
+// not counting its calls.
+fn memory<I: InstructionTrait>(graph: &FlowGraph<I>, call_graph: &CallGraph, function: &Function, may_write: &[HashSet<Location>]) -> Result<(MemoryAccess, MemoryAccess), DecompilerError> {
+    let reaching = dataflow::reaching_definitions(graph, function);
+    let mut addresses = Addresses {
+        graph: graph,
+        call_graph: call_graph,
+        reaching: &reaching,
+        may_write: may_write,
+        registers: HashMap::new()
+    };
+
+    let mut nodes = function.nodes().clone();
+    nodes.sort();
+
+    let (mut reads, mut writes) = (MemoryAccess::new(), MemoryAccess::new());
+    for node in nodes {
+        for &offset in graph.get_instructions_at(node) {
+            if call_target(graph, call_graph, offset).is_some() {
+                continue;
+            }
+
+            let accesses = addresses.evaluate(offset);
+            reads = reads.combine(accesses.reads);
+            writes = writes.combine(accesses.writes);
+        }
+    }
+
+    Ok((reads, writes))
+}
+
+// What the IR of one instruction leaves in the locations it writes,
+// and the memory it touches.
+struct Accesses {
+    values: HashMap<Location, Option<Values>>,
+    reads: MemoryAccess,
+    writes: MemoryAccess
+}
+
+// Follows addresses through the IR of a function, going by the
+// definitions reaching each instruction.
+struct Addresses<'a, I: InstructionTrait + 'a> {
+    graph: &'a FlowGraph<I>,
+    call_graph: &'a CallGraph,
+    reaching: &'a Solution<HashSet<Definition>>,
+    may_write: &'a [HashSet<Location>],
+    // The values of each register just before each instruction.
+    registers: HashMap<(usize, usize), Option<Values>>
+}
+
+impl<'a, I: InstructionTrait> Addresses<'a, I> {
+    // Returns the values register can hold just before the instruction
+    // at offset, if they can be bounded.
+    fn register(&mut self, offset: usize, register: usize) -> Option<Values> {
+        if let Some(values) = self.registers.get(&(offset, register)) {
+            return values.clone();
+        }
+
+        // A register that's defined in terms of itself round a loop
+        // can't be bounded.
+        self.registers.insert((offset, register), None);
+
+        let mut definitions: Vec<Option<usize>> = match self.reaching.before(offset) {
+            Some(reaching) => reaching.iter()
+                .filter(|definition| definition.location == Location::Reg(register))
+                .map(|definition| definition.offset)
+                .collect(),
+            None => Vec::new()
+        };
+        definitions.sort();
+
+        let mut values = Some(Vec::new());
+        for definition in definitions {
+            let defined = match definition {
+                None => None,
+                Some(definition) => match call_target(self.graph, self.call_graph, definition) {
+                    Some(callee) => if self.may_write[callee].contains(&Location::Reg(register)) {
+                        None
+                    } else {
+                        self.register(definition, register)
+                    },
+                    None => {
+                        let effects = dataflow::effects(self.graph, definition);
+                        if effects.clobbers || effects.may_write.contains(&Location::Reg(register)) {
+                            None
+                        } else {
+                            self.evaluate(definition).values.get(&Location::Reg(register))
+                                .cloned().unwrap_or(None)
+                        }
+                    }
+                }
+            };
+
+            values = match (values, defined) {
+                (Some(values), Some(defined)) => Some(join(values, defined)),
+                _ => None
+            };
+        }
+
+        if values == Some(Vec::new()) {
+            values = None;
+        }
+        self.registers.insert((offset, register), values.clone());
+        values
+    }
+
+    // Returns the values the operand of the instruction at offset can
+    // hold, given those of the locations written so far by its IR.
+    fn value(&mut self, offset: usize, values: &HashMap<Location, Option<Values>>, operand: Option<Operand>) -> Option<Values> {
+        let (known, width) = match operand {
+            Some(Operand::Const(value, _)) => return Some(vec!((value, value))),
+            Some(Operand::Reg(index, width)) => match values.get(&Location::Reg(index)) {
+                Some(known) => (known.clone(), width),
+                None => (self.register(offset, index), width)
+            },
+            Some(Operand::Temp(index, width)) =>
+                (values.get(&Location::Temp(index)).cloned().unwrap_or(None), width),
+            _ => return None
+        };
+
+        // Whatever's in a byte is bounded by its width.
+        known.or_else(|| if width.bits() <= 8 {
+            Some(vec!((0, width.mask())))
+        } else {
+            None
+        })
+    }
+
+    fn evaluate(&mut self, offset: usize) -> Accesses {
+        let mut accesses = Accesses {
+            values: HashMap::new(),
+            reads: MemoryAccess::new(),
+            writes: MemoryAccess::new()
+        };
+
+        let effects = dataflow::effects(self.graph, offset);
+        let instructions = match self.graph.listing().get_ir(offset) {
+            Some(instructions) => instructions,
+            None => {
+                if effects.clobbers {
+                    accesses.reads = MemoryAccess::Anywhere;
+                    accesses.writes = MemoryAccess::Anywhere;
+                }
+                return accesses;
+            }
+        };
+
+        // An instruction that loops may run any number of its accesses.
+        let loops = instructions.iter().any(|instruction|
+            [instruction.op1, instruction.op2, instruction.op3].iter().any(|operand| match *operand {
+                Some(Operand::Local(_)) => true,
+                _ => false
+            }));
+
+        for instruction in instructions.iter() {
+            if instruction.clobbers_all() {
+                accesses.reads = MemoryAccess::Anywhere;
+                accesses.writes = MemoryAccess::Anywhere;
+            }
+
+            let value = match instruction.mnemonic {
+                Mnemonic::LDM => {
+                    let address = if loops {
+                        None
+                    } else {
+                        self.value(offset, &accesses.values, instruction.op2)
+                    };
+                    accesses.reads = touch(accesses.reads, address, instruction.op1);
+                    None
+                },
+                Mnemonic::STM => {
+                    let address = if loops {
+                        None
+                    } else {
+                        self.value(offset, &accesses.values, instruction.op1)
+                    };
+                    accesses.writes = touch(accesses.writes, address, instruction.op2);
+                    continue;
+                },
+                Mnemonic::STR | Mnemonic::ZEXT => self.value(offset, &accesses.values, instruction.op2),
+                Mnemonic::ADD | Mnemonic::MUL => {
+                    let a = self.value(offset, &accesses.values, instruction.op2);
+                    let b = self.value(offset, &accesses.values, instruction.op3);
+                    match (a, b) {
+                        (Some(a), Some(b)) => Some(apply(&a, &b, instruction.mnemonic)),
+                        _ => None
+                    }
+                },
+                _ => None
+            };
+
+            // Values that overflow their location wrap round, and so
+            // aren't bounded.
+            let width = instruction.op1.and_then(|operand| operand.width());
+            let value = match (value, width) {
+                (Some(value), Some(width)) if value.iter().all(|&(_, max)| max <= width.mask()) => Some(value),
+                _ => None
+            };
+
+            for location in instruction.writes() {
+                accesses.values.insert(location, value.clone());
+            }
+        }
+
+        accesses
+    }
+}
+
+// Adds the bytes an access of operand's width at address touches.
+fn touch(memory: MemoryAccess, address: Option<Values>, operand: Option<Operand>) -> MemoryAccess {
+    let length = match operand.and_then(|operand| operand.width()) {
+        Some(Width::Word) => 2,
+        Some(Width::Long) => 4,
+        _ => 1
+    };
+
+    match address {
+        None => MemoryAccess::Anywhere,
+        Some(address) => address.into_iter().fold(memory, |memory, (min, max)|
+            memory.insert_range(min as usize, max as usize + length - 1))
+    }
+}
+
+fn apply(a: &Values, b: &Values, mnemonic: Mnemonic) -> Values {
+    let mut values = Vec::new();
+    for &(a_min, a_max) in a.iter() {
+        for &(b_min, b_max) in b.iter() {
+            values = join(values, vec!(match mnemonic {
+                Mnemonic::ADD => (a_min.saturating_add(b_min), a_max.saturating_add(b_max)),
+                _ => (a_min.saturating_mul(b_min), a_max.saturating_mul(b_max))
+            }));
+        }
+    }
+
+    values
+}
+
+// Adds the intervals of b to a, widening them to a single interval if
+// there get to be too many.
+fn join(mut a: Values, b: Values) -> Values {
+    for interval in b {
+        if !a.contains(&interval) {
+            a.push(interval);
+        }
+    }
+    a.sort();
+
+    if a.len() > MAX_INTERVALS {
+        let min = a.iter().map(|&(min, _)| min).min().unwrap();
+        let max = a.iter().map(|&(_, max)| max).max().unwrap();
+        a = vec!((min, max));
+    }
+
+    a
+}
@@ -0,0 +1,387 @@
+use std::collections::VecDeque;
+use std::usize;
+
+// Worklists hold the indices of the live states of a StateFlowGraph,
+// the states that haven't been simulated from yet, and decide which
+// one StateFlowGraph::next_live_state hands out next. The order doesn't
+// change what exhaustive simulation finds, only how soon it finds it
+// and, with widening, how many states it takes.
+//
+// Node indices change when nodes are split, so worklists don't keep
+// them; they ask the Frontier when choosing instead.
+
+pub trait Worklist : Send + Sync {
+    fn push(&mut self, state_index: usize);
+
+    // Removes and returns the state to explore next.
+    fn pop(&mut self, frontier: &dyn Frontier) -> Option<usize>;
+
+    // Returns true if state_index was live.
+    fn remove(&mut self, state_index: usize) -> bool;
+
+    fn contains(&self, state_index: usize) -> bool;
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn state_indices(&self) -> Vec<usize>;
+}
+
+// What a worklist can ask of the graph about its states.
+pub trait Frontier {
+    // The offset of the first instruction of the node holding state_index.
+    fn offset_of(&self, state_index: usize) -> usize;
+
+    // The fewest edges from the node holding state_index to a node
+    // whose code hasn't been simulated yet, or usize::MAX if there's
+    // no path to one.
+    fn distance_to_unexplored(&self, state_index: usize) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    DepthFirst,
+    BreadthFirst,
+    NearestUnexplored,
+    RoundRobin
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "dfs" => Some(Strategy::DepthFirst),
+            "bfs" => Some(Strategy::BreadthFirst),
+            "nearest" => Some(Strategy::NearestUnexplored),
+            "round-robin" => Some(Strategy::RoundRobin),
+            _ => None
+        }
+    }
+
+    pub fn worklist(&self) -> Box<dyn Worklist> {
+        match *self {
+            Strategy::DepthFirst => Box::new(DepthFirst::new()),
+            Strategy::BreadthFirst => Box::new(BreadthFirst::new()),
+            Strategy::NearestUnexplored => Box::new(NearestUnexplored::new()),
+            Strategy::RoundRobin => Box::new(RoundRobin::new())
+        }
+    }
+}
+
+// Explores the most recently added state first.
+#[derive(Default)]
+pub struct DepthFirst {
+    states: Vec<usize>
+}
+
+impl DepthFirst {
+    pub fn new() -> DepthFirst {
+        DepthFirst {
+            states: Vec::new()
+        }
+    }
+}
+
+impl Worklist for DepthFirst {
+    fn push(&mut self, state_index: usize) {
+        self.states.push(state_index);
+    }
+
+    fn pop(&mut self, _frontier: &dyn Frontier) -> Option<usize> {
+        self.states.pop()
+    }
+
+    fn remove(&mut self, state_index: usize) -> bool {
+        remove_from(&mut self.states, state_index)
+    }
+
+    fn contains(&self, state_index: usize) -> bool {
+        self.states.contains(&state_index)
+    }
+
+    fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    fn state_indices(&self) -> Vec<usize> {
+        self.states.clone()
+    }
+}
+
+// Explores the least recently added state first.
+#[derive(Default)]
+pub struct BreadthFirst {
+    states: VecDeque<usize>
+}
+
+impl BreadthFirst {
+    pub fn new() -> BreadthFirst {
+        BreadthFirst {
+            states: VecDeque::new()
+        }
+    }
+}
+
+impl Worklist for BreadthFirst {
+    fn push(&mut self, state_index: usize) {
+        self.states.push_back(state_index);
+    }
+
+    fn pop(&mut self, _frontier: &dyn Frontier) -> Option<usize> {
+        self.states.pop_front()
+    }
+
+    fn remove(&mut self, state_index: usize) -> bool {
+        match self.states.iter().position(|&index| index == state_index) {
+            None => false,
+            Some(position) => {
+                self.states.remove(position);
+                true
+            }
+        }
+    }
+
+    fn contains(&self, state_index: usize) -> bool {
+        self.states.contains(&state_index)
+    }
+
+    fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    fn state_indices(&self) -> Vec<usize> {
+        self.states.iter().cloned().collect()
+    }
+}
+
+// Explores the state closest to code that hasn't been simulated yet,
+// the most recently added one if several are equally close, so that
+// new code is found before known code is simulated again.
+#[derive(Default)]
+pub struct NearestUnexplored {
+    states: Vec<usize>
+}
+
+impl NearestUnexplored {
+    pub fn new() -> NearestUnexplored {
+        NearestUnexplored {
+            states: Vec::new()
+        }
+    }
+}
+
+impl Worklist for NearestUnexplored {
+    fn push(&mut self, state_index: usize) {
+        self.states.push(state_index);
+    }
+
+    fn pop(&mut self, frontier: &dyn Frontier) -> Option<usize> {
+        let mut nearest = None;
+        let mut nearest_distance = usize::MAX;
+        for (position, &state_index) in self.states.iter().enumerate().rev() {
+            let distance = frontier.distance_to_unexplored(state_index);
+            if nearest.is_none() || distance < nearest_distance {
+                nearest = Some(position);
+                nearest_distance = distance;
+            }
+            if distance == 0 {
+                break;
+            }
+        }
+
+        nearest.map(|position| self.states.remove(position))
+    }
+
+    fn remove(&mut self, state_index: usize) -> bool {
+        remove_from(&mut self.states, state_index)
+    }
+
+    fn contains(&self, state_index: usize) -> bool {
+        self.states.contains(&state_index)
+    }
+
+    fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    fn state_indices(&self) -> Vec<usize> {
+        self.states.clone()
+    }
+}
+
+// Takes turns between the nodes with live states, in order of offset,
+// exploring the most recently added state at each, so that no part of
+// the program waits on another part's states running out.
+#[derive(Default)]
+pub struct RoundRobin {
+    states: Vec<usize>,
+    last_offset: Option<usize>
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin {
+            states: Vec::new(),
+            last_offset: None
+        }
+    }
+}
+
+impl Worklist for RoundRobin {
+    fn push(&mut self, state_index: usize) {
+        self.states.push(state_index);
+    }
+
+    fn pop(&mut self, frontier: &dyn Frontier) -> Option<usize> {
+        let offsets: Vec<usize> = self.states.iter()
+            .map(|&state_index| frontier.offset_of(state_index))
+            .collect();
+
+        // The next offset after the last one taken, wrapping around.
+        let next_offset = match self.last_offset {
+            None => None,
+            Some(last_offset) => offsets.iter().cloned()
+                .filter(|&offset| offset > last_offset)
+                .min()
+        };
+        let next_offset = match next_offset.or(offsets.iter().cloned().min()) {
+            None => return None,
+            Some(offset) => offset
+        };
+
+        self.last_offset = Some(next_offset);
+        let position = offsets.iter().rposition(|&offset| offset == next_offset)
+            .expect("no state at chosen offset");
+        Some(self.states.remove(position))
+    }
+
+    fn remove(&mut self, state_index: usize) -> bool {
+        remove_from(&mut self.states, state_index)
+    }
+
+    fn contains(&self, state_index: usize) -> bool {
+        self.states.contains(&state_index)
+    }
+
+    fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    fn state_indices(&self) -> Vec<usize> {
+        self.states.clone()
+    }
+}
+
+fn remove_from(states: &mut Vec<usize>, state_index: usize) -> bool {
+    let length = states.len();
+    states.retain(|&index| index != state_index);
+    states.len() != length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // A graph whose states are at the given offsets and distances from
+    // unexplored code.
+    struct Graph {
+        offsets: HashMap<usize, usize>,
+        distances: HashMap<usize, usize>
+    }
+
+    impl Frontier for Graph {
+        fn offset_of(&self, state_index: usize) -> usize {
+            self.offsets[&state_index]
+        }
+
+        fn distance_to_unexplored(&self, state_index: usize) -> usize {
+            self.distances[&state_index]
+        }
+    }
+
+    fn graph(states: &[(usize, usize, usize)]) -> Graph {
+        Graph {
+            offsets: states.iter().map(|&(state, offset, _)| (state, offset)).collect(),
+            distances: states.iter().map(|&(state, _, distance)| (state, distance)).collect()
+        }
+    }
+
+    fn pop_all(worklist: &mut dyn Worklist, frontier: &dyn Frontier) -> Vec<usize> {
+        let mut popped = Vec::new();
+        while let Some(state_index) = worklist.pop(frontier) {
+            popped.push(state_index);
+        }
+        popped
+    }
+
+    #[test]
+    fn depth_first_explores_the_newest_state() {
+        let frontier = graph(&[]);
+        let mut worklist = DepthFirst::new();
+        for state_index in 1..5 {
+            worklist.push(state_index);
+        }
+
+        assert!(worklist.remove(2));
+        assert!(!worklist.remove(2));
+        assert!(worklist.contains(3) && !worklist.contains(2));
+        assert_eq!(worklist.len(), 3);
+        assert_eq!(pop_all(&mut worklist, &frontier), vec!(4, 3, 1));
+        assert!(worklist.is_empty());
+    }
+
+    #[test]
+    fn breadth_first_explores_the_oldest_state() {
+        let frontier = graph(&[]);
+        let mut worklist = BreadthFirst::new();
+        for state_index in 1..5 {
+            worklist.push(state_index);
+        }
+
+        assert!(worklist.remove(3));
+        assert!(!worklist.remove(5));
+        assert_eq!(worklist.state_indices(), vec!(1, 2, 4));
+        assert_eq!(pop_all(&mut worklist, &frontier), vec!(1, 2, 4));
+        assert!(worklist.is_empty());
+    }
+
+    #[test]
+    fn nearest_unexplored_explores_the_closest_state() {
+        let frontier = graph(&[(1, 0, 2), (2, 0, 0), (3, 0, 1), (4, 0, 0), (5, 0, usize::MAX)]);
+        let mut worklist = NearestUnexplored::new();
+        for state_index in 1..6 {
+            worklist.push(state_index);
+        }
+
+        assert_eq!(pop_all(&mut worklist, &frontier), vec!(4, 2, 3, 1, 5));
+    }
+
+    #[test]
+    fn round_robin_takes_turns_between_offsets() {
+        let frontier = graph(&[(1, 0x10, 0), (2, 0x20, 0), (3, 0x10, 0), (4, 0x30, 0), (5, 0x20, 0)]);
+        let mut worklist = RoundRobin::new();
+        for state_index in 1..6 {
+            worklist.push(state_index);
+        }
+
+        assert_eq!(worklist.pop(&frontier), Some(3));
+        assert_eq!(worklist.pop(&frontier), Some(5));
+
+        // A state added behind the last offset waits for its turn.
+        worklist.push(6);
+        let frontier = graph(&[(1, 0x10, 0), (2, 0x20, 0), (4, 0x30, 0), (6, 0x18, 0)]);
+        assert_eq!(pop_all(&mut worklist, &frontier), vec!(4, 1, 6, 2));
+    }
+
+    #[test]
+    fn strategies_by_name() {
+        assert_eq!(Strategy::from_name("dfs"), Some(Strategy::DepthFirst));
+        assert_eq!(Strategy::from_name("bfs"), Some(Strategy::BreadthFirst));
+        assert_eq!(Strategy::from_name("nearest"), Some(Strategy::NearestUnexplored));
+        assert_eq!(Strategy::from_name("round-robin"), Some(Strategy::RoundRobin));
+        assert_eq!(Strategy::from_name("random"), None);
+        assert!(Strategy::RoundRobin.worklist().is_empty());
+    }
+}
@@ -0,0 +1,313 @@
+use defs::main::*;
+use graph::flow::{FlowGraph, CallGraph, EdgeValue};
+use std::fmt;
+
+// JSON renderings of analysis results, for scripts to read instead of
+// the listings and graphs printed for people. Everything comes out in
+// a fixed order (instructions and edges by offset and node, functions
+// as the call graph numbers them, object keys as written here), so the
+// same analysis always gives the same text.
+//
+// The whole document, as written by analysis:
+//
+// {
+//   "version": 1,
+//   "entry": <offset>,
+//   "listing": {
+//     "instructions": [{ "offset", "bytes", "mnemonic", "operands",
+//                        "label", "indeterminate" }],
+//     "labels": [<offset>],
+//     "indeterminates": [<offset>],
+//     "invalid": [{ "offset", "error" }],
+//     "versions": [{ "offset", "listed_at", "flag", "bytes", "writers" }],
+//     "overlaps": [[<offset>, <offset>]]
+//   },
+//   "blocks": [{ "node", "offsets" }],
+//   "edges": [{ "from", "to", "kind" }],
+//   "functions": [{ "index", "entry", "nodes", "exits" }],
+//   "errors": [{ "offset", "message" }]
+// }
+//
+// Offsets and node indices are numbers, bytes are a string of hex
+// digits, and an edge's kind is "regular", "call", "call-successor" or
+// "return". If the analysis failed, everything but "version" and
+// "errors" is left out. Meta-instructions have a "meta" string in place
+// of bytes, mnemonic and operands.
+//
+// Instructions that other code rewrites have a version for each set of
+// bytes they can hold, the one in the file first. The others are listed
+// past the end of the file, at "listed_at", and have a "version_of"
+// with the offset they run at.
+//
+// Each pair in "overlaps" is two instructions decoded from some of the
+// same bytes, the lower offset first.
+
+pub const VERSION: usize = 1;
+
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(usize),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>)
+}
+
+impl Json {
+    fn string(text: &str) -> Json {
+        Json::String(text.to_string())
+    }
+
+    fn numbers(numbers: &[usize]) -> Json {
+        Json::Array(numbers.iter().map(|&number| Json::Number(number)).collect())
+    }
+
+    fn write(&self, output: &mut String, indent: usize) {
+        match *self {
+            Json::Null => output.push_str("null"),
+            Json::Bool(value) => output.push_str(if value { "true" } else { "false" }),
+            Json::Number(number) => output.push_str(format!("{}", number).as_str()),
+            Json::String(ref text) => write_string(output, text),
+            Json::Array(ref values) => {
+                // Arrays of plain values stay on one line.
+                let flat = values.iter().all(|value| match *value {
+                    Json::Array(_) | Json::Object(_) => false,
+                    _ => true
+                });
+                if values.is_empty() || flat {
+                    output.push('[');
+                    for (index, value) in values.iter().enumerate() {
+                        if index > 0 {
+                            output.push_str(", ");
+                        }
+                        value.write(output, indent);
+                    }
+                    output.push(']');
+                } else {
+                    output.push_str("[\n");
+                    for (index, value) in values.iter().enumerate() {
+                        push_indent(output, indent + 1);
+                        value.write(output, indent + 1);
+                        if index + 1 < values.len() {
+                            output.push(',');
+                        }
+                        output.push('\n');
+                    }
+                    push_indent(output, indent);
+                    output.push(']');
+                }
+            },
+            Json::Object(ref members) => {
+                if members.is_empty() {
+                    output.push_str("{}");
+                    return;
+                }
+                output.push_str("{\n");
+                for (index, &(key, ref value)) in members.iter().enumerate() {
+                    push_indent(output, indent + 1);
+                    write_string(output, key);
+                    output.push_str(": ");
+                    value.write(output, indent + 1);
+                    if index + 1 < members.len() {
+                        output.push(',');
+                    }
+                    output.push('\n');
+                }
+                push_indent(output, indent);
+                output.push('}');
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut output = String::new();
+        self.write(&mut output, 0);
+        write!(f, "{}", output)
+    }
+}
+
+fn push_indent(output: &mut String, indent: usize) {
+    for _ in 0..indent {
+        output.push_str("  ");
+    }
+}
+
+fn write_string(output: &mut String, text: &str) {
+    output.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => output.push(c)
+        }
+    }
+    output.push('"');
+}
+
+// The result of analysing file_buffer, e.g. with analyse::analyse.
+// Errors building the call graph are reported under "errors" too,
+// leaving "functions" empty.
+pub fn analysis<I: InstructionTrait>(file_buffer: &[u8], result: &Result<FlowGraph<I>, DecompilerError>) -> Json {
+    let graph = match *result {
+        Err(ref error) => return Json::Object(vec!(
+            ("version", Json::Number(VERSION)),
+            ("errors", Json::Array(vec!(error_json(error))))
+        )),
+        Ok(ref graph) => graph
+    };
+
+    let mut errors = Vec::new();
+    let functions = match graph.call_graph() {
+        Ok(call_graph) => functions(&call_graph),
+        Err(error) => {
+            errors.push(error_json(&error));
+            Json::Array(Vec::new())
+        }
+    };
+
+    Json::Object(vec!(
+        ("version", Json::Number(VERSION)),
+        ("entry", Json::Number(graph.listing().entry_offset)),
+        ("listing", listing(file_buffer, graph.listing())),
+        ("blocks", blocks(graph)),
+        ("edges", edges(graph)),
+        ("functions", functions),
+        ("errors", Json::Array(errors))
+    ))
+}
+
+pub fn listing<I: InstructionTrait>(file_buffer: &[u8], listing: &Listing<I>) -> Json {
+    let instructions = listing.offsets().iter()
+        .map(|&offset| instruction(file_buffer, listing, offset))
+        .collect();
+    let invalid = listing.invalid_offsets().iter()
+        .map(|&offset| Json::Object(vec!(
+            ("offset", Json::Number(offset)),
+            ("error", Json::String(format!("{}", listing.get_invalid(offset).unwrap())))
+        )))
+        .collect();
+
+    let versions = listing.versioned_offsets().iter()
+        .flat_map(|&offset| listing.versions(offset).iter())
+        .map(|version| Json::Object(vec!(
+            ("offset", Json::Number(version.offset)),
+            ("listed_at", Json::Number(version.listed_at)),
+            ("flag", Json::Number(version.flag)),
+            ("bytes", Json::String(hex(&version.bytes))),
+            ("writers", Json::numbers(&version.writers))
+        )))
+        .collect();
+
+    let overlaps = listing.overlaps().iter()
+        .map(|&(first, second)| Json::numbers(&[first, second]))
+        .collect();
+
+    Json::Object(vec!(
+        ("instructions", Json::Array(instructions)),
+        ("labels", Json::numbers(&listing.labels())),
+        ("indeterminates", Json::numbers(&listing.indeterminates())),
+        ("invalid", Json::Array(invalid)),
+        ("versions", Json::Array(versions)),
+        ("overlaps", Json::Array(overlaps))
+    ))
+}
+
+fn instruction<I: InstructionTrait>(file_buffer: &[u8], listing: &Listing<I>, offset: usize) -> Json {
+    let mut members = vec!(("offset", Json::Number(offset)));
+
+    match *listing.get(offset).unwrap() {
+        Meta::Inst(instruction) => {
+            let version = listing.version_at(offset)
+                .filter(|version| version.listed_at != version.offset);
+            let bytes = match version {
+                Some(version) => hex(&version.bytes),
+                None => {
+                    let end = (offset + instruction.length()).min(file_buffer.len());
+                    hex(&file_buffer[offset.min(end)..end])
+                }
+            };
+            let operands = instruction.operands().iter()
+                .map(|operand| Json::string(operand))
+                .collect();
+            members.push(("bytes", Json::String(bytes)));
+            members.push(("mnemonic", Json::String(instruction.mnemonic())));
+            members.push(("operands", Json::Array(operands)));
+            if let Some(version) = version {
+                members.push(("version_of", Json::Number(version.offset)));
+            }
+        },
+        ref meta => members.push(("meta", Json::String(format!("{}", meta))))
+    }
+
+    members.push(("label", Json::Bool(listing.is_labelled(offset))));
+    members.push(("indeterminate", Json::Bool(listing.is_indeterminate(offset))));
+    Json::Object(members)
+}
+
+// Every node but node 0, which only leads to the entry node.
+pub fn blocks<I: InstructionTrait>(graph: &FlowGraph<I>) -> Json {
+    Json::Array((1..graph.node_count())
+        .map(|node_index| Json::Object(vec!(
+            ("node", Json::Number(node_index)),
+            ("offsets", Json::numbers(graph.get_instructions_at(node_index)))
+        )))
+        .collect())
+}
+
+pub fn edges<I: InstructionTrait>(graph: &FlowGraph<I>) -> Json {
+    let mut edges: Vec<(usize, usize, EdgeValue)> = (0..graph.node_count())
+        .flat_map(|node_index| graph.get_inbound_edges(node_index))
+        .map(|edge| (edge.get_from(), edge.get_to(), edge.value()))
+        .collect();
+    edges.sort_by_key(|&(from, to, _)| (from, to));
+
+    Json::Array(edges.into_iter()
+        .map(|(from, to, value)| Json::Object(vec!(
+            ("from", Json::Number(from)),
+            ("to", Json::Number(to)),
+            ("kind", Json::string(edge_kind(value)))
+        )))
+        .collect())
+}
+
+// The first function's entry is node 0, the others' the nodes they're
+// called at. Nodes are listed in the order they were found in.
+pub fn functions(call_graph: &CallGraph) -> Json {
+    Json::Array(call_graph.functions().iter().enumerate()
+        .map(|(index, function)| Json::Object(vec!(
+            ("index", Json::Number(index)),
+            ("entry", Json::Number(function.entry())),
+            ("nodes", Json::numbers(function.nodes())),
+            ("exits", Json::numbers(function.exits()))
+        )))
+        .collect())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn edge_kind(value: EdgeValue) -> &'static str {
+    match value {
+        EdgeValue::Regular => "regular",
+        EdgeValue::Call => "call",
+        EdgeValue::CallSuccessor => "call-successor",
+        EdgeValue::Return => "return"
+    }
+}
+
+fn error_json(error: &DecompilerError) -> Json {
+    Json::Object(vec!(
+        ("offset", match error.offset() {
+            None => Json::Null,
+            Some(offset) => Json::Number(offset)
+        }),
+        ("message", Json::String(format!("{}", error)))
+    ))
+}
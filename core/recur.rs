@@ -1,4 +1,47 @@
 use defs::main::*;
+use std::collections::HashSet;
+
+// Disassembly modes:
+//
+// Recursive:
+// follow successors from the entry offset only.
+//
+// Linear:
+// decode every instruction from the entry offset to the end of the
+// file, one after the other, marking undecodable bytes as data.
+//
+// Hybrid:
+// recursive descent first, then sweep the gaps it leaves, keeping
+// only the runs of instructions that look like plausible code and
+// marking everything else as data.
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mode {
+    Recursive,
+    Linear,
+    Hybrid
+}
+
+// Scoring weights for candidate runs in hybrid mode. A run is kept if
+// it has at least MIN_RUN_LENGTH instructions and its score is at
+// least half its instruction count.
+const MIN_RUN_LENGTH: usize = 2;
+const KNOWN_TARGET_BONUS: isize = 2;
+const FLOW_END_BONUS: isize = 1;
+const OUTSIDE_TARGET_PENALTY: isize = 4;
+const MISALIGNED_TARGET_PENALTY: isize = 2;
+const DECODE_ERROR_PENALTY: isize = 2;
+
+pub fn disassemble<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_offset: usize, mode: Mode) -> Listing<I>
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    match mode {
+        Mode::Recursive => recursive_descent(file_buffer, architecture, entry_offset),
+        Mode::Linear => linear_sweep(file_buffer, architecture, entry_offset),
+        Mode::Hybrid => hybrid(file_buffer, architecture, entry_offset)
+    }
+}
 
 pub fn recursive_descent<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_offset: usize) -> Listing<I>
     where I: InstructionTrait,
@@ -11,15 +54,25 @@ pub fn recursive_descent<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_off
 
     while let Some(offset) = unexplored.pop() {
         if let None = listing.get(offset) {
+            if listing.is_invalid(offset) {
+                continue;
+            }
+
             let inst = match architecture.decode_instruction(file_buffer, offset) {
                 Ok(instruction) => instruction,
                 Err(err) => {
-                    println!("{}", err);
-                    return listing;
+                    listing.add_invalid(offset, err);
+                    continue;
                 }
             };
 
-            let (targets, calls, branch, indeterminate) = inst.successors(offset);
+            let (targets, calls, branch, indeterminate) = match inst.successors(offset) {
+                Ok(successors) => successors,
+                Err(err) => {
+                    listing.add_invalid(offset, err);
+                    continue;
+                }
+            };
 
             let offsets = [targets, calls].concat();
 
@@ -47,3 +100,318 @@ pub fn recursive_descent<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_off
 
     listing
 }
+
+pub fn linear_sweep<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_offset: usize) -> Listing<I>
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    let mut listing = Listing::with_entry(entry_offset);
+    let mut offset = entry_offset;
+
+    while offset < file_buffer.len() {
+        match architecture.decode_instruction(file_buffer, offset) {
+            Ok(inst) => {
+                if let Ok((targets, calls, branch, indeterminate)) = inst.successors(offset) {
+                    if branch {
+                        for target in [targets, calls].concat() {
+                            listing.add_label(target);
+                        }
+                    }
+
+                    if indeterminate {
+                        listing.add_indeterminate(offset);
+                    }
+                }
+
+                listing.add(offset, inst);
+                offset += inst.length();
+            },
+            Err(_) => {
+                listing.add_data(offset, file_buffer[offset]);
+                offset += 1;
+            }
+        }
+    }
+
+    listing
+}
+
+// Describes the offsets in listing that couldn't be disassembled, for
+// frontends to print with it, or returns an empty string if there are
+// none.
+pub fn describe_invalid<I: InstructionTrait>(listing: &Listing<I>) -> String {
+    let invalid_offsets = listing.invalid_offsets();
+    if invalid_offsets.is_empty() {
+        return String::new();
+    }
+
+    let mut output = format!("{} offset(s) couldn't be disassembled:\n", invalid_offsets.len());
+    for offset in invalid_offsets {
+        if let Some(error) = listing.get_invalid(offset) {
+            output.push_str(&format!("  {}\n", error));
+        }
+    }
+    output
+}
+
+// Describes the pairs of instructions in listing that overlap, or
+// returns an empty string if there are none. Code that jumps into the
+// middle of other code decodes the same bytes as more than one
+// instruction.
+pub fn describe_overlaps<I: InstructionTrait>(listing: &Listing<I>) -> String {
+    let overlaps = listing.overlaps();
+    if overlaps.is_empty() {
+        return String::new();
+    }
+
+    let mut output = format!("{} pair(s) of instructions overlap:\n", overlaps.len());
+    for (first, second) in overlaps {
+        output.push_str(&format!("  0x{:x} and 0x{:x}\n", first, second));
+    }
+    output
+}
+
+pub fn hybrid<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_offset: usize) -> Listing<I>
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    let listing = recursive_descent(file_buffer, architecture, entry_offset);
+    sweep(file_buffer, architecture, entry_offset, listing)
+}
+
+// Returns where the runs of code start that hybrid disassembly finds and
+// recursive descent from the entry doesn't, for analysis to start from
+// as well. Runs whose code leads to something that doesn't decode, or
+// out of the file, are left out.
+pub fn unreached_entries<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_offset: usize) -> Vec<usize>
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    let reached = recursive_descent(file_buffer, architecture, entry_offset);
+    let swept = sweep(file_buffer, architecture, entry_offset, recursive_descent(file_buffer, architecture, entry_offset));
+
+    let mut offsets: Vec<usize> = swept.instructions.keys()
+        .filter(|offset| reached.get(**offset).is_none())
+        .cloned()
+        .collect();
+    offsets.sort();
+
+    let mut entries = Vec::new();
+    let mut falls_to = None;
+
+    for offset in offsets {
+        let inst = match swept.get(offset) {
+            Some(&Meta::Inst(inst)) => inst,
+            _ => continue
+        };
+
+        if falls_to != Some(offset) && decodes_cleanly(file_buffer, architecture, &reached, offset) {
+            entries.push(offset);
+        }
+
+        let next_offset = offset + inst.length();
+        falls_to = match inst.successors(offset) {
+            Ok((ref targets, _, _, _)) if targets.contains(&next_offset) => Some(next_offset),
+            _ => None
+        };
+    }
+
+    entries
+}
+
+// Returns true if all the code reachable from offset, short of the code
+// in reached, decodes and stays in the file.
+fn decodes_cleanly<I, A>(file_buffer: &Vec<u8>, architecture: A, reached: &Listing<I>, offset: usize) -> bool
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    let mut visited = HashSet::new();
+    let mut unexplored = vec!(offset);
+
+    while let Some(offset) = unexplored.pop() {
+        if reached.get(offset).is_some() || !visited.insert(offset) {
+            continue;
+        }
+
+        let successors = architecture.decode_instruction(file_buffer, offset)
+            .and_then(|inst| inst.successors(offset));
+
+        match successors {
+            Ok((targets, calls, _, _)) => for target in targets.into_iter().chain(calls.into_iter()) {
+                if target >= file_buffer.len() {
+                    return false;
+                }
+                unexplored.push(target);
+            },
+            Err(_) => return false
+        }
+    }
+
+    true
+}
+
+// Sweeps the gaps that recursive descent left in listing.
+fn sweep<I, A>(file_buffer: &Vec<u8>, architecture: A, entry_offset: usize, mut listing: Listing<I>) -> Listing<I>
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    let mut covered = covered_offsets(&listing);
+    let mut offset = entry_offset;
+
+    while offset < file_buffer.len() {
+        if covered.contains(&offset) {
+            offset += 1;
+            continue;
+        }
+
+        let mut gap_end = offset;
+        while gap_end < file_buffer.len() && !covered.contains(&gap_end) {
+            gap_end += 1;
+        }
+
+        let run = sweep_run(file_buffer, architecture, &listing, &covered, offset, gap_end);
+
+        if run.is_plausible() {
+            for &(inst_offset, inst) in run.instructions.iter() {
+                for covered_offset in inst_offset..(inst_offset + inst.length()) {
+                    covered.insert(covered_offset);
+                }
+                listing.add(inst_offset, inst);
+            }
+
+            for label in run.labels {
+                listing.add_label(label);
+            }
+
+            for indeterminate in run.indeterminates {
+                listing.add_indeterminate(indeterminate);
+            }
+
+            offset = run.end;
+        } else {
+            listing.add_data(offset, file_buffer[offset]);
+            covered.insert(offset);
+            offset += 1;
+        }
+    }
+
+    listing
+}
+
+// A candidate run of instructions found while sweeping a gap.
+struct Run<I: InstructionTrait> {
+    instructions: Vec<(usize, I)>,
+    labels: Vec<usize>,
+    indeterminates: Vec<usize>,
+    end: usize,
+    score: isize
+}
+
+impl<I: InstructionTrait> Run<I> {
+    fn is_plausible(&self) -> bool {
+        let length = self.instructions.len() as isize;
+        self.instructions.len() >= MIN_RUN_LENGTH && 2 * self.score >= length
+    }
+}
+
+// Decodes instructions from start until the run leaves the gap
+// [start, end), fails to decode, or ends with an instruction that
+// doesn't fall through. Each instruction scores a point; branch
+// targets that land on known code score more, and targets that land
+// outside the file or inside a known instruction are penalized.
+fn sweep_run<I, A>(file_buffer: &Vec<u8>, architecture: A, listing: &Listing<I>,
+    covered: &HashSet<usize>, start: usize, end: usize) -> Run<I>
+    where I: InstructionTrait,
+          A: Architecture<I>
+{
+    let mut run = Run {
+        instructions: Vec::new(),
+        labels: Vec::new(),
+        indeterminates: Vec::new(),
+        end: start,
+        score: 0
+    };
+
+    let mut offset = start;
+
+    while offset < end {
+        let inst = match architecture.decode_instruction(file_buffer, offset) {
+            Ok(instruction) => instruction,
+            Err(_) => {
+                run.score -= DECODE_ERROR_PENALTY;
+                break;
+            }
+        };
+
+        let next_offset = offset + inst.length();
+
+        if next_offset > end {
+            run.score -= DECODE_ERROR_PENALTY;
+            break;
+        }
+
+        let (targets, calls, branch, indeterminate) = match inst.successors(offset) {
+            Ok(successors) => successors,
+            Err(_) => {
+                run.score -= DECODE_ERROR_PENALTY;
+                break;
+            }
+        };
+
+        run.score += 1;
+
+        for &target in targets.iter().chain(calls.iter()) {
+            if target == next_offset {
+                continue;
+            }
+
+            if target >= file_buffer.len() {
+                run.score -= OUTSIDE_TARGET_PENALTY;
+            } else if let Some(_) = listing.get(target) {
+                run.score += KNOWN_TARGET_BONUS;
+            } else if covered.contains(&target) {
+                run.score -= MISALIGNED_TARGET_PENALTY;
+            }
+
+            if branch {
+                run.labels.push(target);
+            }
+        }
+
+        if indeterminate {
+            run.indeterminates.push(offset);
+        }
+
+        run.instructions.push((offset, inst));
+        offset = next_offset;
+        run.end = offset;
+
+        if !indeterminate && !targets.contains(&next_offset) {
+            run.score += FLOW_END_BONUS;
+            break;
+        }
+    }
+
+    if run.end == end {
+        if let Some(_) = listing.get(end) {
+            run.score += KNOWN_TARGET_BONUS;
+        }
+    }
+
+    run
+}
+
+// Returns every offset occupied by an instruction in the listing.
+fn covered_offsets<I: InstructionTrait>(listing: &Listing<I>) -> HashSet<usize> {
+    let mut covered = HashSet::new();
+
+    for (offset, meta) in listing.instructions.iter() {
+        if let Meta::Inst(inst) = *meta {
+            for covered_offset in *offset..(*offset + inst.length()) {
+                covered.insert(covered_offset);
+            }
+        }
+    }
+
+    covered
+}
@@ -0,0 +1,70 @@
+use defs::main::*;
+use graph::flow::*;
+use x86::arch::*;
+use std::collections::HashSet;
+use std::collections::HashMap;
+
+// Resolves what it can of indirect branches and memory writes without
+// simulating. Only writes to direct addresses are followed, taking DS
+// and ES to be CS, as they are when a COM file starts, and other writes
+// are taken not to reach code. Indirect jumps aren't followed.
+//
+// ip_base is the IP of the first byte of the file, as for the lifter.
+pub struct Analyzer {
+    pub ip_base: u16
+}
+
+impl Analyzer {
+    // Returns the file offsets of the bytes the instruction's memory
+    // operand covers, if its address is known.
+    fn direct_offsets(&self, instruction: Instruction) -> Option<Vec<usize>> {
+        let pointer = match instruction.op1 {
+            Some(Operand::Pointer(pointer)) => pointer,
+            _ => return None
+        };
+
+        match (pointer.segment, pointer.value) {
+            (Register::DS, PtrType::Disp16(address))
+            | (Register::CS, PtrType::Disp16(address))
+            | (Register::ES, PtrType::Disp16(address)) => {
+                let offset = address.wrapping_sub(self.ip_base) as usize;
+                let length = if pointer.size == 0 { 1 } else { 2 };
+                Some((offset..offset + length).collect())
+            },
+            _ => None
+        }
+    }
+}
+
+impl AnalyzerTrait<Instruction> for Analyzer {
+    fn written_offsets(&self, _file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize, _limits: &Limits) -> Result<HashSet<usize>, DecompilerError> {
+        let instruction = graph.instruction_at(offset)?;
+        Ok(self.direct_offsets(instruction).unwrap_or(Vec::new()).into_iter().collect())
+    }
+
+    // Only immediates moved into memory have known values.
+    fn written_bytes(&self, _file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize, _limits: &Limits) -> Result<HashMap<usize, Option<HashSet<u8>>>, DecompilerError> {
+        let instruction = graph.instruction_at(offset)?;
+        let offsets = self.direct_offsets(instruction).unwrap_or(Vec::new());
+
+        let value = match (instruction.mnemonic, instruction.op2) {
+            (Mnemonic::MOV, Some(Operand::Imm8(value))) => Some(value as u8 as u16),
+            (Mnemonic::MOV, Some(Operand::Imm16(value))) => Some(value as u16),
+            _ => None
+        };
+
+        Ok(offsets.iter().enumerate().map(|(index, &written_offset)| {
+            let byte = value.map(|value| (value >> (8 * index)) as u8);
+            (written_offset, byte.map(|byte| [byte].iter().cloned().collect()))
+        }).collect())
+    }
+
+    // INT 21h and indirect calls are taken to return.
+    fn determine_successors(&self, _file_buffer: &[u8], graph: &FlowGraph<Instruction>, offset: usize, _limits: &Limits) -> Result<HashSet<usize>, DecompilerError> {
+        let instruction = graph.instruction_at(offset)?;
+        Ok(match instruction.mnemonic {
+            Mnemonic::INT | Mnemonic::CALL => [offset + instruction.length].iter().cloned().collect(),
+            _ => HashSet::new()
+        })
+    }
+}
@@ -1,109 +1,257 @@
 use defs::main::*;
+use defs::data::*;
 use x86::dis;
 use std::fmt;
+use std::collections::HashSet;
 
+// ip_base is the IP of the first byte of the file, which is needed to
+// find the data that direct addresses point to.
 #[derive(Copy, Clone)]
 pub struct X86 {
+    pub ip_base: u16
 }
 
-impl<'a> Architecture<Instruction> for X86 {
-    fn decode_instruction(&self, buffer: &[u8], offset: usize) -> Result<Instruction, String> {
-        dis::decode_instruction(buffer, offset)
+impl X86 {
+    // Returns the file offset of the address, if it lies in the file.
+    fn file_offset(&self, address: u16, file_length: usize) -> Option<usize> {
+        let offset = address.wrapping_sub(self.ip_base) as usize;
+        if offset < file_length {
+            Some(offset)
+        } else {
+            None
+        }
     }
 
-    fn successors(&self, instruction: Instruction, offset: usize) -> (Vec<usize>, Vec<usize>, bool) {
-        println!("{}", instruction);
-        match instruction.mnemonic {
-            Mnemonic::JB | Mnemonic::JO | Mnemonic::JNO
-            | Mnemonic::JB | Mnemonic::JNB | Mnemonic::JZ
-            | Mnemonic::JNZ | Mnemonic::JBE | Mnemonic::JNBE
-            | Mnemonic::JS | Mnemonic::JNS | Mnemonic::JP
-            | Mnemonic::JNP | Mnemonic::JL | Mnemonic::JNL
-            | Mnemonic::JLE | Mnemonic::JNLE | Mnemonic::CALL =>
-                match instruction.unpack_op1() {
-                    Operand::Imm8(byte) => {
-                        let base = offset + instruction.length;
-                        let target = add_rel8(base, byte);
-                        (vec!(base, target), vec!(target), false)
-                    },
-                    Operand::Imm16(word) => {
-                        let base = offset + instruction.length;
-                        let target = add_rel16(base, word);
-                        (vec!(base, target), vec!(target), false)
-                    },
-                    _ => panic!("expected byte or word operand for branch")
-                },
-            Mnemonic::JMP =>
-                match instruction.unpack_op1() {
-                    Operand::Imm8(byte) => {
-                        let base = offset + instruction.length;
-                        let target = add_rel8(base, byte);
-                        (vec!(target), vec!(target), false)
-                    },
-                    Operand::Imm16(word) => {
-                        let base = offset + instruction.length;
-                        let target = add_rel16(base, word);
-                        (vec!(target), vec!(target), false)
-                    },
-                    Operand::Pointer(_) => (Vec::new(), Vec::new(), true),
-                    _ => panic!("unexpected JMP operand")
+    // Returns the file offset of the displacement of a memory operand
+    // and whether it's indexed. DS, CS and ES are taken to be the
+    // segment the file is loaded at, as they are when a COM file
+    // starts, and operands based on BP are on the stack.
+    fn table_base(&self, pointer: Pointer, file_length: usize) -> Option<(usize, bool)> {
+        if pointer.segment == Register::SS {
+            return None;
+        }
+
+        let (displacement, indexed) = match pointer.value {
+            PtrType::Disp16(displacement) => (displacement, false),
+            PtrType::RegDisp16(register, displacement)
+                if register != Register::BP => (displacement, true),
+            PtrType::RegRegDisp16(register, _, displacement)
+                if register != Register::BP => (displacement, true),
+            _ => return None
+        };
+
+        self.file_offset(displacement, file_length).map(|offset| (offset, indexed))
+    }
+
+    // Returns the file offsets that instructions refer to directly, as
+    // memory displacements or as immediate addresses, which end tables
+    // whose length isn't otherwise known.
+    fn referenced_offsets(&self, file_length: usize, listing: &Listing<Instruction>) -> HashSet<usize> {
+        let mut referenced = HashSet::new();
+
+        for offset in 0..file_length {
+            if let Some(&Meta::Inst(instruction)) = listing.get(offset) {
+                for operand in [instruction.op1, instruction.op2].iter() {
+                    match *operand {
+                        Some(Operand::Pointer(pointer)) =>
+                            if let Some((base, _)) = self.table_base(pointer, file_length) {
+                                referenced.insert(base);
+                            },
+                        Some(Operand::Imm16(value)) if instruction.mnemonic == Mnemonic::MOV =>
+                            if let Some(base) = self.file_offset(value as u16, file_length) {
+                                referenced.insert(base);
+                            },
+                        _ => ()
+                    }
+                }
+            }
+        }
+
+        referenced
+    }
+
+    // A table of code addresses used by an indirect jump or call runs
+    // while its entries point into the file, up to the first code it
+    // jumps to, which often follows it.
+    fn jump_table(&self, file_buffer: &[u8], listing: &Listing<Instruction>, base: usize, indexed: bool) -> Option<DataRegion> {
+        let mut end = base;
+        let mut lowest_target = file_buffer.len();
+
+        while end + 2 <= file_buffer.len() && end + 2 <= lowest_target && !listing.is_code(end) {
+            let entry = file_buffer[end] as u16 | (file_buffer[end + 1] as u16) << 8;
+            match self.file_offset(entry, file_buffer.len()) {
+                Some(target) => {
+                    if target > base {
+                        lowest_target = lowest_target.min(target);
+                    }
+                    end += 2;
                 },
-            Mnemonic::RET => (Vec::new(), Vec::new(), false),
-            Mnemonic::INT => match instruction.unpack_op1() {
-                Operand::Imm8(0x20) =>
-                    (Vec::new(), Vec::new(), false),
-                Operand::Imm8(0x21) =>
-                    (Vec::new(), Vec::new(), true),
-                Operand::Imm8(0x27) =>
-                    (Vec::new(), Vec::new(), false),
-                _ => (vec!(offset + instruction.length), Vec::new(), false)
-            },
-            _ => (vec!(offset + instruction.length), Vec::new(), false)
+                _ => break
+            }
+
+            if !indexed {
+                break;
+            }
+        }
+
+        if end > base {
+            Some(DataRegion::new(base, DataType::JumpTable, &file_buffer[base..end]))
+        } else {
+            None
+        }
+    }
+
+    // A table of words read by an indexed load runs up to the next code,
+    // or the next data something else refers to.
+    fn word_table(&self, file_buffer: &[u8], listing: &Listing<Instruction>, referenced: &HashSet<usize>, base: usize, indexed: bool) -> Option<DataRegion> {
+        let mut end = base;
+
+        while end + 2 <= file_buffer.len() && !listing.is_code(end) && !listing.is_code(end + 1)
+            && (end == base || !referenced.contains(&end)) {
+            end += 2;
+
+            if !indexed {
+                break;
+            }
+        }
+
+        if end > base {
+            Some(DataRegion::new(base, DataType::Word, &file_buffer[base..end]))
+        } else {
+            None
         }
     }
 }
-/*
-    fn simulate_next_instruction<C: Context<State<'a>, Instruction>>(&self, state: State<'a>, context: &C, instruction: Instruction) -> SimResult<State<'a>> {
-        sim::simulate_next_instruction(state, context, instruction)
+
+impl<'a> Architecture<Instruction> for X86 {
+    fn decode_instruction(&self, buffer: &[u8], offset: usize) -> Result<Instruction, DecompilerError> {
+        dis::decode_instruction(buffer, offset)
+            .map_err(|reason| DecompilerError::Decode {
+                offset: offset,
+                reason: reason
+            })
     }
-*/
-impl Listing<Instruction> {
-    pub fn print_instructions(&self) {
+
+    fn print_listing(listing: &Listing<Instruction>) {
         let mut output = String::new();
-        for i in 0..self.highest_offset+1 {
-            if let Some(instruction) = self.instructions.get(&i) {
+        let mut region_end = 0;
+        for i in 0..listing.end_offset() {
+            if i < region_end {
+                continue;
+            }
+
+            if let Some(region) = listing.get_region(i) {
+                for (index, directive) in region.directives(false).iter().enumerate() {
+                    if index == 0 && listing.is_labelled(i) {
+                        output.push_str(format!("{:4x}:   ", i).as_str());
+                    } else {
+                        output.push_str("        ");
+                    }
+                    output.push_str(format!("{}\n", directive).as_str());
+                }
+                region_end = region.end();
+                continue;
+            }
+
+            if let Some(error) = listing.get_invalid(i) {
+                output.push_str(format!("{:4x}:   ; {}\n", i, error).as_str());
+                continue;
+            }
+
+            if let Some(&Meta::Inst(instruction)) = listing.instructions.get(&i) {
                 let prefix =
-                    if self.is_labelled(i) {
+                    if listing.is_labelled(i) {
                         format!("{:4x}:   ", i)
                     } else {
-                        format!("        ")
+                        String::from("        ")
                     };
-                let indeterminate = if self.is_indeterminate(i) {
+                let indeterminate = if listing.is_indeterminate(i) {
                     "* "
                 } else {
                     ""
                 };
-                let inst_output = if instruction.is_rel_branch() {
-                    let mut target = i + instruction.length;
-                    match instruction.op1 {
-                        Some(Operand::Imm8(rel)) => target = add_rel8(target, rel),
-                        Some(Operand::Imm16(rel)) => target = add_rel16(target, rel),
-                        _ => panic!("Wrong operand for relative jump.")
-                    }
-                    format!("{:?} <{:x}>", instruction.mnemonic, target)
-                } else {
-                    format!("{}", instruction)
+                let inst_output = match branch_target(instruction, i) {
+                    Ok(Some(target)) => format!("{:?} <{:x}>", instruction.mnemonic, target),
+                    _ => format!("{}", instruction)
                 };
                 output.push_str(format!("{}{}{}{}\n", prefix.as_str(), indeterminate, inst_output,
-                    if i == self.entry_offset {
+                    if i == listing.entry_offset {
                         "\t; program entry point"
                     } else {
                         ""
                     }).as_str());
+
+                // Other instructions decoded from some of the same bytes.
+                let overlapping: Vec<String> = listing.overlapping(i).iter()
+                    .map(|offset| format!("{:x}", offset))
+                    .collect();
+                if !overlapping.is_empty() {
+                    output.push_str(format!("        ; overlaps {}\n",
+                        overlapping.join(", ")).as_str());
+                }
             }
         }
         println!("{}", output);
     }
+
+    // Infers jump tables from the memory operands of indirect jumps and
+    // calls, and word tables from the memory operands of word-sized
+    // loads. Byte-sized loads are left to the generic classification.
+    fn data_regions(&self, file_buffer: &[u8], listing: &Listing<Instruction>) -> Vec<DataRegion> {
+        let referenced = self.referenced_offsets(file_buffer.len(), listing);
+        let mut regions = Vec::new();
+
+        for offset in 0..file_buffer.len() {
+            let instruction = match listing.get(offset) {
+                Some(&Meta::Inst(instruction)) => instruction,
+                _ => continue
+            };
+
+            let region = match (instruction.mnemonic, instruction.op1, instruction.op2) {
+                (Mnemonic::JMP, Some(Operand::Pointer(pointer)), None)
+                | (Mnemonic::CALL, Some(Operand::Pointer(pointer)), None) if pointer.size == 1 =>
+                    self.table_base(pointer, file_buffer.len()).and_then(|(base, indexed)|
+                        self.jump_table(file_buffer, listing, base, indexed)),
+                (Mnemonic::LEA, _, _) => None,
+                (_, Some(Operand::Register16(_)), Some(Operand::Pointer(pointer))) if pointer.size == 1 =>
+                    self.table_base(pointer, file_buffer.len()).and_then(|(base, indexed)|
+                        self.word_table(file_buffer, listing, &referenced, base, indexed)),
+                _ => None
+            };
+
+            if let Some(region) = region {
+                regions.push(region);
+            }
+        }
+
+        regions
+    }
+}
+
+// Returns the file offset a relative branch at offset goes to, or None
+// if the instruction isn't one. Targets before the start of the file
+// are an error.
+fn branch_target(instruction: Instruction, offset: usize) -> Result<Option<usize>, DecompilerError> {
+    if !instruction.is_rel_branch() {
+        return Ok(None);
+    }
+
+    let next = (offset + instruction.length) as isize;
+    let target = match instruction.op1 {
+        Some(Operand::Imm8(rel)) => next + rel as isize,
+        Some(Operand::Imm16(rel)) => next + rel as isize,
+        _ => return Ok(None)
+    };
+
+    if target < 0 {
+        return Err(DecompilerError::UnresolvedBranch {
+            offset: offset,
+            instruction: format!("{}", instruction),
+            reason: String::from("branch target lies before the start of the file.")
+        });
+    }
+
+    Ok(Some(target as usize))
 }
 
 #[derive(Copy, Clone)]
@@ -156,6 +304,62 @@ impl InstructionTrait for Instruction {
             _ => false
         }
     }
+
+    // Pushes and calls write to the stack, which isn't expected to
+    // hold code, so only explicit memory operands and string stores
+    // count.
+    fn writes_memory(&self) -> bool {
+        match self.mnemonic {
+            Mnemonic::STOSB | Mnemonic::STOSW | Mnemonic::MOVSB | Mnemonic::MOVSW => true,
+            Mnemonic::XCHG => match (self.op1, self.op2) {
+                (Some(Operand::Pointer(_)), _) | (_, Some(Operand::Pointer(_))) => true,
+                _ => false
+            },
+            Mnemonic::ADC | Mnemonic::ADD | Mnemonic::AND | Mnemonic::DEC
+            | Mnemonic::INC | Mnemonic::MOV | Mnemonic::NEG | Mnemonic::NOT
+            | Mnemonic::OR | Mnemonic::POP | Mnemonic::SBB | Mnemonic::SUB
+            | Mnemonic::XOR | Mnemonic::ROL | Mnemonic::ROR | Mnemonic::RCL
+            | Mnemonic::RCR | Mnemonic::SHL | Mnemonic::SHR | Mnemonic::SAL
+            | Mnemonic::SAR => match self.op1 {
+                Some(Operand::Pointer(_)) => true,
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    // Indirect jumps and calls go wherever a register or memory says,
+    // and INT 21h may end the program, depending on AH.
+    fn successors(&self, offset: usize) -> Result<(Vec<usize>, Vec<usize>, bool, bool), DecompilerError> {
+        let next = offset + self.length;
+
+        Ok(match self.mnemonic {
+            Mnemonic::CALL => match branch_target(*self, offset)? {
+                Some(target) => (vec!(next), vec!(target), true, false),
+                None => (vec!(next), Vec::new(), true, true)
+            },
+            Mnemonic::JMP => match branch_target(*self, offset)? {
+                Some(target) => (vec!(target), Vec::new(), true, false),
+                None => (Vec::new(), Vec::new(), true, true)
+            },
+            _ if self.mnemonic.is_branch() => match branch_target(*self, offset)? {
+                Some(target) => (vec!(next, target), Vec::new(), true, false),
+                None => return Err(DecompilerError::UnresolvedBranch {
+                    offset: offset,
+                    instruction: format!("{}", self),
+                    reason: String::from("conditional branches should have a relative target.")
+                })
+            },
+            Mnemonic::RET => (Vec::new(), Vec::new(), true, false),
+            Mnemonic::INT => match self.op1 {
+                Some(Operand::Imm8(0x20)) | Some(Operand::Imm8(0x27)) =>
+                    (Vec::new(), Vec::new(), true, false),
+                Some(Operand::Imm8(0x21)) => (vec!(next), Vec::new(), true, true),
+                _ => (vec!(next), Vec::new(), false, false)
+            },
+            _ => (vec!(next), Vec::new(), false, false)
+        })
+    }
 }
 
 impl fmt::Display for Instruction {
@@ -268,10 +472,11 @@ pub enum Mnemonic {
 impl Mnemonic {
     pub fn is_branch(&self) -> bool {
         match *self {
-            Mnemonic::JMP | Mnemonic::JO | Mnemonic::JB | Mnemonic::JNB |
-            Mnemonic::JZ | Mnemonic::JNZ | Mnemonic::JBE | Mnemonic::JNBE |
-            Mnemonic::JS | Mnemonic::JNS | Mnemonic::JP | Mnemonic::JNP |
-            Mnemonic::JL | Mnemonic::JNL | Mnemonic::JNLE | Mnemonic::JCXZ |
+            Mnemonic::JMP | Mnemonic::JO | Mnemonic::JNO | Mnemonic::JB |
+            Mnemonic::JNB | Mnemonic::JZ | Mnemonic::JNZ | Mnemonic::JBE |
+            Mnemonic::JNBE | Mnemonic::JS | Mnemonic::JNS | Mnemonic::JP |
+            Mnemonic::JNP | Mnemonic::JL | Mnemonic::JNL | Mnemonic::JLE |
+            Mnemonic::JNLE | Mnemonic::JCXZ |
             Mnemonic::LOOP | Mnemonic::LOOPNZ | Mnemonic::LOOPZ |
             Mnemonic::CALL => true,
             _ => false
@@ -338,7 +338,7 @@ fn decode_test_ax(buffer: &[u8], offset:usize) -> Result<Instruction, String> {
         0 => Operand::Imm8(buffer[offset + 1] as i8),
         _ => Operand::Imm16(get_word_le(buffer, offset + 1) as i16),
     });
-    inst.length = 2 + size;
+    inst.length = 2 + size as usize;
     return Ok(inst);
 }
 
@@ -554,7 +554,7 @@ fn decode_ff(buffer: &[u8], offset: usize) -> Result<Instruction, String> {
     return Ok(inst);
 }
 
-fn decode_mod_rm(buffer: &[u8], offset: usize, size: u8, dir: u8) -> (Option<Operand>, Option<Operand>, u8) {
+fn decode_mod_rm(buffer: &[u8], offset: usize, size: u8, dir: u8) -> (Option<Operand>, Option<Operand>, usize) {
     let reg = |op| {
         match size {
             0 => Operand::Register8(reg8(op)),
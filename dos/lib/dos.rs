@@ -1,6 +1,7 @@
 use defs::main::*;
 use defs::set::*;
 use x86::arch::*;
+use x86::sim;
 use x86::state::{State, Flag};
 
 // We assume the PSP is loaded at 0x75a and the program at 0x76a.
@@ -32,6 +33,17 @@ impl DOS {
         }
     }
 
+    // Returns the IP of the first byte of the file, when CS is the
+    // segment it's loaded at: 0x100 for COM files, which are loaded
+    // after the PSP, and below 0 for EXE files, whose header isn't.
+    pub fn ip_base(&self) -> u16 {
+        if self.load_module.file_offset == 0 {
+            0x100
+        } else {
+            0u16.wrapping_sub(self.load_module.file_offset as u16)
+        }
+    }
+
     pub fn initial_state<'a>(&'a self, file_buffer: &[u8]) -> State<'a> {
         let mut state = State::new(&self.load_module)
             .set_reg16(Register::DS, Word::new(PSP_SEGMENT))
@@ -52,12 +64,15 @@ impl DOS {
     }
 }
 
-/*
-impl<'a> Context<State<'a>, Instruction> for DOS {
+// The simulator is a reference to the DOS the states are loaded by,
+// since their memory is borrowed from its load module.
+impl<'a, 'b> SimulatorTrait<State<'a>, Instruction> for &'b DOS {
     fn next_inst_offset(state: &State<'a>) -> usize {
-        let address = state.next_inst_address();
-        address - 16 * self.load_module.memory_segment as usize
-            + self.load_module.file_offset
+        state.next_inst_offset()
+    }
+
+    fn simulate_next_instruction(&self, state: State<'a>, instruction: Instruction) -> SimResult<State<'a>> {
+        sim::simulate_next_instruction(state, self, instruction)
     }
 
     fn simulate_system_call(&self, state: State<'a>, inst: Instruction) -> Option<State<'a>> {
@@ -73,7 +88,7 @@ impl<'a> Context<State<'a>, Instruction> for DOS {
             _ => panic!("Expected first operand of INT to be imm8")
         }
     }
-} */
+}
 
 pub struct LoadModule {
     pub file_offset: usize,
@@ -0,0 +1,932 @@
+use defs::main::*;
+use defs::ir;
+use defs::ir::{Mnemonic as Op, Operand as Arg, Width};
+use x86::arch::*;
+
+// Registers are lifted as 16-bit IR registers. AL, AH and the other
+// byte registers are read and written as parts of AX, BX, CX and DX,
+// so that analyses see every alias of a register as one location.
+const REGISTERS: [Register; 14] = [
+    Register::AX, Register::CX, Register::DX, Register::BX,
+    Register::SP, Register::BP, Register::SI, Register::DI,
+    Register::ES, Register::CS, Register::SS, Register::DS,
+    Register::FS, Register::GS
+];
+
+pub const FLAG_CF: usize = 0;
+pub const FLAG_PF: usize = 1;
+pub const FLAG_AF: usize = 2;
+pub const FLAG_ZF: usize = 3;
+pub const FLAG_SF: usize = 4;
+pub const FLAG_OF: usize = 5;
+pub const FLAG_DF: usize = 6;
+pub const FLAG_IF: usize = 7;
+
+const FLAG_NAMES: [&str; 8] = ["CF", "PF", "AF", "ZF", "SF", "OF", "DF", "IF"];
+
+// Ports 0 to ffff are the I/O ports addressed by an immediate. Ports
+// addressed by DX are modeled as a write of DX to PORT_SELECT followed
+// by an access to PORT_DX.
+pub const PORT_SELECT: usize = 0x10000;
+pub const PORT_DX: usize = 0x10001;
+
+// Lifts 8086 instructions. Memory operands become segment * 16 +
+// offset, with BP-based operands defaulting to SS. ip_base is the IP
+// of the first byte of the file (0x100 for COM files); it's used for
+// pushed return addresses and to turn indirect branch targets back
+// into file offsets.
+pub struct Lifter {
+    pub ip_base: u16
+}
+
+impl ir::LifterTrait<Instruction> for Lifter {
+    fn lift(&self, inst: Instruction, offset: usize) -> Vec<ir::Instruction> {
+        let mut builder = Builder::new();
+        self.lift_into(&mut builder, inst, offset);
+        builder.ir
+    }
+
+    fn register_name(&self, index: usize) -> String {
+        match REGISTERS.get(index) {
+            Some(register) => format!("{:?}", register),
+            None => format!("r{}", index)
+        }
+    }
+
+    fn flag_name(&self, index: usize) -> String {
+        match FLAG_NAMES.get(index) {
+            Some(name) => String::from(*name),
+            None => format!("f{}", index)
+        }
+    }
+
+    fn port_name(&self, index: usize) -> String {
+        match index {
+            PORT_SELECT => String::from("PORTSEL"),
+            PORT_DX => String::from("(DX)"),
+            _ => format!("{:02x}h", index)
+        }
+    }
+}
+
+impl Lifter {
+    fn lift_into(&self, b: &mut Builder, inst: Instruction, offset: usize) {
+        let next = offset + inst.length;
+
+        match inst.mnemonic {
+            Mnemonic::MOV => {
+                let dst = inst.unpack_op1();
+                let value = b.read(inst.unpack_op2(), operand_width(dst));
+                b.write(dst, value);
+            },
+            Mnemonic::LEA => match inst.unpack_op2() {
+                Operand::Pointer(pointer) => {
+                    let value = b.effective_offset(pointer.value);
+                    b.write(inst.unpack_op1(), value);
+                },
+                _ => b.unsupported()
+            },
+            Mnemonic::XCHG => {
+                let (op1, op2) = (inst.unpack_op1(), inst.unpack_op2());
+                let width = operand_width(op1);
+                let first = b.read(op1, width);
+                let first = b.copy(first);
+                let second = b.read(op2, width);
+                b.write(op1, second);
+                b.write(op2, first);
+            },
+            Mnemonic::ADD | Mnemonic::ADC | Mnemonic::SUB | Mnemonic::SBB | Mnemonic::CMP => {
+                let dst = inst.unpack_op1();
+                let width = operand_width(dst);
+                let a = b.read(dst, width);
+                let c = b.read(inst.unpack_op2(), width);
+                let result = match inst.mnemonic {
+                    Mnemonic::ADD => b.add(a, c, false, true),
+                    Mnemonic::ADC => b.add(a, c, true, true),
+                    Mnemonic::SBB => b.subtract(a, c, true, true),
+                    _ => b.subtract(a, c, false, true)
+                };
+                if inst.mnemonic != Mnemonic::CMP {
+                    b.write(dst, result);
+                }
+            },
+            Mnemonic::INC | Mnemonic::DEC => {
+                let dst = inst.unpack_op1();
+                let width = operand_width(dst);
+                let a = b.read(dst, width);
+                let result = if inst.mnemonic == Mnemonic::INC {
+                    b.add(a, constant(1, width), false, false)
+                } else {
+                    b.subtract(a, constant(1, width), false, false)
+                };
+                b.write(dst, result);
+            },
+            Mnemonic::NEG => {
+                let dst = inst.unpack_op1();
+                let width = operand_width(dst);
+                let a = b.read(dst, width);
+                let result = b.subtract(constant(0, width), a, false, true);
+                b.write(dst, result);
+            },
+            Mnemonic::NOT => {
+                let dst = inst.unpack_op1();
+                let width = operand_width(dst);
+                let a = b.read(dst, width);
+                let result = b.unop(Op::NOT, width, a);
+                b.write(dst, result);
+            },
+            Mnemonic::AND | Mnemonic::OR | Mnemonic::XOR | Mnemonic::TEST => {
+                let dst = inst.unpack_op1();
+                let width = operand_width(dst);
+                let a = b.read(dst, width);
+                let c = b.read(inst.unpack_op2(), width);
+                let op = match inst.mnemonic {
+                    Mnemonic::OR => Op::OR,
+                    Mnemonic::XOR => Op::XOR,
+                    _ => Op::AND
+                };
+                let result = b.op(op, width, a, c);
+
+                // AF is undefined after logical operations and is
+                // left alone.
+                b.set_flag(FLAG_CF, bit(0));
+                b.set_flag(FLAG_OF, bit(0));
+                b.result_flags(result);
+
+                if inst.mnemonic != Mnemonic::TEST {
+                    b.write(dst, result);
+                }
+            },
+            Mnemonic::RCL | Mnemonic::RCR if inst.op2 != Some(Operand::Imm8(1)) =>
+                b.unsupported(),
+            Mnemonic::ROL | Mnemonic::ROR | Mnemonic::RCL | Mnemonic::RCR
+            | Mnemonic::SHL | Mnemonic::SAL | Mnemonic::SHR | Mnemonic::SAR =>
+                lift_shift(b, inst),
+            Mnemonic::MUL => lift_multiply(b, inst, false),
+            Mnemonic::IMUL => lift_multiply(b, inst, true),
+            Mnemonic::DIV => lift_divide(b, inst),
+            Mnemonic::PUSH => {
+                let value = b.read(inst.unpack_op1(), Width::Word);
+                b.push(value);
+            },
+            Mnemonic::POP => {
+                let value = b.pop();
+                b.write(inst.unpack_op1(), value);
+            },
+            Mnemonic::CALL => {
+                let target = self.branch_target(b, inst, next);
+                b.push(word(self.ip_base.wrapping_add(next as u16) as u32));
+                b.emit(ir::Instruction::unary(Op::CALL, target));
+            },
+            Mnemonic::JMP => {
+                let target = self.branch_target(b, inst, next);
+                b.emit(ir::Instruction::unary(Op::JMP, target));
+            },
+            Mnemonic::RET => {
+                b.pop();
+                if let Some(Operand::Imm16(bytes)) = inst.op1 {
+                    let sp = reg16(Register::SP);
+                    b.emit(ir::Instruction::ternary(Op::ADD, sp, sp, word(bytes as u16 as u32)));
+                }
+                b.emit(ir::Instruction::new(Op::RET));
+            },
+            Mnemonic::JO | Mnemonic::JNO | Mnemonic::JB | Mnemonic::JNB
+            | Mnemonic::JZ | Mnemonic::JNZ | Mnemonic::JBE | Mnemonic::JNBE
+            | Mnemonic::JS | Mnemonic::JNS | Mnemonic::JP | Mnemonic::JNP
+            | Mnemonic::JL | Mnemonic::JNL | Mnemonic::JLE | Mnemonic::JNLE => {
+                let target = self.branch_target(b, inst, next);
+                let (condition, when_set) = b.condition(inst.mnemonic);
+                let jump = if when_set { Op::JMPNZ } else { Op::JMPZ };
+                b.emit(ir::Instruction::binary(jump, condition, target));
+            },
+            Mnemonic::LOOP | Mnemonic::LOOPZ | Mnemonic::LOOPNZ => {
+                let target = self.branch_target(b, inst, next);
+                let cx = reg16(Register::CX);
+                b.emit(ir::Instruction::ternary(Op::SUB, cx, cx, word(1)));
+                let zero = b.unop(Op::CMPZ, Width::Bit, cx);
+                let mut condition = b.op(Op::XOR, Width::Bit, zero, bit(1));
+                if inst.mnemonic == Mnemonic::LOOPZ {
+                    condition = b.op(Op::AND, Width::Bit, condition, Arg::Flag(FLAG_ZF));
+                } else if inst.mnemonic == Mnemonic::LOOPNZ {
+                    let not_zero = b.op(Op::XOR, Width::Bit, Arg::Flag(FLAG_ZF), bit(1));
+                    condition = b.op(Op::AND, Width::Bit, condition, not_zero);
+                }
+                b.emit(ir::Instruction::binary(Op::JMPNZ, condition, target));
+            },
+            Mnemonic::JCXZ => {
+                let target = self.branch_target(b, inst, next);
+                let zero = b.unop(Op::CMPZ, Width::Bit, reg16(Register::CX));
+                b.emit(ir::Instruction::binary(Op::JMPNZ, zero, target));
+            },
+            Mnemonic::INT => match inst.unpack_op1() {
+                Operand::Imm8(number) => {
+                    b.emit(ir::Instruction::unary(Op::SYS,
+                        Arg::Const(number as u8 as u32, Width::Byte)));
+
+                    // INT 20h and INT 27h don't return.
+                    if number == 0x20 || number == 0x27 {
+                        b.emit(ir::Instruction::new(Op::HALT));
+                    }
+                },
+                _ => b.unsupported()
+            },
+            Mnemonic::IN => {
+                let dst = inst.unpack_op1();
+                let port = b.port(inst.unpack_op2());
+                let value = b.temp(operand_width(dst));
+                b.emit(ir::Instruction::binary(Op::IN, value, port));
+                b.write(dst, value);
+            },
+            Mnemonic::OUT => {
+                let src = inst.unpack_op2();
+                let port = b.port(inst.unpack_op1());
+                let value = b.read(src, operand_width(src));
+                b.emit(ir::Instruction::binary(Op::OUT, port, value));
+            },
+            Mnemonic::CLC => b.set_flag(FLAG_CF, bit(0)),
+            Mnemonic::STC => b.set_flag(FLAG_CF, bit(1)),
+            Mnemonic::CLI => b.set_flag(FLAG_IF, bit(0)),
+            Mnemonic::STI => b.set_flag(FLAG_IF, bit(1)),
+            Mnemonic::CLD => b.set_flag(FLAG_DF, bit(0)),
+            Mnemonic::STD => b.set_flag(FLAG_DF, bit(1)),
+            Mnemonic::NOP => b.emit(ir::Instruction::new(Op::NOP)),
+            Mnemonic::MOVSB | Mnemonic::MOVSW | Mnemonic::CMPSB | Mnemonic::CMPSW
+            | Mnemonic::STOSB | Mnemonic::STOSW | Mnemonic::LODSB | Mnemonic::LODSW
+            | Mnemonic::SCASB | Mnemonic::SCASW => lift_string(b, inst),
+            Mnemonic::IDIV | Mnemonic::DAA | Mnemonic::DAS | Mnemonic::AAA
+            | Mnemonic::AAS | Mnemonic::REPZ | Mnemonic::REPNZ => b.unsupported()
+        }
+    }
+
+    // Returns the target of a branch: a file offset for relative
+    // branches, or a temporary holding one for indirect branches.
+    fn branch_target(&self, b: &mut Builder, inst: Instruction, next: usize) -> Arg {
+        match inst.unpack_op1() {
+            Operand::Imm8(rel) => Arg::Address(add_rel8(next, rel)),
+            Operand::Imm16(rel) => Arg::Address(add_rel16(next, rel)),
+            operand => {
+                let ip = b.read(operand, Width::Word);
+                b.op(Op::SUB, Width::Word, ip, word(self.ip_base as u32))
+            }
+        }
+    }
+}
+
+fn lift_shift(b: &mut Builder, inst: Instruction) {
+    let dst = inst.unpack_op1();
+    let width = operand_width(dst);
+    let count = inst.unpack_op2();
+
+    // Shifting by CL leaves everything unchanged if CL is zero.
+    let skip = match count {
+        Operand::Register8(_) => {
+            let cl = b.read(count, Width::Byte);
+            let zero = b.unop(Op::CMPZ, Width::Bit, cl);
+            b.emit(ir::Instruction::binary(Op::JMPNZ, zero, Arg::Local(0)));
+            Some(b.ir.len() - 1)
+        },
+        _ => None
+    };
+
+    let a = b.read(dst, width);
+    let n = b.read(count, width);
+    let top = width.bits() - 1;
+
+    let result = match inst.mnemonic {
+        Mnemonic::SHL | Mnemonic::SAL => {
+            let result = b.op(Op::SHL, width, a, n);
+            let last = b.op(Op::SUB, width, n, constant(1, width));
+            let shifted = b.op(Op::SHL, width, a, last);
+            b.emit(ir::Instruction::binary(Op::SIGN, Arg::Flag(FLAG_CF), shifted));
+            b.sign_xor_carry_overflow(result);
+            b.result_flags(result);
+            result
+        },
+        Mnemonic::SHR | Mnemonic::SAR => {
+            let (op, overflow) = if inst.mnemonic == Mnemonic::SHR {
+                (Op::SHR, true)
+            } else {
+                (Op::SAR, false)
+            };
+            let result = b.op(op, width, a, n);
+            let last = b.op(Op::SUB, width, n, constant(1, width));
+            let shifted = b.op(op, width, a, last);
+            b.emit(ir::Instruction::binary(Op::TRUNC, Arg::Flag(FLAG_CF), shifted));
+            if overflow {
+                b.emit(ir::Instruction::binary(Op::SIGN, Arg::Flag(FLAG_OF), a));
+            } else {
+                b.set_flag(FLAG_OF, bit(0));
+            }
+            b.result_flags(result);
+            result
+        },
+        Mnemonic::ROL => {
+            let result = b.op(Op::ROL, width, a, n);
+            b.emit(ir::Instruction::binary(Op::TRUNC, Arg::Flag(FLAG_CF), result));
+            b.sign_xor_carry_overflow(result);
+            result
+        },
+        Mnemonic::ROR => {
+            let result = b.op(Op::ROR, width, a, n);
+            b.emit(ir::Instruction::binary(Op::SIGN, Arg::Flag(FLAG_CF), result));
+            b.top_bits_overflow(result);
+            result
+        },
+        Mnemonic::RCL => {
+            let carry = b.unop(Op::ZEXT, width, Arg::Flag(FLAG_CF));
+            let shifted = b.op(Op::SHL, width, a, constant(1, width));
+            let result = b.op(Op::OR, width, shifted, carry);
+            b.emit(ir::Instruction::binary(Op::SIGN, Arg::Flag(FLAG_CF), a));
+            b.sign_xor_carry_overflow(result);
+            result
+        },
+        _ => {
+            let carry = b.unop(Op::ZEXT, width, Arg::Flag(FLAG_CF));
+            let carry = b.op(Op::SHL, width, carry, constant(top, width));
+            let shifted = b.op(Op::SHR, width, a, constant(1, width));
+            let result = b.op(Op::OR, width, shifted, carry);
+            b.emit(ir::Instruction::binary(Op::TRUNC, Arg::Flag(FLAG_CF), a));
+            b.top_bits_overflow(result);
+            result
+        }
+    };
+
+    b.write(dst, result);
+
+    if let Some(index) = skip {
+        let end = b.ir.len();
+        b.emit(ir::Instruction::new(Op::NOP));
+        b.ir[index].op2 = Some(Arg::Local(end));
+    }
+}
+
+// MUL and IMUL multiply AL or AX by the operand, leaving the double
+// width result in AX or DX:AX. CF and OF are set if the upper half is
+// significant; the other flags are undefined and left alone.
+fn lift_multiply(b: &mut Builder, inst: Instruction, signed: bool) {
+    let src = inst.unpack_op1();
+    let width = operand_width(src);
+    let (double, low_reg, high_reg) = halves(width);
+    let extend = if signed { Op::SEXT } else { Op::ZEXT };
+
+    let a = b.read(low_reg, width);
+    let c = b.read(src, width);
+    let a = b.unop(extend, double, a);
+    let c = b.unop(extend, double, c);
+    let product = b.op(Op::MUL, double, a, c);
+
+    let low = b.unop(Op::TRUNC, width, product);
+    let high = b.op(Op::SHR, double, product, constant(width.bits(), double));
+    let high = b.unop(Op::TRUNC, width, high);
+
+    let fits = if signed {
+        let sign = b.op(Op::SAR, width, low, constant(width.bits() - 1, width));
+        b.op(Op::CMPEQ, Width::Bit, high, sign)
+    } else {
+        b.unop(Op::CMPZ, Width::Bit, high)
+    };
+    b.emit(ir::Instruction::ternary(Op::XOR, Arg::Flag(FLAG_CF), fits, bit(1)));
+    b.set_flag(FLAG_OF, Arg::Flag(FLAG_CF));
+
+    b.write(low_reg, low);
+    b.write(high_reg, high);
+}
+
+// DIV divides AX or DX:AX by the operand, leaving the quotient in AL
+// or AX and the remainder in AH or DX. The divide error interrupt
+// isn't modeled.
+fn lift_divide(b: &mut Builder, inst: Instruction) {
+    let src = inst.unpack_op1();
+    let width = operand_width(src);
+    let (double, low_reg, high_reg) = halves(width);
+
+    let low = b.read(low_reg, width);
+    let high = b.read(high_reg, width);
+    let low = b.unop(Op::ZEXT, double, low);
+    let high = b.unop(Op::ZEXT, double, high);
+    let high = b.op(Op::SHL, double, high, constant(width.bits(), double));
+    let dividend = b.op(Op::OR, double, high, low);
+
+    let divisor = b.read(src, width);
+    let divisor = b.unop(Op::ZEXT, double, divisor);
+    let quotient = b.op(Op::DIV, double, dividend, divisor);
+    let remainder = b.op(Op::MOD, double, dividend, divisor);
+
+    let quotient = b.unop(Op::TRUNC, width, quotient);
+    let remainder = b.unop(Op::TRUNC, width, remainder);
+    b.write(low_reg, quotient);
+    b.write(high_reg, remainder);
+}
+
+// Returns the double width and the registers holding the low and high
+// halves of a multiplication or division of the given width.
+fn halves(width: Width) -> (Width, Operand, Operand) {
+    match width {
+        Width::Byte => (Width::Word,
+            Operand::Register8(Register::AL), Operand::Register8(Register::AH)),
+        _ => (Width::Long,
+            Operand::Register16(Register::AX), Operand::Register16(Register::DX))
+    }
+}
+
+// String operations read from DS:SI and write to ES:DI, moving both
+// forward or backward by the operand size depending on DF. With a REP
+// prefix, the operation is wrapped in a loop that runs CX times; for
+// CMPS and SCAS, REPZ and REPNZ also stop when ZF is clear or set.
+fn lift_string(b: &mut Builder, inst: Instruction) {
+    let width = match inst.mnemonic {
+        Mnemonic::MOVSB | Mnemonic::CMPSB | Mnemonic::STOSB
+        | Mnemonic::LODSB | Mnemonic::SCASB => Width::Byte,
+        _ => Width::Word
+    };
+    let accumulator = match width {
+        Width::Byte => Operand::Register8(Register::AL),
+        _ => Operand::Register16(Register::AX)
+    };
+
+    let mut exits = Vec::new();
+    let cx = reg16(Register::CX);
+
+    if inst.rep_prefix.is_some() {
+        let done = b.unop(Op::CMPZ, Width::Bit, cx);
+        exits.push(b.ir.len());
+        b.emit(ir::Instruction::binary(Op::JMPNZ, done, Arg::Local(0)));
+    }
+
+    let step = b.string_step(width);
+
+    match inst.mnemonic {
+        Mnemonic::MOVSB | Mnemonic::MOVSW => {
+            let source = b.string_address(Register::DS, Register::SI);
+            let value = b.temp(width);
+            b.emit(ir::Instruction::binary(Op::LDM, value, source));
+            let destination = b.string_address(Register::ES, Register::DI);
+            b.emit(ir::Instruction::binary(Op::STM, destination, value));
+            b.advance(Register::SI, step);
+            b.advance(Register::DI, step);
+        },
+        Mnemonic::STOSB | Mnemonic::STOSW => {
+            let value = b.read(accumulator, width);
+            let destination = b.string_address(Register::ES, Register::DI);
+            b.emit(ir::Instruction::binary(Op::STM, destination, value));
+            b.advance(Register::DI, step);
+        },
+        Mnemonic::LODSB | Mnemonic::LODSW => {
+            let source = b.string_address(Register::DS, Register::SI);
+            let value = b.temp(width);
+            b.emit(ir::Instruction::binary(Op::LDM, value, source));
+            b.write(accumulator, value);
+            b.advance(Register::SI, step);
+        },
+        Mnemonic::CMPSB | Mnemonic::CMPSW => {
+            let source = b.string_address(Register::DS, Register::SI);
+            let a = b.temp(width);
+            b.emit(ir::Instruction::binary(Op::LDM, a, source));
+            let destination = b.string_address(Register::ES, Register::DI);
+            let c = b.temp(width);
+            b.emit(ir::Instruction::binary(Op::LDM, c, destination));
+            b.subtract(a, c, false, true);
+            b.advance(Register::SI, step);
+            b.advance(Register::DI, step);
+        },
+        _ => {
+            let a = b.read(accumulator, width);
+            let destination = b.string_address(Register::ES, Register::DI);
+            let c = b.temp(width);
+            b.emit(ir::Instruction::binary(Op::LDM, c, destination));
+            b.subtract(a, c, false, true);
+            b.advance(Register::DI, step);
+        }
+    }
+
+    if let Some(prefix) = inst.rep_prefix {
+        b.emit(ir::Instruction::ternary(Op::SUB, cx, cx, word(1)));
+
+        let compares = match inst.mnemonic {
+            Mnemonic::CMPSB | Mnemonic::CMPSW | Mnemonic::SCASB | Mnemonic::SCASW => true,
+            _ => false
+        };
+        if compares {
+            let exit = if prefix == Mnemonic::REPZ { Op::JMPZ } else { Op::JMPNZ };
+            exits.push(b.ir.len());
+            b.emit(ir::Instruction::binary(exit, Arg::Flag(FLAG_ZF), Arg::Local(0)));
+        }
+
+        b.emit(ir::Instruction::unary(Op::JMP, Arg::Local(0)));
+
+        let end = b.ir.len();
+        b.emit(ir::Instruction::new(Op::NOP));
+        for index in exits {
+            b.ir[index].op2 = Some(Arg::Local(end));
+        }
+    }
+}
+
+// Accumulates the IR for one native instruction, numbering temporaries
+// as they're needed.
+struct Builder {
+    ir: Vec<ir::Instruction>,
+    temps: usize
+}
+
+impl Builder {
+    fn new() -> Builder {
+        Builder {
+            ir: Vec::new(),
+            temps: 0
+        }
+    }
+
+    fn emit(&mut self, instruction: ir::Instruction) {
+        self.ir.push(instruction);
+    }
+
+    fn unsupported(&mut self) {
+        self.emit(ir::Instruction::new(Op::UNSUPPORTED));
+    }
+
+    fn temp(&mut self, width: Width) -> Arg {
+        self.temps += 1;
+        Arg::Temp(self.temps - 1, width)
+    }
+
+    // Emits dst <- a op c into a new temporary and returns it.
+    fn op(&mut self, mnemonic: Op, width: Width, a: Arg, c: Arg) -> Arg {
+        let dst = self.temp(width);
+        self.emit(ir::Instruction::ternary(mnemonic, dst, a, c));
+        dst
+    }
+
+    // Emits dst <- op a into a new temporary and returns it.
+    fn unop(&mut self, mnemonic: Op, width: Width, a: Arg) -> Arg {
+        let dst = self.temp(width);
+        self.emit(ir::Instruction::binary(mnemonic, dst, a));
+        dst
+    }
+
+    fn copy(&mut self, value: Arg) -> Arg {
+        let width = value.width().unwrap_or(Width::Word);
+        self.unop(Op::STR, width, value)
+    }
+
+    // Zero-extends or truncates value to width.
+    fn resize(&mut self, value: Arg, width: Width) -> Arg {
+        match value.width() {
+            Some(current) if current.bits() < width.bits() => self.unop(Op::ZEXT, width, value),
+            Some(current) if current.bits() > width.bits() => self.unop(Op::TRUNC, width, value),
+            _ => value
+        }
+    }
+
+    // Returns the value of operand as width. Immediates are sign
+    // extended, as they are for instructions like 83 /0.
+    fn read(&mut self, operand: Operand, width: Width) -> Arg {
+        let value = match operand {
+            Operand::Imm8(value) => return constant(value as i32 as u32, width),
+            Operand::Imm16(value) => return constant(value as i32 as u32, width),
+            Operand::Register16(register) => reg16(register),
+            Operand::Register8(register) => {
+                let whole = reg16(register);
+                let part = if is_high_byte(register) {
+                    self.op(Op::SHR, Width::Word, whole, word(8))
+                } else {
+                    whole
+                };
+                self.unop(Op::TRUNC, Width::Byte, part)
+            },
+            Operand::Pointer(pointer) => {
+                let address = self.address(pointer);
+                let value = self.temp(pointer_width(pointer));
+                self.emit(ir::Instruction::binary(Op::LDM, value, address));
+                value
+            }
+        };
+        self.resize(value, width)
+    }
+
+    fn write(&mut self, operand: Operand, value: Arg) {
+        match operand {
+            Operand::Register16(register) => {
+                let value = self.resize(value, Width::Word);
+                self.emit(ir::Instruction::binary(Op::STR, reg16(register), value));
+            },
+            Operand::Register8(register) => {
+                let whole = reg16(register);
+                let value = self.resize(value, Width::Byte);
+                let mut part = self.unop(Op::ZEXT, Width::Word, value);
+                let keep = if is_high_byte(register) {
+                    part = self.op(Op::SHL, Width::Word, part, word(8));
+                    0x00ff
+                } else {
+                    0xff00
+                };
+                self.emit(ir::Instruction::ternary(Op::AND, whole, whole, word(keep)));
+                self.emit(ir::Instruction::ternary(Op::OR, whole, whole, part));
+            },
+            Operand::Pointer(pointer) => {
+                let value = self.resize(value, pointer_width(pointer));
+                let address = self.address(pointer);
+                self.emit(ir::Instruction::binary(Op::STM, address, value));
+            },
+            _ => self.unsupported()
+        }
+    }
+
+    // Returns the offset part of a memory operand.
+    fn effective_offset(&mut self, value: PtrType) -> Arg {
+        let (base, index, displacement) = match value {
+            PtrType::Disp16(disp) => (None, None, disp),
+            PtrType::Reg(reg) => (Some(reg), None, 0),
+            PtrType::RegReg(reg1, reg2) => (Some(reg1), Some(reg2), 0),
+            PtrType::RegDisp8(reg, disp) => (Some(reg), None, disp as i8 as u16),
+            PtrType::RegRegDisp8(reg1, reg2, disp) => (Some(reg1), Some(reg2), disp as i8 as u16),
+            PtrType::RegDisp16(reg, disp) => (Some(reg), None, disp),
+            PtrType::RegRegDisp16(reg1, reg2, disp) => (Some(reg1), Some(reg2), disp)
+        };
+
+        let mut terms: Vec<Arg> = [base, index].iter()
+            .filter_map(|register| *register)
+            .map(reg16)
+            .collect();
+        if displacement != 0 || terms.is_empty() {
+            terms.push(word(displacement as u32));
+        }
+
+        let mut offset = terms[0];
+        for &term in terms[1..].iter() {
+            offset = self.op(Op::ADD, Width::Word, offset, term);
+        }
+        offset
+    }
+
+    // Returns the linear address of a memory operand. The decoder marks
+    // operands without an override as DS, but those based on BP use SS.
+    fn address(&mut self, pointer: Pointer) -> Arg {
+        let uses_bp = match pointer.value {
+            PtrType::Reg(Register::BP) | PtrType::RegReg(Register::BP, _)
+            | PtrType::RegDisp8(Register::BP, _) | PtrType::RegRegDisp8(Register::BP, _, _)
+            | PtrType::RegDisp16(Register::BP, _) | PtrType::RegRegDisp16(Register::BP, _, _) => true,
+            _ => false
+        };
+        let segment = if pointer.segment == Register::DS && uses_bp {
+            Register::SS
+        } else {
+            pointer.segment
+        };
+
+        let offset = self.effective_offset(pointer.value);
+        self.linear(segment, offset)
+    }
+
+    fn linear(&mut self, segment: Register, offset: Arg) -> Arg {
+        let base = self.unop(Op::ZEXT, Width::Long, reg16(segment));
+        let base = self.op(Op::SHL, Width::Long, base, Arg::Const(4, Width::Long));
+        let offset = self.unop(Op::ZEXT, Width::Long, offset);
+        self.op(Op::ADD, Width::Long, base, offset)
+    }
+
+    fn string_address(&mut self, segment: Register, index: Register) -> Arg {
+        self.linear(segment, reg16(index))
+    }
+
+    // Returns the amount SI and DI move by: the operand size, negated
+    // if DF is set.
+    fn string_step(&mut self, width: Width) -> Arg {
+        let size = width.bits() / 8;
+        let direction = self.unop(Op::ZEXT, Width::Word, Arg::Flag(FLAG_DF));
+        let back = self.op(Op::MUL, Width::Word, direction, word(2 * size));
+        self.op(Op::SUB, Width::Word, word(size), back)
+    }
+
+    fn advance(&mut self, register: Register, step: Arg) {
+        let register = reg16(register);
+        self.emit(ir::Instruction::ternary(Op::ADD, register, register, step));
+    }
+
+    fn push(&mut self, value: Arg) {
+        let sp = reg16(Register::SP);
+        self.emit(ir::Instruction::ternary(Op::SUB, sp, sp, word(2)));
+        let address = self.linear(Register::SS, sp);
+        self.emit(ir::Instruction::binary(Op::STM, address, value));
+    }
+
+    fn pop(&mut self) -> Arg {
+        let sp = reg16(Register::SP);
+        let address = self.linear(Register::SS, sp);
+        let value = self.temp(Width::Word);
+        self.emit(ir::Instruction::binary(Op::LDM, value, address));
+        self.emit(ir::Instruction::ternary(Op::ADD, sp, sp, word(2)));
+        value
+    }
+
+    fn port(&mut self, operand: Operand) -> Arg {
+        match operand {
+            Operand::Imm8(port) => Arg::Port(port as u8 as usize),
+            _ => {
+                let dx = reg16(Register::DX);
+                self.emit(ir::Instruction::binary(Op::OUT, Arg::Port(PORT_SELECT), dx));
+                Arg::Port(PORT_DX)
+            }
+        }
+    }
+
+    fn set_flag(&mut self, flag: usize, value: Arg) {
+        self.emit(ir::Instruction::binary(Op::STR, Arg::Flag(flag), value));
+    }
+
+    // Sets ZF, SF and PF from result.
+    fn result_flags(&mut self, result: Arg) {
+        self.emit(ir::Instruction::binary(Op::CMPZ, Arg::Flag(FLAG_ZF), result));
+        self.emit(ir::Instruction::binary(Op::SIGN, Arg::Flag(FLAG_SF), result));
+        self.emit(ir::Instruction::binary(Op::PARITY, Arg::Flag(FLAG_PF), result));
+    }
+
+    // Sets AF to the carry or borrow out of bit 3.
+    fn adjust_flag(&mut self, a: Arg, c: Arg, result: Arg) {
+        let width = result.width().unwrap_or(Width::Word);
+        let bits = self.op(Op::XOR, width, a, c);
+        let bits = self.op(Op::XOR, width, bits, result);
+        let bits = self.op(Op::SHR, width, bits, constant(4, width));
+        self.emit(ir::Instruction::binary(Op::TRUNC, Arg::Flag(FLAG_AF), bits));
+    }
+
+    // OF = SF ^ CF, as after a one-bit left shift or rotate.
+    fn sign_xor_carry_overflow(&mut self, result: Arg) {
+        let sign = self.unop(Op::SIGN, Width::Bit, result);
+        self.emit(ir::Instruction::ternary(Op::XOR, Arg::Flag(FLAG_OF), sign, Arg::Flag(FLAG_CF)));
+    }
+
+    // OF = the top two bits of result differ, as after a one-bit right
+    // rotate.
+    fn top_bits_overflow(&mut self, result: Arg) {
+        let width = result.width().unwrap_or(Width::Word);
+        let sign = self.unop(Op::SIGN, Width::Bit, result);
+        let shifted = self.op(Op::SHL, width, result, constant(1, width));
+        let next = self.unop(Op::SIGN, Width::Bit, shifted);
+        self.emit(ir::Instruction::ternary(Op::XOR, Arg::Flag(FLAG_OF), sign, next));
+    }
+
+    // Returns a + c, plus CF if carry is set, setting the flags as ADD
+    // and ADC do. CF is left alone if set_carry is false, as for INC.
+    fn add(&mut self, a: Arg, c: Arg, carry: bool, set_carry: bool) -> Arg {
+        let width = a.width().unwrap_or(Width::Word);
+
+        let result = if carry {
+            let carry_in = self.unop(Op::ZEXT, width, Arg::Flag(FLAG_CF));
+            let sum = self.op(Op::ADD, width, a, c);
+            let result = self.op(Op::ADD, width, sum, carry_in);
+            let first = self.op(Op::CARRY, Width::Bit, a, c);
+            let second = self.op(Op::CARRY, Width::Bit, sum, carry_in);
+            self.emit(ir::Instruction::ternary(Op::OR, Arg::Flag(FLAG_CF), first, second));
+
+            // OF = a and c have the same sign, which differs from the
+            // result's.
+            let same = self.op(Op::XOR, width, a, c);
+            let same = self.unop(Op::NOT, width, same);
+            let changed = self.op(Op::XOR, width, a, result);
+            let overflow = self.op(Op::AND, width, same, changed);
+            self.emit(ir::Instruction::binary(Op::SIGN, Arg::Flag(FLAG_OF), overflow));
+            result
+        } else {
+            let result = self.op(Op::ADD, width, a, c);
+            if set_carry {
+                self.emit(ir::Instruction::ternary(Op::CARRY, Arg::Flag(FLAG_CF), a, c));
+            }
+            self.emit(ir::Instruction::ternary(Op::OVF, Arg::Flag(FLAG_OF), a, c));
+            result
+        };
+
+        self.adjust_flag(a, c, result);
+        self.result_flags(result);
+        result
+    }
+
+    // Returns a - c, minus CF if borrow is set, setting the flags as
+    // SUB, SBB and CMP do. CF is left alone if set_carry is false, as
+    // for DEC.
+    fn subtract(&mut self, a: Arg, c: Arg, borrow: bool, set_carry: bool) -> Arg {
+        let width = a.width().unwrap_or(Width::Word);
+
+        let result = if borrow {
+            let borrow_in = self.unop(Op::ZEXT, width, Arg::Flag(FLAG_CF));
+            let difference = self.op(Op::SUB, width, a, c);
+            let result = self.op(Op::SUB, width, difference, borrow_in);
+            let first = self.op(Op::BORROW, Width::Bit, a, c);
+            let second = self.op(Op::BORROW, Width::Bit, difference, borrow_in);
+            self.emit(ir::Instruction::ternary(Op::OR, Arg::Flag(FLAG_CF), first, second));
+            result
+        } else {
+            let result = self.op(Op::SUB, width, a, c);
+            if set_carry {
+                self.emit(ir::Instruction::ternary(Op::BORROW, Arg::Flag(FLAG_CF), a, c));
+            }
+            result
+        };
+
+        // OF = a and c have different signs, and the result's sign
+        // differs from a's.
+        let different = self.op(Op::XOR, width, a, c);
+        let changed = self.op(Op::XOR, width, a, result);
+        let overflow = self.op(Op::AND, width, different, changed);
+        self.emit(ir::Instruction::binary(Op::SIGN, Arg::Flag(FLAG_OF), overflow));
+
+        self.adjust_flag(a, c, result);
+        self.result_flags(result);
+        result
+    }
+
+    // Returns the flag condition tested by a conditional jump, and
+    // whether the jump is taken when it's set.
+    fn condition(&mut self, mnemonic: Mnemonic) -> (Arg, bool) {
+        let flag = Arg::Flag;
+        match mnemonic {
+            Mnemonic::JO => (flag(FLAG_OF), true),
+            Mnemonic::JNO => (flag(FLAG_OF), false),
+            Mnemonic::JB => (flag(FLAG_CF), true),
+            Mnemonic::JNB => (flag(FLAG_CF), false),
+            Mnemonic::JZ => (flag(FLAG_ZF), true),
+            Mnemonic::JNZ => (flag(FLAG_ZF), false),
+            Mnemonic::JS => (flag(FLAG_SF), true),
+            Mnemonic::JNS => (flag(FLAG_SF), false),
+            Mnemonic::JP => (flag(FLAG_PF), true),
+            Mnemonic::JNP => (flag(FLAG_PF), false),
+            Mnemonic::JBE | Mnemonic::JNBE => {
+                let condition = self.op(Op::OR, Width::Bit, flag(FLAG_CF), flag(FLAG_ZF));
+                (condition, mnemonic == Mnemonic::JBE)
+            },
+            Mnemonic::JL | Mnemonic::JNL => {
+                let condition = self.op(Op::XOR, Width::Bit, flag(FLAG_SF), flag(FLAG_OF));
+                (condition, mnemonic == Mnemonic::JL)
+            },
+            _ => {
+                let less = self.op(Op::XOR, Width::Bit, flag(FLAG_SF), flag(FLAG_OF));
+                let condition = self.op(Op::OR, Width::Bit, flag(FLAG_ZF), less);
+                (condition, mnemonic == Mnemonic::JLE)
+            }
+        }
+    }
+}
+
+fn constant(value: u32, width: Width) -> Arg {
+    Arg::Const(value & width.mask(), width)
+}
+
+fn word(value: u32) -> Arg {
+    constant(value, Width::Word)
+}
+
+fn bit(value: u32) -> Arg {
+    constant(value, Width::Bit)
+}
+
+fn reg16(register: Register) -> Arg {
+    Arg::Reg(register_index(register), Width::Word)
+}
+
+// Returns the IR register holding register, or the register it's part
+// of.
+fn register_index(register: Register) -> usize {
+    let whole = match register {
+        Register::AL | Register::AH => Register::AX,
+        Register::BL | Register::BH => Register::BX,
+        Register::CL | Register::CH => Register::CX,
+        Register::DL | Register::DH => Register::DX,
+        _ => register
+    };
+    REGISTERS.iter().position(|&candidate| candidate == whole).unwrap()
+}
+
+// Returns the registers DOS functions take their arguments in, for
+// queries of what sets them before INT 21h, or None for other
+// instructions. Which of them a function reads depends on AH.
+pub fn dos_call_reads(inst: Instruction) -> Option<Vec<ir::Location>> {
+    match (inst.mnemonic, inst.op1) {
+        (Mnemonic::INT, Some(Operand::Imm8(0x21))) => Some([
+            Register::AX, Register::BX, Register::CX, Register::DX,
+            Register::SI, Register::DI, Register::DS, Register::ES
+        ].iter().map(|&register| ir::Location::Reg(register_index(register))).collect()),
+        _ => None
+    }
+}
+
+fn is_high_byte(register: Register) -> bool {
+    match register {
+        Register::AH | Register::BH | Register::CH | Register::DH => true,
+        _ => false
+    }
+}
+
+fn pointer_width(pointer: Pointer) -> Width {
+    match pointer.size {
+        0 => Width::Byte,
+        _ => Width::Word
+    }
+}
+
+fn operand_width(operand: Operand) -> Width {
+    match operand {
+        Operand::Register8(_) | Operand::Imm8(_) => Width::Byte,
+        Operand::Register16(_) | Operand::Imm16(_) => Width::Word,
+        Operand::Pointer(pointer) => pointer_width(pointer)
+    }
+}
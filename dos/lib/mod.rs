@@ -1,5 +1,6 @@
 pub mod arch;
 pub mod dos;
 pub mod state;
+pub mod lift;
 mod dis;
 mod sim;
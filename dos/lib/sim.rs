@@ -4,10 +4,13 @@ use x86::state::*;
 use x86::arch::*;
 use std::collections::HashSet;
 
-pub fn simulate_next_instruction<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, context: &C, instruction: Instruction) -> SimResult<State<'a>> {
+pub fn simulate_next_instruction<'a, C: SimulatorTrait<State<'a>, Instruction>>(mut state: State<'a>, context: &C, instruction: Instruction) -> SimResult<State<'a>> {
     println!("{}", instruction);
-    if instruction.mnemonic.is_branch() {
-        SimResult::Branch(branch(state, instruction, context))
+    if instruction.mnemonic.is_branch() || instruction.mnemonic == Mnemonic::RET {
+        match branch(state.clone(), instruction, context) {
+            Ok((states, labels)) => SimResult::Branch(states, labels),
+            Err(error) => SimResult::Error(state, error)
+        }
     } else if instruction.mnemonic == Mnemonic::INT {
         state.ip = state.ip.wrapping_add(instruction.length as u16);
         match context.simulate_system_call(state, instruction) {
@@ -15,39 +18,70 @@ pub fn simulate_next_instruction<'a, C: Context<State<'a>, Instruction>>(mut sta
             Some(state) => SimResult::State(state)
         }
     } else {
-        SimResult::State(simulate_instruction(state, instruction))
+        let offset = C::next_inst_offset(&state);
+        match simulate_instruction(state, instruction, offset) {
+            SimResult::State(mut state) => {
+                state.ip = state.ip.wrapping_add(instruction.length as u16);
+                SimResult::State(state)
+            },
+            result => result
+        }
+    }
+}
+
+fn unresolved<'a, C: SimulatorTrait<State<'a>, Instruction>>(state: &State<'a>, instruction: Instruction, reason: &str) -> DecompilerError {
+    DecompilerError::UnresolvedBranch {
+        offset: C::next_inst_offset(state),
+        instruction: format!("{}", instruction),
+        reason: reason.to_string()
+    }
+}
+
+fn unsupported<'a, C: SimulatorTrait<State<'a>, Instruction>>(state: &State<'a>, instruction: Instruction, reason: &str) -> DecompilerError {
+    unsupported_at(C::next_inst_offset(state), instruction, reason)
+}
+
+fn unsupported_at(offset: usize, instruction: Instruction, reason: &str) -> DecompilerError {
+    DecompilerError::UnsupportedInstruction {
+        offset,
+        instruction: format!("{}", instruction),
+        reason: reason.to_string()
     }
 }
 
-fn branch<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, instruction: Instruction, context: &C) -> (Vec<State<'a>>, Vec<usize>) {
+fn branch<'a, C: SimulatorTrait<State<'a>, Instruction>>(mut state: State<'a>, instruction: Instruction, _context: &C) -> Result<(Vec<State<'a>>, Vec<usize>), DecompilerError> {
     let mut new_states = Vec::new();
     let mut new_labels = Vec::new();
 
     if instruction.mnemonic == Mnemonic::RET {
-        let (state, word) = pop_word(state);
-        match word {
-            Word::Undefined => panic!("can't jump to undefined location."),
-            Word::AnyValue => panic!("can't jump to unlimited location."),
+        let (popped, word) = match pop_word(state.clone()) {
+            Ok(popped) => popped,
+            Err(reason) => return Err(unsupported::<C>(&state, instruction, &reason))
+        };
+        match word.expand() {
+            Word::Undefined => return Err(unresolved::<C>(&state, instruction, "can't jump to undefined location.")),
+            Word::AnyValue => return Err(unresolved::<C>(&state, instruction, "can't jump to unlimited location.")),
             Word::Int(ref set) => {
                 for offset in set {
-                    let mut new_state = state.clone();
+                    let mut new_state = popped.clone();
                     new_state.ip = *offset;
+                    new_labels.push(C::next_inst_offset(&new_state));
                     new_states.push(new_state);
                 }
             },
-            _ => panic!("Unsupported return type.")
+            _ => return Err(unresolved::<C>(&state, instruction, "unsupported return type."))
         };
-        return (new_states, new_labels);
+        return Ok((new_states, new_labels));
     }
 
     let offsets: Vec<u16> = if instruction.op1 == None {
         Vec::new()
     } else {
-        match state.get_value(instruction.unpack_op1()) {
+        match state.get_value(instruction.unpack_op1()).expand() {
             Value::Word(Word::Undefined) | Value::Byte(Byte::Undefined) =>
-                panic!("can't jump to undefined location."),
+                return Err(unresolved::<C>(&state, instruction, "can't jump to undefined location.")),
             Value::Word(Word::AnyValue) | Value::Byte(Byte::AnyValue) =>
-                panic!("can't jump to unlimited location."),
+                return Err(unresolved::<C>(&state, instruction, "can't jump to unlimited location.")),
             Value::Word(Word::Int(set)) => {
                 let mut vec = Vec::new();
                 for word in set {
@@ -62,7 +96,7 @@ fn branch<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, instruct
                 };
                 vec
             },
-            _ => panic!("Unsupport jump offset type.")
+            _ => return Err(unresolved::<C>(&state, instruction, "unsupported jump offset type."))
         }
     };
 
@@ -75,7 +109,10 @@ fn branch<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, instruct
             jump = true; cont = false; flag = None;
     } else if instruction.mnemonic == Mnemonic::LOOP {
         let (new_cx, _) =
-            apply_to_words(state.get_reg16(Register::CX), Word::new(1), Mnemonic::SUB);
+            match apply_to_words(state.get_reg16(Register::CX), Word::new(1), Mnemonic::SUB) {
+                Ok(result) => result,
+                Err(reason) => return Err(unsupported::<C>(&state, instruction, &reason))
+            };
         state = state.set_reg16(Register::CX, new_cx);
 
         let cx = state.get_reg16(Register::CX);
@@ -89,13 +126,13 @@ fn branch<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, instruct
             Mnemonic::JZ => (Flag::Zero, true),
             Mnemonic::JNZ => (Flag::Zero, false),
             Mnemonic::LOOP => (Flag::Zero, true),
-            _ => panic!("jump opeand not yet implemented.")
+            _ => return Err(unsupported::<C>(&state, instruction, "conditional jump not yet implemented."))
         };
 
         let flag_bit = state.get_flag(flag_to_check);
 
         if flag_bit == Bit::Undefined {
-            panic!("flag bit for conditional jump is undefined!");
+            return Err(unresolved::<C>(&state, instruction, "flag bit for conditional jump is undefined."));
         };
 
         jump = flag_bit.has_truth_value(true);
@@ -109,10 +146,13 @@ fn branch<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, instruct
             new_state.ip = new_state.ip.wrapping_add(instruction.length as u16);
             if instruction.mnemonic == Mnemonic::CALL {
                 let return_address = Word::new(new_state.ip);
-                new_state = push_word(new_state, return_address);
+                new_state = match push_word(new_state, return_address) {
+                    Ok(pushed) => pushed,
+                    Err(reason) => return Err(unsupported::<C>(&state, instruction, &reason))
+                };
             }
             new_state.ip = new_state.ip.wrapping_add(offset as u16);
-            new_labels.push(context.next_inst_offset(&new_state));
+            new_labels.push(C::next_inst_offset(&new_state));
             new_states.push(
                 match flag {
                     Some((flag_to_check, truth_value)) =>
@@ -128,6 +168,7 @@ fn branch<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, instruct
             state = state.set_reg16(Register::CS, Word::new(0));
         };
         state.ip = state.ip.wrapping_add(instruction.length as u16);
+        new_labels.push(C::next_inst_offset(&state));
         new_states.push(
             match flag {
                 Some((flag_to_check, truth_value)) =>
@@ -137,68 +178,73 @@ fn branch<'a, C: Context<State<'a>, Instruction>>(mut state: State<'a>, instruct
         );
     }
 
-    (new_states, new_labels)
+    Ok((new_states, new_labels))
 }
 
-fn simulate_instruction<'a>(mut state: State<'a>, inst: Instruction) -> State<'a> {
-    state.ip = state.ip.wrapping_add(inst.length as u16);
+// Simulates an instruction at offset that neither branches nor calls
+// the system. The IP is left for the caller to advance.
+fn simulate_instruction<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
     match inst.mnemonic {
         Mnemonic::DEC | Mnemonic::INC =>
-            simulate_unary_operator(state, inst),
+            simulate_unary_operator(state, inst, offset),
         Mnemonic::ADD | Mnemonic::AND | Mnemonic::OR | Mnemonic::SHL =>
-            simulate_binary_operator(state, inst),
+            simulate_binary_operator(state, inst, offset),
         Mnemonic::XOR | Mnemonic::SUB =>
-            simulate_nullifying_operator(state, inst),
-        Mnemonic::CLD => state.clear_flag(Flag::Direction),
-        Mnemonic::CMP => simulate_cmp(state, inst),
-        Mnemonic::IN => simulate_in(state, inst),
-        Mnemonic::OUT => state,
-        Mnemonic::MOV => simulate_mov(state, inst),
-        Mnemonic::NOP => state,
-        Mnemonic::POP => simulate_pop(state, inst),
-        Mnemonic::PUSH => simulate_push(state, inst),
-        _ => panic!("Instruction:\n{}\nunimplemented in simulator.", inst)
+            simulate_nullifying_operator(state, inst, offset),
+        Mnemonic::CLD => SimResult::State(state.clear_flag(Flag::Direction)),
+        Mnemonic::CMP => simulate_cmp(state, inst, offset),
+        Mnemonic::IN => simulate_in(state, inst, offset),
+        Mnemonic::OUT => SimResult::State(state),
+        Mnemonic::MOV => SimResult::State(simulate_mov(state, inst)),
+        Mnemonic::NOP => SimResult::State(state),
+        Mnemonic::POP => simulate_pop(state, inst, offset),
+        Mnemonic::PUSH => simulate_push(state, inst, offset),
+        _ => SimResult::Error(state, unsupported_at(offset, inst, "unimplemented in simulator."))
     }
 }
 
-fn simulate_unary_operator<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
+fn simulate_unary_operator<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
     let op = inst.unpack_op1();
-    let (result, flags) = apply_unary_op(state.get_value(op), inst.mnemonic);
-    state.set_value(op, result).set_flags(flags)
+    match apply_unary_op(state.get_value(op), inst.mnemonic) {
+        Ok((result, flags)) => SimResult::State(state.set_value(op, result).set_flags(flags)),
+        Err(reason) => SimResult::Error(state, unsupported_at(offset, inst, &reason))
+    }
 }
 
-fn simulate_binary_operator<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
+fn simulate_binary_operator<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
     let (op1, op2) = (inst.unpack_op1(), inst.unpack_op2());
-    let (result, flags) =
-        apply_binary_op(state.get_value(op1), state.get_value(op2), inst.mnemonic);
-    state.set_value(op1, result).set_flags(flags)
+    match apply_binary_op(state.get_value(op1), state.get_value(op2), inst.mnemonic) {
+        Ok((result, flags)) => SimResult::State(state.set_value(op1, result).set_flags(flags)),
+        Err(reason) => SimResult::Error(state, unsupported_at(offset, inst, &reason))
+    }
 }
 
-fn simulate_nullifying_operator<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
+fn simulate_nullifying_operator<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
     if inst.op1 == inst.op2 {
-        state.clear_value(inst.unpack_op1())
+        SimResult::State(state.clear_value(inst.unpack_op1())
             .set_flag(Flag::Zero, Bit::True)
             .set_flag(Flag::Overflow, Bit::False)
-            .set_flag(Flag::Carry, Bit::False)
+            .set_flag(Flag::Carry, Bit::False))
     } else {
-        simulate_binary_operator(state, inst)
+        simulate_binary_operator(state, inst, offset)
     }
 }
 
-fn simulate_cmp<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
+fn simulate_cmp<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
     let (op1, op2) = (inst.unpack_op1(), inst.unpack_op2());
-    let (_, flags) =
-        apply_binary_op(state.get_value(op1), state.get_value(op2), Mnemonic::SUB);
-    state.set_flags(flags)
+    match apply_binary_op(state.get_value(op1), state.get_value(op2), Mnemonic::SUB) {
+        Ok((_, flags)) => SimResult::State(state.set_flags(flags)),
+        Err(reason) => SimResult::Error(state, unsupported_at(offset, inst, &reason))
+    }
 }
 
-fn simulate_in<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
+fn simulate_in<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
     match inst.unpack_op1() {
         Operand::Register8(Register::AL) =>
-            state.set_reg8(Register::AL, Byte::AnyValue),
+            SimResult::State(state.set_reg8(Register::AL, Byte::AnyValue)),
         Operand::Register16(Register::AX) =>
-            state.set_reg16(Register::AX, Word::AnyValue),
-        _ => panic!("Wrong destination for IN instruction: {}", inst)
+            SimResult::State(state.set_reg16(Register::AX, Word::AnyValue)),
+        _ => SimResult::Error(state, unsupported_at(offset, inst, "wrong destination for IN instruction."))
     }
 }
 
@@ -208,72 +254,77 @@ fn simulate_mov<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
     state.set_value(op1, value)
 }
 
-fn simulate_pop<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
-    let (new_state, word) = pop_word(state);
-    new_state.set_word(inst.unpack_op1(), word)
+fn simulate_pop<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
+    match pop_word(state.clone()) {
+        Ok((new_state, word)) => SimResult::State(new_state.set_word(inst.unpack_op1(), word)),
+        Err(reason) => SimResult::Error(state, unsupported_at(offset, inst, &reason))
+    }
 }
 
-fn simulate_push<'a>(state: State<'a>, inst: Instruction) -> State<'a> {
+fn simulate_push<'a>(state: State<'a>, inst: Instruction, offset: usize) -> SimResult<State<'a>> {
     let word = state.get_word(inst.unpack_op1());
-    push_word(state, word)
+    match push_word(state.clone(), word) {
+        Ok(new_state) => SimResult::State(new_state),
+        Err(reason) => SimResult::Error(state, unsupported_at(offset, inst, &reason))
+    }
 }
 
-pub fn pop_word(state: State) -> (State, Word) {
+pub fn pop_word(state: State) -> Result<(State, Word), String> {
     let pointer = Operand::Pointer(
         Pointer::new(1, PtrType::Reg(Register::SP)).set_segment(Register::SS));
     let word = state.get_word(pointer);
     let (new_sp, _) =
-        apply_to_words(state.get_reg16(Register::SP), Word::new(2), Mnemonic::ADD);
-    (state.set_reg16(Register::SP, new_sp), word)
+        apply_to_words(state.get_reg16(Register::SP), Word::new(2), Mnemonic::ADD)?;
+    Ok((state.set_reg16(Register::SP, new_sp), word))
 }
 
-pub fn push_word(state: State, word: Word) -> State {
+pub fn push_word(state: State, word: Word) -> Result<State, String> {
     let (new_sp, _) =
-        apply_to_words(state.get_reg16(Register::SP), Word::new(2), Mnemonic::SUB);
+        apply_to_words(state.get_reg16(Register::SP), Word::new(2), Mnemonic::SUB)?;
     let pointer = Operand::Pointer(
         Pointer::new(1, PtrType::Reg(Register::SP)).set_segment(Register::SS));
-    state.set_reg16(Register::SP, new_sp).set_word(pointer, word)
+    Ok(state.set_reg16(Register::SP, new_sp).set_word(pointer, word))
 }
 
-fn apply_unary_op(op: Value, mnemonic: Mnemonic) -> (Value, Flags) {
+fn apply_unary_op(op: Value, mnemonic: Mnemonic) -> Result<(Value, Flags), String> {
     match op {
         Value::Byte(byte) => {
-            let (result, flags) = apply_to_byte(byte, mnemonic);
-            (Value::Byte(result), flags)
+            let (result, flags) = apply_to_byte(byte, mnemonic)?;
+            Ok((Value::Byte(result), flags))
         },
         Value::Word(word) => {
-            let (result, flags) = apply_to_word(word, mnemonic);
-            (Value::Word(result), flags)
+            let (result, flags) = apply_to_word(word, mnemonic)?;
+            Ok((Value::Word(result), flags))
         }
     }
 }
 
-fn apply_binary_op(op1: Value, op2: Value, op: Mnemonic) -> (Value, Flags) {
+fn apply_binary_op(op1: Value, op2: Value, op: Mnemonic) -> Result<(Value, Flags), String> {
     match (op1, op2) {
         (Value::Byte(byte1), Value::Byte(byte2)) => {
-            let (byte, flags) = apply_to_bytes(byte1, byte2, op);
-            (Value::Byte(byte), flags)
+            let (byte, flags) = apply_to_bytes(byte1, byte2, op)?;
+            Ok((Value::Byte(byte), flags))
         },
         (Value::Word(word1), Value::Word(word2)) => {
-            let (word, flags) = apply_to_words(word1, word2, op);
-            (Value::Word(word), flags)
+            let (word, flags) = apply_to_words(word1, word2, op)?;
+            Ok((Value::Word(word), flags))
         },
         (Value::Word(word1), Value::Byte(byte2)) => {
-            let (word, flags) = apply_to_words(word1, byte2.to_word(), op);
-            (Value::Word(word), flags)
+            let (word, flags) = apply_to_words(word1, byte2.to_word(), op)?;
+            Ok((Value::Word(word), flags))
         },
-        _ => panic!("Can't apply word source to byte target.")
+        _ => Err(String::from("can't apply word source to byte target."))
     }
 }
 
-fn apply_to_word(word: Word, op: Mnemonic) -> (Word, Flags) {
+fn apply_to_word(word: Word, op: Mnemonic) -> Result<(Word, Flags), String> {
     let new_word = if let Word::Bytes(bytel, byteh) = word {
         bytel.combine(byteh)
     } else {
         word
     };
     let mut flags = Flags::new();
-    match new_word {
+    Ok(match new_word.expand() {
         Word::Undefined => (Word::Undefined, flags),
         Word::AnyValue => (Word::AnyValue, Flags::new()
             .set(Flag::Sign, Bit::TrueAndFalse)
@@ -284,7 +335,7 @@ fn apply_to_word(word: Word, op: Mnemonic) -> (Word, Flags) {
             let mut zero_flag = Bit::new();
             for word in word_set {
                 let (result, new_flags) =
-                    apply_to_u16(word, op);
+                    apply_to_u16(word, op)?;
                 set.insert(result);
                 flags = flags.union(new_flags);
                 if result == 0 {
@@ -293,15 +344,15 @@ fn apply_to_word(word: Word, op: Mnemonic) -> (Word, Flags) {
                     zero_flag.add_false();
                 }
             };
-            (Word::Int(set), flags.set(Flag::Zero, zero_flag))
+            (Word::from_set(set), flags.set(Flag::Zero, zero_flag))
         },
-        _ => panic!("shouldn't be here")
-    }
+        _ => return Err(String::from("word can't be expanded."))
+    })
 }
 
-fn apply_to_byte(byte: Byte, op: Mnemonic) -> (Byte, Flags) {
+fn apply_to_byte(byte: Byte, op: Mnemonic) -> Result<(Byte, Flags), String> {
     let mut flags = Flags::new();
-    match byte {
+    Ok(match byte.expand() {
         Byte::Undefined => (Byte::Undefined, flags),
         Byte::AnyValue => (Byte::AnyValue, Flags::new()
             .set(Flag::Sign, Bit::TrueAndFalse)
@@ -312,7 +363,7 @@ fn apply_to_byte(byte: Byte, op: Mnemonic) -> (Byte, Flags) {
             let mut zero_flag = Bit::new();
             for byte in byte_set {
                 let (result, new_flags) =
-                    apply_to_u8(byte, op);
+                    apply_to_u8(byte, op)?;
                 set.insert(result);
                 flags = flags.union(new_flags);
                 if result == 0 {
@@ -321,12 +372,13 @@ fn apply_to_byte(byte: Byte, op: Mnemonic) -> (Byte, Flags) {
                     zero_flag.add_false();
                 }
             };
-            (Byte::Int(set), flags.set(Flag::Zero, zero_flag))
-        }
-    }
+            (Byte::from_set(set), flags.set(Flag::Zero, zero_flag))
+        },
+        _ => return Err(String::from("byte can't be expanded."))
+    })
 }
 
-fn apply_to_words(wordl: Word, wordr: Word, op: Mnemonic) -> (Word, Flags) {
+fn apply_to_words(wordl: Word, wordr: Word, op: Mnemonic) -> Result<(Word, Flags), String> {
     let word1 = if let Word::Bytes(bytel, byteh) = wordl {
         bytel.combine(byteh)
     } else {
@@ -337,8 +389,13 @@ fn apply_to_words(wordl: Word, wordr: Word, op: Mnemonic) -> (Word, Flags) {
     } else {
         wordr
     };
+    match op {
+        Mnemonic::AND | Mnemonic::OR | Mnemonic::XOR | Mnemonic::SHL =>
+            return apply_bitwise_to_words(word1, word2, op),
+        _ => ()
+    }
     let mut flags = Flags::new();
-    match word1 {
+    Ok(match word1.expand() {
         Word::Undefined => (Word::Undefined, flags),
         Word::AnyValue =>
             match word2 {
@@ -349,7 +406,7 @@ fn apply_to_words(wordl: Word, wordr: Word, op: Mnemonic) -> (Word, Flags) {
                     .set(Flag::Overflow, Bit::TrueAndFalse)
                 )
             },
-        Word::Int(set1) => match word2 {
+        Word::Int(set1) => match word2.expand() {
             Word::Undefined => (Word::Undefined, flags),
             Word::AnyValue => (Word::AnyValue, Flags::new()
                 .set(Flag::Sign, Bit::TrueAndFalse)
@@ -362,7 +419,7 @@ fn apply_to_words(wordl: Word, wordr: Word, op: Mnemonic) -> (Word, Flags) {
                 for word1 in set1 {
                     for word2 in set2.clone() {
                         let (result, new_flags) =
-                            apply_to_u16s(word1, word2, op);
+                            apply_to_u16s(word1, word2, op)?;
                         set.insert(result);
                         flags = flags.union(new_flags);
                         if result == 0 {
@@ -372,17 +429,22 @@ fn apply_to_words(wordl: Word, wordr: Word, op: Mnemonic) -> (Word, Flags) {
                         }
                     }
                 };
-                (Word::Int(set), flags.set(Flag::Zero, zero_flag))
+                (Word::from_set(set), flags.set(Flag::Zero, zero_flag))
             },
-            _ => panic!("shouldn't be here")
+            _ => return Err(String::from("word can't be expanded."))
         },
-        _ => panic!("shouldn't be here")
-    }
+        _ => return Err(String::from("word can't be expanded."))
+    })
 }
 
-fn apply_to_bytes(op1: Byte, op2: Byte, op: Mnemonic) -> (Byte, Flags) {
+fn apply_to_bytes(op1: Byte, op2: Byte, op: Mnemonic) -> Result<(Byte, Flags), String> {
+    match op {
+        Mnemonic::AND | Mnemonic::OR | Mnemonic::XOR | Mnemonic::SHL =>
+            return apply_bitwise_to_bytes(op1, op2, op),
+        _ => ()
+    }
     let mut flags = Flags::new();
-    match op1 {
+    Ok(match op1.expand() {
         Byte::Undefined => (Byte::Undefined, flags),
         Byte::AnyValue =>
             match op2 {
@@ -393,7 +455,7 @@ fn apply_to_bytes(op1: Byte, op2: Byte, op: Mnemonic) -> (Byte, Flags) {
                     .set(Flag::Overflow, Bit::TrueAndFalse)
                 )
             },
-        Byte::Int(set1) => match op2 {
+        Byte::Int(set1) => match op2.expand() {
             Byte::Undefined => (Byte::Undefined, flags),
             Byte::AnyValue => (Byte::AnyValue, Flags::new()
                 .set(Flag::Sign, Bit::TrueAndFalse)
@@ -406,7 +468,7 @@ fn apply_to_bytes(op1: Byte, op2: Byte, op: Mnemonic) -> (Byte, Flags) {
                 for byte1 in set1 {
                     for byte2 in set2.clone() {
                         let (result, new_flags) =
-                            apply_to_u8s(byte1, byte2, op);
+                            apply_to_u8s(byte1, byte2, op)?;
                         flags = flags.union(new_flags);
                         set.insert(result);
                         if result == 0 {
@@ -416,16 +478,74 @@ fn apply_to_bytes(op1: Byte, op2: Byte, op: Mnemonic) -> (Byte, Flags) {
                         }
                     }
                 };
-                (Byte::Int(set), flags.set(Flag::Zero, zero_flag))
-            }
-        }
+                (Byte::from_set(set), flags.set(Flag::Zero, zero_flag))
+            },
+            _ => return Err(String::from("byte can't be expanded."))
+        },
+        _ => return Err(String::from("byte can't be expanded."))
+    })
+}
+
+// Bitwise operators and shifts work on known bits rather than on each
+// value, so they stay precise when masking unknown registers.
+fn apply_bitwise_to_words(word1: Word, word2: Word, op: Mnemonic) -> Result<(Word, Flags), String> {
+    let word = match op {
+        Mnemonic::AND => word1.and(word2),
+        Mnemonic::OR => word1.or(word2),
+        Mnemonic::XOR => word1.xor(word2),
+        Mnemonic::SHL => match word2.expand() {
+            Word::Undefined => Word::Undefined,
+            Word::Int(counts) => counts.iter().fold(Word::Int(HashSet::new()),
+                |word, &count| word.union(word1.clone().shl(count as u32))),
+            _ => Word::AnyValue
+        },
+        _ => return Err(format!("{:?} is not bitwise.", op))
+    };
+    if let Word::Undefined = word {
+        return Ok((word, Flags::new()));
     }
+
+    let mut zero_flag = Bit::new();
+    if word.can_be(0) {
+        zero_flag.add_true();
+    }
+    if word.len() > 1 || !word.can_be(0) {
+        zero_flag.add_false();
+    }
+    Ok((word, Flags::new().set(Flag::Zero, zero_flag)))
 }
 
-fn apply_to_u16(word: u16, op: Mnemonic) -> (u16, Flags) {
-    match op {
+fn apply_bitwise_to_bytes(byte1: Byte, byte2: Byte, op: Mnemonic) -> Result<(Byte, Flags), String> {
+    let byte = match op {
+        Mnemonic::AND => byte1.and(byte2),
+        Mnemonic::OR => byte1.or(byte2),
+        Mnemonic::XOR => byte1.xor(byte2),
+        Mnemonic::SHL => match byte2.expand() {
+            Byte::Undefined => Byte::Undefined,
+            Byte::Int(counts) => counts.iter().fold(Byte::Int(HashSet::new()),
+                |byte, &count| byte.union(byte1.clone().shl(count as u32))),
+            _ => Byte::AnyValue
+        },
+        _ => return Err(format!("{:?} is not bitwise.", op))
+    };
+    if let Byte::Undefined = byte {
+        return Ok((byte, Flags::new()));
+    }
+
+    let mut zero_flag = Bit::new();
+    if byte.can_be(0) {
+        zero_flag.add_true();
+    }
+    if byte.len() > 1 || !byte.can_be(0) {
+        zero_flag.add_false();
+    }
+    Ok((byte, Flags::new().set(Flag::Zero, zero_flag)))
+}
+
+fn apply_to_u16(word: u16, op: Mnemonic) -> Result<(u16, Flags), String> {
+    Ok(match op {
         Mnemonic::INC => {
-            (word + 1, if word == 0xffff {
+            (word.wrapping_add(1), if word == 0xffff {
                 Flags::new().set(Flag::Overflow, Bit::True)
                     .set(Flag::Carry, Bit::True)
             } else {
@@ -434,7 +554,7 @@ fn apply_to_u16(word: u16, op: Mnemonic) -> (u16, Flags) {
             })
         },
         Mnemonic::DEC => {
-            ((word as i16 - 1) as u16, if word == 0x0000 {
+            (word.wrapping_sub(1), if word == 0x0000 {
                 Flags::new().set(Flag::Overflow, Bit::True)
                     .set(Flag::Carry, Bit::True)
             } else {
@@ -442,14 +562,14 @@ fn apply_to_u16(word: u16, op: Mnemonic) -> (u16, Flags) {
                     .set(Flag::Carry, Bit::False)
             })
         },
-        _ => panic!("Operation {:?} not implemented for words.", op)
-    }
+        _ => return Err(format!("{:?} not implemented for words.", op))
+    })
 }
 
-fn apply_to_u8(word: u8, op: Mnemonic) -> (u8, Flags) {
-    match op {
+fn apply_to_u8(word: u8, op: Mnemonic) -> Result<(u8, Flags), String> {
+    Ok(match op {
         Mnemonic::INC => {
-            (word + 1, if word == 0xff {
+            (word.wrapping_add(1), if word == 0xff {
                 Flags::new().set(Flag::Overflow, Bit::True)
                     .set(Flag::Carry, Bit::True)
             } else {
@@ -458,7 +578,7 @@ fn apply_to_u8(word: u8, op: Mnemonic) -> (u8, Flags) {
             })
         },
         Mnemonic::DEC => {
-            ((word as i8 - 1) as u8, if word == 0x0000 {
+            (word.wrapping_sub(1), if word == 0x0000 {
                 Flags::new().set(Flag::Overflow, Bit::True)
                     .set(Flag::Carry, Bit::True)
             } else {
@@ -466,12 +586,12 @@ fn apply_to_u8(word: u8, op: Mnemonic) -> (u8, Flags) {
                     .set(Flag::Carry, Bit::False)
             })
         },
-        _ => panic!("Operation {:?} not implemented for words.", op)
-    }
+        _ => return Err(format!("{:?} not implemented for words.", op))
+    })
 }
 
-fn apply_to_u16s(word1: u16, word2: u16, op: Mnemonic) -> (u16, Flags) {
-    match op {
+fn apply_to_u16s(word1: u16, word2: u16, op: Mnemonic) -> Result<(u16, Flags), String> {
+    Ok(match op {
         Mnemonic::ADD => {
             (word1.wrapping_add(word2), if 0xffff - word1 < word2 {
                 Flags::new().set(Flag::Overflow, Bit::True)
@@ -490,24 +610,12 @@ fn apply_to_u16s(word1: u16, word2: u16, op: Mnemonic) -> (u16, Flags) {
                     .set(Flag::Carry, Bit::False)
             })
         },
-        Mnemonic::AND => {
-            (word1 & word2, Flags::new())
-        },
-        Mnemonic::OR => {
-            (word1 | word2, Flags::new())
-        },
-        Mnemonic::XOR => {
-            (word1 ^ word2, Flags::new())
-        },
-        Mnemonic::SHL => {
-            (word1 << word2, Flags::new())
-        },
-        _ => panic!("Operation {:?} not implemented for words.", op)
-    }
+        _ => return Err(format!("{:?} not implemented for words.", op))
+    })
 }
 
-fn apply_to_u8s(byte1: u8, byte2: u8, op: Mnemonic) -> (u8, Flags) {
-    match op {
+fn apply_to_u8s(byte1: u8, byte2: u8, op: Mnemonic) -> Result<(u8, Flags), String> {
+    Ok(match op {
         Mnemonic::ADD => {
             (byte1.wrapping_add(byte2), if 0xff - byte1 < byte2 {
                 Flags::new().set(Flag::Overflow, Bit::True)
@@ -526,18 +634,6 @@ fn apply_to_u8s(byte1: u8, byte2: u8, op: Mnemonic) -> (u8, Flags) {
                     .set(Flag::Carry, Bit::False)
             })
         },
-        Mnemonic::AND => {
-            (byte1 & byte2, Flags::new())
-        },
-        Mnemonic::OR => {
-            (byte1 | byte2, Flags::new())
-        },
-        Mnemonic::XOR => {
-            (byte1 ^ byte2, Flags::new())
-        },
-        Mnemonic::SHL => {
-            (byte1 << byte2, Flags::new())
-        },
-        _ => panic!("Operation {:?} not implemented for bytes.", op)
-    }
+        _ => return Err(format!("{:?} not implemented for bytes.", op))
+    })
 }
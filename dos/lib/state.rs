@@ -11,7 +11,8 @@ pub struct State<'a> {
     pub ip : u16,
     regs : Registers,
     flags : Flags,
-    memory : Memory<'a>
+    memory : Memory<'a>,
+    load_module : &'a LoadModule
 }
 
 impl<'a> StateTrait<State<'a>> for State<'a> {
@@ -26,7 +27,8 @@ impl<'a> StateTrait<State<'a>> for State<'a> {
             ip: self.ip,
             regs: self.regs.union(state.regs),
             flags: self.flags.union(state.flags),
-            memory: self.memory.union(state.memory)
+            memory: self.memory.union(state.memory),
+            load_module: self.load_module
         }
     }
 
@@ -41,6 +43,51 @@ impl<'a> StateTrait<State<'a>> for State<'a> {
     fn combine(&self, state: &State<'a>) -> CombineResult<State<'a>> {
         CombineResult::Uncombinable
     }
+
+    fn widen(&self, state: &State<'a>) -> Option<State<'a>> {
+        if self.cs != state.cs || self.ip != state.ip {
+            return None;
+        }
+        Some(State {
+            cs: self.cs,
+            ip: self.ip,
+            regs: self.regs.clone().widen(state.regs.clone()),
+            flags: self.flags.union(state.flags),
+            memory: self.memory.clone().widen(state.memory.clone()),
+            load_module: self.load_module
+        })
+    }
+
+    // Flags only take a few values, so widening them is just union and
+    // there's nothing to narrow.
+    fn narrow(&self, state: &State<'a>) -> Option<State<'a>> {
+        if self.cs != state.cs || self.ip != state.ip {
+            return None;
+        }
+        Some(State {
+            cs: self.cs,
+            ip: self.ip,
+            regs: self.regs.clone().narrow(state.regs.clone()),
+            flags: self.flags,
+            memory: self.memory.clone().narrow(state.memory.clone()),
+            load_module: self.load_module
+        })
+    }
+
+    // Unlike Display, this doesn't need DS to be known, since memory
+    // is shown by address.
+    fn debug_string(&self) -> String {
+        let regs = &self.regs;
+        let line1 = format!("AX={:?}  BX={:?}  CX={:?}  DX={:?}  SP={:?}  BP={:?}  SI={:?}  DI={:?}\n",
+            regs.ax, regs.bx, regs.cx, regs.dx, regs.sp, regs.bp, regs.si, regs.di);
+        let line2 = format!("DS={:?}  ES={:?}  SS={:?}  CS={:04x}  IP={:04x}  {}\n",
+            regs.ds, regs.es, regs.ss, self.cs, self.ip, self.flags);
+        let mut memory = String::new();
+        for (address, value) in self.memory.get_deltas() {
+            memory.push_str(format!("[{:x}] = {:?}\t", address, value).as_str());
+        }
+        format!("{}{}{}", line1, line2, memory)
+    }
 }
 
 impl<'a> State<'a> {
@@ -52,7 +99,8 @@ impl<'a> State<'a> {
             flags : Flags::new(),
             memory : Memory::new(&load_module.buffer,
                 16 * load_module.memory_segment as usize,
-                Endian::Little)
+                Endian::Little),
+            load_module : load_module
         }
     }
     
@@ -60,6 +108,13 @@ impl<'a> State<'a> {
         16 * self.cs as usize + self.ip as usize
     }
 
+    // Returns the offset in the file of the next instruction, going by
+    // where the load module was put in memory.
+    pub fn next_inst_offset(&self) -> usize {
+        (self.next_inst_address() + self.load_module.file_offset)
+            .wrapping_sub(16 * self.load_module.memory_segment as usize)
+    }
+
     pub fn get_reg8(&self, reg: Register) -> Byte {
         match reg {
             Register::AL => self.regs.ax.split_low(),
@@ -75,7 +130,7 @@ impl<'a> State<'a> {
     }
 
     pub fn unpack_reg8(&self, reg: Register) -> Option<u8> {
-        match self.get_reg8(reg) {
+        match self.get_reg8(reg).expand() {
             Byte::Undefined | Byte::AnyValue => None,
             Byte::Int(set) => {
                 if set.len() > 1 {
@@ -83,7 +138,8 @@ impl<'a> State<'a> {
                 } else {
                     Some(*set.iter().collect::<Vec<&u8>>()[0])
                 }
-            }
+            },
+            _ => panic!("shouldn't be here")
         }
     }
 
@@ -115,7 +171,7 @@ impl<'a> State<'a> {
             word => word
         };
         
-        match register {
+        match register.expand() {
             Word::Undefined | Word::AnyValue => None,
             Word::Int(set) => {
                 if set.len() > 1 {
@@ -231,11 +287,11 @@ impl<'a> State<'a> {
                 panic!("can't get word from byte source"),
             Operand::Register8(reg) => self.get_reg8(reg),
             Operand::Imm8(imm) => Byte::new(imm as u8),
-            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment) {
+            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment).expand() {
                 Word::Undefined => Byte::Undefined,
                 Word::AnyValue => panic!("trying to read from unlimited segment"),
                 Word::Int(segments) => {
-                    match self.pointer_offset(pointer.value) {
+                    match self.pointer_offset(pointer.value).expand() {
                         Word::Undefined => Byte::Undefined,
                         Word::AnyValue => panic!("trying to read from unlimited offset"),
                         Word::Int(offsets) => self.read_memory_byte(segments, offsets),
@@ -253,11 +309,11 @@ impl<'a> State<'a> {
                 panic!("can't get word from byte source"),
             Operand::Register16(reg) => self.get_reg16(reg),
             Operand::Imm16(imm) => Word::new(imm as u16),
-            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment) {
+            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment).expand() {
                 Word::Undefined => Word::Undefined,
                 Word::AnyValue => panic!("trying to read from unlimited segment"),
                 Word::Int(segments) => {
-                    match self.pointer_offset(pointer.value) {
+                    match self.pointer_offset(pointer.value).expand() {
                         Word::Undefined => Word::Undefined,
                         Word::AnyValue => panic!("trying to read from unlimited offset"),
                         Word::Int(offsets) => self.read_memory_word(segments, offsets),
@@ -280,12 +336,12 @@ impl<'a> State<'a> {
         match operand {
             Operand::Register16(target_reg) =>
                 self.set_reg16(target_reg, word),
-            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment) {
+            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment).expand() {
                 Word::Undefined => self,
                 Word::AnyValue =>
                     panic!("trying to write to memory with unlimited segment."),
                 Word::Int(segments) => {
-                    match self.pointer_offset(pointer.value) {
+                    match self.pointer_offset(pointer.value).expand() {
                         Word::Undefined =>
                             panic!("trying to write to undefined memory offset."),
                         Word::AnyValue =>
@@ -319,12 +375,12 @@ impl<'a> State<'a> {
         match operand {
             Operand::Register8(target_reg) =>
                 self.set_reg8(target_reg, byte),
-            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment) {
+            Operand::Pointer(pointer) => match self.get_reg16(pointer.segment).expand() {
                 Word::Undefined => self,
                 Word::AnyValue =>
                     panic!("trying to write to memory with unlimited segment."),
                 Word::Int(segments) => {
-                    match self.pointer_offset(pointer.value) {
+                    match self.pointer_offset(pointer.value).expand() {
                         Word::Undefined =>
                             panic!("trying to write to undefined memory offset."),
                         Word::AnyValue =>
@@ -469,6 +525,38 @@ impl Registers {
         }
     }
 
+    pub fn widen(self, regs: Registers) -> Registers {
+        Registers {
+            ax: self.ax.widen(regs.ax),
+            bx: self.bx.widen(regs.bx),
+            cx: self.cx.widen(regs.cx),
+            dx: self.dx.widen(regs.dx),
+            sp: self.sp.widen(regs.sp),
+            bp: self.bp.widen(regs.bp),
+            si: self.si.widen(regs.si),
+            di: self.di.widen(regs.di),
+            ds: self.ds.widen(regs.ds),
+            es: self.es.widen(regs.es),
+            ss: self.ss.widen(regs.ss)
+        }
+    }
+
+    pub fn narrow(self, regs: Registers) -> Registers {
+        Registers {
+            ax: self.ax.narrow(regs.ax),
+            bx: self.bx.narrow(regs.bx),
+            cx: self.cx.narrow(regs.cx),
+            dx: self.dx.narrow(regs.dx),
+            sp: self.sp.narrow(regs.sp),
+            bp: self.bp.narrow(regs.bp),
+            si: self.si.narrow(regs.si),
+            di: self.di.narrow(regs.di),
+            ds: self.ds.narrow(regs.ds),
+            es: self.es.narrow(regs.es),
+            ss: self.ss.narrow(regs.ss)
+        }
+    }
+
     pub fn is_subset(&self, regs: &Registers) -> bool {
         self.ax.is_subset(&regs.ax) &&
         self.bx.is_subset(&regs.bx) &&
@@ -1,7 +1,12 @@
 mod analyse;
+mod recur;
 mod defs;
 mod graph;
 mod x86;
+mod x86analyzer;
+mod decompile;
+
+use defs::main::{Architecture, Limits};
 
 fn main() {
     use std::env;
@@ -16,17 +21,46 @@ fn main() {
 			"Failed to read into buffer.");
 
         let context = x86::dos::DOS::new(&buffer);
+        let lifter = x86::lift::Lifter { ip_base: context.ip_base() };
 
-        let result = analyse::analyse(
-            &buffer, x86::arch::X86 {},
-            context.load_module.file_offset
+        let arch = x86::arch::X86 { ip_base: context.ip_base() };
+        let roots = recur::unreached_entries(&buffer, arch, context.load_module.file_offset);
+        let result = analyse::analyse_from(
+            &buffer, arch,
+            &x86analyzer::Analyzer { ip_base: context.ip_base() },
+            context.load_module.file_offset, &roots, &Limits::none()
         );
 
         match result {
-            Ok(graph) => println!("{}", graph),
+            Ok(mut graph) => {
+                if env::args().nth(2).map_or(false, |flag| flag == "--decompile") {
+                    graph.lift(&lifter);
+                    match decompile::decompile(&graph, &lifter) {
+                        Ok(functions) => for function in functions {
+                            println!("{}", function);
+                        },
+                        Err(error) => println!("{}", error)
+                    }
+                } else if env::args().nth(2).map_or(false, |flag| flag == "--reaching") {
+                    // --reaching shows which instructions may have set
+                    // the registers each INT 21h reads.
+                    graph.lift(&lifter);
+                    match graph.call_graph() {
+                        Ok(call_graph) => {
+                            let queried = graph::dataflow::reaching_reads_of(&graph, &call_graph, x86::lift::dos_call_reads);
+                            print!("{}", graph::dataflow::format_reaching_reads(&graph, &queried, &lifter, 0));
+                        },
+                        Err(error) => println!("{}", error)
+                    }
+                } else {
+                    println!("{}", graph);
+                    print!("{}", recur::describe_overlaps(graph.listing()));
+                    x86::arch::X86::print_listing(graph.listing());
+                }
+            },
             Err(error) => println!("{}", error)
         }
     } else {
-		println!("usage: dis <file-to-disassemble>");
+		println!("usage: dis <file-to-disassemble> [--decompile | --reaching]");
     }
 }
@@ -1,8 +1,11 @@
-mod analyse;
+mod recur;
+mod classify;
 mod defs;
 mod graph;
 mod x86;
 
+use defs::main::Architecture;
+
 fn main() {
 	use std::env;
 	use std::io::Read;
@@ -16,15 +19,28 @@ fn main() {
 			"Failed to read into buffer.");
 
         let context = x86::dos::DOS::new(&buffer);
+        let arch = x86::arch::X86 { ip_base: context.ip_base() };
+
+        let mode = match env::args().nth(2) {
+            Some(ref arg) if arg == "--linear" => recur::Mode::Linear,
+            Some(ref arg) if arg == "--hybrid" => recur::Mode::Hybrid,
+            _ => recur::Mode::Recursive
+        };
 
-        let listing = analyse::recursive_descent(
+        let mut listing = recur::disassemble(
             &buffer,
-            x86::arch::X86 {},
-            context.load_module.file_offset
+            arch,
+            context.load_module.file_offset,
+            mode
         );
 
-        listing.print_instructions();
+        print!("{}", recur::describe_invalid(&listing));
+        print!("{}", recur::describe_overlaps(&listing));
+
+        classify::classify(&buffer, &mut listing, arch);
+
+        x86::arch::X86::print_listing(&listing);
     } else {
-		println!("usage: dis <file-to-disassemble>");
+		println!("usage: dis <file-to-disassemble> [--linear | --hybrid]");
     }
 }
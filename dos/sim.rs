@@ -1,15 +1,23 @@
-mod analyse;
 mod defs;
 mod graph;
 mod x86;
+mod exhaust;
+
+use defs::main::{Architecture, Limits};
+use x86::arch::X86;
+use graph::worklist::Strategy;
 
 fn main() {
 	use std::env;
 	use std::io::Read;
 	use std::fs::File;
+	use std::time::{Duration, Instant};
+
+    let mut args = env::args();
+    args.next();
 
-	if let Some(arg) = env::args().nth(1) {
-		let mut file = File::open(arg).expect(
+	if let Some(file_arg) = args.next() {
+		let mut file = File::open(file_arg).expect(
 			"Failed to open file.");
 		let mut buffer = Vec::new();
 		file.read_to_end(&mut buffer).expect(
@@ -18,18 +26,93 @@ fn main() {
         let context = x86::dos::DOS::new(&buffer);
         let initial_state = context.initial_state(&buffer);
 
-        let (analysis, error) = analyse::simulate(
+        let mut log_type = None;
+        let mut widen_after = 0;
+        let mut narrowing_passes = 0;
+        let mut limits = Limits::none();
+        let mut threads = 1;
+
+        while let Some(arg) = args.next() {
+            if arg == "-v" {
+                log_type = Some(exhaust::LogType::Verbose);
+            }
+
+            if arg == "-c" {
+                log_type = Some(exhaust::LogType::StateCount);
+            }
+
+            // -w <visits> widens states at loop headers once they've
+            // been visited that many times. States aren't widened
+            // unless it's given, or if it's 0.
+            if arg == "-w" {
+                widen_after = args.next().and_then(|visits| visits.parse().ok())
+                    .expect("-w needs a number of visits.");
+            }
+
+            // -n <passes> narrows widened states in that many passes.
+            if arg == "-n" {
+                narrowing_passes = args.next().and_then(|passes| passes.parse().ok())
+                    .expect("-n needs a number of passes.");
+            }
+
+            // -s <states>, -i <instructions> and -t <seconds> stop
+            // exploring after that many states, simulated instructions
+            // or seconds, printing what was found so far.
+            if arg == "-s" {
+                limits.max_states = Some(args.next().and_then(|states| states.parse().ok())
+                    .expect("-s needs a number of states."));
+            }
+
+            if arg == "-i" {
+                limits.max_steps = Some(args.next().and_then(|steps| steps.parse().ok())
+                    .expect("-i needs a number of instructions."));
+            }
+
+            if arg == "-t" {
+                let seconds = args.next().and_then(|seconds| seconds.parse().ok())
+                    .expect("-t needs a number of seconds.");
+                limits.deadline = Some(Instant::now() + Duration::from_secs(seconds));
+            }
+
+            // -j <threads> simulates states ahead of time on that many
+            // threads. The listing is the same as with one.
+            if arg == "-j" {
+                threads = args.next().and_then(|threads| threads.parse().ok())
+                    .filter(|&threads| threads > 0)
+                    .expect("-j needs a number of threads.");
+            }
+        }
+
+        let widening = match widen_after {
+            0 => None,
+            _ => Some(exhaust::Widening {
+                widen_after,
+                narrowing_passes
+            })
+        };
+
+        let result = exhaust::simulate_exhaustively(
             &buffer,
+            &context,
             initial_state,
-            x86::arch::X86 {},
-            &context
+            X86 { ip_base: context.ip_base() },
+            log_type,
+            widening,
+            limits,
+            Strategy::DepthFirst,
+            threads,
         );
 
-        analysis.print_instructions();
-        if let Some(message) = error {
-            println!("{}", message);
+        let (state_graph, stop) = match result {
+            exhaust::ExhaustResult::Complete(state_graph) => (state_graph, None),
+            exhaust::ExhaustResult::Partial(state_graph, stop) => (state_graph, Some(stop))
+        };
+
+        X86::print_listing(state_graph.listing());
+        if let Some(stop) = stop {
+            println!("{}", stop);
         }
     } else {
-		println!("usage: dis <file-to-disassemble>");
+		println!("usage: dis <file-to-disassemble> [-v | -c] [-w <visits>] [-n <passes>] [-s <states>] [-i <instructions>] [-t <seconds>] [-j <threads>]");
     }
 }